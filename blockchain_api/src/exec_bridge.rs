@@ -2,7 +2,7 @@ use std::fs::OpenOptions;
 use std::net::IpAddr;
 use std::time::{Duration, Instant};
 use std::{collections::HashMap, time::SystemTime};
-use lorawan::{utils::{PrettyHexSlice, eui::EUI64}, device::Device};
+use lorawan::{utils::{PrettyHexSlice, eui::EUI64}, device::Device, lorawan_packet::join::JoinRequestType};
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
 use tokio::process::Command;
@@ -333,6 +333,20 @@ impl crate::BlockchainClient for BlockchainExeClient {
         Ok(())
     }
     
+    async fn update_rj_count(&self, dev_eui: &EUI64, rejoin_type: JoinRequestType, rj_count: u16) -> Result<(), BlockchainError> {
+        let args = BlockchainArgs {
+            Args: vec![
+                "UpdateRJCount".to_owned(),
+                dev_eui.to_string(),
+                rejoin_type.to_byte().to_string(),
+                rj_count.to_string(),
+            ],
+        };
+
+        self.create_command::<()>(true,args, None).await.map_err(BlockchainError::GenericError)?;
+        Ok(())
+    }
+
     async fn create_uplink(&self, packet: &[u8], answer: Option<&[u8]>) -> Result<(),BlockchainError> {
         let date = format!("{}",SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
         let mut transient_data = HashMap::from([
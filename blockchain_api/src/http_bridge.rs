@@ -1,6 +1,6 @@
 use std::{net::{Ipv4Addr, IpAddr}, time::SystemTime};
 
-use lorawan::{utils::{PrettyHexSlice, eui::EUI64}, device::Device};
+use lorawan::{utils::{PrettyHexSlice, eui::EUI64}, device::Device, lorawan_packet::join::JoinRequestType};
 use reqwest::{Client, header::{HeaderMap, CONTENT_TYPE}};
 use serde_json::json;
 
@@ -218,6 +218,10 @@ impl crate::BlockchainClient for BlockchainHTTPClient {
         }
     }
 
+    async fn update_rj_count(&self, _dev_eui: &EUI64, _rejoin_type: JoinRequestType, _rj_count: u16) -> Result<(), BlockchainError> {
+        unimplemented!("Not implemented because deprecated")
+    }
+
     async fn join_procedure(&self, _join_request: &[u8], _join_accept: &[u8], _dev_eui: &EUI64) -> Result<HyperledgerJoinDeduplicationAns,BlockchainError> {
         unimplemented!("Not implemented because deprecated")
     }
@@ -13,7 +13,7 @@ use lorawan::device::session_context::{
 use lorawan::device::{ActivationMode, Device, DeviceClass, LoRaWANVersion};
 use lorawan::encryption::key::Key;
 use lorawan::lorawan_packet::join::JoinRequestType;
-use lorawan::regional_parameters::region::Region;
+use lorawan::regional_parameters::region::{Region, RegionalParameters};
 use lorawan::utils::eui::EUI64;
 use serde::{Deserialize, Serialize};
 
@@ -60,9 +60,10 @@ pub struct BlockchainDeviceConfig {
 impl From<BlockchainDeviceConfig> for Device {
     fn from(c: BlockchainDeviceConfig) -> Self {
         let mut d = Device::new(
-            c.class, None, c.dev_eui, c.join_eui, c.nwk_key, c.app_key, c.version,
+            c.class, Some(RegionalParameters::new(c.region)), c.dev_eui, c.join_eui, c.nwk_key, c.app_key, c.version,
         );
         d.set_dev_nonce(c.dev_nonce);
+        d.join_context_mut().update_rj_count1(c.rj_count1);
         d.set_last_join_request_received(c.last_join_request_received);
         d
     }
@@ -74,7 +75,7 @@ impl From<&Device> for BlockchainDeviceConfig {
         Self {
             class: *d.class(),
             version: *d.version(),
-            region: *d.regional_parameters().unwrap().region(),
+            region: *d.regional_parameters().unwrap_or_default().region(),
             activation_mode: if d.is_otaa() { ActivationMode::OTAA} else { ActivationMode::ABP },
             dev_nonce: d.dev_nonce(),
             dev_eui: *d.dev_eui(),
@@ -218,6 +219,8 @@ pub trait BlockchainClient: Send + Sync {
     fn create_device_config(&self, device: &Device) -> impl std::future::Future<Output = Result<(), BlockchainError>> + Send;
     fn delete_device(&self, dev_eui: &EUI64) -> impl std::future::Future<Output = Result<(), BlockchainError>> + Send;
     fn delete_device_session(&self, dev_addr: &[u8; 4]) -> impl std::future::Future<Output = Result<(), BlockchainError>> + Send;
+    ///Store the RJcount of an accepted rejoin request, RJcount1 of the device for type 1 and RJcount0 of its session for types 0 and 2
+    fn update_rj_count(&self, dev_eui: &EUI64, rejoin_type: JoinRequestType, rj_count: u16) -> impl std::future::Future<Output = Result<(), BlockchainError>> + Send;
     fn create_uplink(&self, packet: &[u8], answer: Option<&[u8]>) -> impl std::future::Future<Output = Result<(),BlockchainError>> + Send;
     fn join_procedure(&self, join_request: &[u8], join_accept: &[u8], dev_id: &EUI64) -> impl std::future::Future<Output = Result<HyperledgerJoinDeduplicationAns,BlockchainError>> + Send;
     fn session_generation(&self, keys: &[&str], dev_eui: &str) -> impl std::future::Future<Output = Result<(),BlockchainError>> + Send;
@@ -1,5 +1,5 @@
 use rand::RngCore;
-use std::{net::{IpAddr, Ipv4Addr}, vec};
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr}, sync::{Arc, Mutex}, vec};
 
 use lorawan::{
     device::{
@@ -7,7 +7,8 @@ use lorawan::{
         Device, DeviceClass, LoRaWANVersion,
     },
     encryption::key::Key,
-    utils::eui::EUI64,
+    lorawan_packet::{join::JoinRequestType, payload::Payload, LoRaWANPacket, MicContext},
+    utils::{eui::EUI64, increment_nonce, nonce_valid},
 };
 use rand::SeedableRng;
 
@@ -38,8 +39,25 @@ macro_rules! s {
     };
 }
 
+///What the mock stores of the devices, the generated devices start from 0
 #[derive(Default)]
-pub struct BlockchainMockClient {}
+pub struct MockLedger {
+    ///RJcount0 and RJcount1 of the accepted rejoin requests
+    rj_counts: Mutex<HashMap<EUI64, (u16, u16)>>,
+    ///FCntUp, NFCntDown and AFCntDown of the uplinks and answers stored
+    f_cnts: Mutex<HashMap<[u8; 4], (u32, u32, u32)>>,
+    ///devices that joined, with the nonces and the session of their last join
+    joined: Mutex<HashMap<EUI64, Device>>,
+    ///DevEUI of the sessions created by the joins
+    sessions: Mutex<HashMap<[u8; 4], EUI64>>,
+}
+
+#[derive(Default)]
+pub struct BlockchainMockClient {
+    nc_id: String,
+    nc_ids: Vec<String>,
+    ledger: Arc<MockLedger>,
+}
 
 impl BlockchainMockClient {
     pub fn create_uninitialized_device(t_seed: &[u8]) -> Device {
@@ -128,16 +146,34 @@ impl BlockchainMockClient {
         device
     }
 
+    ///Unconfirmed data uplink on FPort 1 with the given FCnt, sending the same FCnt again gives a retransmission
+    pub fn create_data_up(device: &mut Device, fcnt: u32, payload: &[u8], mic_context: &MicContext) -> Vec<u8> {
+        device.session_mut().unwrap().network_context_mut().update_f_cnt_up(fcnt - 1);
+        device.create_uplink(Some(payload), false, Some(1), None, mic_context).unwrap()
+    }
 }
 
 #[derive(Default, Clone)]
-pub struct BlockchainMockClientConfig;
+pub struct BlockchainMockClientConfig {
+    ///network controller using the client, the first one asking wins the join procedure
+    pub nc_id: String,
+    ///network controllers listed in every session, they run the consensus round of the uplinks
+    pub nc_ids: Vec<String>,
+    ///shared by the clients created from the config, like the ledger of the network controllers
+    pub ledger: Arc<MockLedger>,
+}
+
+///Full counter of a frame from its lower half and the last value stored
+fn full_fcnt(last: u32, fcnt: u16) -> u32 {
+    let same_half = last & 0xffff0000 | fcnt as u32;
+    if same_half < last { same_half.wrapping_add(0x10000) } else { same_half }
+}
 
 impl BlockchainClient for BlockchainMockClient {
     type Config = BlockchainMockClientConfig;
 
-    async fn from_config(_config: &Self::Config) -> Result<Box<Self>, BlockchainError> {
-        Ok(Box::default())
+    async fn from_config(config: &Self::Config) -> Result<Box<Self>, BlockchainError> {
+        Ok(Box::new(Self { nc_id: config.nc_id.clone(), nc_ids: config.nc_ids.clone(), ledger: config.ledger.clone() }))
     }
 
     async fn get_hash(&self) -> Result<String, BlockchainError> {
@@ -150,19 +186,33 @@ impl BlockchainClient for BlockchainMockClient {
         &self,
         dev_addr: &[u8; 4],
     ) -> Result<BlockchainDeviceSession, BlockchainError> {
-        let d = Self::create_initialized_device(dev_addr);
-        Ok(BlockchainDeviceSession::from(
+        let joined = self.ledger.sessions.lock().unwrap().get(dev_addr).and_then(|dev_eui| self.ledger.joined.lock().unwrap().get(dev_eui).cloned());
+        let d = joined.unwrap_or_else(|| Self::create_initialized_device(dev_addr));
+        let mut session = BlockchainDeviceSession::from(
             d.session().unwrap(),
             d.dev_eui(),
-        ))
+        );
+        session.nc_ids = self.nc_ids.clone();
+        if let Some((rj_count0, _)) = self.ledger.rj_counts.lock().unwrap().get(d.dev_eui()) {
+            session.rj_count0 = *rj_count0;
+        }
+        if let Some((f_cnt_up, nf_cnt_dwn, af_cnt_dwn)) = self.ledger.f_cnts.lock().unwrap().get(dev_addr) {
+            (session.f_cnt_up, session.nf_cnt_dwn, session.af_cnt_dwn) = (*f_cnt_up, *nf_cnt_dwn, *af_cnt_dwn);
+        }
+        Ok(session)
     }
 
     async fn get_device_config(
         &self,
         dev_eui: &EUI64,
     ) -> Result<BlockchainDeviceConfig, BlockchainError> {
-        let d = Self::create_initialized_device(&**dev_eui);
-        Ok((&d).into())
+        let joined = self.ledger.joined.lock().unwrap().get(dev_eui).cloned();
+        let d = joined.unwrap_or_else(|| Self::create_initialized_device(&**dev_eui));
+        let mut config: BlockchainDeviceConfig = (&d).into();
+        if let Some((_, rj_count1)) = self.ledger.rj_counts.lock().unwrap().get(dev_eui) {
+            config.rj_count1 = *rj_count1;
+        }
+        Ok(config)
     }
 
     async fn get_device(&self, dev_eui: &EUI64) -> Result<Device, BlockchainError> {
@@ -188,19 +238,63 @@ impl BlockchainClient for BlockchainMockClient {
         Ok(())
     }
 
+    async fn update_rj_count(&self, dev_eui: &EUI64, rejoin_type: JoinRequestType, rj_count: u16) -> Result<(), BlockchainError> {
+        let mut rj_counts = self.ledger.rj_counts.lock().unwrap();
+        let (rj_count0, rj_count1) = rj_counts.entry(*dev_eui).or_default();
+        match rejoin_type {
+            JoinRequestType::RejoinRequest1 => *rj_count1 = rj_count,
+            _ => *rj_count0 = rj_count,
+        }
+        Ok(())
+    }
+
     async fn create_uplink(
         &self,
-        _packet: &[u8],
-        _answer: Option<&[u8]>,
+        packet: &[u8],
+        answer: Option<&[u8]>,
     ) -> Result<(), BlockchainError> {
+        //the counters are in clear in the FHDR, the frames are not decrypted
+        let uplink = match LoRaWANPacket::from_bytes(packet, None, true).map(|p| p.into_payload()) {
+            Ok(Payload::MACPayload(p)) => p,
+            _ => return Err(BlockchainError::GenericError("Not a data uplink".to_string())),
+        };
+        let mut f_cnts = self.ledger.f_cnts.lock().unwrap();
+        let (f_cnt_up, nf_cnt_dwn, af_cnt_dwn) = f_cnts.entry(uplink.fhdr().dev_addr()).or_default();
+        *f_cnt_up = full_fcnt(*f_cnt_up, uplink.fhdr().fcnt());
+        if let Some(Ok(Payload::MACPayload(p))) = answer.map(|a| LoRaWANPacket::from_bytes(a, None, false).map(|p| p.into_payload())) {
+            let counter = if p.is_application() { af_cnt_dwn } else { nf_cnt_dwn };
+            *counter = full_fcnt(*counter, p.fhdr().fcnt());
+        }
         Ok(())
     }
 
-    async fn join_procedure(&self, _join_request: &[u8], _join_accept: &[u8], _dev_eui: &EUI64) -> Result<HyperledgerJoinDeduplicationAns,BlockchainError> {
-    Ok(HyperledgerJoinDeduplicationAns {
-        winner: "a1b2c3d4".to_owned(),
-            keys: vec!["key1".to_owned(), "key2".to_owned()]
-        })
+    async fn join_procedure(&self, join_request: &[u8], join_accept: &[u8], dev_eui: &EUI64) -> Result<HyperledgerJoinDeduplicationAns,BlockchainError> {
+        let keys = vec!["key1".to_owned(), "key2".to_owned()];
+        //rejoins are not stored, nobody wins them
+        let dev_nonce = match LoRaWANPacket::from_bytes(join_request, None, true).map(|p| p.into_payload()) {
+            Ok(Payload::JoinRequest(jr)) => jr.dev_nonce(),
+            _ => return Ok(HyperledgerJoinDeduplicationAns { winner: "a1b2c3d4".to_owned(), keys }),
+        };
+        let mut device: Device = self.get_device_config(dev_eui).await?.into();
+        let (dev_nonce_valid, dev_nonce_looped) = nonce_valid(dev_nonce, device.dev_nonce() as u16);
+        if !dev_nonce_valid {
+            //another network controller already stored this join
+            return Ok(HyperledgerJoinDeduplicationAns { winner: String::new(), keys });
+        }
+        device.set_dev_nonce(increment_nonce(dev_nonce, device.dev_nonce(), dev_nonce_looped));
+
+        let join_accept = LoRaWANPacket::from_bytes(join_accept, Some(&device), false).map_err(|e| BlockchainError::GenericError(e.to_string()))?;
+        let Payload::JoinAccept(ja) = join_accept.payload() else {
+            return Err(BlockchainError::GenericError("Not a join accept".to_string()));
+        };
+        let join_nonce = *ja.join_nonce();
+        device.join_context_mut().update_join_nonce(u32::from_le_bytes([join_nonce[0], join_nonce[1], join_nonce[2], 0]));
+        device.generate_session_context(ja).map_err(|e| BlockchainError::GenericError(e.to_string()))?;
+
+        self.ledger.sessions.lock().unwrap().insert(*ja.dev_addr(), *dev_eui);
+        self.ledger.f_cnts.lock().unwrap().remove(ja.dev_addr());
+        self.ledger.joined.lock().unwrap().insert(*dev_eui, device);
+        Ok(HyperledgerJoinDeduplicationAns { winner: self.nc_id.clone(), keys })
     }
 
     
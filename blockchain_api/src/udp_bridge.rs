@@ -1,6 +1,6 @@
 use std::{fs::{File, OpenOptions}, net::IpAddr, time::{Instant, SystemTime, UNIX_EPOCH}};
 
-use lorawan::{device::Device, utils::{eui::EUI64, PrettyHexSlice}};
+use lorawan::{device::Device, lorawan_packet::join::JoinRequestType, utils::{eui::EUI64, PrettyHexSlice}};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::{net::UdpSocket, sync::Mutex};
@@ -290,6 +290,32 @@ impl crate::BlockchainClient for BlockchainUDPClient {
             Ok(())
         }
     }
+    async fn update_rj_count(&self, dev_eui: &EUI64, rejoin_type: JoinRequestType, rj_count: u16) -> Result<(), BlockchainError> {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.map_err(|_| BlockchainError::Error("Cannot connect to "))?;
+
+        let v = json!({
+            "type": "update_rj_count",
+            "dev_id": dev_eui.to_string(),
+            "rejoin_type": rejoin_type.to_byte(),
+            "rj_count": rj_count
+        });
+
+        sock.send_to(v.to_string().as_bytes(), format!("127.0.0.1:{}", self.port)).await.map_err(|_| BlockchainError::Error("Cannot send data to API server"))?;
+
+        let mut vec = [0_u8; 1024];
+        let recvd = sock.recv(&mut vec).await.map_err(|_| BlockchainError::Error("Cannot receive data from API server"))?;
+
+        let ans = serde_json::from_str::<BlockchainUDPAns<()>>(std::str::from_utf8(&vec[..recvd]).unwrap()).map_err(|e| {
+            println!("{e}");
+            BlockchainError::JSONParsingError
+        })?;
+
+        if !ans.ok {
+            Err(BlockchainError::GenericError(ans.error_message.unwrap()))
+        } else {
+            Ok(())
+        }
+    }
     async fn delete_device(&self, _dev_eui: &EUI64) -> Result<(), BlockchainError> {
         unimplemented!("delete_device")
     }
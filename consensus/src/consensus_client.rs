@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use tonic::{transport::{Certificate, Channel, Identity}, Request};
 
-use crate::{consensus_server::ConsensusServer, protos::{uplink_deduplication_consensus_client::UplinkDeduplicationConsensusClient, ReceptionSetDisseminationRequest, ReceptionSetDisseminationResponse, UplinkReceivedDisseminationResponse}, ConsensusCerts, ConsensusError};
+use crate::{consensus_server::ConsensusServer, protos::{uplink_deduplication_consensus_client::UplinkDeduplicationConsensusClient, ReceptionSetDisseminationRequest, ReceptionSetDisseminationResponse, UplinkReceivedDisseminationResponse}, ConsensusCerts, ConsensusError, ConsensusTransport};
 
 
 pub struct ConsensusClient {
@@ -39,3 +39,16 @@ impl ConsensusClient {
         Ok(response.into_inner())
     }
 }
+
+///gRPC over mutual TLS, the certificates tell the other servers who is calling
+impl ConsensusTransport for ConsensusCerts {
+    async fn broadcast_reception(&self, _src: &str, dest: &str, dev_addr: &str, packet: &[u8], rssi: i32) -> Result<UplinkReceivedDisseminationResponse, tonic::Status> {
+        let mut client = ConsensusClient::new(format!("https://{}:5050", dest), self).await.map_err(|e| tonic::Status::unavailable(format!("{e:?}")))?;
+        client.broadcast_reception(dev_addr, packet, rssi).await
+    }
+
+    async fn broadcast_nc_set(&self, _src: &str, dest: &str, dev_addr: String, set: HashMap<String, i32>) -> Result<ReceptionSetDisseminationResponse, tonic::Status> {
+        let mut client = ConsensusClient::new(format!("https://{}:5050", dest), self).await.map_err(|e| tonic::Status::unavailable(format!("{e:?}")))?;
+        client.broadcast_nc_set(dev_addr, set).await
+    }
+}
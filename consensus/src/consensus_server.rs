@@ -25,11 +25,15 @@ use crate::{
         ReceptionSetDisseminationRequest, ReceptionSetDisseminationResponse,
         UplinkReceivedDisseminationRequest, UplinkReceivedDisseminationResponse,
     },
-    ConsensusCerts, ConsensusError, ConsensusMessage, ConsensusRound, ConsensusState,
+    ConsensusCerts, ConsensusError, ConsensusMessage, ConsensusRound, ConsensusState, ConsensusTransport,
 };
 
 type ConsensusResult<T> = Result<Response<T>, tonic::Status>;
-type Rounds = Arc<RwLock<HashMap<String, Mutex<ConsensusRound>>>>;
+pub(crate) type Rounds = Arc<RwLock<HashMap<String, Mutex<ConsensusRound>>>>;
+
+///A round not ended by then is lost, the receive windows of the device are over. Copies of the packet arriving
+///meanwhile, from other gateways of the same network controller, do not start a new round
+pub const ROUND_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ConsensusConfig {
@@ -56,6 +60,13 @@ impl ConsensusServer {
         ConsensusServer { id, rounds, certs }
     }
 
+    ///Server whose messages go through the transport instead of gRPC, e.g. to the other servers of the process
+    pub(crate) fn run_with_transport(id: String, rounds: Rounds, transport: impl ConsensusTransport) -> Sender<ConsensusMessage> {
+        let (sender, receiver) = tokio::sync::mpsc::channel::<ConsensusMessage>(10000);
+        tokio::spawn(ConsensusServer::consensus_receiver_broadcaster_routine(rounds, receiver, id, transport));
+        sender
+    }
+
     pub fn run_instance(
         id: String,
         config: ConsensusConfig,
@@ -88,36 +99,48 @@ impl ConsensusServer {
         rounds: Rounds,
         mut receiver: Receiver<ConsensusMessage>,
         id: String,
-        certs: ConsensusCerts,
+        transport: impl ConsensusTransport,
     ) {
+        let mut recent_packets: HashMap<Vec<u8>, Instant> = HashMap::new();
         while let Some(msg) = receiver.recv().await {
+            let started_at = Instant::now();
+            recent_packets.retain(|_, started| started_at.duration_since(*started) < ROUND_TIMEOUT);
+            if recent_packets.insert(msg.packet.clone(), started_at).is_some() {
+                //another gateway got the same packet, the round started with the first copy answers for it
+                let _ = msg.response.send(false);
+                continue;
+            }
+
             let mut nc_set = HashMap::new();
             nc_set.insert(id.clone(), 1);
             let dev_addr: String = msg.dev_addr.clone();
 
-            rounds.write().await.insert(
-                dev_addr,
-                Mutex::new(ConsensusRound {
-                    started_at: Instant::now(),
-                    status: ConsensusState::ReceivingDisseminations,
-                    nc_list: msg.nc_list.clone(),
-                    nc_set,
-                    packet: msg.packet.clone(),
-                    received_sets: Vec::from([id.clone()]),
-                    sender: msg.response,
-                }),
-            );
+            let round = ConsensusRound {
+                started_at,
+                status: ConsensusState::ReceivingDisseminations,
+                nc_list: msg.nc_list.clone(),
+                nc_set,
+                packet: msg.packet.clone(),
+                received_sets: Vec::from([id.clone()]),
+                sender: msg.response,
+            };
+            //the network controller is the only one of the round, there is nobody to agree with
+            if round.nc_list == [id.clone()] {
+                let winner = ConsensusServer::is_winner(&id, &round).await;
+                let _ = round.sender.send(winner);
+                continue;
+            }
+            rounds.write().await.insert(dev_addr.clone(), Mutex::new(round));
+            tokio::spawn(ConsensusServer::round_timeout(rounds.clone(), dev_addr, started_at));
 
             //tokio::time::sleep(Duration::from_millis(100)).await;
             let list = msg.nc_list.clone();
             for nc in list.iter() {
                 if nc != &id {
-                    //let mut client = ConsensusClient::new(format!("https://{}", get_addr(nc)), &certs).await.unwrap();
-                    let mut client = ConsensusClient::new(format!("https://{}:5050", nc), &certs).await.unwrap();
-                    match client.broadcast_reception(&msg.dev_addr, &msg.packet, msg.rssi).await {
+                    match transport.broadcast_reception(&id, nc, &msg.dev_addr, &msg.packet, msg.rssi).await {
                         Ok(UplinkReceivedDisseminationResponse { answer }) => {
                             if let Some(answer) = answer {
-                                if let Err(_e) = ConsensusServer::broadcast_reception_handler(&id, nc, &rounds, answer, &certs,).await {
+                                if let Err(_e) = ConsensusServer::broadcast_reception_handler(&id, nc, &rounds, answer, &transport).await {
                                     //eprintln!("[{id}]: {e:?}");
                                 }
                             }
@@ -129,6 +152,21 @@ impl ConsensusServer {
         }
     }
 
+    ///Lose the round if it is still running after ROUND_TIMEOUT, e.g. because a network controller of the list did not get the packet
+    async fn round_timeout(rounds: Rounds, dev_addr: String, started_at: Instant) {
+        tokio::time::sleep_until(started_at + ROUND_TIMEOUT).await;
+        let mut rounds = rounds.write().await;
+        let expired = match rounds.get(&dev_addr) {
+            Some(round) => round.lock().await.started_at == started_at,
+            None => false,
+        };
+        if expired {
+            if let Some(round) = rounds.remove(&dev_addr) {
+                let _ = round.into_inner().sender.send(false);
+            }
+        }
+    }
+
     pub async fn is_round_full(rounds: &Rounds, dev_addr: &str) -> bool {
         if let Some(round) = rounds.read().await.get(dev_addr) {
             let round = round.lock().await;
@@ -305,7 +343,7 @@ impl ConsensusServer {
         src: &str,
         rounds: &Rounds,
         r: UplinkReceivedDisseminationRequest,
-        certs: &ConsensusCerts,
+        transport: &impl ConsensusTransport,
     ) -> Result<Response<UplinkReceivedDisseminationResponse>, tonic::Status> {
         //println!("{} received a dissemination message from {}", id, src);
 
@@ -356,7 +394,7 @@ impl ConsensusServer {
 
                     let rounds = rounds.clone();
                     let id_cloned = id.to_string();
-                    let certs_cloned = certs.clone();
+                    let transport = transport.clone();
                     tokio::spawn(async move {
                         for nc in nc_list {
                             if nc != id_cloned {
@@ -366,9 +404,7 @@ impl ConsensusServer {
                                 //    ca_cert_path: format!("/home/rastafan/Documenti/Dottorato/code/DeLoRaN/lorawan-blockchain/pure_network/crypto-config/peerOrganizations/org1.dlwan.phd/peers/{}/tls/ca.crt", id_cloned),
                                 //};
 
-                                //let mut client = ConsensusClient::new(format!("https://{}", get_addr(&nc)), &certs_cloned).await.unwrap();
-                                let mut client = ConsensusClient::new( format!("https://{}:5050", nc), &certs_cloned, ).await.unwrap();
-                                match client .broadcast_nc_set(r.dev_addr.clone(), nc_set.clone()).await {
+                                match transport.broadcast_nc_set(&id_cloned, &nc, r.dev_addr.clone(), nc_set.clone()).await {
                                     Err(_e) => {} //eprintln!("[{id_cloned}]: {:?}", e),
                                     Ok(r) => {
                                         if let Some(answer) = r.answer {
@@ -1,5 +1,6 @@
-use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr}};
+use std::{collections::HashMap, future::Future, net::{Ipv4Addr, SocketAddr}};
 
+use protos::{ReceptionSetDisseminationResponse, UplinkReceivedDisseminationResponse};
 use serde::{Serialize, Deserialize};
 use tokio::{sync::oneshot::Sender, time::Instant};
 
@@ -9,6 +10,7 @@ pub mod protos {
 pub mod consensus_server;
 pub mod malicious_consensus_server;
 pub mod consensus_client;
+pub mod local_consensus;
 
 
 #[derive(Debug)]
@@ -20,6 +22,13 @@ pub struct ConsensusMessage {
     pub response: Sender<bool>
 }
 
+///How the consensus server of src reaches the one of dest
+pub trait ConsensusTransport: Clone + Send + Sync + 'static {
+    fn broadcast_reception(&self, src: &str, dest: &str, dev_addr: &str, packet: &[u8], rssi: i32) -> impl Future<Output = Result<UplinkReceivedDisseminationResponse, tonic::Status>> + Send;
+
+    fn broadcast_nc_set(&self, src: &str, dest: &str, dev_addr: String, set: HashMap<String, i32>) -> impl Future<Output = Result<ReceptionSetDisseminationResponse, tonic::Status>> + Send;
+}
+
 #[derive(PartialEq, Debug)]
 pub enum ConsensusState {
     ReceivingDisseminations,
@@ -0,0 +1,101 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    consensus_server::{ConsensusServer, Rounds},
+    protos::{ReceptionSetDisseminationRequest, ReceptionSetDisseminationResponse, UplinkReceivedDisseminationResponse},
+    ConsensusMessage, ConsensusTransport,
+};
+
+///Consensus servers of the same process, the messages are handed to the handlers of the other server without network or TLS.
+///Nothing waits on sockets, so the rounds can run on the virtual clock of a simulation
+#[derive(Clone, Default)]
+pub struct LocalConsensusNetwork {
+    servers: Arc<RwLock<HashMap<String, Rounds>>>,
+}
+
+impl LocalConsensusNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Start the consensus server of a network controller, the others of the network reach it by its id
+    pub fn run_instance(&self, id: String) -> Sender<ConsensusMessage> {
+        let rounds = Rounds::default();
+        self.servers.write().unwrap().insert(id.clone(), rounds.clone());
+        ConsensusServer::run_with_transport(id, rounds, self.clone())
+    }
+
+    fn rounds(&self, id: &str) -> Option<Rounds> {
+        self.servers.read().unwrap().get(id).cloned()
+    }
+}
+
+impl ConsensusTransport for LocalConsensusNetwork {
+    async fn broadcast_reception(&self, src: &str, dest: &str, dev_addr: &str, packet: &[u8], rssi: i32) -> Result<UplinkReceivedDisseminationResponse, tonic::Status> {
+        let request = ConsensusServer::create_dissemination_request(dev_addr, packet, rssi);
+        let rounds = self.rounds(dest).ok_or_else(|| tonic::Status::unavailable(format!("No consensus server {dest}")))?;
+        ConsensusServer::broadcast_reception_handler(dest, src, &rounds, request, self).await.map(|r| r.into_inner())
+    }
+
+    async fn broadcast_nc_set(&self, src: &str, dest: &str, dev_addr: String, set: HashMap<String, i32>) -> Result<ReceptionSetDisseminationResponse, tonic::Status> {
+        let rounds = self.rounds(dest).ok_or_else(|| tonic::Status::unavailable(format!("No consensus server {dest}")))?;
+        ConsensusServer::broadcast_nc_set_handler(dest, src.to_string(), &rounds, ReceptionSetDisseminationRequest { dev_addr, set }).await.map(|r| r.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::consensus_server::ROUND_TIMEOUT;
+
+    async fn round(sender: &Sender<ConsensusMessage>, nc_list: &[&str], packet: &[u8]) -> oneshot::Receiver<bool> {
+        let (response, receiver) = oneshot::channel();
+        let nc_list = nc_list.iter().map(|nc| nc.to_string()).collect();
+        sender.send(ConsensusMessage { nc_list, dev_addr: "01020304".to_string(), packet: packet.to_vec(), rssi: -80_000, response }).await.unwrap();
+        receiver
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_winner_per_packet() {
+        let network = LocalConsensusNetwork::new();
+        let ncs = ["nc0", "nc1", "nc2"];
+        let senders: Vec<_> = ncs.iter().map(|nc| network.run_instance(nc.to_string())).collect();
+        let packet = [0x40, 4, 3, 2, 1, 0, 1, 0, 1, 0xaa, 0x11, 0x22, 0x33, 0x44];
+
+        let mut receivers = Vec::new();
+        for sender in senders.iter() {
+            receivers.push(round(sender, &ncs, &packet).await);
+        }
+        //a copy from another gateway of the same network controller does not take part
+        let copy = round(&senders[0], &ncs, &packet).await;
+        let mut winners = 0;
+        for receiver in receivers {
+            winners += receiver.await.unwrap() as usize;
+        }
+        assert_eq!(winners, 1);
+        assert!(!copy.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_without_every_network_controller() {
+        let network = LocalConsensusNetwork::new();
+        let sender = network.run_instance("nc0".to_string());
+        let _ = network.run_instance("nc1".to_string());
+        let packet = [0x40, 4, 3, 2, 1, 0, 2, 0, 1, 0xaa, 0x11, 0x22, 0x33, 0x44];
+
+        //nc1 did not get the packet
+        let start = tokio::time::Instant::now();
+        assert!(!round(&sender, &["nc0", "nc1"], &packet).await.await.unwrap());
+        assert_eq!(start.elapsed(), ROUND_TIMEOUT);
+
+        //alone in its round the network controller wins
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(round(&sender, &["nc0"], &packet).await.await.unwrap());
+    }
+}
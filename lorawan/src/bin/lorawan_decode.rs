@@ -0,0 +1,189 @@
+use std::io::BufRead;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lorawan::{
+    decoder::{decode, DecoderContext},
+    device::LoRaWANVersion,
+    encryption::key::Key,
+    lorawan_packet::join::JoinRequestType,
+    utils::eui::EUI64,
+};
+use serde_json::Value;
+
+const USAGE: &str = "Usage: lorawan_decode [options] [PHYPayload...]
+PHYPayloads are hex or base64, one per line from stdin when none is given.
+Without --hex or --base64 a PHYPayload is read as hex if it has an even number of hex digits
+and does not end with '=', as base64 otherwise: use --base64 for unpadded base64 made only of hex digits
+Options:
+    --hex                    --base64                 encoding of every PHYPayload
+    --lorawan-version <1.0|1.0.1|1.0.2|1.0.3|1.0.4|1.1>   default 1.1
+    --app-key <key>          --nwk-key <key>
+    --nwk-s-key <key>        --app-s-key <key>
+    --f-nwk-s-int-key <key>  --s-nwk-s-int-key <key>  --nwk-s-enc-key <key>
+    --dev-eui <eui>          --join-eui <eui>         --dev-nonce <n>   RJcount0/RJcount1 for rejoins
+    --join-type <join|rejoin0|rejoin1|rejoin2>      request answered by the JoinAccepts, default join
+    --fcnt-msb <n>           upper 16 bits of the frame counter
+    --conf-fcnt <n>          --tx-dr <n>              --tx-ch <n>   LoRaWAN 1.1 MIC values
+    --tree                   print a tree instead of JSON";
+
+fn version(v: &str) -> Result<LoRaWANVersion, String> {
+    match v {
+        "1.0" => Ok(LoRaWANVersion::V1_0),
+        "1.0.1" => Ok(LoRaWANVersion::V1_0_1),
+        "1.0.2" => Ok(LoRaWANVersion::V1_0_2),
+        "1.0.3" => Ok(LoRaWANVersion::V1_0_3),
+        "1.0.4" => Ok(LoRaWANVersion::V1_0_4),
+        "1.1" => Ok(LoRaWANVersion::V1_1),
+        _ => Err(format!("unknown LoRaWAN version {v}")),
+    }
+}
+
+fn join_type(t: &str) -> Result<JoinRequestType, String> {
+    match t {
+        "join" => Ok(JoinRequestType::JoinRequest),
+        "rejoin0" => Ok(JoinRequestType::RejoinRequest0),
+        "rejoin1" => Ok(JoinRequestType::RejoinRequest1),
+        "rejoin2" => Ok(JoinRequestType::RejoinRequest2),
+        _ => Err(format!("unknown join type {t}")),
+    }
+}
+
+fn key(k: &str) -> Result<Option<Key>, String> {
+    Key::from_hex(k).map(Some).map_err(|e| format!("invalid key {k}: {e}"))
+}
+
+fn eui(e: &str) -> Result<Option<EUI64>, String> {
+    EUI64::from_hex(e).map(Some).map_err(|e2| format!("invalid EUI {e}: {e2}"))
+}
+
+fn number<T: std::str::FromStr>(n: &str) -> Result<T, String> {
+    n.parse().map_err(|_| format!("invalid number {n}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    ///hex for an even number of hex digits not ending with '=', base64 otherwise
+    Auto,
+    Hex,
+    Base64,
+}
+
+fn phy_payload(s: &str, encoding: Encoding) -> Result<Vec<u8>, String> {
+    let is_hex = match encoding {
+        Encoding::Auto => s.len().is_multiple_of(2) && !s.ends_with('=') && s.bytes().all(|b| b.is_ascii_hexdigit()),
+        Encoding::Hex => true,
+        Encoding::Base64 => false,
+    };
+    if is_hex {
+        hex::decode(s).map_err(|e| format!("{s} is not hex: {e}"))
+    } else {
+        STANDARD.decode(s).map_err(|e| format!("{s} is not base64: {e}"))
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(DecoderContext, Encoding, bool, Vec<String>), String> {
+    let mut context = DecoderContext::default();
+    let mut encoding = Encoding::Auto;
+    let mut tree = false;
+    let mut payloads = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--tree" {
+            tree = true;
+            continue;
+        }
+        if arg == "--hex" || arg == "--base64" {
+            encoding = if arg == "--hex" { Encoding::Hex } else { Encoding::Base64 };
+            continue;
+        }
+        if !arg.starts_with("--") {
+            payloads.push(arg.clone());
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("missing value of {arg}"))?;
+        match arg.as_str() {
+            "--lorawan-version" => context.version = version(value)?,
+            "--app-key" => context.app_key = key(value)?,
+            "--nwk-key" => context.nwk_key = key(value)?,
+            "--nwk-s-key" => context.nwk_s_key = key(value)?,
+            "--f-nwk-s-int-key" => context.f_nwk_s_int_key = key(value)?,
+            "--s-nwk-s-int-key" => context.s_nwk_s_int_key = key(value)?,
+            "--nwk-s-enc-key" => context.nwk_s_enc_key = key(value)?,
+            "--app-s-key" => context.app_s_key = key(value)?,
+            "--dev-eui" => context.dev_eui = eui(value)?,
+            "--join-eui" => context.join_eui = eui(value)?,
+            "--dev-nonce" => context.dev_nonce = Some(number(value)?),
+            "--join-type" => context.join_request_type = join_type(value)?,
+            "--fcnt-msb" => context.fcnt_msb = number(value)?,
+            "--conf-fcnt" => context.mic.conf_fcnt = Some(number(value)?),
+            "--tx-dr" => context.mic.tx_dr = number(value)?,
+            "--tx-ch" => context.mic.tx_ch = number(value)?,
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+    Ok((context, encoding, tree, payloads))
+}
+
+fn print_tree(name: &str, value: &Value, depth: usize) {
+    let indent = "    ".repeat(depth);
+    match value {
+        Value::Object(map) => {
+            println!("{indent}{name}");
+            for (k, v) in map {
+                print_tree(k, v, depth + 1);
+            }
+        },
+        Value::Array(array) => {
+            println!("{indent}{name}");
+            for (i, v) in array.iter().enumerate() {
+                print_tree(&i.to_string(), v, depth + 1);
+            }
+        },
+        Value::String(s) => println!("{indent}{name}: {s}"),
+        v => println!("{indent}{name}: {v}"),
+    }
+}
+
+///false if the payload cannot be decoded
+fn print_decode(payload: &str, context: &DecoderContext, encoding: Encoding, tree: bool) -> bool {
+    let decoded = phy_payload(payload, encoding).and_then(|bytes| decode(&bytes, context).map_err(|e| format!("cannot decode {payload}: {e}")));
+    match decoded {
+        Ok(decoded) if tree => print_tree("PHYPayload", &decoded, 0),
+        Ok(decoded) => println!("{}", serde_json::to_string_pretty(&decoded).unwrap()),
+        Err(e) => {
+            eprintln!("{e}");
+            return false;
+        },
+    }
+    true
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        return println!("{USAGE}");
+    }
+    let (context, encoding, tree, payloads) = match parse_args(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(1);
+        },
+    };
+    let mut all_decoded = true;
+    if payloads.is_empty() {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            let line = line.trim();
+            if !line.is_empty() {
+                all_decoded &= print_decode(line, &context, encoding, tree);
+            }
+        }
+    } else {
+        for payload in &payloads {
+            all_decoded &= print_decode(payload, &context, encoding, tree);
+        }
+    }
+    if !all_decoded {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,270 @@
+use serde_json::{json, Value};
+
+use crate::{
+    device::{
+        session_context::{ApplicationSessionContext, NetworkSessionContext, SessionContext},
+        Device, DeviceClass, LoRaWANVersion,
+    },
+    encryption::{aes_128_encrypt_with_padding, key::Key},
+    lorawan_packet::{
+        fctrl::FCtrl,
+        fhdr::FHDR,
+        join::{JoinAcceptPayload, JoinRequestType, RejoinRequestPayload},
+        mac_commands::{EDMacCommands, NCMacCommands},
+        mac_payload::MACPayload,
+        mhdr::MType,
+        payload::Payload,
+        LoRaWANPacket, MicContext,
+    },
+    utils::{errors::LoRaWANError, eui::EUI64, PrettyHexSlice},
+};
+
+///What is known about the device of a frame. Every missing key leaves undecoded the part of the frame it protects
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DecoderContext {
+    pub version: LoRaWANVersion,
+    ///Root key of LoRaWAN 1.0 devices, of the application keys derivation in 1.1
+    pub app_key: Option<Key>,
+    ///Root key of the network keys derivation in 1.1, the AppKey is used if missing
+    pub nwk_key: Option<Key>,
+    ///NwkSKey of LoRaWAN 1.0, it stands for every missing network session key of 1.1
+    pub nwk_s_key: Option<Key>,
+    pub f_nwk_s_int_key: Option<Key>,
+    pub s_nwk_s_int_key: Option<Key>,
+    pub nwk_s_enc_key: Option<Key>,
+    pub app_s_key: Option<Key>,
+    ///DevEUI, JoinEUI and DevNonce of the JoinRequest, the MIC of a 1.1 JoinAccept depends on them.
+    ///For a JoinAccept answering a rejoin dev_nonce is the RJcount0 or RJcount1 of the RejoinRequest
+    pub dev_eui: Option<EUI64>,
+    pub join_eui: Option<EUI64>,
+    pub dev_nonce: Option<u16>,
+    ///Request answered by a JoinAccept, the ones answering a rejoin are encrypted with JSEncKey, derived from the DevEUI
+    pub join_request_type: JoinRequestType,
+    ///Upper 16 bits of the frame counter, frames only carry the lower ones
+    pub fcnt_msb: u16,
+    ///ConfFCnt, TxDr and TxCh of LoRaWAN 1.1 data frame MICs
+    pub mic: MicContext,
+}
+
+impl DecoderContext {
+    fn root_keys(&self) -> Option<(Key, Key)> {
+        let nwk_key = self.nwk_key.or(self.app_key)?;
+        Some((nwk_key, self.app_key.unwrap_or(nwk_key)))
+    }
+
+    fn f_nwk_s_int_key(&self) -> Option<Key> {
+        self.f_nwk_s_int_key.or(self.nwk_s_key)
+    }
+
+    fn s_nwk_s_int_key(&self) -> Option<Key> {
+        self.s_nwk_s_int_key.or(self.nwk_s_key)
+    }
+
+    fn nwk_s_enc_key(&self) -> Option<Key> {
+        self.nwk_s_enc_key.or(self.nwk_s_key)
+    }
+
+    ///Device with the given identifiers and an ABP session made of the known keys, with every counter set to the one of the frame
+    fn device(&self, dev_eui: EUI64, join_eui: EUI64, dev_addr: [u8; 4], counter: u32) -> Device {
+        let (nwk_key, app_key) = self.root_keys().unwrap_or_default();
+        let mut device = Device::new(DeviceClass::A, None, dev_eui, join_eui, nwk_key, app_key, self.version);
+        device.set_dev_nonce(self.dev_nonce.unwrap_or_default() as u32);
+        device.join_context_mut().update_rj_count1(self.dev_nonce.unwrap_or_default());
+        device.set_last_join_request_received(self.join_request_type);
+        let network = NetworkSessionContext::new(
+            self.f_nwk_s_int_key().unwrap_or_default(),
+            self.s_nwk_s_int_key().unwrap_or_default(),
+            self.nwk_s_enc_key().unwrap_or_default(),
+            [0; 3],
+            dev_addr,
+            counter,
+            counter,
+            self.dev_nonce.unwrap_or_default(),
+        );
+        let application = ApplicationSessionContext::new(self.app_s_key.unwrap_or_default(), counter);
+        device.set_activation_abp(SessionContext::new(application, network));
+        device
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    PrettyHexSlice(bytes).to_string()
+}
+
+///None when the keys to check the MIC are missing
+fn mic_valid(checked: Option<Result<(), LoRaWANError>>) -> Result<Option<bool>, LoRaWANError> {
+    match checked {
+        None => Ok(None),
+        Some(Ok(())) => Ok(Some(true)),
+        Some(Err(LoRaWANError::InvalidMic)) => Ok(Some(false)),
+        Some(Err(e)) => Err(e),
+    }
+}
+
+///MAC commands sent by devices on uplinks and by the network on downlinks
+fn mac_commands(bytes: &[u8], is_uplink: bool) -> Value {
+    let commands = if is_uplink {
+        EDMacCommands::from_bytes(bytes).map(|c| c.iter().map(|c| format!("{c:?}")).collect::<Vec<_>>())
+    } else {
+        NCMacCommands::from_bytes(bytes).map(|c| c.iter().map(|c| format!("{c:?}")).collect::<Vec<_>>())
+    };
+    match commands {
+        Ok(commands) => json!(commands),
+        Err(e) => json!({ "error": e.to_string() }),
+    }
+}
+
+fn join_accept(payload: &JoinAcceptPayload) -> Value {
+    json!({
+        "join_nonce": hex(payload.join_nonce()),
+        "home_net_id": hex(payload.home_net_id()),
+        "dev_addr": hex(payload.dev_addr()),
+        "dl_settings": {
+            "opt_neg": payload.opt_neg(),
+            "rx1_dr_offset": payload.rx1_dr_offset(),
+            "rx2_data_rate": payload.rx2_data_rate(),
+        },
+        "rx_delay": payload.rx_delay(),
+        "cf_list": payload.cf_list(),
+    })
+}
+
+fn mac_payload(packet: &LoRaWANPacket, payload: &MACPayload, bytes: &[u8], context: &DecoderContext, is_uplink: bool) -> Result<(Value, Option<bool>), LoRaWANError> {
+    let fhdr = payload.fhdr();
+    let dev_addr = fhdr.dev_addr();
+    let counter = (context.fcnt_msb as u32) << 16 | fhdr.fcnt() as u32;
+    let device = context.device(EUI64::default(), EUI64::default(), dev_addr, counter);
+
+    let fopts_len = fhdr.fctrl().f_opts_len() as usize;
+    //FOpts are encrypted since LoRaWAN 1.1
+    let fopts = if fopts_len == 0 || !context.version.is_1_1_or_greater() {
+        Some(fhdr.fopts()[..fopts_len].to_vec())
+    } else if context.nwk_s_enc_key().is_some() {
+        Some(FHDR::from_bytes(&bytes[1..8 + fopts_len], Some(&device), is_uplink)?.fopts()[..fopts_len].to_vec())
+    } else {
+        None
+    };
+
+    let frm_payload = match (payload.fport(), payload.frm_payload()) {
+        (Some(fport), Some(frm_payload)) => {
+            let key = if fport == 0 { context.nwk_s_enc_key() } else { context.app_s_key };
+            key.map(|key| {
+                let mut decrypted = frm_payload.clone();
+                MACPayload::encrypt_payload(&key, &mut decrypted, &dev_addr, u8::from(!is_uplink), counter).map(|_| decrypted)
+            }).transpose()?
+        },
+        _ => None,
+    };
+
+    let fctrl = match fhdr.fctrl() {
+        FCtrl::Uplink(f) => json!(f),
+        FCtrl::Downlink(f) => json!(f),
+    };
+    let mut decoded = json!({
+        "fhdr": {
+            "dev_addr": hex(&dev_addr),
+            "fctrl": fctrl,
+            "fcnt": fhdr.fcnt(),
+            "fopts": hex(&bytes[8..8 + fopts_len]),
+        },
+        "fport": payload.fport(),
+        "frm_payload": payload.frm_payload().map(|p| hex(p)),
+    });
+    if let Some(fopts) = fopts.filter(|f| !f.is_empty()) {
+        decoded["fhdr"]["fopts_decrypted"] = json!(hex(&fopts));
+        decoded["fhdr"]["mac_commands"] = mac_commands(&fopts, is_uplink);
+    }
+    if let Some(frm_payload) = frm_payload {
+        decoded["frm_payload_decrypted"] = json!(hex(&frm_payload));
+        if payload.fport() == Some(0) {
+            decoded["mac_commands"] = mac_commands(&frm_payload, is_uplink);
+        }
+    }
+
+    //uplinks of LoRaWAN 1.1 carry half of the MIC of each integrity key
+    let mic_keys = if !is_uplink {
+        context.s_nwk_s_int_key().is_some()
+    } else if context.version.is_1_1_or_greater() {
+        context.f_nwk_s_int_key().is_some() && context.s_nwk_s_int_key().is_some()
+    } else {
+        context.f_nwk_s_int_key().is_some()
+    };
+    let mic = mic_valid(mic_keys.then(|| LoRaWANPacket::validate_mic(bytes, packet, &device, &context.mic)))?;
+    Ok((decoded, mic))
+}
+
+///Decodes a PHYPayload: every field of the frame, FOpts and FRMPayload decrypted and MAC commands parsed when the keys are known,
+///and the MIC validity, null if the keys to check it are missing
+pub fn decode(bytes: &[u8], context: &DecoderContext) -> Result<Value, LoRaWANError> {
+    if bytes.len() < 12 {
+        return Err(LoRaWANError::InvalidBufferLength);
+    }
+    let mtype = LoRaWANPacket::extract_mtype(bytes[0]);
+    let is_uplink = !matches!(mtype, MType::JoinAccept | MType::UnconfirmedDataDown | MType::ConfirmedDataDown);
+    let packet = LoRaWANPacket::from_bytes(bytes, None, is_uplink)?;
+    let mut mic = Some(hex(&bytes[bytes.len() - 4..]));
+
+    let (name, payload, mic_valid) = match packet.payload() {
+        Payload::JoinRequest(request) => {
+            let device = context.root_keys().map(|_| context.device(*request.dev_eui(), *request.join_eui(), [0; 4], 0));
+            let decoded = json!({
+                "join_eui": request.join_eui().to_string(),
+                "dev_eui": request.dev_eui().to_string(),
+                "dev_nonce": request.dev_nonce(),
+            });
+            ("join_request", decoded, mic_valid(device.map(|d| LoRaWANPacket::validate_mic(bytes, &packet, &d, &context.mic)))?)
+        },
+        Payload::RejoinRequest(request) => {
+            let (decoded, device) = match request {
+                RejoinRequestPayload::T02(r) => (
+                    json!({ "type": if r.is_type_zero() { 0 } else { 2 }, "net_id": hex(r.net_id()), "dev_eui": r.dev_eui().to_string(), "rj_count0": r.rj_count0() }),
+                    context.s_nwk_s_int_key().map(|_| context.device(*r.dev_eui(), EUI64::default(), [0; 4], 0)),
+                ),
+                RejoinRequestPayload::T1(r) => (
+                    json!({ "type": 1, "join_eui": r.join_eui().to_string(), "dev_eui": r.dev_eui().to_string(), "rj_count1": r.rj_count1() }),
+                    context.root_keys().map(|_| context.device(*r.dev_eui(), *r.join_eui(), [0; 4], 0)),
+                ),
+            };
+            ("rejoin_request", decoded, mic_valid(device.map(|d| LoRaWANPacket::validate_mic(bytes, &packet, &d, &context.mic)))?)
+        },
+        Payload::JoinAccept(_) => match context.root_keys().filter(|_| !context.join_request_type.is_rejoin() || context.dev_eui.is_some()) {
+            //JoinAccepts are encrypted together with their MIC
+            Some((nwk_key, _)) => {
+                let key = if context.join_request_type.is_rejoin() {
+                    *context.device(context.dev_eui.unwrap_or_default(), EUI64::default(), [0; 4], 0).join_context().js_enc_key()
+                } else {
+                    nwk_key
+                };
+                let decrypted = aes_128_encrypt_with_padding(&key, &mut bytes[1..].to_vec())?;
+                let (decrypted, decrypted_mic) = decrypted.split_at(decrypted.len() - 4);
+                mic = Some(hex(decrypted_mic));
+                let payload = JoinAcceptPayload::from_bytes(decrypted, &context.join_request_type)?;
+                let known_request = !payload.opt_neg() || (context.dev_eui.is_some() && context.join_eui.is_some() && context.dev_nonce.is_some());
+                let device = known_request.then(|| context.device(context.dev_eui.unwrap_or_default(), context.join_eui.unwrap_or_default(), [0; 4], 0));
+                let checked = device.map(|d| LoRaWANPacket::from_bytes(bytes, Some(&d), false).map(|_| ()));
+                ("join_accept", join_accept(&payload), mic_valid(checked)?)
+            },
+            None => {
+                mic = None;
+                ("join_accept", json!({ "encrypted": hex(&bytes[1..]) }), None)
+            },
+        },
+        Payload::MACPayload(payload) => {
+            let (decoded, mic_valid) = mac_payload(&packet, payload, bytes, context, is_uplink)?;
+            ("mac_payload", decoded, mic_valid)
+        },
+        Payload::Proprietary(payload) => {
+            mic = None;
+            ("proprietary", json!(hex(payload)), None)
+        },
+    };
+
+    let mut decoded = json!({
+        "phy_payload": hex(bytes),
+        "mhdr": { "mtype": packet.mhdr().mtype(), "major": packet.mhdr().major() },
+        "mic": mic,
+        "mic_valid": mic_valid,
+    });
+    decoded[name] = payload;
+    Ok(decoded)
+}
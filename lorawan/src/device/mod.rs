@@ -7,7 +7,7 @@ use serde::{Serialize, Deserialize};
 
 use crate::{
     encryption::key::Key,
-    lorawan_packet::{join::{JoinAcceptPayload, JoinRequestType, JoinRequestPayload}, mhdr::{MHDR, MType, Major}, payload::Payload, LoRaWANPacket, fctrl::{FCtrl, UplinkFCtrl}, fhdr::FHDR, mac_commands::EDMacCommands, mac_payload::MACPayload},
+    lorawan_packet::{join::{JoinAcceptPayload, JoinRequestType, JoinRequestPayload, ReJoinRequest02, ReJoinRequest1, RejoinRequestPayload, RejoinType}, mhdr::{MHDR, MType, Major}, payload::Payload, LoRaWANPacket, MicContext, fctrl::{FCtrl, UplinkFCtrl}, fhdr::FHDR, mac_commands::EDMacCommands, mac_payload::MACPayload},
     utils::{errors::LoRaWANError, eui::EUI64, traits::{ToBytes, ToBytesWithContext}},
     device::session_context::{JoinSessionContext, SessionContext}, regional_parameters::region::RegionalParameters
 };
@@ -21,19 +21,21 @@ pub enum DeviceClass {
 }
 
 impl DeviceClass {
+    ///Class field of DeviceModeInd/Conf: 0x00 class A, 0x02 class C. Class B is not switched this way and takes the RFU value 0x01
     pub fn to_byte(&self) -> u8 {
         match self {
-            DeviceClass::A => 0x01,
-            DeviceClass::B => 0x02,
-            DeviceClass::C => 0x03,
+            DeviceClass::A => 0x00,
+            DeviceClass::B => 0x01,
+            DeviceClass::C => 0x02,
         }
     }
     
-    pub fn from_byte(b: u8) -> Self {
+    ///None for the RFU values, class B included
+    pub fn from_byte(b: u8) -> Option<Self> {
         match b {
-            0x01 => DeviceClass::A,
-            0x03 => DeviceClass::C,
-            _ =>    DeviceClass::B,
+            0x00 => Some(DeviceClass::A),
+            0x02 => Some(DeviceClass::C),
+            _ =>    None,
         }
     }
 }
@@ -118,19 +120,34 @@ impl Device {
     
 
     pub fn generate_session_context(&mut self, join_accept_payload: &JoinAcceptPayload) -> Result<(), LoRaWANError> {
+        let dev_nonce = self.join_accept_nonce(join_accept_payload.join_req_type());
         self.session = Some(SessionContext::derive(
             join_accept_payload.opt_neg(),
             &self.nwk_key,
             &self.app_key,
             join_accept_payload.join_nonce(),
             self.join_eui,
-            self.dev_nonce,
+            dev_nonce,
             join_accept_payload.dev_addr(),
             join_accept_payload.home_net_id(),
         )?);
         Ok(())
     }
 
+    ///Value used in place of the DevNonce in the JoinAccept MIC and in the key derivation: RJcount0 or RJcount1 when answering a rejoin
+    pub fn join_accept_nonce(&self, join_req_type: &JoinRequestType) -> u32 {
+        match join_req_type {
+            JoinRequestType::JoinRequest => self.dev_nonce,
+            JoinRequestType::RejoinRequest0 | JoinRequestType::RejoinRequest2 => self.session.map_or(0, |s| s.network_context().rj_count0() as u32),
+            JoinRequestType::RejoinRequest1 => self.join_context.rj_count1() as u32,
+        }
+    }
+
+    ///Replace the session keeping the activation mode, e.g. after a rejoin
+    pub fn set_session(&mut self, session: SessionContext) {
+        self.session = Some(session);
+    }
+
     pub fn set_activation_abp(&mut self, session: SessionContext) {
         self.activation_mode = ActivationMode::ABP;
         self.session = Some(session);
@@ -228,11 +245,34 @@ impl Device {
             self.dev_eui,
             (self.dev_nonce_autoinc()) as u16,
         );
+        self.last_join_request_received = JoinRequestType::JoinRequest;
         let packet = LoRaWANPacket::new(mhdr, Payload::JoinRequest(payload));
         packet.to_bytes_with_context(self)
     }
 
-    pub fn create_uplink(&mut self, payload: Option<&[u8]>, confirmed: bool, fport: Option<u8>, fopts: Option<Vec<u8>>) -> Result<Vec<u8>, LoRaWANError> {
+    ///Type 0 and 2 need an active session and increment RJcount0, type 1 increments RJcount1. Rejoins are a LoRaWAN 1.1 feature
+    pub fn create_rejoin_request(&mut self, rejoin_type: RejoinType) -> Result<Vec<u8>, LoRaWANError> {
+        let mhdr = MHDR::new(MType::RejoinRequest, Major::R1);
+        let payload = match rejoin_type {
+            RejoinType::Type1 => {
+                let rj_count1 = self.join_context.rj_count1_autoinc();
+                RejoinRequestPayload::T1(ReJoinRequest1::new(self.join_eui, self.dev_eui, rj_count1))
+            },
+            RejoinType::Type0 | RejoinType::Type2 => {
+                let network_context = self.session.as_mut().ok_or(LoRaWANError::SessionContextMissing)?.network_context_mut();
+                let rj_count0 = network_context.rj_count0_autoinc();
+                let net_id = network_context.home_net_id();
+                RejoinRequestPayload::T02(ReJoinRequest02::new(rejoin_type == RejoinType::Type0, net_id, self.dev_eui, rj_count0))
+            },
+        };
+        //the JoinAccept answering a rejoin is encrypted with JSEncKey
+        self.last_join_request_received = rejoin_type.into();
+        let packet = LoRaWANPacket::new(mhdr, Payload::RejoinRequest(payload));
+        packet.to_bytes_with_context(self)
+    }
+
+    ///The uplink acknowledges a confirmed downlink when mic_context has its ConfFCnt, TxDr and TxCh are the radio parameters of the transmission
+    pub fn create_uplink(&mut self, payload: Option<&[u8]>, confirmed: bool, fport: Option<u8>, fopts: Option<Vec<u8>>, mic_context: &MicContext) -> Result<Vec<u8>, LoRaWANError> {
         let mtype = if confirmed {
             MType::ConfirmedDataUp
         } else {
@@ -251,7 +291,7 @@ impl Device {
         });
         
         let session_context = self.session.as_mut().ok_or(LoRaWANError::SessionContextMissing)?;
-        let fctrl = FCtrl::Uplink(UplinkFCtrl::new(false, false, false, false, f_opts_len));
+        let fctrl = FCtrl::Uplink(UplinkFCtrl::new(false, false, mic_context.conf_fcnt.is_some(), false, f_opts_len));
         let fcnt = session_context.network_context_mut().f_cnt_up_autoinc() as u16;
         
         let mut fhdr = FHDR::new(*self.session.as_ref().ok_or(LoRaWANError::SessionContextMissing)?.network_context().dev_addr(), fctrl);
@@ -266,7 +306,7 @@ impl Device {
 
         let packet = LoRaWANPacket::new(mhdr, payload);
         //println!("{packet:?}");
-        packet.to_bytes_with_context(self)
+        packet.to_bytes_with_mic_context(self, mic_context)
     }
     
     pub fn create_maccommands(mac_commands: &[EDMacCommands]) -> Result<Vec<u8>, LoRaWANError> {        
@@ -3,4 +3,5 @@ pub mod device;
 pub mod utils;
 pub mod encryption;
 pub mod regional_parameters;
-pub mod physical_parameters;
\ No newline at end of file
+pub mod physical_parameters;
+pub mod decoder;
\ No newline at end of file
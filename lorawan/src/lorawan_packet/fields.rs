@@ -0,0 +1,281 @@
+use std::{convert::TryFrom, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::utils::errors::LoRaWANError;
+
+///MaxEIRP values in dBm for the 4 bits index of TxParamSetupReq
+const MAX_EIRP_TABLE: [u8; 16] = [8, 10, 12, 13, 14, 16, 18, 20, 21, 24, 26, 27, 29, 30, 33, 36];
+
+fn check(value: u8, max: u8, field: &'static str) -> Result<u8, LoRaWANError> {
+    if value > max { Err(LoRaWANError::InvalidFieldValue(field)) } else { Ok(value) }
+}
+
+//the fields below are (de)serialized as the byte sent on air, deserializing goes through the same checks as new
+
+///DLSettings of JoinAccept and RXParamSetupReq: 7 -> OptNeg (RFU in RXParamSetupReq), 6..4 -> RX1DROffset, 3..0 -> RX2DataRate
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(from = "u8", into = "u8")]
+pub struct DLSettings {
+    opt_neg: bool,
+    rx1_dr_offset: u8,
+    rx2_data_rate: u8,
+}
+
+impl DLSettings {
+    pub fn new(opt_neg: bool, rx1_dr_offset: u8, rx2_data_rate: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self {
+            opt_neg,
+            rx1_dr_offset: check(rx1_dr_offset, 0b111, "RX1DROffset")?,
+            rx2_data_rate: check(rx2_data_rate, 0b1111, "RX2DataRate")?,
+        })
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            opt_neg: byte & 0b10000000 > 0,
+            rx1_dr_offset: (byte & 0b01110000) >> 4,
+            rx2_data_rate: byte & 0b00001111,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        (u8::from(self.opt_neg) << 7) | (self.rx1_dr_offset << 4) | self.rx2_data_rate
+    }
+
+    pub fn opt_neg(&self) -> bool {
+        self.opt_neg
+    }
+
+    pub fn rx1_dr_offset(&self) -> u8 {
+        self.rx1_dr_offset
+    }
+
+    pub fn rx2_data_rate(&self) -> u8 {
+        self.rx2_data_rate
+    }
+
+    ///The same settings with OptNeg set, it tells a LoRaWAN 1.1 device that the network is 1.1 too
+    pub fn with_opt_neg(self, opt_neg: bool) -> Self {
+        Self { opt_neg, ..self }
+    }
+}
+
+impl From<u8> for DLSettings {
+    fn from(byte: u8) -> Self {
+        Self::from_byte(byte)
+    }
+}
+
+impl From<DLSettings> for u8 {
+    fn from(value: DLSettings) -> Self {
+        value.to_byte()
+    }
+}
+
+///RxDelay of JoinAccept and RXTimingSetupReq: 7..4 -> RFU, 3..0 -> Delay in seconds, 0 is 1s as well
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct RxDelay(u8);
+
+impl RxDelay {
+    pub fn new(delay: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self(check(delay, 0b1111, "RxDelay")?))
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self(byte & 0b00001111)
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        self.0
+    }
+
+    ///Delay of RX1 from the end of the uplink
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.0.max(1) as u64)
+    }
+}
+
+impl TryFrom<u8> for RxDelay {
+    type Error = LoRaWANError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::new(byte)
+    }
+}
+
+impl From<RxDelay> for u8 {
+    fn from(value: RxDelay) -> Self {
+        value.to_byte()
+    }
+}
+
+///MaxEIRP index of TxParamSetupReq, 3..0 of the EIRP_DwellTime byte
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct MaxEIRP(u8);
+
+impl MaxEIRP {
+    pub fn new(index: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self(check(index, 0b1111, "MaxEIRP")?))
+    }
+
+    ///Smallest index allowing at least dbm, None if dbm is over 36 dBm
+    pub fn from_dbm(dbm: u8) -> Option<Self> {
+        MAX_EIRP_TABLE.iter().position(|d| *d >= dbm).map(|i| Self(i as u8))
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self(byte & 0b00001111)
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        self.0
+    }
+
+    pub fn dbm(&self) -> u8 {
+        MAX_EIRP_TABLE[self.0 as usize]
+    }
+}
+
+impl TryFrom<u8> for MaxEIRP {
+    type Error = LoRaWANError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::new(byte)
+    }
+}
+
+impl From<MaxEIRP> for u8 {
+    fn from(value: MaxEIRP) -> Self {
+        value.to_byte()
+    }
+}
+
+///ChMaskCntl of LinkADRReq, how ChMask is applied. The meaning of each value is region specific
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct ChMaskCntl(u8);
+
+impl ChMaskCntl {
+    pub fn new(value: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self(check(value, 0b111, "ChMaskCntl")?))
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for ChMaskCntl {
+    type Error = LoRaWANError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::new(byte)
+    }
+}
+
+impl From<ChMaskCntl> for u8 {
+    fn from(value: ChMaskCntl) -> Self {
+        value.value()
+    }
+}
+
+///Redundancy byte of LinkADRReq: 7 -> RFU, 6..4 -> ChMaskCntl, 3..0 -> NbTrans (0 keeps the current one)
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Redundancy {
+    ch_mask_cntl: ChMaskCntl,
+    nb_trans: u8,
+}
+
+impl Redundancy {
+    pub fn new(ch_mask_cntl: ChMaskCntl, nb_trans: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self { ch_mask_cntl, nb_trans: check(nb_trans, 0b1111, "NbTrans")? })
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            ch_mask_cntl: ChMaskCntl((byte & 0b01110000) >> 4),
+            nb_trans: byte & 0b00001111,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        (self.ch_mask_cntl.0 << 4) | self.nb_trans
+    }
+
+    pub fn ch_mask_cntl(&self) -> ChMaskCntl {
+        self.ch_mask_cntl
+    }
+
+    pub fn nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
+}
+
+impl TryFrom<u8> for Redundancy {
+    type Error = LoRaWANError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        check(byte, 0b01111111, "Redundancy").map(Self::from_byte)
+    }
+}
+
+impl From<Redundancy> for u8 {
+    fn from(value: Redundancy) -> Self {
+        value.to_byte()
+    }
+}
+
+///DataRate_TXPower byte of LinkADRReq: 7..4 -> DataRate, 3..0 -> TXPower. 0xF in a field keeps the current value
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(from = "u8", into = "u8")]
+pub struct DataRateTxPower {
+    data_rate: u8,
+    tx_power: u8,
+}
+
+impl DataRateTxPower {
+    ///Value of a field that must be ignored by the device
+    pub const UNCHANGED: u8 = 0x0F;
+
+    pub fn new(data_rate: u8, tx_power: u8) -> Result<Self, LoRaWANError> {
+        Ok(Self {
+            data_rate: check(data_rate, 0b1111, "DataRate")?,
+            tx_power: check(tx_power, 0b1111, "TXPower")?,
+        })
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            data_rate: (byte & 0b11110000) >> 4,
+            tx_power: byte & 0b00001111,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        (self.data_rate << 4) | self.tx_power
+    }
+
+    pub fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    pub fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+}
+
+impl From<u8> for DataRateTxPower {
+    fn from(byte: u8) -> Self {
+        Self::from_byte(byte)
+    }
+}
+
+impl From<DataRateTxPower> for u8 {
+    fn from(value: DataRateTxPower) -> Self {
+        value.to_byte()
+    }
+}
@@ -4,6 +4,8 @@ use serde::{Serialize, Deserialize};
 
 use crate::utils::{errors::LoRaWANError, eui::EUI64, traits::ToBytes};
 
+use super::fields::{DLSettings, RxDelay};
+
 
 
 #[derive(Clone, Debug, PartialEq, Eq, Copy, Serialize, Deserialize, Hash)]
@@ -40,6 +42,98 @@ impl JoinRequestType {
     }
 }
 
+///Type of a RejoinRequest: 0 resets the session, 1 restores a lost session, 2 only refreshes the radio parameters
+#[derive(Clone, Debug, PartialEq, Eq, Copy, Serialize, Deserialize, Hash)]
+pub enum RejoinType {
+    Type0,
+    Type1,
+    Type2,
+}
+
+impl RejoinType {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(RejoinType::Type0),
+            1 => Some(RejoinType::Type1),
+            2 => Some(RejoinType::Type2),
+            _ => None,
+        }
+    }
+}
+
+impl From<RejoinType> for JoinRequestType {
+    fn from(value: RejoinType) -> Self {
+        match value {
+            RejoinType::Type0 => JoinRequestType::RejoinRequest0,
+            RejoinType::Type1 => JoinRequestType::RejoinRequest1,
+            RejoinType::Type2 => JoinRequestType::RejoinRequest2,
+        }
+    }
+}
+
+///Optional last 16 bytes of the JoinAccept, the last byte is the CFListType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum CFList {
+    ///Type 0: frequencies in Hz of up to five extra channels, 0 means no channel. Dynamic channel plan regions only
+    Frequencies([u32; 5]),
+    ///Type 1: ChMask0..ChMask5, one bit per channel of the fixed channel plan. ChMask5 is only used by CN470
+    ChannelMasks([u16; 6]),
+}
+
+impl CFList {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoRaWANError> {
+        if bytes.len() != 16 {
+            return Err(LoRaWANError::InvalidBufferLength);
+        }
+        match bytes[15] {
+            0 => {
+                let mut frequencies = [0_u32; 5];
+                for (i, f) in frequencies.iter_mut().enumerate() {
+                    let b = &bytes[i * 3..i * 3 + 3];
+                    *f = u32::from_le_bytes([b[0], b[1], b[2], 0]) * 100;
+                }
+                Ok(CFList::Frequencies(frequencies))
+            },
+            1 => {
+                let mut masks = [0_u16; 6];
+                for (i, m) in masks.iter_mut().enumerate() {
+                    *m = u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+                }
+                Ok(CFList::ChannelMasks(masks))
+            },
+            _ => Err(LoRaWANError::InvalidBufferContent),
+        }
+    }
+
+    pub fn cf_list_type(&self) -> u8 {
+        match self {
+            CFList::Frequencies(_) => 0,
+            CFList::ChannelMasks(_) => 1,
+        }
+    }
+}
+
+impl ToBytes for CFList {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(16);
+        match self {
+            CFList::Frequencies(frequencies) => {
+                for f in frequencies {
+                    ret.extend_from_slice(&(f / 100).to_le_bytes()[..3]);
+                }
+            },
+            CFList::ChannelMasks(masks) => {
+                for m in masks {
+                    ret.extend_from_slice(&m.to_le_bytes());
+                }
+            },
+        }
+        ret.resize(15, 0);
+        ret.push(self.cf_list_type());
+        ret
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct JoinAcceptPayload {
     join_req_type: JoinRequestType,
@@ -47,11 +141,9 @@ pub struct JoinAcceptPayload {
     join_nonce: [u8; 3],
     home_net_id: [u8; 3],
     dev_addr: [u8; 4],
-    ///7: OptNeg, 6..4 RX1DRoffset 3..0 RX2DataRate //TODO forse fare una struct?
-    dl_settings: u8,
-    rx_delay: u8,
-    ///TODO region specific and optional
-    cf_list: Option<[u8; 16]>,
+    dl_settings: DLSettings,
+    rx_delay: RxDelay,
+    cf_list: Option<CFList>,
 }
 
 
@@ -62,9 +154,9 @@ impl JoinAcceptPayload {
         join_nonce: [u8; 3],
         home_net_id: [u8; 3],
         dev_addr: [u8; 4],
-        dl_settings: u8,
-        rx_delay: u8,
-        cf_list: Option<[u8; 16]>,
+        dl_settings: DLSettings,
+        rx_delay: RxDelay,
+        cf_list: Option<CFList>,
     ) -> Self {
         Self {
             join_req_type,
@@ -92,29 +184,29 @@ impl JoinAcceptPayload {
     }
 
     /// Get the join accept's dl settings.
-    pub fn dl_settings(&self) -> u8 {
+    pub fn dl_settings(&self) -> DLSettings {
         self.dl_settings
     }
 
     pub fn opt_neg(&self) -> bool {
-        (self.dl_settings & 0b10000000) > 0
+        self.dl_settings.opt_neg()
     }
 
     pub fn rx1_dr_offset(&self) -> u8 {
-        (self.dl_settings & 0b01110000) >> 4
+        self.dl_settings.rx1_dr_offset()
     }
 
     pub fn rx2_data_rate(&self) -> u8 {
-        self.dl_settings & 0b00001111
+        self.dl_settings.rx2_data_rate()
     }
 
     /// Get the join accept's rx delay.
-    pub fn rx_delay(&self) -> u8 {
+    pub fn rx_delay(&self) -> RxDelay {
         self.rx_delay
     }
 
     /// Get the join accept's cf list.
-    pub fn cf_list(&self) -> &Option<[u8; 16]>  {
+    pub fn cf_list(&self) -> &Option<CFList>  {
         &self.cf_list
     }
 
@@ -140,12 +232,10 @@ impl JoinAcceptPayload {
             let mut dev_addr: [u8;4] = bytes[6..10].try_into()?;
             dev_addr.reverse();
     
-            let dl_settings = bytes[10];
-            let rx_delay = bytes[11];
+            let dl_settings = DLSettings::from_byte(bytes[10]);
+            let rx_delay = RxDelay::from_byte(bytes[11]);
             let cf_list = if len > 12 {
-                let mut list = [0; 16];
-                list.copy_from_slice(&bytes[12..]);
-                Some(list)
+                Some(CFList::from_bytes(&bytes[12..])?)
             } else {
                 None    
             };
@@ -177,10 +267,10 @@ impl ToBytes for JoinAcceptPayload {
         ret.extend_from_slice(&join_nonce_reversed);
         ret.extend_from_slice(&home_net_id_reversed);
         ret.extend_from_slice(&dev_addr_reversed);
-        ret.push(self.dl_settings);
-        ret.push(self.rx_delay);
+        ret.push(self.dl_settings.to_byte());
+        ret.push(self.rx_delay.to_byte());
         if let Some(cflist) = &self.cf_list {
-            ret.extend_from_slice(cflist);
+            ret.extend_from_slice(&cflist.to_bytes());
         }
         ret 
     }
@@ -255,6 +345,29 @@ pub enum RejoinRequestPayload {
 }
 
 impl RejoinRequestPayload {
+    pub fn join_request_type(&self) -> JoinRequestType {
+        match self {
+            RejoinRequestPayload::T1(_) => JoinRequestType::RejoinRequest1,
+            RejoinRequestPayload::T02(r) if r.is_type_zero() => JoinRequestType::RejoinRequest0,
+            RejoinRequestPayload::T02(_) => JoinRequestType::RejoinRequest2,
+        }
+    }
+
+    pub fn dev_eui(&self) -> &EUI64 {
+        match self {
+            RejoinRequestPayload::T1(r) => r.dev_eui(),
+            RejoinRequestPayload::T02(r) => r.dev_eui(),
+        }
+    }
+
+    ///RJcount0 for type 0 and 2, RJcount1 for type 1
+    pub fn rj_count(&self) -> u16 {
+        match self {
+            RejoinRequestPayload::T1(r) => r.rj_count1(),
+            RejoinRequestPayload::T02(r) => r.rj_count0(),
+        }
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoRaWANError> {
         let len = bytes.len();
         if len != 14 && len != 19  {
@@ -303,15 +416,34 @@ impl ReJoinRequest02 {
         }
     }
 
+    pub fn is_type_zero(&self) -> bool {
+        self.is_type_zero
+    }
+
+    pub fn net_id(&self) -> &[u8; 3] {
+        &self.net_id
+    }
+
+    pub fn dev_eui(&self) -> &EUI64 {
+        &self.dev_eui
+    }
+
+    pub fn rj_count0(&self) -> u16 {
+        self.rj_count0
+    }
+
     fn from_bytes(bytes: &[u8]) -> Result<Self, LoRaWANError> {
         if bytes.len() != 14 {
             Err(LoRaWANError::InvalidBufferLength)
         }
         else {
-            let eui_bytes: [u8; 8] = bytes[4..12].try_into()?;
+            let mut net_id: [u8; 3] = bytes[1..4].try_into()?;
+            net_id.reverse();
+            let mut eui_bytes: [u8; 8] = bytes[4..12].try_into()?;
+            eui_bytes.reverse();
             Ok(Self {
                 is_type_zero: bytes[0] == 0,
-                net_id: bytes[1..4].try_into()?,
+                net_id,
                 dev_eui: EUI64::from(eui_bytes),
                 rj_count0: u16::from_le_bytes(bytes[12..14].try_into()?),
                 
@@ -353,17 +485,32 @@ impl ReJoinRequest1 {
         }
     }
 
+    pub fn join_eui(&self) -> &EUI64 {
+        &self.join_eui
+    }
+
+    pub fn dev_eui(&self) -> &EUI64 {
+        &self.dev_eui
+    }
+
+    pub fn rj_count1(&self) -> u16 {
+        self.rj_count1
+    }
+
     fn from_bytes(bytes: &[u8]) -> Result<Self, LoRaWANError> {
         if bytes.len() != 19 {
             Err(LoRaWANError::InvalidBufferLength)
         }
         else {
-            let join_eui_bytes: [u8; 8] = bytes[0..8].try_into()?;
-            let dev_eui_bytes: [u8; 8] = bytes[8..16].try_into()?;
+            //bytes[0] is the rejoin type
+            let mut join_eui_bytes: [u8; 8] = bytes[1..9].try_into()?;
+            join_eui_bytes.reverse();
+            let mut dev_eui_bytes: [u8; 8] = bytes[9..17].try_into()?;
+            dev_eui_bytes.reverse();
             Ok(Self {
                 join_eui: EUI64::from(join_eui_bytes),
                 dev_eui: EUI64::from(dev_eui_bytes),
-                rj_count1: u16::from_le_bytes(bytes[16..18].try_into()?),
+                rj_count1: u16::from_le_bytes(bytes[17..19].try_into()?),
             })
         }
     }
@@ -1,8 +1,10 @@
 use crate::{utils::{traits::ToBytes, errors::LoRaWANError}, device::DeviceClass};
 
+use super::fields::{DLSettings, DataRateTxPower, MaxEIRP, Redundancy, RxDelay};
+
 
 ///Commands sent by the end device
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EDMacCommands {
     /// 7..4 -> RFU, 3..0 -> Minor version ( -> Values: 0 -> RFU, 1 -> Lorawan x.1, -> 2..15 -> RFU)
     ResetInd(u8),
@@ -31,9 +33,9 @@ pub enum EDMacCommands {
         data_range_ok: bool,
         channel_frequency_ok: bool
     },
-    /// 7..2 -> RFU, 1 -> UplinkFrequency ACK, 0 -> ChannelFrequency ACK |||  if one of the acks is 0 everything was ignored
     RXTimingSetupAns,
     TxParamSetupAns,
+    /// 7..2 -> RFU, 1 -> UplinkFrequency ACK, 0 -> ChannelFrequency ACK |||  if one of the acks is 0 everything was ignored
     DlChannelAns {
         uplink_frequency_exists: bool,
         channel_frequency_ok: bool
@@ -48,13 +50,18 @@ pub enum EDMacCommands {
     },
 
     //CLASS B MAC COMMANDS
+    /// 7..3 -> RFU, 2..0 -> Periodicity ||| ping slots every 2^periodicity seconds
     PingSlotInfoReq {
         periodicity: u8
     },
-    PingSlotChannelAns { //TODO check if this is PingSlotChannelAns or PingSlotFreqAns
+    /// 7..2 -> RFU, 1 -> DataRate ACK, 0 -> ChannelFrequency ACK ||| CID 0x11 is PingSlotChannelReq/Ans, it was PingSlotFreq in drafts of the Class B spec
+    PingSlotChannelAns {
         data_rate_ok: bool,
         channel_frequency_ok: bool
     },
+    ///deprecated from LoRaWAN 1.0.3 in favour of DeviceTimeReq
+    BeaconTimingReq,
+    /// 7..1 -> RFU, 0 -> BeaconFrequency ACK
     BeaconFreqAns {
         beacon_freq_ok: bool,
     },
@@ -64,166 +71,92 @@ pub enum EDMacCommands {
 
     ///mac code 0x80 to 0xFF - custom payload
     Proprietary(u8, Vec<u8>),
+    ///CID not defined for this direction, the rest of the payload since its length is unknown
+    Unknown(u8, Vec<u8>),
+}
+
+///Splits FOpts or a FPort 0 payload with the CID length table. A CID missing from the table ends the parsing:
+///proprietary and unknown commands take the rest of the payload since their length cannot be known
+fn parse_commands<T>(bytes: &[u8], payload_len: fn(u8) -> Option<usize>, command: fn(u8, &[u8]) -> T) -> Result<Vec<T>, LoRaWANError> {
+    if bytes.is_empty() {
+        return Err(LoRaWANError::InvalidBufferLength)
+    }
+    let mut commands = Vec::new();
+    let mut rest = bytes;
+    while let Some((&cid, tail)) = rest.split_first() {
+        let len = payload_len(cid).unwrap_or(tail.len());
+        if tail.len() < len {
+            return Err(LoRaWANError::TruncatedMACCommand { cid, expected: len, received: tail.len() })
+        }
+        let (payload, next) = tail.split_at(len);
+        commands.push(command(cid, payload));
+        rest = next;
+    }
+    Ok(commands)
 }
 
 impl EDMacCommands {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, LoRaWANError> {
-        if bytes.is_empty() {
-            Err(LoRaWANError::InvalidBufferLength)
+    ///Length of the payload after the CID, None for proprietary CIDs and the ones not sent by end devices
+    pub fn payload_len(cid: u8) -> Option<usize> {
+        match cid {
+            0x02 | 0x04 | 0x08 | 0x09 | 0x0C | 0x0D | 0x12 => Some(0),
+            0x01 | 0x03 | 0x05 | 0x07 | 0x0A | 0x0B | 0x0F | 0x10 | 0x11 | 0x13 | 0x20 => Some(1),
+            0x06 => Some(2),
+            _ => None,
         }
-        else {
-            let mut mac_commands_vec = Vec::new();
-            let mut bytes_iterator = bytes.iter();
-            while let Some(&b) = bytes_iterator.next() {
-                let new_command = match b {
-                    0x01 => {
-                        if let Some(ind) = bytes_iterator.next() {
-                            EDMacCommands::ResetInd(ind & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x02 => EDMacCommands::LinkCheckReq,
-                    0x03 => {
-                        if let Some(status) = bytes_iterator.next() {
-                            EDMacCommands::LinkADRAns { 
-                                power_ack:        *status & 0b00000100 > 0, 
-                                data_rate_ack:    *status & 0b00000010 > 0, 
-                                channel_mask_ack: *status & 0b00000001 > 0 
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x04 => EDMacCommands::DutyCycleAns,
-                    0x05 => {
-                        if let Some(status) = bytes_iterator.next() {
-                            EDMacCommands::RXParamSetupAns { 
-                                rx1_dr_offset_ack: *status & 0b00000100 > 0, 
-                                rx2_data_rate_ack: *status & 0b00000010 > 0, 
-                                channel_ack:       *status & 0b00000001 > 0 
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x06 => {
-                        let battery = bytes_iterator.next();
-                        let margin = bytes_iterator.next();
-                        if let (Some(b), Some(m)) = (battery, margin) {
-                            EDMacCommands::DevStatusAns { 
-                                battery: *b, 
-                                margin: *m & 0b00111111 
-                            }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x07 => {
-                        if let Some(status) = bytes_iterator.next() {
-                            EDMacCommands::NewChannelAns { 
-                                data_range_ok:        *status & 0b00000010 > 0, 
-                                channel_frequency_ok: *status & 0b00000001 > 0 
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x08 => EDMacCommands::RXTimingSetupAns,
-                    0x09 => EDMacCommands::TxParamSetupAns,
-                    0x0A => {
-                        if let Some(status) = bytes_iterator.next() {
-                            EDMacCommands::DlChannelAns { 
-                                uplink_frequency_exists:  *status & 0b00000010 > 0, 
-                                channel_frequency_ok:     *status & 0b00000001 > 0 
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0B => {
-                        if let Some(version) = bytes_iterator.next() {
-                            EDMacCommands::RekeyInd(*version & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0C => EDMacCommands::ADRParamSetupAns,
-                    0x0D => EDMacCommands::DeviceTimeReq,
-                    0x0E => {
-                        return Err(LoRaWANError::MalformedMACCommand)
-                    }
-                    0x0F => {
-                        if let Some(ta) = bytes_iterator.next() {
-                            EDMacCommands::RejoinParamSetupAns {
-                                time_ack: *ta & 0b00000001 > 0,
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x10 => {
-                        if let Some(param) = bytes_iterator.next() {
-                            EDMacCommands::PingSlotInfoReq {
-                                periodicity: *param & 0b00000011,
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x11 => {
-                        if let Some(ans) = bytes_iterator.next() {
-                            EDMacCommands::PingSlotChannelAns {
-                                data_rate_ok: ans & 0b00000010 > 0,
-                                channel_frequency_ok: ans & 0b00000001 > 0,
-                            }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x13 => {
-                        if let Some(status) = bytes_iterator.next() {
-                            EDMacCommands::BeaconFreqAns { beacon_freq_ok: status & 0b00000001 > 0 }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x20 => {
-                        if let Some(&class) = bytes_iterator.next() {
-                            EDMacCommands::DeviceModeInd(DeviceClass::from_byte(class))
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, LoRaWANError> {
+        parse_commands(bytes, Self::payload_len, Self::from_payload)
+    }
 
-                    }
-                    code if (0x80..=0xff).contains(&b) => {
-                        let payload: Vec<u8> = bytes_iterator.by_ref().copied().collect();
-                        EDMacCommands::Proprietary(code, payload)
-                    }
-                    _ => { //invalid code
-                        return Err(LoRaWANError::MalformedMACCommand)
-                    }
-                };
-                mac_commands_vec.push(new_command);
-            }
-            Ok(mac_commands_vec)
+    ///payload is as long as payload_len(cid) tells
+    fn from_payload(cid: u8, payload: &[u8]) -> Self {
+        match (cid, payload) {
+            (0x01, &[minor]) => EDMacCommands::ResetInd(minor & 0b00001111),
+            (0x02, []) => EDMacCommands::LinkCheckReq,
+            (0x03, &[status]) => EDMacCommands::LinkADRAns {
+                power_ack:        status & 0b00000100 > 0,
+                data_rate_ack:    status & 0b00000010 > 0,
+                channel_mask_ack: status & 0b00000001 > 0
+            },
+            (0x04, []) => EDMacCommands::DutyCycleAns,
+            (0x05, &[status]) => EDMacCommands::RXParamSetupAns {
+                rx1_dr_offset_ack: status & 0b00000100 > 0,
+                rx2_data_rate_ack: status & 0b00000010 > 0,
+                channel_ack:       status & 0b00000001 > 0
+            },
+            (0x06, &[battery, margin]) => EDMacCommands::DevStatusAns { battery, margin: margin & 0b00111111 },
+            (0x07, &[status]) => EDMacCommands::NewChannelAns {
+                data_range_ok:        status & 0b00000010 > 0,
+                channel_frequency_ok: status & 0b00000001 > 0
+            },
+            (0x08, []) => EDMacCommands::RXTimingSetupAns,
+            (0x09, []) => EDMacCommands::TxParamSetupAns,
+            (0x0A, &[status]) => EDMacCommands::DlChannelAns {
+                uplink_frequency_exists: status & 0b00000010 > 0,
+                channel_frequency_ok:    status & 0b00000001 > 0
+            },
+            (0x0B, &[minor]) => EDMacCommands::RekeyInd(minor & 0b00001111),
+            (0x0C, []) => EDMacCommands::ADRParamSetupAns,
+            (0x0D, []) => EDMacCommands::DeviceTimeReq,
+            (0x0F, &[status]) => EDMacCommands::RejoinParamSetupAns { time_ack: status & 0b00000001 > 0 },
+            (0x10, &[param]) => EDMacCommands::PingSlotInfoReq { periodicity: param & 0b00000111 },
+            (0x11, &[status]) => EDMacCommands::PingSlotChannelAns {
+                data_rate_ok:         status & 0b00000010 > 0,
+                channel_frequency_ok: status & 0b00000001 > 0,
+            },
+            (0x12, []) => EDMacCommands::BeaconTimingReq,
+            (0x13, &[status]) => EDMacCommands::BeaconFreqAns { beacon_freq_ok: status & 0b00000001 > 0 },
+            (0x20, &[class]) => DeviceClass::from_byte(class).map_or_else(|| EDMacCommands::Unknown(cid, payload.to_vec()), EDMacCommands::DeviceModeInd),
+            (0x80..=0xff, _) => EDMacCommands::Proprietary(cid, payload.to_vec()),
+            _ => EDMacCommands::Unknown(cid, payload.to_vec()),
         }
     }
 }
 
 ///Commands sent by the server
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum NCMacCommands {
     /// same logic but regarding the server minor version and must be equal to the one sent by the device
     ResetConf(u8),
@@ -235,24 +168,17 @@ pub enum NCMacCommands {
     },
     ///multiple blocks are allowed if contiguous
     LinkADRReq {
-        ///data_rate_tx_power byte: 7..4 -> data rate, 3..0 -> TxPower |||| 0xF in these fields means that this value must be ignored
-        data_rate: u8,
-        tx_power: u8,
-        
+        data_rate_tx_power: DataRateTxPower,
         ///encodes the channels for uplink, with LSB order, if the bit is set it means that that channel can be used
         ch_mask: u16,
-        
-        /// redundancy byte: 7 -> RFU, 6..4 -> ChMaskCntl, 3..0 -> NbTrans. If NbTrans is 0 it should be ignored ||||  ChMaskCntl is region specific and defined in PHY  // TODO
-        ch_mask_cntl: u8,
-        nb_trans: u8,
+        redundancy: Redundancy,
     },
     ///7..4 ->RFU, 3..0 -> MaxDCycle
     DutyCycleReq(u8),
     
     RXParamSetupReq {
-        ///dl_settings byte: 7 -> RFU, 6..4 -> RX1DRoffset, 3..0 -> RX2DataRate
-        rx1_dr_offset: u8,
-        rx2_data_rate: u8,
+        ///OptNeg is RFU here and always sent as 0
+        dl_settings: DLSettings,
         ///coded as defined in NewChannelReq command
         freq: u32,
     },
@@ -264,13 +190,12 @@ pub enum NCMacCommands {
         max_dr: u8,
         min_dr: u8,
     },
-    /// 7..4 -> RFU, 3..0 -> Delay (0 is 1s, 1 is 1s and 15 is 15s)
-    RXTimingSetupReq(u8),
+    RXTimingSetupReq(RxDelay),
     /// 7..6 -> RFU, 5 -> DownlinkDwellTime*, 4 -> UplinkDwellTime*, 3..0 -> MaxEIRP***
     TxParamSetupReq {
         downlink_dwell_time: bool,
         uplink_dwell_time: bool,
-        max_eirp: u8
+        max_eirp: MaxEIRP
     },
     DlChannelReq {
         ch_index: u8,
@@ -288,7 +213,7 @@ pub enum NCMacCommands {
         ///indicates steps of ½^8 seconds
         second_fraction: u8,
     },
-    /// 15..14 -> RFU, 13..11 -> period, 10..8 -> max_retries, 7 RFU, 6..4 -> rejoin_type, 3..0 -> DR ****
+    /// 15..14 -> RFU, 13..11 -> period, 10..8 -> max_retries, 7 RFU, 6..4 -> rejoin_type, 3..0 -> DR, little endian ****
     ForceRejoinReq {
         period: u8,
         max_retries: u8,
@@ -306,7 +231,14 @@ pub enum NCMacCommands {
     PingSlotChannelReq {
         frequency: u32,
         data_rate: u8
-    },  
+    },
+    ///deprecated from LoRaWAN 1.0.3 in favour of DeviceTimeAns
+    BeaconTimingAns {
+        ///the next beacon starts in 30ms * (delay + 1) from the end of this downlink
+        delay: u16,
+        ///index of the beacon channel, always 0 in the regions with a single beacon frequency
+        channel: u8,
+    },
     BeaconFreqReq {
         frequency: u32,
     },
@@ -316,6 +248,8 @@ pub enum NCMacCommands {
 
     ///mac code 0x80 to 0xFF - custom payload
     Proprietary(u8, Vec<u8>),
+    ///CID not defined for this direction, the rest of the payload since its length is unknown
+    Unknown(u8, Vec<u8>),
 }
 
 /*** Dwell Time ***                     ||| **** period -> delay between retransmissions = 32s x 2^period + rand(0,32)s        ||| ***** MaxCountN -> 0 to 15, the device has to send a rejoin after 2^(MaxCountN + 4) uplink
@@ -339,222 +273,82 @@ pub enum NCMacCommands {
 /        14  -> 33                      |||
 /        15  -> 36                      |||*/
 impl NCMacCommands {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, LoRaWANError> {
-        if bytes.is_empty() {
-            Err(LoRaWANError::InvalidBufferLength)
+    ///Length of the payload after the CID, None for proprietary CIDs and the ones not sent by the network
+    pub fn payload_len(cid: u8) -> Option<usize> {
+        match cid {
+            0x06 | 0x10 => Some(0),
+            0x01 | 0x04 | 0x08 | 0x09 | 0x0B | 0x0C | 0x0F | 0x20 => Some(1),
+            0x02 | 0x0E => Some(2),
+            0x12 | 0x13 => Some(3),
+            0x03 | 0x05 | 0x0A | 0x11 => Some(4),
+            0x07 | 0x0D => Some(5),
+            _ => None,
         }
-        else {
-            let mut mac_commands_vec = Vec::new();
-            let mut bytes_iterator = bytes.iter();
-            while let Some(&b) = bytes_iterator.next() {
-                let new_command = match b {
-                    0x01 => {
-                        if let Some(version) = bytes_iterator.next() {
-                            NCMacCommands::ResetConf(version & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x02 => {
-                        let margin = bytes_iterator.next();
-                        let gw_cnt = bytes_iterator.next();
-                        if let (Some(&m), Some(&gc)) = (margin, gw_cnt) {
-                            NCMacCommands::LinkCheckAns { margin: m, gw_cnt: gc }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x03 => {
-                        let data_rate_tx_power = bytes_iterator.next();
-                        let ch_mask_byte_1 = bytes_iterator.next();
-                        let ch_mask_byte_2 = bytes_iterator.next();
-                        let redundancy = bytes_iterator.next();
-                        if let (Some(&drtp), Some(&cmb1), Some(&cmb2), Some(&r)) = (data_rate_tx_power, ch_mask_byte_1, ch_mask_byte_2, redundancy) {
-                            let data_rate = (drtp & 0b11110000) >> 4;
-                            let tx_power = drtp & 0b00001111;
-                            let ch_mask = u16::from_le_bytes([cmb1, cmb2]);
-                            let ch_mask_cntl = (r & 0b01110000) >> 4;
-                            let nb_trans = r & 0b00001111;
-                            NCMacCommands::LinkADRReq { data_rate, tx_power, ch_mask, ch_mask_cntl, nb_trans}
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x04 => {
-                        if let Some(b) = bytes_iterator.next() {
-                            NCMacCommands::DutyCycleReq(b & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x05 => {
-                        let dl_settings = bytes_iterator.next();
-                        let freq_b1 = bytes_iterator.next();
-                        let freq_b2 = bytes_iterator.next();
-                        let freq_b3 = bytes_iterator.next();
-                        if let (Some(&ds), Some(&fb1), Some(&fb2), Some(&fb3)) = (dl_settings, freq_b1, freq_b2, freq_b3) {
-                            let rx1_dr_offset = (ds & 0b01110000) >> 4;
-                            let rx2_data_rate = ds & 0b00001111;
-                            let freq = u32::from_le_bytes([fb1, fb2, fb3, 0]);
-                            NCMacCommands::RXParamSetupReq { rx1_dr_offset, rx2_data_rate, freq }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x06 => NCMacCommands::DevStatusReq,
-                    0x07 => {
-                        let ch_index = bytes_iterator.next();
-                        let freq_b1 = bytes_iterator.next();
-                        let freq_b2 = bytes_iterator.next();
-                        let freq_b3 = bytes_iterator.next();
-                        let data_range = bytes_iterator.next();
-                        if let (Some(&ci), Some(&fb1), Some(&fb2), Some(&fb3), Some(&dr)) = (ch_index, freq_b1, freq_b2, freq_b3, data_range) {
-                            let freq = u32::from_le_bytes([fb1, fb2, fb3, 0]);
-                            let max_dr = (dr & 0b11110000) >> 4;
-                            let min_dr = dr & 0b00001111;
-
-                            NCMacCommands::NewChannelReq { ch_index: ci, freq, max_dr, min_dr }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x08 => {
-                        if let Some(settings) = bytes_iterator.next() {
-                            NCMacCommands::RXTimingSetupReq(settings & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x09 => {
-                        if let Some(eirp_dwell_time) = bytes_iterator.next() {
-                            let downlink_dwell_time = (eirp_dwell_time & 0b00100000) > 0; 
-                            let uplink_dwell_time =   (eirp_dwell_time & 0b00010000) > 0; 
-                            let max_eirp = eirp_dwell_time & 0b00001111;
-                            NCMacCommands::TxParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0A => {
-                        let ch_index = bytes_iterator.next();
-                        let freq_b1 = bytes_iterator.next();
-                        let freq_b2 = bytes_iterator.next();
-                        let freq_b3 = bytes_iterator.next();
-                        if let (Some(&ci), Some(&fb1), Some(&fb2), Some(&fb3)) = (ch_index, freq_b1, freq_b2, freq_b3) {
-                            let freq = u32::from_le_bytes([fb1, fb2, fb3, 0]);
-                            NCMacCommands::DlChannelReq { ch_index: ci, freq }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0B => {
-                        if let Some(&version) = bytes_iterator.next() {
-                            NCMacCommands::RekeyConf(version & 0b00001111)
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0C => {
-                        if let Some(&adr_params) = bytes_iterator.next() {
-                            let limit_exp = (adr_params & 0b11110000) >> 4;
-                            let delay_exp = adr_params & 0b00001111;
-
-                            NCMacCommands::ADRParamSetupReq { limit_exp, delay_exp }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0D => {
-                        let epoch_b0 = bytes_iterator.next();
-                        let epoch_b1 = bytes_iterator.next();
-                        let epoch_b2 = bytes_iterator.next();
-                        let epoch_b3 = bytes_iterator.next();
-                        let second_fraction = bytes_iterator.next();
-                        if let (Some(&eb0), Some(&eb1), Some(&eb2), Some(&eb3), Some(&sf)) = (epoch_b0, epoch_b1, epoch_b2, epoch_b3, second_fraction) {
-                            let epoch = u32::from_le_bytes([eb0, eb1, eb2, eb3]);
-                            NCMacCommands::DeviceTimeAns { epoch, second_fraction: sf }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0E => {
-                        let first_byte = bytes_iterator.next();
-                        let second_byte = bytes_iterator.next();
-                        if let (Some(&fb), Some(&sb)) = (first_byte, second_byte) {
-                            let period = (fb & 0b00111000) >> 3;
-                            let max_retries = fb & 0b00000111;
-
-                            let rejoin_type = (sb & 0b01110000) >> 4;
-                            let dr = sb & 0b00001111;
-
-                            NCMacCommands::ForceRejoinReq { period, max_retries, rejoin_type, dr }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x0F => {
-                        if let Some(&payload) = bytes_iterator.next() {
-                            let max_time_n  = (payload & 0b11110000) >> 4;
-                            let max_count_n =  payload & 0b00001111;
-
-                            NCMacCommands::RejoinParamSetupReq { max_time_n, max_count_n }
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x10 => NCMacCommands::PingSlotInfoAns,
-                    0x11 => {
-                        let freq_b0 = bytes_iterator.next();
-                        let freq_b1 = bytes_iterator.next();
-                        let freq_b2 = bytes_iterator.next();
-                        let data_rate = bytes_iterator.next();
-                        if let (Some(&fb0), Some(&fb1), Some(&fb2), Some(&dr)) = (freq_b0, freq_b1, freq_b2, data_rate) {
-                            let frequency = u32::from_le_bytes([fb0, fb1, fb2, 0]);
-                            NCMacCommands::PingSlotChannelReq { frequency, data_rate: dr & 0b00001111 }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x13 => {
-                        let freq_b0 = bytes_iterator.next();
-                        let freq_b1 = bytes_iterator.next();
-                        let freq_b2 = bytes_iterator.next();
-                        if let (Some(&fb0), Some(&fb1), Some(&fb2)) = (freq_b0, freq_b1, freq_b2) {
-                            let frequency = u32::from_le_bytes([fb0, fb1, fb2, 0]);
-                            NCMacCommands::BeaconFreqReq { frequency }
-                        } else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
-                    },
-                    0x20 => {
-                        if let Some(&class) = bytes_iterator.next() {
-                            NCMacCommands::DeviceModeConf(DeviceClass::from_byte(class))
-                        }
-                        else {
-                            return Err(LoRaWANError::MalformedMACCommand)
-                        }
+    }
 
-                    }
-                    code if (0x80..=0xff).contains(&b) => {
-                        let payload: Vec<u8> = bytes_iterator.by_ref().copied().collect();
-                        NCMacCommands::Proprietary(code, payload)
-                    }
-                    _ => { //invalid code
-                        return Err(LoRaWANError::MalformedMACCommand)
-                    }
-                    
-                };
-                mac_commands_vec.push(new_command);
-            }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, LoRaWANError> {
+        parse_commands(bytes, Self::payload_len, Self::from_payload)
+    }
 
-            Ok(mac_commands_vec)
+    ///payload is as long as payload_len(cid) tells
+    fn from_payload(cid: u8, payload: &[u8]) -> Self {
+        let frequency = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+        match (cid, payload) {
+            (0x01, &[minor]) => NCMacCommands::ResetConf(minor & 0b00001111),
+            (0x02, &[margin, gw_cnt]) => NCMacCommands::LinkCheckAns { margin, gw_cnt },
+            (0x03, &[drtp, cmb1, cmb2, redundancy]) => NCMacCommands::LinkADRReq {
+                data_rate_tx_power: DataRateTxPower::from_byte(drtp),
+                ch_mask: u16::from_le_bytes([cmb1, cmb2]),
+                redundancy: Redundancy::from_byte(redundancy),
+            },
+            (0x04, &[max_duty_cycle]) => NCMacCommands::DutyCycleReq(max_duty_cycle & 0b00001111),
+            (0x05, &[dl_settings, ref freq @ ..]) => NCMacCommands::RXParamSetupReq {
+                dl_settings: DLSettings::from_byte(dl_settings).with_opt_neg(false),
+                freq: frequency(freq),
+            },
+            (0x06, []) => NCMacCommands::DevStatusReq,
+            (0x07, &[ch_index, f0, f1, f2, data_range]) => NCMacCommands::NewChannelReq {
+                ch_index,
+                freq: frequency(&[f0, f1, f2]),
+                max_dr: (data_range & 0b11110000) >> 4,
+                min_dr: data_range & 0b00001111,
+            },
+            (0x08, &[settings]) => NCMacCommands::RXTimingSetupReq(RxDelay::from_byte(settings)),
+            (0x09, &[eirp_dwell_time]) => NCMacCommands::TxParamSetupReq {
+                downlink_dwell_time: eirp_dwell_time & 0b00100000 > 0,
+                uplink_dwell_time:   eirp_dwell_time & 0b00010000 > 0,
+                max_eirp: MaxEIRP::from_byte(eirp_dwell_time),
+            },
+            (0x0A, &[ch_index, ref freq @ ..]) => NCMacCommands::DlChannelReq { ch_index, freq: frequency(freq) },
+            (0x0B, &[minor]) => NCMacCommands::RekeyConf(minor & 0b00001111),
+            (0x0C, &[adr_params]) => NCMacCommands::ADRParamSetupReq {
+                limit_exp: (adr_params & 0b11110000) >> 4,
+                delay_exp: adr_params & 0b00001111,
+            },
+            (0x0D, &[e0, e1, e2, e3, second_fraction]) => NCMacCommands::DeviceTimeAns {
+                epoch: u32::from_le_bytes([e0, e1, e2, e3]),
+                second_fraction,
+            },
+            (0x0E, &[low, high]) => NCMacCommands::ForceRejoinReq {
+                period: (high & 0b00111000) >> 3,
+                max_retries: high & 0b00000111,
+                rejoin_type: (low & 0b01110000) >> 4,
+                dr: low & 0b00001111,
+            },
+            (0x0F, &[params]) => NCMacCommands::RejoinParamSetupReq {
+                max_time_n: (params & 0b11110000) >> 4,
+                max_count_n: params & 0b00001111,
+            },
+            (0x10, []) => NCMacCommands::PingSlotInfoAns,
+            (0x11, &[f0, f1, f2, data_rate]) => NCMacCommands::PingSlotChannelReq {
+                frequency: frequency(&[f0, f1, f2]),
+                data_rate: data_rate & 0b00001111,
+            },
+            (0x12, &[d0, d1, channel]) => NCMacCommands::BeaconTimingAns { delay: u16::from_le_bytes([d0, d1]), channel },
+            (0x13, freq) => NCMacCommands::BeaconFreqReq { frequency: frequency(freq) },
+            (0x20, &[class]) => DeviceClass::from_byte(class).map_or_else(|| NCMacCommands::Unknown(cid, payload.to_vec()), NCMacCommands::DeviceModeConf),
+            (0x80..=0xff, _) => NCMacCommands::Proprietary(cid, payload.to_vec()),
+            _ => NCMacCommands::Unknown(cid, payload.to_vec()),
         }
     }
 }
@@ -621,7 +415,7 @@ impl ToBytes for EDMacCommands {
                 vec![0x0F, ans]
             },
             EDMacCommands::PingSlotInfoReq { periodicity } => {
-                vec![0x10, periodicity & 0b00000011]
+                vec![0x10, periodicity & 0b00000111]
             },
             EDMacCommands::PingSlotChannelAns { data_rate_ok, channel_frequency_ok } => {
                 let mut ans = 0;
@@ -629,6 +423,9 @@ impl ToBytes for EDMacCommands {
                 if *channel_frequency_ok { ans |= 0b00000001 }
                 vec![0x11, ans]
             },
+            EDMacCommands::BeaconTimingReq => {
+                vec![0x12]
+            },
             EDMacCommands::BeaconFreqAns { beacon_freq_ok } => {
                 let mut ans = 0;
                 if *beacon_freq_ok { ans |= 0b00000001 }
@@ -637,7 +434,7 @@ impl ToBytes for EDMacCommands {
             EDMacCommands::DeviceModeInd(class) => {
                 vec![0x20, class.to_byte()]
             },
-            EDMacCommands::Proprietary(code, payload) => {
+            EDMacCommands::Proprietary(code, payload) | EDMacCommands::Unknown(code, payload) => {
                 let mut r = vec![0; payload.len() + 1];
                 let slice = &mut r[1..];
                 slice.copy_from_slice(payload);
@@ -657,21 +454,16 @@ impl ToBytes for NCMacCommands {
             NCMacCommands::LinkCheckAns { margin, gw_cnt } => {
                 vec![0x02, *margin, *gw_cnt]
             },
-            NCMacCommands::LinkADRReq { data_rate, tx_power, ch_mask, ch_mask_cntl, nb_trans } => {
-                let data_rate_tx_power = ((data_rate & 0b00001111) << 4) | (tx_power & 0b00001111);
-                let redundancy = ((ch_mask_cntl & 0b00000111) << 4) | (nb_trans & 0b00001111);
+            NCMacCommands::LinkADRReq { data_rate_tx_power, ch_mask, redundancy } => {
                 let ch_mask_bytes:[u8; 2] = ch_mask.to_le_bytes();
-                vec![0x03, data_rate_tx_power, ch_mask_bytes[0], ch_mask_bytes[1], redundancy]                
+                vec![0x03, data_rate_tx_power.to_byte(), ch_mask_bytes[0], ch_mask_bytes[1], redundancy.to_byte()]
             },
             NCMacCommands::DutyCycleReq(b) => {
                 vec![0x04, 0b00001111 & b]                
             },
-            NCMacCommands::RXParamSetupReq { rx1_dr_offset, rx2_data_rate, freq } => {
-                let mut dl_settings = 0;
-                dl_settings |= (rx1_dr_offset & 0b00000111) << 4;
-                dl_settings |= rx2_data_rate & 0b00001111;
+            NCMacCommands::RXParamSetupReq { dl_settings, freq } => {
                 let frequency: [u8; 4] = freq.to_le_bytes();
-                vec![0x05, dl_settings, frequency[0], frequency[1], frequency[2]]               
+                vec![0x05, dl_settings.with_opt_neg(false).to_byte(), frequency[0], frequency[1], frequency[2]]
             },
             NCMacCommands::DevStatusReq => {
                 vec![0x06]                
@@ -681,14 +473,14 @@ impl ToBytes for NCMacCommands {
                 let frequency: [u8; 4] = freq.to_le_bytes();
                 vec![0x07, *ch_index, frequency[0], frequency[1], frequency[2], data_range]
             },
-            NCMacCommands::RXTimingSetupReq(settings) => {
-                vec![0x08, settings & 0b00001111]                
+            NCMacCommands::RXTimingSetupReq(delay) => {
+                vec![0x08, delay.to_byte()]
             },
             NCMacCommands::TxParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp }=> {
                 let mut eirp_dwell_time = 0;
                 if *downlink_dwell_time { eirp_dwell_time |= 0b00100000 }
                 if *uplink_dwell_time   { eirp_dwell_time |= 0b00010000 }
-                eirp_dwell_time |= max_eirp & 0b00001111;
+                eirp_dwell_time |= max_eirp.to_byte();
                 vec![0x09, eirp_dwell_time]                
             },
             NCMacCommands::DlChannelReq { ch_index, freq } => {
@@ -709,16 +501,16 @@ impl ToBytes for NCMacCommands {
                 vec![0x0D, epoch_as_bytes[0], epoch_as_bytes[1], epoch_as_bytes[2], epoch_as_bytes[3], *second_fraction]
             },
             NCMacCommands::ForceRejoinReq { period, max_retries, rejoin_type, dr }=> {
-                let mut first_byte = 0;
-                let mut second_byte = 0;
+                let mut low_byte = 0;
+                let mut high_byte = 0;
 
-                first_byte |= (period     & 0b00000111) << 3;
-                first_byte |= max_retries & 0b00000111;
+                high_byte |= (period     & 0b00000111) << 3;
+                high_byte |= max_retries & 0b00000111;
                 
-                second_byte |= (rejoin_type & 0b00000111) << 4;
-                second_byte |= dr  & 0b00001111;
+                low_byte |= (rejoin_type & 0b00000111) << 4;
+                low_byte |= dr  & 0b00001111;
 
-                vec![0x0E, first_byte, second_byte]                
+                vec![0x0E, low_byte, high_byte]
             },
             NCMacCommands::RejoinParamSetupReq { max_time_n, max_count_n }=> {
                 let mut payload = 0;
@@ -735,6 +527,10 @@ impl ToBytes for NCMacCommands {
                 let fb: [u8; 4] = frequency.to_le_bytes();
                 vec![0x11, fb[0], fb[1], fb[2], data_rate & 0b00001111]
             },
+            NCMacCommands::BeaconTimingAns { delay, channel } => {
+                let db: [u8; 2] = delay.to_le_bytes();
+                vec![0x12, db[0], db[1], *channel]
+            },
             NCMacCommands::BeaconFreqReq { frequency } => {
                 let fb: [u8; 4] = frequency.to_le_bytes();
                 vec![0x13, fb[0], fb[1], fb[2]]
@@ -744,7 +540,7 @@ impl ToBytes for NCMacCommands {
                 vec![0x20, class.to_byte()]
             },
             
-            NCMacCommands::Proprietary(code, payload) => {
+            NCMacCommands::Proprietary(code, payload) | NCMacCommands::Unknown(code, payload) => {
                 let mut r = vec![0; payload.len() + 1];
                 let slice = &mut r[1..];
                 slice.copy_from_slice(payload);
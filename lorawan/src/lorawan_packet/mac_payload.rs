@@ -20,7 +20,8 @@ impl MACPayload {
         }
     }
 
-    fn encrypt_payload(key: &Key, payload: &mut Vec<u8>, dev_addr: &[u8;4], direction_byte: u8, counter: u32) -> Result<(), LoRaWANError> {
+    ///Encrypts or decrypts in place an FRMPayload, counter is the full 32 bits frame counter
+    pub fn encrypt_payload(key: &Key, payload: &mut Vec<u8>, dev_addr: &[u8;4], direction_byte: u8, counter: u32) -> Result<(), LoRaWANError> {
         const CHUNK_SIZE: usize = 16;
         let len = payload.len();
         utils::pad_to_16(payload);
@@ -107,17 +108,15 @@ impl MACPayload {
                         };
                         let direction_byte = if is_uplink { 0 } else { 1 };
     
-                        //TODO ma come fanno i cli vari a scomporre il pacchetto senza avere il contesto del pacchetto?
-                        //solo con il fcnt dell'fhdr? mancano i primi 2 bytes!! -> in realtà pare con un for a caso -> https://vscode.dev/github/TheThingsNetwork/lorawan-stack/cmd/ttn-lw-cli/commands/lorawan.go line 81
-                        // --> studiarlo dalle varie implementazioni
-                        /*let counter = if direction_byte == 0 {
+                        //the upper half of the counter comes from the session, LoRaWANPacket::from_bytes_with_fcnt_search looks for it
+                        let session_counter = if direction_byte == 0 {
                             session_context.network_context().f_cnt_up()
                         } else if fport == 0 {
                             session_context.network_context().nf_cnt_dwn()
                         } else {
                             session_context.application_context().af_cnt_dwn()
-                        };*/
-                        let counter = fhdr.fcnt() as u32;
+                        };
+                        let counter = session_counter & 0xffff0000 | fhdr.fcnt() as u32;
                         MACPayload::encrypt_payload(key, &mut decrypted_payload, session_context.network_context().dev_addr(), direction_byte, counter)?;
                     } else {
                         //println!("No device context, skipping MACPayload decryption");
@@ -143,7 +142,8 @@ impl ToBytesWithContext for MACPayload {
     fn to_bytes_with_context(&self, device_context: &Device) -> Result<Vec<u8>, LoRaWANError> {
         let mut ret = Vec::with_capacity(64);
 
-        if  (!self.is_application() && self.fhdr.fctrl().f_opts_len() > 0) ||
+        //FOpts can go without FPort, not together with FPort 0
+        if  (self.fport == Some(0) && self.fhdr.fctrl().f_opts_len() > 0) ||
             (self.fport.is_some() && self.frm_payload.is_none()) || 
             (self.fport.is_none() && self.frm_payload.is_some()) {
             return Err(LoRaWANError::FPortInvalidValue);
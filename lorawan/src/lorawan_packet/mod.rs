@@ -1,11 +1,12 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{device::{Device, LoRaWANVersion}, utils::{errors::LoRaWANError, traits::{ToBytes, ToBytesWithContext}}, encryption::{self, aes_128_decrypt_with_padding, aes_128_encrypt_with_padding}};
+use crate::{device::Device, utils::{errors::LoRaWANError, traits::{ToBytes, ToBytesWithContext}}, encryption::{self, aes_128_decrypt_with_padding, aes_128_encrypt_with_padding}};
 
 use self::{mac_payload::MACPayload, mhdr::{MHDR, MType}, payload::Payload, join::{JoinAcceptPayload, RejoinRequestPayload, JoinRequestPayload}};
 pub mod fctrl;
+pub mod fields;
 pub mod fhdr;
 pub mod join;
 pub mod mac_commands;
@@ -13,6 +14,30 @@ pub mod mac_payload;
 pub mod payload;
 pub mod mhdr;
 
+///Roll-overs of the 16 bits FCnt searched after the most likely one, for devices that lost frames across several of them
+pub const FCNT_ROLLOVER_SEARCH: u32 = 2;
+
+///Full frame counters a FCnt can stand for: the first one after the last counter, the following roll-overs, then the previous one
+fn fcnt_candidates(last: u32, fcnt: u16) -> Vec<u32> {
+    let same_half = (last & 0xffff0000 | fcnt as u32) as u64;
+    //a FCnt not above the last one means a roll-over, or a frame already received
+    let next = if same_half > last as u64 { same_half } else { same_half + 0x10000 };
+    (0..=FCNT_ROLLOVER_SEARCH as u64).map(|i| next + (i << 16))
+        .chain(next.checked_sub(0x10000))
+        .filter_map(|c| u32::try_from(c).ok())
+        .collect()
+}
+
+///Values of the LoRaWAN 1.1 data frame MIC that are not in the frame. LoRaWAN 1.0 MICs do not use them
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MicContext {
+    ///FCnt of the confirmed frame acknowledged by this one, Some sets the ACK bit of the uplinks built by Device::create_uplink
+    pub conf_fcnt: Option<u16>,
+    ///Data rate and channel index of the uplink
+    pub tx_dr: u8,
+    pub tx_ch: u8,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct LoRaWANPacket {
     mhdr: MHDR,
@@ -49,39 +74,22 @@ impl LoRaWANPacket {
         self.payload
     }
 
-    fn extract_macpayload_mic(payload: &MACPayload, device_context: &Device, full_buffer: &[u8], ) -> Result<[u8;4], LoRaWANError> {
+    fn extract_macpayload_mic(payload: &MACPayload, device_context: &Device, full_buffer: &[u8], mic_context: &MicContext) -> Result<[u8;4], LoRaWANError> {
         let session = device_context.session().ok_or(LoRaWANError::SessionContextMissing)?;
         let dev_addr = session.network_context().dev_addr();
         let is_downlink = payload.fhdr().fctrl().is_downlink(); 
+        let is_1_1 = device_context.version().is_1_1_or_greater();
 
-        let conf_fcnt: [u8; 2] = if payload.fhdr().fctrl().is_ack() {
-            if is_downlink && *device_context.version() == LoRaWANVersion::V1_1 {
-                //TODO qui ci va il fnct dell'uplink di cui devi fare l'ack -> come me lo passo? -> probabilmente è l'ultimo uplink ricevuto ->
-                session.network_context().f_cnt_up() as u16
-            } else if !is_downlink {
-                //FIXME se il pacchetto è un uplink in risposta ad un downlink con ack set devo prendere il counter usato in quel pacchetto ma 
-                //devo trovare un modo per distinguere se il pacchetto aveva fport == 0 o no, in quel caso devo usare l'application context.
-                session.network_context().nf_cnt_dwn() as u16 
-            } else {
-                0_u16  //downlink if version < 1.1
-            }
+        let conf_fcnt: [u8; 2] = if is_1_1 && payload.fhdr().fctrl().is_ack() {
+            mic_context.conf_fcnt.unwrap_or(0)
         } else {
-            0_u16
+            0
         }.to_le_bytes();
 
-        let txdr_txch: [u8;2] = if is_downlink {
-            [0,0]
+        let txdr_txch: [u8; 2] = if is_1_1 && !is_downlink {
+            [mic_context.tx_dr, mic_context.tx_ch]
         } else {
-            match device_context.version() {
-                LoRaWANVersion::V1_1 => {
-                    let tx = 0; //TODO bho prenderli in qualche modo, tocca modellare anche lo strato fisico prima o poi
-                    let ch = 0;
-                    [tx,ch]
-                },
-                _ => {
-                    [0,0]
-                }
-            }
+            [0, 0]
         };
 
         let direction_byte: u8 = u8::from(is_downlink);
@@ -117,23 +125,18 @@ impl LoRaWANPacket {
 
         let mic = if is_downlink {
             encryption::extract_mic(session.network_context().snwk_s_int_key(), &block)?
+        } else if is_1_1 {
+            let cmac_s = encryption::extract_mic(session.network_context().snwk_s_int_key(), &block)?;
+            
+            block[1] = 0;
+            block[2] = 0;
+            block[3] = 0;
+            block[4] = 0;
+            
+            let cmac_f = encryption::extract_mic(session.network_context().fnwk_s_int_key(), &block)?;
+            [cmac_s[0], cmac_s[1], cmac_f[0], cmac_f[1]]
         } else {
-            match device_context.version() {
-                LoRaWANVersion::V1_1 => {
-                    let cmac_s = encryption::extract_mic(session.network_context().snwk_s_int_key(), &block)?;
-                    
-                    block[1] = 0;
-                    block[2] = 0;
-                    block[3] = 0;
-                    block[4] = 0;
-                    
-                    let cmac_f = encryption::extract_mic(session.network_context().fnwk_s_int_key(), &block)?;
-                    [cmac_s[0], cmac_s[1], cmac_f[0], cmac_f[1]]
-                },
-                _ => {
-                    encryption::extract_mic(session.network_context().fnwk_s_int_key(), &block)?
-                },
-            }
+            encryption::extract_mic(session.network_context().fnwk_s_int_key(), &block)?
         };
 
         Ok(mic)
@@ -149,7 +152,7 @@ impl LoRaWANPacket {
             let key = device_context.join_context().js_int_key();
             let j_req_type = join_accept.join_req_type().to_byte();
             let join_eui = **device_context.join_eui();
-            let dev_nonce: [u8; 2] = (device_context.dev_nonce() as u16).to_be_bytes();
+            let dev_nonce: [u8; 2] = (device_context.join_accept_nonce(join_accept.join_req_type()) as u16).to_be_bytes();
             
             let mut buffer = vec![j_req_type];
             buffer.extend_from_slice(&join_eui);
@@ -171,12 +174,12 @@ impl LoRaWANPacket {
         encryption::extract_mic(key, full_buffer)
     }
 
-    fn extract_mic(&self, device_context: &Device, full_buffer: &[u8]) -> Result<[u8;4], LoRaWANError> {
+    fn extract_mic(&self, device_context: &Device, full_buffer: &[u8], mic_context: &MicContext) -> Result<[u8;4], LoRaWANError> {
         match &self.payload {
             Payload::JoinRequest(_) => LoRaWANPacket::extract_join_request_mic(device_context, full_buffer),
             Payload::RejoinRequest(rj) => LoRaWANPacket::extract_rejoin_request_mic(rj, device_context, full_buffer),
             Payload::JoinAccept(ja) => LoRaWANPacket::extract_join_accept_mic(ja, device_context, full_buffer), //teoricamente non esiste
-            Payload::MACPayload(mp) => LoRaWANPacket::extract_macpayload_mic(mp, device_context, full_buffer),
+            Payload::MACPayload(mp) => LoRaWANPacket::extract_macpayload_mic(mp, device_context, full_buffer, mic_context),
             //Payload::Proprietary(_) => if let Some(handlers) = device_context.proprietary_payload_handlers() {
             //    handlers.custom_mic_function()(full_buffer)
             //} else {
@@ -225,18 +228,23 @@ impl LoRaWANPacket {
         }
     }
 
-    pub fn validate_mic(buffer: &[u8], packet: &LoRaWANPacket, device_context: &Device) -> Result<(), LoRaWANError> {
+    pub fn validate_mic(buffer: &[u8], packet: &LoRaWANPacket, device_context: &Device, mic_context: &MicContext) -> Result<(), LoRaWANError> {
         let len = buffer.len();
         if len < 12 {
             return Err(LoRaWANError::InvalidBufferLength);
         }
         let (packet_bytes, mic) = buffer.split_at(len - 4);
         let mic: [u8; 4] = mic.try_into()?;
-        let expected_mic = packet.extract_mic(device_context, packet_bytes)?;
+        let expected_mic = packet.extract_mic(device_context, packet_bytes, mic_context)?;
         if mic != expected_mic { Err(LoRaWANError::InvalidMic) } else { Ok(()) }
     }
 
     pub fn from_bytes(bytes: &[u8], device_context: Option<&Device>, is_uplink: bool) -> Result<Self, LoRaWANError> {
+        LoRaWANPacket::from_bytes_with_mic_context(bytes, device_context, is_uplink, &MicContext::default())
+    }
+
+    ///from_bytes validating the MIC of LoRaWAN 1.1 data frames with the ConfFCnt, TxDr and TxCh of mic_context
+    pub fn from_bytes_with_mic_context(bytes: &[u8], device_context: Option<&Device>, is_uplink: bool, mic_context: &MicContext) -> Result<Self, LoRaWANError> {
         let len = bytes.len(); 
         if len < 12 {
             Err(LoRaWANError::InvalidBufferLength)
@@ -309,7 +317,7 @@ impl LoRaWANPacket {
             packet.check_coherence_mhdr_fctrl()?;
             if MType::JoinAccept != packet.mhdr.mtype() { //join accept MIC is already handled
                 if let Some(device) = device_context {
-                    LoRaWANPacket::validate_mic(bytes, &packet, device)?;
+                    LoRaWANPacket::validate_mic(bytes, &packet, device, mic_context)?;
                 }
                 else {
                     //println!("No device context, skipping mic validation");
@@ -320,6 +328,55 @@ impl LoRaWANPacket {
         }
     }
 
+    ///Parses a data frame without trusting the upper half of the session counters. The counter of the session for the frame direction
+    ///is taken as the last one received, the candidates around it are tried and the first one with a valid MIC wins.
+    ///Returns the packet, decrypted with the full counter, and the counter itself: callers reject it if not greater than the last one
+    pub fn from_bytes_with_fcnt_search(bytes: &[u8], device_context: &Device, is_uplink: bool, mic_context: &MicContext) -> Result<(Self, u32), LoRaWANError> {
+        let packet = LoRaWANPacket::from_bytes(bytes, None, is_uplink)?;
+        let payload = match packet.payload() {
+            Payload::MACPayload(p) => p,
+            _ => return Err(LoRaWANError::MHDRNotCoherentWithPayload),
+        };
+        let session = device_context.session().ok_or(LoRaWANError::SessionContextMissing)?;
+        let last = if is_uplink {
+            session.network_context().f_cnt_up()
+        } else if payload.is_application() {
+            session.application_context().af_cnt_dwn()
+        } else {
+            session.network_context().nf_cnt_dwn()
+        };
+
+        let mut candidate_device = *device_context;
+        for counter in fcnt_candidates(last, payload.fhdr().fcnt()) {
+            let session = candidate_device.session_mut().ok_or(LoRaWANError::SessionContextMissing)?;
+            if is_uplink {
+                session.network_context_mut().update_f_cnt_up(counter);
+            } else if payload.is_application() {
+                session.application_context_mut().update_af_cnt_dwn(counter);
+            } else {
+                session.network_context_mut().update_nf_cnt_dwn(counter);
+            }
+            match LoRaWANPacket::from_bytes_with_mic_context(bytes, Some(&candidate_device), is_uplink, mic_context) {
+                Ok(packet) => return Ok((packet, counter)),
+                Err(LoRaWANError::InvalidMic) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(LoRaWANError::InvalidMic)
+    }
+
+    ///Replaces the MIC of a data frame, e.g. for a LoRaWAN 1.1 retransmission on another channel
+    pub fn update_mic(bytes: &mut [u8], device_context: &Device, is_uplink: bool, mic_context: &MicContext) -> Result<(), LoRaWANError> {
+        let packet = LoRaWANPacket::from_bytes(bytes, None, is_uplink)?;
+        if !matches!(packet.payload(), Payload::MACPayload(_)) {
+            return Err(LoRaWANError::MHDRNotCoherentWithPayload);
+        }
+        let len = bytes.len();
+        let mic = packet.extract_mic(device_context, &bytes[..len - 4], mic_context)?;
+        bytes[len - 4..].copy_from_slice(&mic);
+        Ok(())
+    }
+
     pub fn is_join_request(&self) -> bool {
         matches!(self.mhdr.mtype(), MType::JoinRequest)
     }
@@ -330,8 +387,9 @@ impl LoRaWANPacket {
 
 }
 
-impl ToBytesWithContext for LoRaWANPacket {
-    fn to_bytes_with_context(&self, device_context: &Device) -> Result<Vec<u8>, LoRaWANError> {
+impl LoRaWANPacket {
+    ///to_bytes_with_context computing the MIC of LoRaWAN 1.1 data frames with the ConfFCnt, TxDr and TxCh of mic_context
+    pub fn to_bytes_with_mic_context(&self, device_context: &Device, mic_context: &MicContext) -> Result<Vec<u8>, LoRaWANError> {
         self.check_coherence_mhdr_fctrl()?;
         let mut ret: Vec<u8> = Vec::new();
         ret.extend_from_slice(&self.mhdr.to_bytes());
@@ -340,7 +398,7 @@ impl ToBytesWithContext for LoRaWANPacket {
                 //nella join accept il mic si calcola prima e poi si cripta tutto quanto
                 let mut payload_mic_buffer = ret.clone();
                 payload_mic_buffer.append(&mut self.payload.to_bytes_with_context(device_context)?);
-                payload_mic_buffer.extend_from_slice(&self.extract_mic(device_context, &payload_mic_buffer)?);
+                payload_mic_buffer.extend_from_slice(&self.extract_mic(device_context, &payload_mic_buffer, mic_context)?);
                 payload_mic_buffer.remove(0); //remove the mhdr for encryption
 
                 let key = if ja.is_rejoin() {
@@ -352,9 +410,15 @@ impl ToBytesWithContext for LoRaWANPacket {
             },
             _ => {
                 ret.extend_from_slice(&self.payload.to_bytes_with_context(device_context)?);
-                ret.extend_from_slice(&self.extract_mic(device_context, &ret)?);
+                ret.extend_from_slice(&self.extract_mic(device_context, &ret, mic_context)?);
             },
         }
         Ok(ret)
     }
-}
\ No newline at end of file
+}
+
+impl ToBytesWithContext for LoRaWANPacket {
+    fn to_bytes_with_context(&self, device_context: &Device) -> Result<Vec<u8>, LoRaWANError> {
+        self.to_bytes_with_mic_context(device_context, &MicContext::default())
+    }
+}
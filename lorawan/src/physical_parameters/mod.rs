@@ -90,6 +90,18 @@ impl SpreadingFactor {
             SpreadingFactor::SF12 => 12,
         }
     }
+
+    ///Minimum SNR in dB required to demodulate a frame sent with this spreading factor
+    pub fn demodulation_floor(&self) -> f32 {
+        match self {
+            SpreadingFactor::SF7 => -7.5,
+            SpreadingFactor::SF8 => -10.0,
+            SpreadingFactor::SF9 => -12.5,
+            SpreadingFactor::SF10 => -15.0,
+            SpreadingFactor::SF11 => -17.5,
+            SpreadingFactor::SF12 => -20.0,
+        }
+    }
 }
 
 impl Display for SpreadingFactor {
@@ -98,7 +110,7 @@ impl Display for SpreadingFactor {
     }
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub enum DataRate {
     DR0,   //SF 12 BW 125
     DR1,   //SF 11 BW 125
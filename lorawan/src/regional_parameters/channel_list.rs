@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{lorawan_packet::{fields::ChMaskCntl, join::CFList}, utils::errors::LoRaWANError};
+
+use super::region::{Channel, Region};
+
+///Maximum number of channels a device in a dynamic channel plan region can hold
+pub const MAX_DYNAMIC_CHANNELS: usize = 16;
+
+///Channels a device is currently allowed to use for uplinks.
+///Dynamic channel plan regions keep up to 16 channels that can be added or removed (CFList type 0, NewChannelReq),
+///fixed channel plan regions (US915, AU915, CN470) use the regional channels and only toggle them via masks (CFList type 1, LinkADRReq)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct ChannelList {
+    region: Region,
+    channels: [Option<Channel>; MAX_DYNAMIC_CHANNELS],
+    ///one bit per channel, channel i is bit i % 16 of masks[i / 16]
+    masks: [u16; 6],
+}
+
+impl ChannelList {
+    pub fn new(region: Region) -> Self {
+        let mut ret = Self {
+            region,
+            channels: [None; MAX_DYNAMIC_CHANNELS],
+            masks: [0; 6],
+        };
+        if region.has_fixed_channel_plan() {
+            for i in 0..region.default_channels().len() {
+                ret.set_enabled(i, true);
+            }
+        } else {
+            for (i, c) in region.default_channels().iter().enumerate() {
+                ret.channels[i] = Some(*c);
+                ret.set_enabled(i, true);
+            }
+        }
+        ret
+    }
+
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    ///Number of channel slots, 16 for dynamic channel plans or the size of the fixed channel plan
+    pub fn len(&self) -> usize {
+        if self.region.has_fixed_channel_plan() {
+            self.region.default_channels().len()
+        } else {
+            MAX_DYNAMIC_CHANNELS
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.enabled_channels().is_empty()
+    }
+
+    pub fn channel(&self, index: usize) -> Option<Channel> {
+        if self.region.has_fixed_channel_plan() {
+            self.region.default_channels().get(index).copied()
+        } else {
+            self.channels.get(index).copied().flatten()
+        }
+    }
+
+    ///Index of the channel on frequency, the TxCh of the LoRaWAN 1.1 MIC
+    pub fn index_of(&self, frequency: u32) -> Option<usize> {
+        (0..self.len()).find(|i| self.channel(*i).is_some_and(|c| c.frequency() == frequency))
+    }
+
+    pub fn is_enabled(&self, index: usize) -> bool {
+        index < self.len() && self.masks[index / 16] & (1 << (index % 16)) != 0
+    }
+
+    fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if enabled {
+            self.masks[index / 16] |= 1 << (index % 16);
+        } else {
+            self.masks[index / 16] &= !(1 << (index % 16));
+        }
+    }
+
+    pub fn enabled_channels(&self) -> Vec<Channel> {
+        (0..self.len())
+            .filter(|i| self.is_enabled(*i))
+            .filter_map(|i| self.channel(i))
+            .collect()
+    }
+
+    pub fn masks(&self) -> &[u16; 6] {
+        &self.masks
+    }
+
+    ///Add, replace or (with None) remove a channel. Only valid for dynamic channel plans, the default channels cannot be modified
+    pub fn set_channel(&mut self, index: usize, channel: Option<Channel>) -> Result<(), LoRaWANError> {
+        if self.region.has_fixed_channel_plan() || index < self.region.default_channels().len() || index >= MAX_DYNAMIC_CHANNELS {
+            return Err(LoRaWANError::InvalidChannel);
+        }
+        self.channels[index] = channel;
+        self.set_enabled(index, channel.is_some());
+        Ok(())
+    }
+
+    ///Replace the 16 bits mask of the block-th group of channels, masking out the channels that are not defined
+    pub fn set_mask(&mut self, block: usize, mask: u16) -> Result<(), LoRaWANError> {
+        if block * 16 >= self.len() {
+            return Err(LoRaWANError::InvalidChannel);
+        }
+        for bit in 0..16 {
+            let index = block * 16 + bit;
+            let enabled = mask & (1 << bit) != 0 && self.channel(index).is_some();
+            if index < self.len() {
+                self.set_enabled(index, enabled);
+            }
+        }
+        Ok(())
+    }
+
+    ///ChMaskCntl and ChMask of a LinkADRReq, their semantics are region specific (RP002 LinkAdrReq sections).
+    ///On error the list may be partially changed, apply a block of requests to a copy
+    pub fn apply_ch_mask(&mut self, ch_mask_cntl: ChMaskCntl, ch_mask: u16) -> Result<(), LoRaWANError> {
+        let ch_mask_cntl = ch_mask_cntl.value();
+        let all_on = |channels: &mut Self, blocks: usize| (0..blocks).try_for_each(|b| channels.set_mask(b, 0xFFFF));
+        match self.region {
+            Region::US902_928 | Region::AU915_928 => match ch_mask_cntl {
+                0..=4 => self.set_mask(ch_mask_cntl as usize, ch_mask),
+                5 => {
+                    //bits 0 to 7 each turn on or off a sub-band, 8 channels of 125 kHz and the 500 kHz channel 64 + bit. 8 to 15 are RFU
+                    let mut masks = [0_u16; 5];
+                    for sub_band in (0..8).filter(|b| ch_mask & (1 << b) != 0) {
+                        masks[sub_band / 2] |= 0xFF << (sub_band % 2 * 8);
+                        masks[4] |= 1 << sub_band;
+                    }
+                    masks.iter().enumerate().try_for_each(|(block, mask)| self.set_mask(block, *mask))
+                },
+                6 => {
+                    all_on(self, 4)?;
+                    self.set_mask(4, ch_mask)
+                },
+                7 => {
+                    (0..4).try_for_each(|b| self.set_mask(b, 0))?;
+                    self.set_mask(4, ch_mask)
+                },
+                _ => Err(LoRaWANError::InvalidChannel),
+            },
+            Region::CN470_510 => match ch_mask_cntl {
+                0..=5 => self.set_mask(ch_mask_cntl as usize, ch_mask),
+                6 => all_on(self, 6),
+                _ => Err(LoRaWANError::InvalidChannel),
+            },
+            _ => match ch_mask_cntl {
+                0 => {
+                    //enabling a channel that is not defined is an error
+                    if (0..16).any(|i| ch_mask & (1 << i) != 0 && self.channel(i).is_none()) {
+                        return Err(LoRaWANError::InvalidChannel);
+                    }
+                    self.set_mask(0, ch_mask)
+                },
+                6 => all_on(self, 1),
+                _ => Err(LoRaWANError::InvalidChannel),
+            },
+        }
+    }
+
+    pub fn apply_cf_list(&mut self, cf_list: &CFList) -> Result<(), LoRaWANError> {
+        match cf_list {
+            CFList::Frequencies(frequencies) => {
+                if self.region.has_fixed_channel_plan() {
+                    return Err(LoRaWANError::InvalidChannel);
+                }
+                let first = self.region.default_channels().len();
+                let default_channel = self.region.default_channels()[0];
+                for (i, f) in frequencies.iter().enumerate() {
+                    let channel = if *f == 0 { None } else { Some(Channel::new(*f, default_channel.min_dr(), default_channel.max_dr())) };
+                    self.set_channel(first + i, channel)?;
+                }
+                Ok(())
+            },
+            CFList::ChannelMasks(masks) => {
+                if !self.region.has_fixed_channel_plan() {
+                    return Err(LoRaWANError::InvalidChannel);
+                }
+                let blocks = self.len().div_ceil(16);
+                for (block, mask) in masks.iter().enumerate().take(blocks) {
+                    self.set_mask(block, *mask)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Default for ChannelList {
+    fn default() -> Self {
+        Self::new(Region::default())
+    }
+}
@@ -1 +1,2 @@
-pub mod region;
\ No newline at end of file
+pub mod region;
+pub mod channel_list;
\ No newline at end of file
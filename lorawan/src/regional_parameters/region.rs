@@ -1,5 +1,19 @@
+use std::time::Duration;
+
 use serde::{Serialize, Deserialize};
 
+use crate::physical_parameters::{DataRate::{self, *}, LoRaBandwidth::{self, *}, SpreadingFactor::{self, *}};
+use crate::lorawan_packet::fields::{DLSettings, RxDelay};
+
+///Delays and counters shared by every region (RP002-1.0.4, table "Default settings")
+pub const RECEIVE_DELAY1: Duration = Duration::from_secs(1);
+pub const RECEIVE_DELAY2: Duration = Duration::from_secs(2);
+pub const JOIN_ACCEPT_DELAY1: Duration = Duration::from_secs(5);
+pub const JOIN_ACCEPT_DELAY2: Duration = Duration::from_secs(6);
+pub const MAX_FCNT_GAP: u32 = 16384;
+pub const ADR_ACK_LIMIT: u16 = 64;
+pub const ADR_ACK_DELAY: u16 = 32;
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default, Hash)]
 pub enum Region {
     #[default] EU863_870,
@@ -13,6 +27,359 @@ pub enum Region {
     INDIA865_867,
 }
 
+///Uplink channel: frequency in Hz and the inclusive range of data rates allowed on it
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Channel {
+    frequency: u32,
+    min_dr: DataRate,
+    max_dr: DataRate,
+}
+
+impl Channel {
+    pub const fn new(frequency: u32, min_dr: DataRate, max_dr: DataRate) -> Self {
+        Self {
+            frequency,
+            min_dr,
+            max_dr,
+        }
+    }
+
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    pub fn min_dr(&self) -> DataRate {
+        self.min_dr
+    }
+
+    pub fn max_dr(&self) -> DataRate {
+        self.max_dr
+    }
+
+    pub fn supports(&self, dr: DataRate) -> bool {
+        (self.min_dr.value()..=self.max_dr.value()).contains(&dr.value())
+    }
+}
+
+///64 BW125 channels followed by 8 BW500 channels, the layout used by US915 and AU915
+const fn fixed_channel_plan(first_125: u32, max_dr_125: DataRate, first_500: u32, dr_500: DataRate) -> [Channel; 72] {
+    let mut ret = [Channel::new(0, DataRate::DR0, DataRate::DR0); 72];
+    let mut i = 0;
+    while i < 64 {
+        ret[i] = Channel::new(first_125 + 200_000 * i as u32, DataRate::DR0, max_dr_125);
+        i += 1;
+    }
+    while i < 72 {
+        ret[i] = Channel::new(first_500 + 1_600_000 * (i - 64) as u32, dr_500, dr_500);
+        i += 1;
+    }
+    ret
+}
+
+const fn cn470_channel_plan() -> [Channel; 96] {
+    let mut ret = [Channel::new(0, DataRate::DR0, DataRate::DR0); 96];
+    let mut i = 0;
+    while i < 96 {
+        ret[i] = Channel::new(470_300_000 + 200_000 * i as u32, DataRate::DR0, DataRate::DR5);
+        i += 1;
+    }
+    ret
+}
+
+static EU868_CHANNELS: [Channel; 3] = [
+    Channel::new(868_100_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(868_300_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(868_500_000, DataRate::DR0, DataRate::DR5),
+];
+static EU433_CHANNELS: [Channel; 3] = [
+    Channel::new(433_175_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(433_375_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(433_575_000, DataRate::DR0, DataRate::DR5),
+];
+static CN779_CHANNELS: [Channel; 3] = [
+    Channel::new(779_500_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(779_700_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(779_900_000, DataRate::DR0, DataRate::DR5),
+];
+static AS923_CHANNELS: [Channel; 2] = [
+    Channel::new(923_200_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(923_400_000, DataRate::DR0, DataRate::DR5),
+];
+static KR920_CHANNELS: [Channel; 3] = [
+    Channel::new(922_100_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(922_300_000, DataRate::DR0, DataRate::DR5),
+    Channel::new(922_500_000, DataRate::DR0, DataRate::DR5),
+];
+static IN865_CHANNELS: [Channel; 3] = [
+    Channel::new(865_062_500, DataRate::DR0, DataRate::DR5),
+    Channel::new(865_402_500, DataRate::DR0, DataRate::DR5),
+    Channel::new(865_985_000, DataRate::DR0, DataRate::DR5),
+];
+static US915_CHANNELS: [Channel; 72] = fixed_channel_plan(902_300_000, DataRate::DR3, 903_000_000, DataRate::DR4);
+static AU915_CHANNELS: [Channel; 72] = fixed_channel_plan(915_200_000, DataRate::DR5, 915_900_000, DataRate::DR6);
+static CN470_CHANNELS: [Channel; 96] = cn470_channel_plan();
+
+type DataRateTable = [Option<(SpreadingFactor, LoRaBandwidth)>; 16];
+
+///DR0..DR5 on BW125 from SF12 down to SF7, DR6 SF7 on BW250, DR7 FSK
+static EU_LIKE_DATA_RATES: DataRateTable = [
+    Some((SF12, BW125)), Some((SF11, BW125)), Some((SF10, BW125)), Some((SF9, BW125)),
+    Some((SF8, BW125)), Some((SF7, BW125)), Some((SF7, BW250)), None,
+    None, None, None, None, None, None, None, None,
+];
+///Same as EU but without the BW250 data rate (KR920, IN865)
+static BW125_ONLY_DATA_RATES: DataRateTable = [
+    Some((SF12, BW125)), Some((SF11, BW125)), Some((SF10, BW125)), Some((SF9, BW125)),
+    Some((SF8, BW125)), Some((SF7, BW125)), None, None,
+    None, None, None, None, None, None, None, None,
+];
+///DR6 is SF7 on BW500, DR7 FSK
+static CN470_DATA_RATES: DataRateTable = [
+    Some((SF12, BW125)), Some((SF11, BW125)), Some((SF10, BW125)), Some((SF9, BW125)),
+    Some((SF8, BW125)), Some((SF7, BW125)), Some((SF7, BW500)), None,
+    None, None, None, None, None, None, None, None,
+];
+///DR5, DR6 are LR-FHSS, DR7 is RFU
+static US915_DATA_RATES: DataRateTable = [
+    Some((SF10, BW125)), Some((SF9, BW125)), Some((SF8, BW125)), Some((SF7, BW125)),
+    Some((SF8, BW500)), None, None, None,
+    Some((SF12, BW500)), Some((SF11, BW500)), Some((SF10, BW500)), Some((SF9, BW500)),
+    Some((SF8, BW500)), Some((SF7, BW500)), None, None,
+];
+///DR7 is LR-FHSS
+static AU915_DATA_RATES: DataRateTable = [
+    Some((SF12, BW125)), Some((SF11, BW125)), Some((SF10, BW125)), Some((SF9, BW125)),
+    Some((SF8, BW125)), Some((SF7, BW125)), Some((SF8, BW500)), None,
+    Some((SF12, BW500)), Some((SF11, BW500)), Some((SF10, BW500)), Some((SF9, BW500)),
+    Some((SF8, BW500)), Some((SF7, BW500)), None, None,
+];
+
+///Maximum MACPayload size (M) per data rate, with no dwell time limitation. 0 means the DR is not defined
+type PayloadTable = [u8; 16];
+static EU_LIKE_MAX_PAYLOAD: PayloadTable = [59, 59, 59, 123, 230, 230, 230, 230, 0, 0, 0, 0, 0, 0, 0, 0];
+static AS923_MAX_PAYLOAD: PayloadTable = [59, 59, 123, 123, 250, 250, 250, 250, 0, 0, 0, 0, 0, 0, 0, 0];
+static BW125_ONLY_MAX_PAYLOAD: PayloadTable = [59, 59, 59, 123, 230, 230, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+static CN470_MAX_PAYLOAD: PayloadTable = [59, 59, 59, 123, 230, 230, 230, 230, 0, 0, 0, 0, 0, 0, 0, 0];
+static IN865_MAX_PAYLOAD: PayloadTable = [59, 59, 59, 123, 230, 230, 0, 230, 0, 0, 0, 0, 0, 0, 0, 0];
+static US915_MAX_PAYLOAD: PayloadTable = [19, 61, 133, 250, 250, 58, 133, 0, 61, 137, 250, 250, 250, 250, 0, 0];
+static AU915_MAX_PAYLOAD: PayloadTable = [59, 59, 59, 123, 230, 230, 230, 58, 61, 137, 250, 250, 250, 250, 0, 0];
+
+static EU_LIKE_UPLINK_DRS: [DataRate; 8] = [DR0, DR1, DR2, DR3, DR4, DR5, DR6, DR7];
+static BW125_ONLY_UPLINK_DRS: [DataRate; 6] = [DR0, DR1, DR2, DR3, DR4, DR5];
+static IN865_UPLINK_DRS: [DataRate; 7] = [DR0, DR1, DR2, DR3, DR4, DR5, DR7];
+static US915_UPLINK_DRS: [DataRate; 5] = [DR0, DR1, DR2, DR3, DR4];
+static AU915_UPLINK_DRS: [DataRate; 7] = [DR0, DR1, DR2, DR3, DR4, DR5, DR6];
+
+///RX1 data rate for the US915 uplink data rates DR0..DR4, indexed by [uplink_dr][rx1_dr_offset]
+static US915_RX1_DR: [[DataRate; 4]; 5] = [
+    [DR10, DR9, DR8, DR8],
+    [DR11, DR10, DR9, DR8],
+    [DR12, DR11, DR10, DR9],
+    [DR13, DR12, DR11, DR10],
+    [DR13, DR13, DR12, DR11],
+];
+///RX1 data rate for the AU915 uplink data rates DR0..DR6, indexed by [uplink_dr][rx1_dr_offset]
+static AU915_RX1_DR: [[DataRate; 6]; 7] = [
+    [DR8, DR8, DR8, DR8, DR8, DR8],
+    [DR9, DR8, DR8, DR8, DR8, DR8],
+    [DR10, DR9, DR8, DR8, DR8, DR8],
+    [DR11, DR10, DR9, DR8, DR8, DR8],
+    [DR12, DR11, DR10, DR9, DR8, DR8],
+    [DR13, DR12, DR11, DR10, DR9, DR8],
+    [DR13, DR13, DR12, DR11, DR10, DR9],
+];
+
+impl Region {
+    ///Default uplink channels a device can use right after the join
+    pub fn default_channels(&self) -> &'static [Channel] {
+        match self {
+            Region::EU863_870 => &EU868_CHANNELS,
+            Region::EU443 => &EU433_CHANNELS,
+            Region::US902_928 => &US915_CHANNELS,
+            Region::CN779_787 => &CN779_CHANNELS,
+            Region::AU915_928 => &AU915_CHANNELS,
+            Region::CN470_510 => &CN470_CHANNELS,
+            Region::AS923 => &AS923_CHANNELS,
+            Region::KR920_923 => &KR920_CHANNELS,
+            Region::INDIA865_867 => &IN865_CHANNELS,
+        }
+    }
+
+    ///Frequency band in Hz (inclusive) the region is allowed to operate in
+    pub fn frequency_range(&self) -> (u32, u32) {
+        match self {
+            Region::EU863_870 => (863_000_000, 870_000_000),
+            Region::EU443 => (433_050_000, 434_790_000),
+            Region::US902_928 => (902_000_000, 928_000_000),
+            Region::CN779_787 => (779_000_000, 787_000_000),
+            Region::AU915_928 => (915_000_000, 928_000_000),
+            Region::CN470_510 => (470_000_000, 510_000_000),
+            Region::AS923 => (915_000_000, 928_000_000),
+            Region::KR920_923 => (920_900_000, 923_300_000),
+            Region::INDIA865_867 => (865_000_000, 867_000_000),
+        }
+    }
+
+    pub fn is_valid_frequency(&self, frequency: u32) -> bool {
+        let (min, max) = self.frequency_range();
+        (min..=max).contains(&frequency)
+    }
+
+    ///true for the regions with a fixed channel plan, where CFList carries channel masks instead of frequencies
+    pub fn has_fixed_channel_plan(&self) -> bool {
+        matches!(self, Region::US902_928 | Region::AU915_928 | Region::CN470_510)
+    }
+
+    fn data_rate_table(&self) -> &'static DataRateTable {
+        match self {
+            Region::EU863_870 | Region::EU443 | Region::CN779_787 | Region::AS923 => &EU_LIKE_DATA_RATES,
+            Region::KR920_923 | Region::INDIA865_867 => &BW125_ONLY_DATA_RATES,
+            Region::CN470_510 => &CN470_DATA_RATES,
+            Region::US902_928 => &US915_DATA_RATES,
+            Region::AU915_928 => &AU915_DATA_RATES,
+        }
+    }
+
+    ///LoRa modulation parameters of a data rate, None for FSK, LR-FHSS and RFU data rates
+    pub fn data_rate(&self, dr: DataRate) -> Option<(SpreadingFactor, LoRaBandwidth)> {
+        self.data_rate_table()[dr.value() as usize]
+    }
+
+    ///Inverse of data_rate, uplink data rates are preferred when a (SF, BW) pair appears twice
+    pub fn data_rate_from(&self, sf: SpreadingFactor, bw: LoRaBandwidth) -> Option<DataRate> {
+        self.data_rate_table().iter().position(|v| *v == Some((sf, bw))).map(|i| DataRate::new(i as u8))
+    }
+
+    ///Data rates a device can use for uplinks
+    pub fn uplink_data_rates(&self) -> &'static [DataRate] {
+        match self {
+            Region::EU863_870 | Region::EU443 | Region::CN779_787 | Region::AS923 => &EU_LIKE_UPLINK_DRS,
+            Region::KR920_923 | Region::CN470_510 => &BW125_ONLY_UPLINK_DRS,
+            Region::INDIA865_867 => &IN865_UPLINK_DRS,
+            Region::US902_928 => &US915_UPLINK_DRS,
+            Region::AU915_928 => &AU915_UPLINK_DRS,
+        }
+    }
+
+    ///Maximum MACPayload size (M) for a data rate, None if the data rate is not defined in the region
+    pub fn max_mac_payload_size(&self, dr: DataRate) -> Option<u8> {
+        let table = match self {
+            Region::EU863_870 | Region::EU443 | Region::CN779_787 => &EU_LIKE_MAX_PAYLOAD,
+            Region::AS923 => &AS923_MAX_PAYLOAD,
+            Region::KR920_923 => &BW125_ONLY_MAX_PAYLOAD,
+            Region::CN470_510 => &CN470_MAX_PAYLOAD,
+            Region::INDIA865_867 => &IN865_MAX_PAYLOAD,
+            Region::US902_928 => &US915_MAX_PAYLOAD,
+            Region::AU915_928 => &AU915_MAX_PAYLOAD,
+        };
+        Some(table[dr.value() as usize]).filter(|v| *v > 0)
+    }
+
+    ///Maximum FRMPayload size (N) for a data rate, assuming no FOpts
+    pub fn max_app_payload_size(&self, dr: DataRate) -> Option<u8> {
+        self.max_mac_payload_size(dr).map(|m| m - 8)
+    }
+
+    pub fn rx2_frequency(&self) -> u32 {
+        match self {
+            Region::EU863_870 => 869_525_000,
+            Region::EU443 => 434_665_000,
+            Region::US902_928 => 923_300_000,
+            Region::CN779_787 => 786_000_000,
+            Region::AU915_928 => 923_300_000,
+            Region::CN470_510 => 505_300_000,
+            Region::AS923 => 923_200_000,
+            Region::KR920_923 => 921_900_000,
+            Region::INDIA865_867 => 866_550_000,
+        }
+    }
+
+    pub fn rx2_data_rate(&self) -> DataRate {
+        match self {
+            Region::US902_928 | Region::AU915_928 => DR8,
+            Region::AS923 | Region::INDIA865_867 => DR2,
+            _ => DR0,
+        }
+    }
+
+    ///Highest RX1DROffset accepted in the region
+    pub fn max_rx1_dr_offset(&self) -> u8 {
+        match self {
+            Region::US902_928 => 3,
+            Region::AS923 | Region::INDIA865_867 => 7,
+            _ => 5,
+        }
+    }
+
+    ///RX1 data rate given the uplink data rate and the RX1DROffset, None if the combination is not valid
+    pub fn rx1_data_rate(&self, uplink_dr: DataRate, rx1_dr_offset: u8) -> Option<DataRate> {
+        if rx1_dr_offset > self.max_rx1_dr_offset() || !self.uplink_data_rates().contains(&uplink_dr) {
+            return None;
+        }
+        let dr = uplink_dr.value();
+        match self {
+            Region::US902_928 => Some(US915_RX1_DR[dr as usize][rx1_dr_offset as usize]),
+            Region::AU915_928 => Some(AU915_RX1_DR[dr as usize][rx1_dr_offset as usize]),
+            Region::AS923 | Region::INDIA865_867 => {
+                //offsets 6 and 7 correspond to -1 and -2, the result is clamped to DR0..DR5
+                let effective = match rx1_dr_offset {
+                    6 => dr as i8 + 1,
+                    7 => dr as i8 + 2,
+                    o => dr as i8 - o as i8,
+                };
+                Some(DataRate::new(effective.clamp(0, 5) as u8))
+            },
+            _ => Some(DataRate::new(dr.saturating_sub(rx1_dr_offset))),
+        }
+    }
+
+    ///RX1 frequency for a downlink answering an uplink sent on uplink_frequency
+    pub fn rx1_frequency(&self, uplink_frequency: u32) -> u32 {
+        match self {
+            Region::US902_928 | Region::AU915_928 => {
+                let channels = self.default_channels();
+                let index = channels.iter().position(|c| c.frequency == uplink_frequency).unwrap_or(0);
+                923_300_000 + 600_000 * (index as u32 % 8)
+            },
+            Region::CN470_510 => {
+                let index = uplink_frequency.saturating_sub(470_300_000) / 200_000;
+                500_300_000 + 200_000 * (index % 48)
+            },
+            _ => uplink_frequency,
+        }
+    }
+
+    ///Default max EIRP in dBm, TXPower 0 corresponds to this value
+    pub fn default_max_eirp(&self) -> i8 {
+        match self {
+            Region::EU863_870 | Region::AS923 => 16,
+            Region::EU443 | Region::CN779_787 => 12,
+            Region::US902_928 | Region::AU915_928 | Region::INDIA865_867 => 30,
+            Region::CN470_510 => 19,
+            Region::KR920_923 => 14,
+        }
+    }
+
+    ///Highest TXPower index defined in the region
+    pub fn max_tx_power_index(&self) -> u8 {
+        match self {
+            Region::US902_928 | Region::AU915_928 => 14,
+            Region::INDIA865_867 => 10,
+            Region::EU443 | Region::CN779_787 => 5,
+            _ => 7,
+        }
+    }
+
+    ///EIRP in dBm for a TXPower index, MaxEIRP - 2*index
+    pub fn tx_power(&self, index: u8) -> Option<i8> {
+        if index > self.max_tx_power_index() {
+            None
+        } else {
+            Some(self.default_max_eirp() - 2 * index as i8)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Hash)]
 pub struct RegionalParameters {
     region: Region,
@@ -28,5 +395,18 @@ impl RegionalParameters {
     pub fn region(&self) -> &Region {
         &self.region
     }
-} 
 
+    pub fn default_rx1_dr_offset(&self) -> u8 {
+        0
+    }
+
+    ///DLSettings advertised in the JoinAccept: RX1DROffset and RX2DataRate with the regional defaults, OptNeg not set
+    pub fn default_dl_settings(&self) -> DLSettings {
+        DLSettings::from_byte((self.default_rx1_dr_offset() << 4) | self.region.rx2_data_rate().value())
+    }
+
+    ///RxDelay advertised in the JoinAccept
+    pub fn default_rx_delay(&self) -> RxDelay {
+        RxDelay::from_byte(RECEIVE_DELAY1.as_secs() as u8)
+    }
+}
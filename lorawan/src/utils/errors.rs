@@ -16,6 +16,7 @@ pub enum LoRaWANError {
     InvalidEUI64Buffer,
     OpenSSLErrorStack(ErrorStack),
     MalformedMACCommand,
+    TruncatedMACCommand { cid: u8, expected: usize, received: usize },
     
     InvalidMic,
     InvalidNonce,
@@ -23,6 +24,8 @@ pub enum LoRaWANError {
     InvalidBufferContent,
     InvalidDevAddr,
     MissingDownlink,
+    InvalidChannel,
+    InvalidFieldValue(&'static str),
 }
 
 impl From<ErrorStack> for LoRaWANError {
@@ -54,12 +57,15 @@ impl Display for LoRaWANError {
             LoRaWANError::InvalidEUI64Buffer => write!(f, "Invalid EUI64 buffer"),
             LoRaWANError::OpenSSLErrorStack(e) => write!(f, "OpenSSL error: {}", e),
             LoRaWANError::MalformedMACCommand => write!(f, "Malformed MAC command"),
+            LoRaWANError::TruncatedMACCommand { cid, expected, received } => write!(f, "MAC command 0x{cid:02X} truncated, {expected} bytes expected after the CID, {received} received"),
             LoRaWANError::InvalidMic => write!(f, "Invalid MIC"),
             LoRaWANError::InvalidNonce => write!(f, "Invalid nonce"),
             LoRaWANError::InvalidBufferLength => write!(f, "Invalid buffer length"),
             LoRaWANError::InvalidBufferContent => write!(f, "Invalid buffer content"),
             LoRaWANError::InvalidDevAddr => write!(f, "Invalid DevAddr"),
             LoRaWANError::MissingDownlink => write!(f, "Missing downlink"),
+            LoRaWANError::InvalidChannel => write!(f, "Invalid channel"),
+            LoRaWANError::InvalidFieldValue(field) => write!(f, "Invalid {field} value"),
         }
     }
 }
@@ -2,6 +2,7 @@
 mod tests {
     use hex::FromHex;
     use lorawan::{
+        decoder::{decode, DecoderContext},
         device::{
             session_context::{ApplicationSessionContext, NetworkSessionContext, SessionContext},
             Device, DeviceClass, LoRaWANVersion,
@@ -13,16 +14,19 @@ mod tests {
         lorawan_packet::{
             fctrl::{DownlinkFCtrl, FCtrl, UplinkFCtrl},
             fhdr::FHDR,
+            fields::{ChMaskCntl, DLSettings, DataRateTxPower, MaxEIRP, Redundancy, RxDelay},
             join::{
-                JoinAcceptPayload, JoinRequestPayload, JoinRequestType, ReJoinRequest02,
+                CFList, JoinAcceptPayload, JoinRequestPayload, JoinRequestType, ReJoinRequest02, RejoinType,
                 ReJoinRequest1, RejoinRequestPayload,
             },
             mac_commands::{EDMacCommands, NCMacCommands},
             mac_payload::MACPayload,
             mhdr::{MType, Major, MHDR},
             payload::Payload,
-            LoRaWANPacket,
+            LoRaWANPacket, MicContext,
         },
+        physical_parameters::{DataRate, LoRaBandwidth, SpreadingFactor},
+        regional_parameters::{channel_list::ChannelList, region::{Region, RegionalParameters}},
         utils::{self, traits::ToBytesWithContext},
         utils::traits::ToBytes,
         utils::{errors::LoRaWANError, eui::EUI64, PrettyHexSlice},
@@ -126,7 +130,7 @@ mod tests {
 
         let payload = Device::create_maccommands(&mac_commands).unwrap(); 
 
-        let uplink = device.create_uplink(Some(&payload), true, Some(0), None).unwrap();
+        let uplink = device.create_uplink(Some(&payload), true, Some(0), None, &MicContext::default()).unwrap();
 
         println!("{uplink:?}");
     }
@@ -330,7 +334,7 @@ mod tests {
         let mhdr = MHDR::new(MType::JoinAccept, Major::R1);
 
         //let dl_settings = 0b10010001;
-        let dl_settings = 0b10000000;
+        let dl_settings = DLSettings::new(true, 0, 0).unwrap();
 
         let join_nonce: [u8; 3] = Vec::from_hex("AA0845").unwrap().try_into().unwrap();
 
@@ -340,8 +344,7 @@ mod tests {
         let mut dev_addr = [0; 4];
         dev_addr.copy_from_slice(&Vec::from_hex("E0103718").unwrap());
 
-        let mut cf_list = [0; 16];
-        cf_list.copy_from_slice(&Vec::from_hex("184F84B85E84886684586E84E8568400").unwrap());
+        let cf_list = CFList::from_bytes(&Vec::from_hex("184F84B85E84886684586E84E8568400").unwrap()).unwrap();
 
         let join_accept = JoinAcceptPayload::new(
             JoinRequestType::JoinRequest,
@@ -349,7 +352,7 @@ mod tests {
             home_net_id,
             dev_addr,
             dl_settings,
-            0x1,
+            RxDelay::new(1).unwrap(),
             Some(cf_list),
         );
 
@@ -496,6 +499,51 @@ mod tests {
         println!("{}", PrettyHexSlice(&buffer));
     }
 
+    #[test]
+    fn rejoin_from_bytes() {
+        let device = create_initialized_device();
+
+        let rj1 = ReJoinRequest1::new(*device.join_eui(), *device.dev_eui(), 7);
+        let packet = LoRaWANPacket::new(MHDR::new(MType::RejoinRequest, Major::R1), Payload::RejoinRequest(RejoinRequestPayload::T1(rj1)));
+        let buffer = packet.to_bytes_with_context(&device).unwrap();
+        let parsed = LoRaWANPacket::from_bytes(&buffer, Some(&device), true).unwrap();
+        if let Payload::RejoinRequest(rj) = parsed.payload() {
+            assert_eq!(rj.join_request_type(), JoinRequestType::RejoinRequest1);
+            assert_eq!(rj.dev_eui(), device.dev_eui());
+            assert_eq!(rj.rj_count(), 7);
+        } else { panic!("Expected a rejoin request") }
+
+        let rj2 = ReJoinRequest02::new(false, [1, 2, 3], *device.dev_eui(), 3);
+        let packet = LoRaWANPacket::new(MHDR::new(MType::RejoinRequest, Major::R1), Payload::RejoinRequest(RejoinRequestPayload::T02(rj2)));
+        let buffer = packet.to_bytes_with_context(&device).unwrap();
+        let parsed = LoRaWANPacket::from_bytes(&buffer, Some(&device), true).unwrap();
+        if let Payload::RejoinRequest(RejoinRequestPayload::T02(rj)) = parsed.payload() {
+            assert_eq!(rj.net_id(), &[1, 2, 3]);
+            assert_eq!(rj.dev_eui(), device.dev_eui());
+            assert_eq!(rj.rj_count0(), 3);
+        } else { panic!("Expected a rejoin request") }
+    }
+
+    #[test]
+    fn create_rejoin_request() {
+        let mut device = create_initialized_device();
+        let rj_count0 = device.session().unwrap().network_context().rj_count0();
+
+        let buffer = device.create_rejoin_request(RejoinType::Type2).unwrap();
+        assert_eq!(device.last_join_request_received(), &JoinRequestType::RejoinRequest2);
+        assert_eq!(device.session().unwrap().network_context().rj_count0(), rj_count0 + 1);
+        let parsed = LoRaWANPacket::from_bytes(&buffer, Some(&device), true).unwrap();
+        if let Payload::RejoinRequest(rj) = parsed.payload() {
+            assert_eq!(rj.join_request_type(), JoinRequestType::RejoinRequest2);
+            assert_eq!(rj.rj_count(), rj_count0 + 1);
+        } else { panic!("Expected a rejoin request") }
+
+        device.create_rejoin_request(RejoinType::Type1).unwrap();
+        assert_eq!(device.join_context().rj_count1(), 1);
+        device.create_join_request().unwrap();
+        assert_eq!(device.last_join_request_received(), &JoinRequestType::JoinRequest);
+    }
+
     #[test]
     fn ed_mac_commands_payload() {
         let device = create_initialized_device();
@@ -809,4 +857,381 @@ mod tests {
         let nfhdr = FHDR::from_bytes(&bytes, Some(&d), true).unwrap();
         println!("{nfhdr:?}");
     }
+
+    #[test]
+    fn regional_parameters_data_rates() {
+        let eu = Region::EU863_870;
+        assert_eq!(eu.data_rate(DataRate::DR0), Some((SpreadingFactor::SF12, LoRaBandwidth::BW125)));
+        assert_eq!(eu.data_rate(DataRate::DR6), Some((SpreadingFactor::SF7, LoRaBandwidth::BW250)));
+        assert_eq!(eu.data_rate(DataRate::DR7), None);
+        assert_eq!(eu.data_rate_from(SpreadingFactor::SF9, LoRaBandwidth::BW125), Some(DataRate::DR3));
+        assert_eq!(eu.max_mac_payload_size(DataRate::DR0), Some(59));
+        assert_eq!(eu.max_app_payload_size(DataRate::DR5), Some(222));
+        assert_eq!(eu.max_mac_payload_size(DataRate::DR8), None);
+
+        let us = Region::US902_928;
+        assert_eq!(us.data_rate(DataRate::DR0), Some((SpreadingFactor::SF10, LoRaBandwidth::BW125)));
+        assert_eq!(us.data_rate_from(SpreadingFactor::SF8, LoRaBandwidth::BW500), Some(DataRate::DR4));
+        assert_eq!(us.max_mac_payload_size(DataRate::DR0), Some(19));
+        assert_eq!(us.default_channels().len(), 72);
+        assert_eq!(us.default_channels()[63].frequency(), 914_900_000);
+        assert_eq!(us.default_channels()[64].frequency(), 903_000_000);
+
+        let as923 = Region::AS923;
+        assert_eq!(as923.max_mac_payload_size(DataRate::DR2), Some(123));
+        assert_eq!(as923.max_mac_payload_size(DataRate::DR5), Some(250));
+
+        let cn = Region::CN470_510;
+        assert_eq!(cn.data_rate(DataRate::DR6), Some((SpreadingFactor::SF7, LoRaBandwidth::BW500)));
+        assert_eq!(cn.max_mac_payload_size(DataRate::DR6), Some(230));
+        assert_eq!(Region::KR920_923.max_mac_payload_size(DataRate::DR6), None);
+    }
+
+    #[test]
+    fn regional_parameters_rx_windows() {
+        let eu = Region::EU863_870;
+        assert_eq!(eu.rx2_frequency(), 869_525_000);
+        assert_eq!(eu.rx2_data_rate(), DataRate::DR0);
+        assert_eq!(eu.rx1_data_rate(DataRate::DR5, 2), Some(DataRate::DR3));
+        assert_eq!(eu.rx1_data_rate(DataRate::DR1, 3), Some(DataRate::DR0));
+        assert_eq!(eu.rx1_data_rate(DataRate::DR1, 6), None);
+        assert_eq!(eu.rx1_frequency(868_300_000), 868_300_000);
+
+        let us = Region::US902_928;
+        assert_eq!(us.rx1_data_rate(DataRate::DR0, 0), Some(DataRate::DR10));
+        assert_eq!(us.rx1_data_rate(DataRate::DR4, 1), Some(DataRate::DR13));
+        assert_eq!(us.rx1_frequency(902_300_000 + 200_000 * 9), 923_900_000);
+
+        let as923 = Region::AS923;
+        assert_eq!(as923.rx1_data_rate(DataRate::DR4, 7), Some(DataRate::DR5));
+        assert_eq!(as923.rx2_data_rate(), DataRate::DR2);
+
+        let rp = RegionalParameters::new(Region::AS923);
+        assert_eq!(rp.default_dl_settings().to_byte(), 0b00000010);
+        assert_eq!(RegionalParameters::new(Region::US902_928).default_dl_settings().to_byte(), 0b00001000);
+    }
+
+    #[test]
+    fn regional_parameters_tx_power() {
+        assert_eq!(Region::EU863_870.tx_power(0), Some(16));
+        assert_eq!(Region::EU863_870.tx_power(7), Some(2));
+        assert_eq!(Region::EU863_870.tx_power(8), None);
+        assert_eq!(Region::US902_928.tx_power(14), Some(2));
+    }
+
+    #[test]
+    fn cf_list_frequencies() {
+        let bytes = Vec::from_hex("184F84B85E84886684586E84E8568400").unwrap();
+        let cf_list = CFList::from_bytes(&bytes).unwrap();
+        assert_eq!(cf_list, CFList::Frequencies([867_100_000, 867_500_000, 867_700_000, 867_900_000, 867_300_000]));
+        assert_eq!(cf_list.to_bytes(), bytes);
+
+        let mut channels = ChannelList::new(Region::EU863_870);
+        channels.apply_cf_list(&cf_list).unwrap();
+        assert_eq!(channels.enabled_channels().len(), 8);
+        assert_eq!(channels.channel(3).unwrap().frequency(), 867_100_000);
+        assert!(ChannelList::new(Region::US902_928).apply_cf_list(&cf_list).is_err());
+    }
+
+    #[test]
+    fn cf_list_channel_masks() {
+        let cf_list = CFList::ChannelMasks([0xff00, 0, 0, 0, 0x0002, 0]);
+        let bytes = cf_list.to_bytes();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bytes[15], 1);
+        assert_eq!(CFList::from_bytes(&bytes).unwrap(), cf_list);
+
+        let mut channels = ChannelList::new(Region::US902_928);
+        assert_eq!(channels.enabled_channels().len(), 72);
+        channels.apply_cf_list(&cf_list).unwrap();
+        let enabled = channels.enabled_channels();
+        assert_eq!(enabled.len(), 9);
+        assert_eq!(enabled[0].frequency(), 903_900_000);
+        assert_eq!(enabled[8].frequency(), 904_600_000);
+        assert!(ChannelList::new(Region::EU863_870).apply_cf_list(&cf_list).is_err());
+
+        let mut invalid = bytes.clone();
+        invalid[15] = 2;
+        assert!(CFList::from_bytes(&invalid).is_err());
+    }
+
+    fn decoder_context(device: &Device) -> DecoderContext {
+        let session = device.session().unwrap();
+        DecoderContext {
+            version: *device.version(),
+            app_key: Some(*device.app_key()),
+            f_nwk_s_int_key: Some(*session.network_context().fnwk_s_int_key()),
+            s_nwk_s_int_key: Some(*session.network_context().snwk_s_int_key()),
+            nwk_s_enc_key: Some(*session.network_context().nwk_s_enc_key()),
+            app_s_key: Some(*session.application_context().app_s_key()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decode_data_uplink() {
+        let mut device = create_initialized_device();
+        let bytes = device.create_uplink(Some(&[0xca, 0xfe, 0x01]), true, Some(10), Some(vec![0x02]), &MicContext::default()).unwrap();
+        let context = decoder_context(&device);
+
+        let decoded = decode(&bytes, &context).unwrap();
+        assert_eq!(decoded["mhdr"]["mtype"], "ConfirmedDataUp");
+        assert_eq!(decoded["mac_payload"]["fhdr"]["dev_addr"], "e0113b2a");
+        assert_eq!(decoded["mac_payload"]["fhdr"]["fctrl"]["f_opts_len"], 1);
+        assert_eq!(decoded["mac_payload"]["fhdr"]["fopts_decrypted"], "02");
+        assert_eq!(decoded["mac_payload"]["fhdr"]["mac_commands"][0], "LinkCheckReq");
+        assert_eq!(decoded["mac_payload"]["fport"], 10);
+        assert_eq!(decoded["mac_payload"]["frm_payload_decrypted"], "cafe01");
+        assert_eq!(decoded["mic_valid"], true);
+
+        //without keys only the plain fields are there
+        let decoded = decode(&bytes, &DecoderContext::default()).unwrap();
+        assert!(decoded["mac_payload"]["frm_payload_decrypted"].is_null());
+        assert!(decoded["mac_payload"]["fhdr"]["fopts_decrypted"].is_null());
+        assert!(decoded["mic_valid"].is_null());
+
+        let wrong_key = Some(Key::from_hex("00000000000000000000000000000001").unwrap());
+        let decoded = decode(&bytes, &DecoderContext { s_nwk_s_int_key: wrong_key, ..context }).unwrap();
+        assert_eq!(decoded["mic_valid"], false);
+        assert_eq!(decoded["mac_payload"]["frm_payload_decrypted"], "cafe01");
+        assert!(decode(&bytes[..10], &context).is_err());
+    }
+
+    #[test]
+    fn decode_join() {
+        let mut device = create_uninitialized_device();
+        let join_request = device.create_join_request().unwrap();
+        let context = DecoderContext { version: *device.version(), app_key: Some(*device.app_key()), ..Default::default() };
+        let decoded = decode(&join_request, &context).unwrap();
+        assert_eq!(decoded["join_request"]["dev_eui"], "50de2646f9a7ac8e");
+        assert_eq!(decoded["join_request"]["dev_nonce"], 1);
+        assert_eq!(decoded["mic_valid"], true);
+
+        let join_accept = JoinAcceptPayload::new(JoinRequestType::JoinRequest, [1, 2, 3], [0, 0, 0x13], [0x26, 0x01, 0x1b, 0xda], DLSettings::from_byte(0x03), RxDelay::new(1).unwrap(), None);
+        let packet = LoRaWANPacket::new(MHDR::new(MType::JoinAccept, Major::R1), Payload::JoinAccept(join_accept));
+        let bytes = packet.to_bytes_with_context(&device).unwrap();
+        let decoded = decode(&bytes, &context).unwrap();
+        assert_eq!(decoded["join_accept"]["dev_addr"], "26011bda");
+        assert_eq!(decoded["join_accept"]["dl_settings"]["rx2_data_rate"], 3);
+        assert_eq!(decoded["mic_valid"], true);
+        let decoded = decode(&bytes, &DecoderContext::default()).unwrap();
+        assert!(decoded["join_accept"]["encrypted"].is_string());
+        assert!(decoded["mic_valid"].is_null());
+    }
+
+    #[test]
+    fn decode_rejoin_join_accept() {
+        let mut device = create_initialized_device();
+        device.create_rejoin_request(RejoinType::Type0).unwrap();
+        let dl_settings = DLSettings::from_byte(0x03).with_opt_neg(true);
+        let join_accept = JoinAcceptPayload::new(JoinRequestType::RejoinRequest0, [1, 2, 3], [0, 0, 0x13], [0x26, 0x01, 0x1b, 0xda], dl_settings, RxDelay::new(1).unwrap(), None);
+        let packet = LoRaWANPacket::new(MHDR::new(MType::JoinAccept, Major::R1), Payload::JoinAccept(join_accept));
+        let bytes = packet.to_bytes_with_context(&device).unwrap();
+
+        //RJcount0 in place of the DevNonce, JSEncKey in place of the NwkKey
+        let context = DecoderContext {
+            version: *device.version(),
+            app_key: Some(*device.app_key()),
+            dev_eui: Some(*device.dev_eui()),
+            join_eui: Some(*device.join_eui()),
+            dev_nonce: Some(1),
+            join_request_type: JoinRequestType::RejoinRequest0,
+            ..Default::default()
+        };
+        let decoded = decode(&bytes, &context).unwrap();
+        assert_eq!(decoded["join_accept"]["dev_addr"], "26011bda");
+        assert_eq!(decoded["mic_valid"], true);
+        let decoded = decode(&bytes, &DecoderContext { dev_nonce: Some(2), ..context }).unwrap();
+        assert_eq!(decoded["mic_valid"], false);
+    }
+
+    fn frm_payload(packet: &LoRaWANPacket) -> Vec<u8> {
+        match packet.payload() {
+            Payload::MACPayload(p) => p.frm_payload().unwrap().clone(),
+            _ => panic!("not a data frame"),
+        }
+    }
+
+    #[test]
+    fn fcnt_rollover_search() {
+        let mut device = create_initialized_device();
+        device.session_mut().unwrap().network_context_mut().update_f_cnt_up(65534);
+        let mut network_side = device;
+        let first = device.create_uplink(Some(&[1, 2, 3]), false, Some(1), None, &MicContext::default()).unwrap();
+        let _lost = device.create_uplink(Some(&[4, 5, 6]), false, Some(1), None, &MicContext::default()).unwrap();
+        let after_rollover = device.create_uplink(Some(&[7, 8, 9]), false, Some(1), None, &MicContext::default()).unwrap();
+
+        let (packet, counter) = LoRaWANPacket::from_bytes_with_fcnt_search(&first, &network_side, true, &MicContext::default()).unwrap();
+        assert_eq!((counter, frm_payload(&packet)), (65535, vec![1, 2, 3]));
+        network_side.session_mut().unwrap().network_context_mut().update_f_cnt_up(counter);
+
+        //FCnt 1 after 65535: the upper half is the next one
+        let (packet, counter) = LoRaWANPacket::from_bytes_with_fcnt_search(&after_rollover, &network_side, true, &MicContext::default()).unwrap();
+        assert_eq!((counter, frm_payload(&packet)), (65537, vec![7, 8, 9]));
+
+        //a stale session only validates the MIC after the search
+        network_side.session_mut().unwrap().network_context_mut().update_f_cnt_up(0);
+        assert!(matches!(LoRaWANPacket::from_bytes(&after_rollover, Some(&network_side), true), Err(LoRaWANError::InvalidMic)));
+        assert_eq!(LoRaWANPacket::from_bytes_with_fcnt_search(&after_rollover, &network_side, true, &MicContext::default()).unwrap().1, 65537);
+
+        //a frame already received is found, the caller sees it is not newer
+        network_side.session_mut().unwrap().network_context_mut().update_f_cnt_up(65537);
+        assert_eq!(LoRaWANPacket::from_bytes_with_fcnt_search(&after_rollover, &network_side, true, &MicContext::default()).unwrap().1, 65537);
+
+        let mut tampered = after_rollover.clone();
+        tampered[9] ^= 1;
+        assert!(matches!(LoRaWANPacket::from_bytes_with_fcnt_search(&tampered, &network_side, true, &MicContext::default()), Err(LoRaWANError::InvalidMic)));
+    }
+
+    #[test]
+    fn mic_context_1_1() {
+        let mut device = create_initialized_device();
+        let network_side = device;
+        let context = MicContext { conf_fcnt: Some(7), tx_dr: 5, tx_ch: 2 };
+        let mut bytes = device.create_uplink(Some(&[1, 2, 3]), false, Some(1), None, &context).unwrap();
+
+        let (packet, _) = LoRaWANPacket::from_bytes_with_fcnt_search(&bytes, &network_side, true, &context).unwrap();
+        match packet.payload() {
+            Payload::MACPayload(p) => assert!(p.fhdr().fctrl().is_ack()),
+            _ => panic!("not a data frame"),
+        }
+        for wrong in [MicContext { tx_ch: 3, ..context }, MicContext { tx_dr: 4, ..context }, MicContext { conf_fcnt: Some(8), ..context }] {
+            assert!(matches!(LoRaWANPacket::from_bytes_with_fcnt_search(&bytes, &network_side, true, &wrong), Err(LoRaWANError::InvalidMic)));
+        }
+
+        //a retransmission on another channel only needs a new MIC
+        let retransmission = MicContext { tx_ch: 0, ..context };
+        let mic = bytes[bytes.len() - 4..].to_vec();
+        LoRaWANPacket::update_mic(&mut bytes, &device, true, &retransmission).unwrap();
+        assert_ne!(mic, bytes[bytes.len() - 4..].to_vec());
+        assert!(LoRaWANPacket::from_bytes_with_fcnt_search(&bytes, &network_side, true, &retransmission).is_ok());
+    }
+
+    #[test]
+    fn typed_fields() {
+        assert!(matches!(DLSettings::new(false, 8, 0), Err(LoRaWANError::InvalidFieldValue("RX1DROffset"))));
+        assert!(DLSettings::new(false, 0, 16).is_err());
+        assert!(RxDelay::new(16).is_err());
+        assert!(MaxEIRP::new(16).is_err());
+        assert!(ChMaskCntl::new(8).is_err());
+        assert!(Redundancy::new(ChMaskCntl::default(), 16).is_err());
+        assert!(DataRateTxPower::new(16, 0).is_err());
+
+        let dl_settings = DLSettings::new(true, 5, 9).unwrap();
+        assert_eq!(dl_settings.to_byte(), 0b11011001);
+        assert_eq!(DLSettings::from_byte(0b11011001), dl_settings);
+        assert_eq!(RxDelay::from_byte(0xf0).delay(), std::time::Duration::from_secs(1));
+        assert_eq!(MaxEIRP::from_dbm(14).unwrap().dbm(), 14);
+        assert_eq!(MaxEIRP::from_byte(0x0f).dbm(), 36);
+
+        //deserializing checks the values like new does
+        assert_eq!(serde_json::to_string(&dl_settings).unwrap(), "217");
+        assert_eq!(serde_json::from_str::<DLSettings>("217").unwrap(), dl_settings);
+        assert_eq!(serde_json::from_str::<MaxEIRP>("15").unwrap().dbm(), 36);
+        assert!(serde_json::from_str::<MaxEIRP>("16").is_err());
+        assert!(serde_json::from_str::<RxDelay>("17").is_err());
+        assert!(serde_json::from_str::<ChMaskCntl>("8").is_err());
+        assert!(serde_json::from_str::<Redundancy>("128").is_err());
+        assert_eq!(serde_json::from_str::<Redundancy>("98").unwrap(), Redundancy::new(ChMaskCntl::new(6).unwrap(), 2).unwrap());
+
+        let commands = vec![
+            NCMacCommands::LinkADRReq {
+                data_rate_tx_power: DataRateTxPower::new(5, DataRateTxPower::UNCHANGED).unwrap(),
+                ch_mask: 0x00ff,
+                redundancy: Redundancy::new(ChMaskCntl::new(6).unwrap(), 2).unwrap(),
+            },
+            NCMacCommands::RXParamSetupReq { dl_settings: DLSettings::new(false, 2, 3).unwrap(), freq: 8_695_250 },
+            NCMacCommands::RXTimingSetupReq(RxDelay::new(3).unwrap()),
+            NCMacCommands::TxParamSetupReq { downlink_dwell_time: true, uplink_dwell_time: false, max_eirp: MaxEIRP::new(5).unwrap() },
+        ];
+        let bytes: Vec<u8> = commands.iter().flat_map(|c| c.to_bytes()).collect();
+        assert_eq!(&bytes[..5], &[0x03, 0x5f, 0xff, 0x00, 0x62]);
+        assert_eq!(NCMacCommands::from_bytes(&bytes).unwrap(), commands);
+    }
+
+    #[test]
+    fn mac_commands_round_trip() {
+        let ed_commands = vec![
+            EDMacCommands::ResetInd(1),
+            EDMacCommands::LinkCheckReq,
+            EDMacCommands::LinkADRAns { power_ack: true, data_rate_ack: false, channel_mask_ack: true },
+            EDMacCommands::DutyCycleAns,
+            EDMacCommands::RXParamSetupAns { rx1_dr_offset_ack: false, rx2_data_rate_ack: true, channel_ack: true },
+            EDMacCommands::DevStatusAns { battery: 200, margin: 0b00111011 },
+            EDMacCommands::NewChannelAns { data_range_ok: true, channel_frequency_ok: false },
+            EDMacCommands::RXTimingSetupAns,
+            EDMacCommands::TxParamSetupAns,
+            EDMacCommands::DlChannelAns { uplink_frequency_exists: false, channel_frequency_ok: true },
+            EDMacCommands::RekeyInd(1),
+            EDMacCommands::ADRParamSetupAns,
+            EDMacCommands::DeviceTimeReq,
+            EDMacCommands::RejoinParamSetupAns { time_ack: true },
+            EDMacCommands::PingSlotInfoReq { periodicity: 7 },
+            EDMacCommands::PingSlotChannelAns { data_rate_ok: true, channel_frequency_ok: false },
+            EDMacCommands::BeaconTimingReq,
+            EDMacCommands::BeaconFreqAns { beacon_freq_ok: true },
+            EDMacCommands::DeviceModeInd(DeviceClass::C),
+            EDMacCommands::Proprietary(0x80, vec![1, 2, 3]),
+        ];
+        for c in &ed_commands {
+            let bytes = c.to_bytes();
+            assert_eq!(EDMacCommands::payload_len(bytes[0]).unwrap_or(bytes.len() - 1), bytes.len() - 1, "{c:?}");
+            assert_eq!(&EDMacCommands::from_bytes(&bytes).unwrap(), std::slice::from_ref(c));
+        }
+        let bytes: Vec<u8> = ed_commands.iter().flat_map(|c| c.to_bytes()).collect();
+        assert_eq!(EDMacCommands::from_bytes(&bytes).unwrap(), ed_commands);
+
+        let nc_commands = vec![
+            NCMacCommands::ResetConf(1),
+            NCMacCommands::LinkCheckAns { margin: 20, gw_cnt: 3 },
+            NCMacCommands::LinkADRReq {
+                data_rate_tx_power: DataRateTxPower::new(2, 3).unwrap(),
+                ch_mask: 0x0107,
+                redundancy: Redundancy::new(ChMaskCntl::new(0).unwrap(), 1).unwrap(),
+            },
+            NCMacCommands::DutyCycleReq(4),
+            NCMacCommands::RXParamSetupReq { dl_settings: DLSettings::new(false, 1, 8).unwrap(), freq: 8_695_250 },
+            NCMacCommands::DevStatusReq,
+            NCMacCommands::NewChannelReq { ch_index: 3, freq: 8_671_000, max_dr: 5, min_dr: 0 },
+            NCMacCommands::RXTimingSetupReq(RxDelay::new(15).unwrap()),
+            NCMacCommands::TxParamSetupReq { downlink_dwell_time: false, uplink_dwell_time: true, max_eirp: MaxEIRP::new(9).unwrap() },
+            NCMacCommands::DlChannelReq { ch_index: 1, freq: 8_683_000 },
+            NCMacCommands::RekeyConf(1),
+            NCMacCommands::ADRParamSetupReq { limit_exp: 6, delay_exp: 5 },
+            NCMacCommands::DeviceTimeAns { epoch: 1_400_000_000, second_fraction: 128 },
+            NCMacCommands::ForceRejoinReq { period: 1, max_retries: 2, rejoin_type: 2, dr: 3 },
+            NCMacCommands::RejoinParamSetupReq { max_time_n: 14, max_count_n: 2 },
+            NCMacCommands::PingSlotInfoAns,
+            NCMacCommands::PingSlotChannelReq { frequency: 8_695_250, data_rate: 3 },
+            NCMacCommands::BeaconTimingAns { delay: 0x1234, channel: 0 },
+            NCMacCommands::BeaconFreqReq { frequency: 8_695_250 },
+            NCMacCommands::DeviceModeConf(DeviceClass::A),
+            NCMacCommands::Proprietary(0xff, vec![]),
+        ];
+        for c in &nc_commands {
+            let bytes = c.to_bytes();
+            assert_eq!(NCMacCommands::payload_len(bytes[0]).unwrap_or(bytes.len() - 1), bytes.len() - 1, "{c:?}");
+            assert_eq!(&NCMacCommands::from_bytes(&bytes).unwrap(), std::slice::from_ref(c));
+        }
+        let bytes: Vec<u8> = nc_commands.iter().flat_map(|c| c.to_bytes()).collect();
+        assert_eq!(NCMacCommands::from_bytes(&bytes).unwrap(), nc_commands);
+
+        //ForceRejoinReq is a little endian 16 bits field
+        assert_eq!(nc_commands[13].to_bytes(), vec![0x0E, 0x23, 0x0A]);
+        assert_eq!(EDMacCommands::DeviceModeInd(DeviceClass::C).to_bytes(), vec![0x20, 0x02]);
+
+        //an unknown CID ends the parsing, the commands before it are kept
+        assert_eq!(
+            EDMacCommands::from_bytes(&[0x02, 0x0E, 0x01, 0x0D]).unwrap(),
+            vec![EDMacCommands::LinkCheckReq, EDMacCommands::Unknown(0x0E, vec![0x01, 0x0D])]
+        );
+        //RFU classes are not a DeviceModeInd/Conf
+        assert_eq!(EDMacCommands::from_bytes(&[0x20, 0x01]).unwrap(), vec![EDMacCommands::Unknown(0x20, vec![0x01])]);
+        assert_eq!(NCMacCommands::from_bytes(&[0x20, 0x03]).unwrap(), vec![NCMacCommands::Unknown(0x20, vec![0x03])]);
+        assert!(matches!(
+            NCMacCommands::from_bytes(&[0x06, 0x03, 0x50, 0x00]),
+            Err(LoRaWANError::TruncatedMACCommand { cid: 0x03, expected: 4, received: 2 })
+        ));
+        assert!(matches!(EDMacCommands::from_bytes(&[0x06, 0xff]), Err(LoRaWANError::TruncatedMACCommand { cid: 0x06, expected: 2, received: 1 })));
+    }
 }
@@ -0,0 +1,20 @@
+use std::{sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use tokio::time::Instant;
+
+///Unix time in ms when the clock started and the tokio instant it corresponds to
+static EPOCH: Mutex<Option<(u128, Instant)>> = Mutex::new(None);
+
+///Milliseconds since the UNIX epoch following the tokio clock. In a runtime with paused time the timestamps
+///advance with the virtual time instead of the wall clock
+pub fn now_ms() -> u128 {
+    let (unix_ms, instant) = *EPOCH.lock().unwrap().get_or_insert_with(|| {
+        (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), Instant::now())
+    });
+    unix_ms + instant.elapsed().as_millis()
+}
+
+///Restart the clock from unix_ms, a simulation sets it when it starts to get the same timestamps at every run
+pub fn set_epoch(unix_ms: u128) {
+    *EPOCH.lock().unwrap() = Some((unix_ms, Instant::now()));
+}
@@ -1,4 +1,4 @@
-use std::{hash::Hash, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{hash::Hash, time::Duration};
 
 use lorawan::{
     physical_parameters::{LoRaBandwidth, CodeRate, SpreadingFactor},
@@ -6,6 +6,8 @@ use lorawan::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::clock;
+
 pub fn extract_dev_id(dev_eui: Option<EUI64>) -> u16 {
     dev_eui.map_or(0, |v| {
         let prime: u64 = 31;
@@ -33,6 +35,8 @@ pub enum CommunicatorError {
     Radio(String),
     TCP(std::io::Error),
     UDP(std::io::Error),
+    ///the gateway refused the packet
+    Gateway(String),
     LoRaWANError(LoRaWANError),
 }
 
@@ -52,6 +56,18 @@ pub trait LoRaWANCommunicator: Send + Sync + Sized {
         &self,
         timeout: Option<Duration>,
     ) -> impl std::future::Future<Output = Result<Vec<ReceivedTransmission>, CommunicatorError>> + Send;
+
+    ///Tune the receiver on the frequency (Hz) and data rate of the next receive window. Communicators not bound to a radio channel ignore it
+    fn set_rx_window(&mut self, _frequency: u32, _spreading_factor: SpreadingFactor, _bandwidth: LoRaBandwidth) {}
+
+    ///Channel (Hz), data rate and EIRP (dBm) of the next uplink. Communicators not bound to a radio channel ignore it
+    fn set_tx_parameters(&mut self, _frequency: u32, _spreading_factor: SpreadingFactor, _bandwidth: LoRaBandwidth, _power: i8) {}
+
+    ///Whether set_tx_parameters really moves the uplinks on that channel and data rate. When it does not the receiver
+    ///cannot know them, so TxDr and TxCh of the LoRaWAN 1.1 MIC are 0
+    fn tx_parameters_applied(&self) -> bool {
+        false
+    }
 }
 
 impl From<LoRaWANError> for CommunicatorError {
@@ -79,11 +95,27 @@ impl Position {
     }
 }
 
+///Value of the µs counter of a gateway (tmst of the packet forwarder), it is not related to any other clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GatewayTimestamp {
+    pub gateway: EUI64,
+    pub tmst: u32,
+}
+
+impl GatewayTimestamp {
+    pub fn after(&self, delay: Duration) -> Self {
+        Self { gateway: self.gateway, tmst: self.tmst.wrapping_add(delay.as_micros() as u32) }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
 pub struct ArrivalStats {
     pub time: u128,
     pub rssi: f32,
     pub snr: f32,
+    ///end of the frame for the gateway that received it, only packet forwarders report it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_tmst: Option<GatewayTimestamp>,
 }
 
 impl Eq for ArrivalStats {}
@@ -98,6 +130,9 @@ pub struct Transmission {
     pub code_rate: CodeRate,
     pub starting_power: f32,
     pub uplink: bool,
+    ///start of a downlink timed by the gateway, when missing the gateway sends it as soon as it gets it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_tmst: Option<GatewayTimestamp>,
 
     pub payload: Vec<u8>,
 }
@@ -168,7 +203,7 @@ impl Transmission {
     }
 
     pub fn ended(&self) -> bool {
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() > self.start_time + self.time_on_air()
+        clock::now_ms() > self.start_time + self.time_on_air()
     }
 }
 
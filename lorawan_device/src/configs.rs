@@ -1,8 +1,10 @@
 use std::net::IpAddr;
 
-use lorawan::{device::Device, physical_parameters::{CodeRate, DataRate, LoRaBandwidth, SpreadingFactor}, regional_parameters::region::Region};
+use lorawan::{device::Device, utils::eui::EUI64, physical_parameters::{CodeRate, DataRate, LoRaBandwidth, SpreadingFactor}, regional_parameters::region::Region};
 use serde::{Serialize, Deserialize};
 
+use crate::{communicator::Position, propagation::PropagationConfig};
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct TcpDeviceConfig {
     pub addr: String,
@@ -15,6 +17,81 @@ pub struct UDPNCConfig {
     pub port: u16
 }
 
+///Address the network controller listens on for the Semtech UDP packet forwarders
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GWMPNCConfig {
+    pub addr: String,
+    pub port: u16
+}
+
+///Network server the emulated gateway forwards the uplinks to
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GWMPGatewayConfig {
+    pub addr: String,
+    pub port: u16,
+    pub gateway_eui: EUI64,
+    pub position: Position,
+    ///log-distance without shadowing if missing
+    #[serde(default)]
+    pub propagation: Option<PropagationConfig>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GWMPGatewayDeviceConfig {
+    pub gateway: GWMPGatewayConfig,
+    pub position: Position,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum MediumRole {
+    Device,
+    Gateway,
+}
+
+///Node of the in-process radio medium with the given name
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct VirtualMediumConfig {
+    pub medium: String,
+    pub position: Position,
+    pub role: MediumRole,
+    ///replaces the propagation model of the medium, log-distance without shadowing if never set
+    #[serde(default)]
+    pub propagation: Option<PropagationConfig>,
+}
+
+fn default_speedup() -> f64 {
+    1.0
+}
+
+fn default_payload_size() -> usize {
+    12
+}
+
+///Reception trace like gateway.csv played back to a network controller
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplayConfig {
+    pub trace: String,
+    ///1 plays the trace at the recorded pace, 60 plays a minute of it every second
+    #[serde(default = "default_speedup")]
+    pub speedup: f64,
+    ///seconds of the trace to play, all of it if missing
+    #[serde(default)]
+    pub duration: Option<f64>,
+    ///devices whose frames get a valid payload, the frames of the other DevAddrs fail the MIC check
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    ///bytes of FRMPayload of the synthesized frames
+    #[serde(default = "default_payload_size")]
+    pub payload_size: usize,
+}
+
+///Config of a communicator whose frames are captured in a pcap file
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PcapConfig<C> {
+    pub communicator: C,
+    pub path: String,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct UDPDeviceConfig {
     pub addr: String,
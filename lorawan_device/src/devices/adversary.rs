@@ -0,0 +1,329 @@
+use std::time::Duration;
+
+use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{
+    clock::now_ms,
+    communicator::{Position, Transmission},
+    configs::MediumRole,
+    devices::virtual_medium::{OnAir, VirtualMedium},
+    gwmp::dev_addr,
+};
+
+///Who put a frame on the medium, the label of the receptions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FrameOrigin {
+    #[default]
+    Legitimate,
+    Jammer,
+    ///an uplink captured and sent again by an attacker
+    Replay,
+    ///a frame forged by an attacker with the DevAddr of another device
+    Spoof,
+}
+
+impl FrameOrigin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameOrigin::Legitimate => "legitimate",
+            FrameOrigin::Jammer => "jammer",
+            FrameOrigin::Replay => "replay",
+            FrameOrigin::Spoof => "spoof",
+        }
+    }
+}
+
+fn default_frequency() -> u32 {
+    868_100_000
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+///Jammers and attackers of a virtual medium. Times are in seconds, frequencies in Hz and powers in dBm.
+///Reactive jammers sense every frame start on their channel wherever it comes from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AdversaryConfig {
+    ///Noise on the channel for on seconds every on + off, always if off is 0
+    ConstantJammer {
+        position: Position,
+        #[serde(default = "default_frequency")]
+        frequency: u32,
+        #[serde(default)]
+        bandwidth: LoRaBandwidth,
+        power: i8,
+        on: f64,
+        #[serde(default)]
+        off: f64,
+    },
+    ///Noise for duration seconds starting reaction seconds after the start of each frame on the channel
+    ReactiveJammer {
+        position: Position,
+        #[serde(default = "default_frequency")]
+        frequency: u32,
+        #[serde(default)]
+        bandwidth: LoRaBandwidth,
+        power: i8,
+        reaction: f64,
+        duration: f64,
+    },
+    ///Reactive jammer sending chirps of one spreading factor, frames of the other spreading factors are nearly unaffected
+    SfSelectiveJammer {
+        position: Position,
+        #[serde(default = "default_frequency")]
+        frequency: u32,
+        #[serde(default)]
+        bandwidth: LoRaBandwidth,
+        power: i8,
+        spreading_factor: u8,
+        reaction: f64,
+        duration: f64,
+    },
+    ///Sends again the uplinks it receives, each one with the given probability, delay seconds later
+    Replay {
+        position: Position,
+        power: i8,
+        delay: f64,
+        #[serde(default = "default_probability")]
+        probability: f64,
+    },
+    ///Forges data uplinks with the DevAddrs it has received, on their channel and data rate, with exponential inter-arrival times
+    Spoofer {
+        position: Position,
+        power: i8,
+        mean_interval: f64,
+    },
+}
+
+fn non_negative(seconds: f64) -> bool {
+    seconds.is_finite() && seconds >= 0.0
+}
+
+fn positive(seconds: f64) -> bool {
+    seconds.is_finite() && seconds > 0.0
+}
+
+impl AdversaryConfig {
+    ///Durations must be finite and not negative, those the virtual clock has to advance by (on, duration, mean_interval)
+    ///must be positive and probabilities between 0 and 1
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            AdversaryConfig::ConstantJammer { on, off, .. } => positive(on) && non_negative(off),
+            AdversaryConfig::ReactiveJammer { reaction, duration, .. } => non_negative(reaction) && positive(duration),
+            AdversaryConfig::SfSelectiveJammer { spreading_factor, reaction, duration, .. } => {
+                (7..=12).contains(&spreading_factor) && non_negative(reaction) && positive(duration)
+            },
+            AdversaryConfig::Replay { delay, probability, .. } => non_negative(delay) && (0.0..=1.0).contains(&probability),
+            AdversaryConfig::Spoofer { mean_interval, .. } => positive(mean_interval),
+        };
+        if valid { Ok(()) } else { Err(format!("{self:?} has invalid parameters")) }
+    }
+}
+
+fn exponential(mean: f64, rng: &mut StdRng) -> Duration {
+    Duration::from_secs_f64(-mean * (1.0 - rng.gen::<f64>()).ln())
+}
+
+impl VirtualMedium {
+    ///Start the adversary on the medium, every random choice comes from the seed
+    pub fn add_adversary(&self, config: &AdversaryConfig, seed: u64) -> JoinHandle<()> {
+        let medium = self.clone();
+        let config = config.clone();
+        let mut rng = StdRng::seed_from_u64(seed);
+        tokio::spawn(async move {
+            match config {
+                AdversaryConfig::ConstantJammer { position, frequency, bandwidth, power, on, off } => {
+                    let (node, _) = medium.attach_node(position, MediumRole::Device, true);
+                    let (on, off) = (Duration::from_secs_f64(on), Duration::from_secs_f64(off));
+                    loop {
+                        let end = medium.jam(node, jamming(position, frequency, bandwidth, SpreadingFactor::SF7, power), on, true);
+                        tokio::time::sleep_until(end + off).await;
+                    }
+                },
+                AdversaryConfig::ReactiveJammer { position, frequency, bandwidth, power, reaction, duration } => {
+                    medium.react(jamming(position, frequency, bandwidth, SpreadingFactor::SF7, power), false, reaction, duration).await
+                },
+                AdversaryConfig::SfSelectiveJammer { position, frequency, bandwidth, power, spreading_factor, reaction, duration } => {
+                    let signal = jamming(position, frequency, bandwidth, SpreadingFactor::new(spreading_factor), power);
+                    medium.react(signal, true, reaction, duration).await
+                },
+                AdversaryConfig::Replay { position, power, delay, probability } => {
+                    let (node, mut inbox) = medium.attach_node(position, MediumRole::Gateway, true);
+                    let delay = Duration::from_secs_f64(delay);
+                    while let Some((received, _, origin)) = inbox.recv().await {
+                        //frames of other attackers are not replayed, two of them would echo each other forever
+                        if origin != FrameOrigin::Legitimate || !rng.gen_bool(probability) {
+                            continue;
+                        }
+                        let medium = medium.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            medium.forge(node, received.transmission, position, power, FrameOrigin::Replay);
+                        });
+                    }
+                },
+                AdversaryConfig::Spoofer { position, power, mean_interval } => {
+                    let (node, mut inbox) = medium.attach_node(position, MediumRole::Gateway, true);
+                    let mut stolen: Vec<([u8; 4], Transmission)> = Vec::new();
+                    let mut next = Instant::now() + exponential(mean_interval, &mut rng);
+                    loop {
+                        tokio::select! {
+                            //the order of the branches must not be random, runs with the same seed give the same frames
+                            biased;
+                            received = inbox.recv() => {
+                                let Some((received, _, _)) = received else { break };
+                                if let Some(addr) = dev_addr(&received.transmission.payload) {
+                                    if !stolen.iter().any(|(a, _)| *a == addr) {
+                                        stolen.push((addr, received.transmission));
+                                    }
+                                }
+                            },
+                            _ = tokio::time::sleep_until(next) => {
+                                if !stolen.is_empty() {
+                                    let (addr, template) = &stolen[rng.gen_range(0..stolen.len())];
+                                    let payload = forged_uplink(addr, &mut rng);
+                                    medium.forge(node, Transmission { payload, ..template.clone() }, position, power, FrameOrigin::Spoof);
+                                }
+                                next += exponential(mean_interval, &mut rng);
+                            },
+                        }
+                    }
+                },
+            }
+        })
+    }
+
+    ///Jam the frames on the channel of the signal, only those of its spreading factor if selective
+    async fn react(&self, signal: Transmission, selective: bool, reaction: f64, duration: f64) {
+        let (node, _) = self.attach_node(signal.start_position, MediumRole::Device, true);
+        let mut starts = self.watch();
+        let (reaction, duration) = (Duration::from_secs_f64(reaction), Duration::from_secs_f64(duration));
+        let mut busy_until = Instant::now();
+        while let Some(frame) = starts.recv().await {
+            let t = &frame.transmission;
+            let target = frame.origin != FrameOrigin::Jammer
+                && (t.frequency - signal.frequency).abs() < (t.bandwidth.hz().max(signal.bandwidth.hz()) / 2.0) as f64
+                && (!selective || t.spreading_factor == signal.spreading_factor);
+            if !target || frame.start < busy_until {
+                continue;
+            }
+            tokio::time::sleep_until(frame.start + reaction).await;
+            busy_until = self.jam(node, signal.clone(), duration, !selective);
+        }
+    }
+
+    fn jam(&self, node: usize, transmission: Transmission, duration: Duration, noise: bool) -> Instant {
+        let start = Instant::now();
+        self.put_on_air(OnAir { node, transmission: Transmission { start_time: now_ms(), ..transmission }, start, end: start + duration, origin: FrameOrigin::Jammer, noise })
+    }
+
+    fn forge(&self, node: usize, transmission: Transmission, position: Position, power: i8, origin: FrameOrigin) {
+        let start = Instant::now();
+        let transmission = Transmission { start_position: position, start_time: now_ms(), starting_power: power as f32, uplink: true, ..transmission };
+        let end = start + Duration::from_millis(transmission.time_on_air() as u64);
+        self.put_on_air(OnAir { node, transmission, start, end, origin, noise: false });
+    }
+}
+
+fn jamming(position: Position, frequency: u32, bandwidth: LoRaBandwidth, spreading_factor: SpreadingFactor, power: i8) -> Transmission {
+    Transmission {
+        start_position: position,
+        frequency: frequency as f64,
+        bandwidth,
+        spreading_factor,
+        starting_power: power as f32,
+        uplink: true,
+        ..Default::default()
+    }
+}
+
+///Unconfirmed data uplink of the DevAddr (as on the air) with random FCnt, payload and MIC
+fn forged_uplink(dev_addr: &[u8; 4], rng: &mut StdRng) -> Vec<u8> {
+    let mut frame = vec![0x40];
+    frame.extend_from_slice(dev_addr);
+    frame.push(0x00);
+    frame.extend_from_slice(&rng.gen::<u16>().to_le_bytes());
+    frame.push(1);
+    frame.extend((0..rng.gen_range(4..20)).map(|_| rng.gen::<u8>()));
+    frame.extend_from_slice(&rng.gen::<[u8; 4]>());
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{communicator::LoRaWANCommunicator, devices::virtual_medium::VirtualMediumCommunicator};
+
+    fn device(medium: &VirtualMedium, x: f32) -> VirtualMediumCommunicator {
+        let mut device = medium.attach(Position { x, y: 0.0, z: 0.0 }, MediumRole::Device);
+        device.set_tx_parameters(868_100_000, SpreadingFactor::SF7, LoRaBandwidth::BW125, 14);
+        device
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jammers_and_attackers() {
+        let medium = VirtualMedium::new();
+        medium.record_receptions();
+        let gateway = medium.attach(Position::default(), MediumRole::Gateway);
+        let near = device(&medium, 500.0);
+        let mut sf9 = device(&medium, 500.0);
+        sf9.set_tx_parameters(868_100_000, SpreadingFactor::SF9, LoRaBandwidth::BW125, 14);
+        let uplink = [0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x01, 0x00, 0x01, 0xAA, 0, 0, 0, 0];
+
+        //an SF7 jammer close to the gateway destroys SF7 frames and leaves SF9 ones
+        let jammer = medium.add_adversary(&AdversaryConfig::SfSelectiveJammer {
+            position: Position { x: 100.0, y: 0.0, z: 0.0 }, frequency: 868_100_000, bandwidth: LoRaBandwidth::BW125,
+            power: 14, spreading_factor: 7, reaction: 0.005, duration: 0.5,
+        }, 1);
+        tokio::task::yield_now().await;
+        near.send(&uplink, None, None).await.unwrap();
+        sf9.send(&uplink, None, None).await.unwrap();
+        let received = gateway.receive(Some(Duration::from_secs(1))).await.unwrap();
+        assert_eq!(received[0].transmission.spreading_factor, SpreadingFactor::SF9);
+        assert!(gateway.receive(Some(Duration::from_millis(100))).await.is_err());
+        jammer.abort();
+
+        //the replay sends the uplink again, the spoofer forges one with the same DevAddr
+        let replay = medium.add_adversary(&AdversaryConfig::Replay { position: Position { x: 200.0, y: 0.0, z: 0.0 }, power: 14, delay: 5.0, probability: 1.0 }, 2);
+        let spoofer = medium.add_adversary(&AdversaryConfig::Spoofer { position: Position { x: 300.0, y: 0.0, z: 0.0 }, power: 14, mean_interval: 10.0 }, 3);
+        tokio::task::yield_now().await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        near.send(&uplink, None, None).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        replay.abort();
+        spoofer.abort();
+
+        let receptions = medium.take_receptions();
+        let labels: Vec<&str> = receptions.iter().map(|r| r.label()).collect();
+        assert_eq!(labels[..2], ["jammed", "legitimate"]);
+        assert!(labels.contains(&"replay") && labels.contains(&"spoof"));
+        assert!(labels[2..].iter().all(|l| *l == "spoof" || *l == "replay"));
+        let spoofed = receptions.iter().find(|r| r.origin == FrameOrigin::Spoof).unwrap();
+        assert_eq!(dev_addr(&spoofed.transmission.payload), Some([0x04, 0x03, 0x02, 0x01]));
+        assert_ne!(spoofed.transmission.payload, uplink);
+        assert!(receptions[0].to_csv().ends_with(",1,jammed"));
+        assert!(receptions[0].to_csv().contains(",01020304,0,868100000,9,125000,"));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let position = Position::default();
+        let constant = |on, off| AdversaryConfig::ConstantJammer { position, frequency: 868_100_000, bandwidth: LoRaBandwidth::BW125, power: 14, on, off };
+        let replay = |delay, probability| AdversaryConfig::Replay { position, power: 14, delay, probability };
+        assert!(constant(1.0, 0.0).validate().is_ok());
+        assert!(constant(0.0, 0.0).validate().is_err());
+        assert!(constant(1.0, -1.0).validate().is_err());
+        assert!(constant(f64::INFINITY, 1.0).validate().is_err());
+        assert!(replay(0.0, 0.5).validate().is_ok());
+        assert!(replay(f64::NAN, 0.5).validate().is_err());
+        assert!(replay(1.0, 1.5).validate().is_err());
+        assert!(replay(1.0, f64::NAN).validate().is_err());
+        assert!(AdversaryConfig::Spoofer { position, power: 14, mean_interval: 0.0 }.validate().is_err());
+        assert!(AdversaryConfig::ReactiveJammer { position, frequency: 868_100_000, bandwidth: LoRaBandwidth::BW125, power: 14, reaction: -0.1, duration: 1.0 }.validate().is_err());
+    }
+}
@@ -67,12 +67,14 @@ impl From<PyLoRaPacket> for ReceivedTransmission {
                 code_rate: CodeRate::CR4_5,
                 starting_power: packet.rssi,
                 uplink: false,
+                gateway_tmst: None,
                 payload: packet.payload,
             },
             arrival_stats: ArrivalStats {
                 time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
                 rssi: packet.rssi,
                 snr: packet.snr,
+                gateway_tmst: None,
             },
         }
     }
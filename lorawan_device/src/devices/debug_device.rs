@@ -9,6 +9,7 @@ use crate::{
 use blockchain_api::{exec_bridge::BlockchainExeClient, BlockchainClient};
 use lorawan::{
     device::Device,
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
     utils::{eui::EUI64, PrettyHexSlice},
 };
 
@@ -133,6 +134,18 @@ impl<T: LoRaWANCommunicator> LoRaWANCommunicator for DebugCommunicator<T> {
         );
         Ok(r)
     }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        self.inner.set_rx_window(frequency, spreading_factor, bandwidth)
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        self.inner.set_tx_parameters(frequency, spreading_factor, bandwidth, power)
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        self.inner.tx_parameters_applied()
+    }
 }
 
 #[derive(Debug)]
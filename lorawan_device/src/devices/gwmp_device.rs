@@ -0,0 +1,315 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{atomic::{AtomicU16, Ordering}, Arc, Mutex}, time::Duration};
+
+use lorawan::utils::{errors::LoRaWANError, eui::EUI64};
+use tokio::{net::UdpSocket, sync::oneshot, time::Instant};
+
+use crate::{
+    communicator::{CommunicatorError, LoRaWANCommunicator, ReceivedTransmission, Transmission},
+    configs::GWMPNCConfig,
+    gwmp::{dev_addr, GWMPPacket, PacketType, PullResp, PushData, TxAck, Txpk},
+    split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
+};
+
+///Time the sender waits for the TX_ACK of a PULL_RESP, forwarders implementing the first version of the protocol never send it
+pub const TX_ACK_TIMEOUT: Duration = Duration::from_millis(50);
+///Copies of the same uplink forwarded by different gateways arrive within this interval
+const DEDUPLICATION_WINDOW: Duration = Duration::from_millis(200);
+///Uplinks older than this are not used to choose the gateway of a downlink (JoinAccept is sent at most 6 s later)
+const ROUTE_VALIDITY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gateway: EUI64,
+    rssi: f32,
+    received: Instant,
+}
+
+impl Route {
+    ///Keep the gateway that received the last uplink with the best RSSI
+    fn update(slot: &mut Option<Route>, route: Route) {
+        match slot {
+            Some(old) if route.received.duration_since(old.received) < DEDUPLICATION_WINDOW && old.rssi >= route.rssi => (),
+            _ => *slot = Some(route),
+        }
+    }
+
+    fn valid(&self) -> bool {
+        self.received.elapsed() < ROUTE_VALIDITY
+    }
+}
+
+#[derive(Default)]
+struct GatewayTable {
+    ///address the PULL_DATA of each gateway came from, downlinks are sent there
+    pull_addresses: HashMap<EUI64, SocketAddr>,
+    routes: HashMap<[u8; 4], Option<Route>>,
+    last_uplink: Option<Route>,
+    pending_acks: HashMap<[u8; 2], oneshot::Sender<Option<String>>>,
+}
+
+impl GatewayTable {
+    fn register_uplink(&mut self, gateway: EUI64, transmission: &ReceivedTransmission) {
+        let route = Route { gateway, rssi: transmission.arrival_stats.rssi, received: Instant::now() };
+        if let Some(dev_addr) = dev_addr(&transmission.transmission.payload) {
+            Route::update(self.routes.entry(dev_addr).or_default(), route);
+        }
+        Route::update(&mut self.last_uplink, route);
+    }
+
+    ///Gateway for a downlink: the best one of the last uplink of the device, for JoinAccepts the one of the last uplink
+    fn gateway_for(&self, payload: &[u8]) -> Option<EUI64> {
+        let route = dev_addr(payload).and_then(|dev_addr| self.routes.get(&dev_addr).copied().flatten());
+        let single = (self.pull_addresses.len() == 1).then(|| self.pull_addresses.keys().next().copied()).flatten();
+        route.or(self.last_uplink).filter(Route::valid).map(|r| r.gateway).or(single)
+    }
+}
+
+///State shared by the halves of the communicator
+struct GWMPServer {
+    socket: UdpSocket,
+    gateways: Mutex<GatewayTable>,
+    next_token: AtomicU16,
+}
+
+impl GWMPServer {
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let deadline = timeout.map(|d| Instant::now() + d);
+        let mut buf = vec![0_u8; 65535];
+        loop {
+            let received = match deadline {
+                Some(d) => match tokio::time::timeout_at(d, self.socket.recv_from(&mut buf)).await {
+                    Ok(v) => v,
+                    Err(_) => return Err(CommunicatorError::LoRaWANError(LoRaWANError::MissingDownlink)),
+                },
+                None => self.socket.recv_from(&mut buf).await,
+            };
+            let (len, addr) = received.map_err(CommunicatorError::UDP)?;
+
+            match GWMPPacket::from_bytes(&buf[..len]) {
+                Ok(packet) => {
+                    let transmissions = self.handle(packet, addr).await?;
+                    if !transmissions.is_empty() {
+                        return Ok(transmissions);
+                    }
+                },
+                Err(e) => eprintln!("Invalid GWMP packet from {addr}: {e:?}"),
+            }
+        }
+    }
+
+    async fn handle(&self, packet: GWMPPacket, addr: SocketAddr) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let mut ret = Vec::new();
+        match (packet.packet_type, packet.gateway) {
+            (PacketType::PushData, Some(gateway)) => {
+                self.socket.send_to(&packet.ack(PacketType::PushAck).to_bytes(), addr).await.map_err(CommunicatorError::UDP)?;
+                let push_data = match serde_json::from_slice::<PushData>(&packet.payload) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        eprintln!("Invalid PUSH_DATA from {gateway}: {e:?}");
+                        return Ok(ret);
+                    },
+                };
+                let mut gateways = self.gateways.lock().unwrap();
+                for rxpk in push_data.rxpk {
+                    //packets with a wrong CRC and FSK ones are dropped
+                    if let Ok(transmission) = rxpk.to_received_transmission(gateway) {
+                        gateways.register_uplink(gateway, &transmission);
+                        ret.push(transmission);
+                    }
+                }
+            },
+            (PacketType::PullData, Some(gateway)) => {
+                self.gateways.lock().unwrap().pull_addresses.insert(gateway, addr);
+                self.socket.send_to(&packet.ack(PacketType::PullAck).to_bytes(), addr).await.map_err(CommunicatorError::UDP)?;
+            },
+            (PacketType::TxAck, _) => {
+                if let Some(ack) = self.gateways.lock().unwrap().pending_acks.remove(&packet.token) {
+                    //the sender may have stopped waiting
+                    let _ = ack.send(TxAck::error(&packet.payload));
+                }
+            },
+            (t, _) => eprintln!("Unexpected GWMP packet {t:?} from {addr}"),
+        }
+        Ok(ret)
+    }
+
+    ///Send a PULL_RESP to the given gateway or, if missing, to the one whose clock times the transmission or to the one chosen from the last uplinks
+    async fn send(&self, transmission: &Transmission, gateway: Option<EUI64>) -> Result<(), CommunicatorError> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let (ack, ack_receiver) = oneshot::channel();
+        let addr = {
+            let mut gateways = self.gateways.lock().unwrap();
+            let gateway = gateway.or(transmission.gateway_tmst.map(|t| t.gateway)).or_else(|| gateways.gateway_for(&transmission.payload))
+                .ok_or(CommunicatorError::Gateway(String::from("no gateway available for the downlink")))?;
+            let addr = *gateways.pull_addresses.get(&gateway)
+                .ok_or(CommunicatorError::Gateway(format!("gateway {gateway} never sent a PULL_DATA")))?;
+            gateways.pending_acks.insert(token, ack);
+            addr
+        };
+
+        let pull_resp = serde_json::to_vec(&PullResp { txpk: Txpk::from_transmission(transmission) }).unwrap();
+        let sent = self.socket.send_to(&GWMPPacket::new(token, PacketType::PullResp, None, pull_resp).to_bytes(), addr).await;
+        let ack = match sent {
+            Ok(_) => tokio::time::timeout(TX_ACK_TIMEOUT, ack_receiver).await,
+            Err(e) => {
+                self.gateways.lock().unwrap().pending_acks.remove(&token);
+                return Err(CommunicatorError::UDP(e));
+            },
+        };
+        match ack {
+            Ok(Ok(Some(error))) => Err(CommunicatorError::Gateway(error)),
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.gateways.lock().unwrap().pending_acks.remove(&token);
+                Ok(())
+            },
+        }
+    }
+}
+
+///Semtech UDP packet forwarder server: receives the uplinks of the gateways and sends them the downlinks.
+///Bytes to send are a JSON `Transmission`, the same format the downlink scheduler produces
+pub struct GWMPCommunicator {
+    server: Arc<GWMPServer>,
+}
+
+impl GWMPCommunicator {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            server: Arc::new(GWMPServer {
+                socket,
+                gateways: Mutex::new(GatewayTable::default()),
+                next_token: AtomicU16::new(0),
+            }),
+        }
+    }
+
+    pub fn gateways(&self) -> Vec<EUI64> {
+        self.server.gateways.lock().unwrap().pull_addresses.keys().copied().collect()
+    }
+}
+
+impl LoRaWANCommunicator for GWMPCommunicator {
+    type Config = GWMPNCConfig;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        let socket = UdpSocket::bind(format!("{}:{}", config.addr, config.port)).await.map_err(CommunicatorError::UDP)?;
+        Ok(Self::new(socket))
+    }
+
+    async fn send(&self, bytes: &[u8], _src: Option<EUI64>, dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        let transmission = serde_json::from_slice::<Transmission>(bytes).map_err(|_| LoRaWANError::InvalidBufferContent)?;
+        self.server.send(&transmission, dest).await
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        self.server.receive(timeout).await
+    }
+}
+
+pub struct GWMPSender {
+    server: Arc<GWMPServer>,
+}
+
+pub struct GWMPReceiver {
+    server: Arc<GWMPServer>,
+}
+
+impl LoRaSender for GWMPSender {
+    ///EUI of the gateway that has to send the downlink
+    type OptionalInfo = EUI64;
+
+    async fn send(&self, bytes: &[u8], optional_info: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
+        let transmission = serde_json::from_slice::<Transmission>(bytes).map_err(|_| LoRaWANError::InvalidBufferContent)?;
+        self.server.send(&transmission, optional_info).await
+    }
+}
+
+impl LoRaReceiver for GWMPReceiver {
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        self.server.receive(timeout).await
+    }
+}
+
+impl SplitCommunicator for GWMPCommunicator {
+    type Sender = GWMPSender;
+    type Receiver = GWMPReceiver;
+
+    async fn split_communicator(self) -> Result<(Self::Sender, Self::Receiver), CommunicatorError> {
+        Ok((
+            GWMPSender { server: self.server.clone() },
+            GWMPReceiver { server: self.server },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+
+    use crate::{communicator::GatewayTimestamp, gwmp::{Datr, PullResp, PROTOCOL_VERSION}};
+
+    use super::*;
+
+    const GATEWAY: [u8; 8] = [0xAA, 0x55, 0x5A, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+    async fn expect(forwarder: &UdpSocket, packet_type: PacketType) -> GWMPPacket {
+        let mut buf = vec![0_u8; 4096];
+        let len = tokio::time::timeout(Duration::from_secs(1), forwarder.recv(&mut buf)).await.unwrap().unwrap();
+        let packet = GWMPPacket::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(packet.packet_type, packet_type);
+        packet
+    }
+
+    #[tokio::test]
+    async fn fake_forwarder() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forwarder.connect(socket.local_addr().unwrap()).await.unwrap();
+        let (sender, receiver) = GWMPCommunicator::new(socket).split_communicator().await.unwrap();
+        let receiver = Arc::new(receiver);
+
+        let r = receiver.clone();
+        let uplink = tokio::spawn(async move { r.receive(Some(Duration::from_secs(1))).await });
+
+        forwarder.send(&GWMPPacket::new([0, 1], PacketType::PullData, Some(EUI64::from(GATEWAY)), Vec::new()).to_bytes()).await.unwrap();
+        assert_eq!(expect(&forwarder, PacketType::PullAck).await.token, [0, 1]);
+
+        let rxpk = r#"{"rxpk":[
+            {"tmst":3512348611,"chan":2,"rfch":0,"freq":868.500000,"stat":1,"modu":"LORA","datr":"SF9BW125","codr":"4/6","rssi":-35,"lsnr":5.1,"size":12,"data":"QAQDAgEAAQABAgME"},
+            {"tmst":3512348612,"chan":9,"rfch":1,"freq":869.100000,"stat":1,"modu":"FSK","datr":50000,"rssi":-75,"size":1,"data":"AA=="}
+        ],"stat":{"rxnb":2}}"#;
+        forwarder.send(&GWMPPacket::new([0, 2], PacketType::PushData, Some(EUI64::from(GATEWAY)), rxpk.as_bytes().to_vec()).to_bytes()).await.unwrap();
+        assert_eq!(expect(&forwarder, PacketType::PushAck).await.token, [0, 2]);
+
+        let received = uplink.await.unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].transmission.payload, vec![0x40, 4, 3, 2, 1, 0, 1, 0, 1, 2, 3, 4]);
+        assert_eq!(received[0].transmission.frequency, 868_500_000.0);
+        assert_eq!(received[0].transmission.spreading_factor, SpreadingFactor::SF9);
+        assert_eq!(received[0].transmission.bandwidth, LoRaBandwidth::BW125);
+        assert_eq!(received[0].arrival_stats.rssi, -35.0);
+        assert_eq!(received[0].arrival_stats.snr, 5.1);
+        let uplink_tmst = received[0].arrival_stats.gateway_tmst.unwrap();
+        assert_eq!(uplink_tmst, GatewayTimestamp { gateway: EUI64::from(GATEWAY), tmst: 3512348611 });
+
+        //the downlink goes to the gateway of the last uplink of the DevAddr, timed on its clock
+        let r = receiver.clone();
+        tokio::spawn(async move { r.receive(Some(Duration::from_secs(1))).await });
+        let gateway_tmst = Some(uplink_tmst.after(Duration::from_secs(1)));
+        let downlink = Transmission { frequency: 868_500_000.0, spreading_factor: SpreadingFactor::SF9, gateway_tmst, payload: vec![0x60, 4, 3, 2, 1, 0, 0, 0], ..Default::default() };
+        let bytes = serde_json::to_vec(&downlink).unwrap();
+        let (sent, _) = tokio::join!(sender.send(&bytes, None), async {
+            let pull_resp = expect(&forwarder, PacketType::PullResp).await;
+            assert_eq!(pull_resp.version, PROTOCOL_VERSION);
+            let txpk = serde_json::from_slice::<PullResp>(&pull_resp.payload).unwrap().txpk;
+            assert!(!txpk.imme && txpk.ipol);
+            assert_eq!(txpk.tmst, Some(3513348611));
+            assert_eq!(txpk.datr, Datr::LoRa(String::from("SF9BW125")));
+            assert_eq!(txpk.to_transmission().unwrap().payload, downlink.payload);
+            let tx_ack = br#"{"txpk_ack":{"error":"TOO_LATE"}}"#.to_vec();
+            forwarder.send(&GWMPPacket::new(pull_resp.token, PacketType::TxAck, Some(EUI64::from(GATEWAY)), tx_ack).to_bytes()).await.unwrap();
+        });
+        assert!(matches!(sent, Err(CommunicatorError::Gateway(e)) if e == "TOO_LATE"));
+    }
+}
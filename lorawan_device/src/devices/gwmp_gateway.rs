@@ -0,0 +1,405 @@
+use std::{sync::{atomic::{AtomicU16, Ordering}, Arc, Mutex, Weak}, time::Duration};
+
+use lorawan::{
+    lorawan_packet::mhdr::MHDR,
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
+    utils::{errors::LoRaWANError, eui::EUI64},
+};
+use tokio::{net::UdpSocket, select, sync::mpsc, time::Instant};
+
+use crate::{
+    clock::now_ms,
+    communicator::{ArrivalStats, CommunicatorError, LoRaWANCommunicator, Position, ReceivedTransmission, Transmission},
+    configs::{GWMPGatewayConfig, GWMPGatewayDeviceConfig},
+    gwmp::{dev_addr, GWMPPacket, PacketType, PullResp, PushData, Rxpk, TxAck},
+    propagation::{link_budget, LogDistance, PropagationModel},
+};
+
+///Time between two PULL_DATA, keeps the downlink path open through NATs
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+///Downlinks are routed only to devices whose last uplink ended at most this long ago, RX2 of a JoinAccept closes 7 s after the uplink
+const DOWNLINK_ROUTING_WINDOW: Duration = Duration::from_secs(7);
+///A downlink is received if its preamble starts at most this long before the receive window opens
+pub(super) const RX_WINDOW_TOLERANCE: Duration = Duration::from_millis(50);
+///Uplink parameters used before the device selects a channel
+pub(super) const DEFAULT_FREQUENCY: u32 = 868_100_000;
+pub(super) const DEFAULT_POWER: i8 = 14;
+
+struct DeviceSlot {
+    dev_addr: Option<[u8; 4]>,
+    ///end of the last uplink and whether it was a join or rejoin request
+    last_uplink: Option<(Instant, bool)>,
+    downlinks: mpsc::UnboundedSender<(Transmission, Instant)>,
+}
+
+struct GatewayState {
+    devices: Vec<DeviceSlot>,
+    ///end of the downlink on air, the gateway sends one packet at a time
+    busy_until: Instant,
+}
+
+impl GatewayState {
+    ///Devices that may be waiting for the downlink, among the ones whose uplink is recent enough: the owner of the DevAddr
+    ///or, for JoinAccepts, every one that sent a join or rejoin request. As on air, each device keeps the frame only
+    ///if it arrives in its RX window and, for a JoinAccept, if it can decrypt it
+    fn route(&self, payload: &[u8], now: Instant) -> impl Iterator<Item = &DeviceSlot> {
+        let dev_addr = dev_addr(payload);
+        self.devices.iter()
+            .filter(move |d| d.last_uplink.is_some_and(|(end, join)| now.duration_since(end) <= DOWNLINK_ROUTING_WINDOW && match dev_addr {
+                Some(a) => d.dev_addr == Some(a),
+                None => join,
+            }))
+    }
+}
+
+struct GatewayInner {
+    eui: EUI64,
+    position: Position,
+    socket: Arc<UdpSocket>,
+    ///tmst counts the µs elapsed since this instant
+    epoch: Instant,
+    next_token: AtomicU16,
+    state: Mutex<GatewayState>,
+    propagation: Mutex<Arc<dyn PropagationModel>>,
+}
+
+impl GatewayInner {
+    fn tmst(&self, instant: Instant) -> u32 {
+        instant.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn link_budget(&self, transmission: &Transmission, receiver: &Position) -> (f32, f32) {
+        let model = self.propagation.lock().unwrap().clone();
+        link_budget(model.as_ref(), transmission, receiver)
+    }
+
+    async fn send_packet(&self, packet_type: PacketType, payload: Vec<u8>) -> Result<(), CommunicatorError> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        self.socket.send(&GWMPPacket::new(token, packet_type, Some(self.eui), payload).to_bytes()).await.map_err(CommunicatorError::UDP)?;
+        Ok(())
+    }
+
+    ///Forward an uplink that has just ended, frames under the demodulation floor are lost
+    async fn push_uplink(&self, device: usize, transmission: &Transmission) -> Result<(), CommunicatorError> {
+        let mhdr = MHDR::from_bytes(*transmission.payload.first().ok_or(LoRaWANError::InvalidBufferLength)?);
+        let now = Instant::now();
+        let (rssi, snr) = self.link_budget(transmission, &self.position);
+        if snr < transmission.spreading_factor.demodulation_floor() {
+            return Ok(());
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            let slot = &mut state.devices[device];
+            let join = mhdr.is_join_rejoin();
+            slot.last_uplink = Some((now, join));
+            if let Some(dev_addr) = dev_addr(&transmission.payload) {
+                slot.dev_addr = Some(dev_addr);
+            }
+        }
+        let push_data = PushData { rxpk: vec![Rxpk::from_transmission(transmission, self.tmst(now), rssi, snr)] };
+        self.send_packet(PacketType::PushData, serde_json::to_vec(&push_data).unwrap()).await
+    }
+
+    ///Put the txpk on air at its tmst and hand it to the device that is waiting for it
+    async fn handle_pull_resp(&self, packet: GWMPPacket) {
+        let txpk = match serde_json::from_slice::<PullResp>(&packet.payload).map(|p| p.txpk) {
+            Ok(v) => v,
+            Err(e) => return eprintln!("Invalid PULL_RESP: {e:?}"),
+        };
+        let mut transmission = match txpk.to_transmission() {
+            Ok(v) => v,
+            Err(e) => return eprintln!("Unsupported txpk {txpk:?}: {e:?}"),
+        };
+
+        let now = Instant::now();
+        let start = match txpk.tmst {
+            Some(tmst) if !txpk.imme => {
+                let delta = tmst.wrapping_sub(self.tmst(now)) as i32;
+                (delta >= 0).then(|| now + Duration::from_micros(delta as u64))
+            },
+            _ => Some(now),
+        };
+        let error = match start {
+            None => "TOO_LATE",
+            Some(start) => {
+                let mut state = self.state.lock().unwrap();
+                if state.busy_until > start {
+                    "COLLISION_PACKET"
+                } else {
+                    state.busy_until = start + Duration::from_millis(transmission.time_on_air() as u64);
+                    transmission.start_position = self.position;
+                    transmission.start_time = now_ms() + start.duration_since(now).as_millis();
+                    for device in state.route(&transmission.payload, now) {
+                        //the device dropped its communicator, nobody is listening
+                        let _ = device.downlinks.send((transmission.clone(), start));
+                    }
+                    "NONE"
+                }
+            },
+        };
+
+        let ack = GWMPPacket::new(packet.token, PacketType::TxAck, Some(self.eui), serde_json::to_vec(&TxAck::new(error)).unwrap());
+        if let Err(e) = self.socket.send(&ack.to_bytes()).await {
+            eprintln!("Error sending TX_ACK: {e:?}");
+        }
+    }
+}
+
+///Semtech packet forwarder emulator: the uplinks of the devices created with `communicator` are forwarded
+///to the network server as PUSH_DATA and the PULL_RESP are delivered back to the devices
+#[derive(Clone)]
+pub struct GWMPGateway {
+    inner: Arc<GatewayInner>,
+}
+
+impl GWMPGateway {
+    pub async fn connect(config: &GWMPGatewayConfig) -> Result<Self, CommunicatorError> {
+        let propagation = match &config.propagation {
+            Some(propagation) => propagation.build().map_err(|e| CommunicatorError::Radio(e.to_string()))?,
+            None => Arc::new(LogDistance::default()),
+        };
+        let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(CommunicatorError::UDP)?;
+        socket.connect(format!("{}:{}", config.addr, config.port)).await.map_err(CommunicatorError::UDP)?;
+        let socket = Arc::new(socket);
+        let now = Instant::now();
+        let inner = Arc::new(GatewayInner {
+            eui: config.gateway_eui,
+            position: config.position,
+            socket: socket.clone(),
+            epoch: now,
+            next_token: AtomicU16::new(0),
+            state: Mutex::new(GatewayState { devices: Vec::new(), busy_until: now }),
+            propagation: Mutex::new(propagation),
+        });
+        tokio::spawn(Self::run(socket, Arc::downgrade(&inner)));
+        Ok(Self { inner })
+    }
+
+    pub fn eui(&self) -> EUI64 {
+        self.inner.eui
+    }
+
+    ///Path loss between the gateway and its devices, log-distance without shadowing by default
+    pub fn set_propagation(&self, model: Arc<dyn PropagationModel>) {
+        *self.inner.propagation.lock().unwrap() = model;
+    }
+
+    ///Communicator of a device placed at position, in range of this gateway only
+    pub fn communicator(&self, position: Position) -> GWMPGatewayCommunicator {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut state = self.inner.state.lock().unwrap();
+        state.devices.push(DeviceSlot { dev_addr: None, last_uplink: None, downlinks: sender });
+        GWMPGatewayCommunicator {
+            gateway: self.inner.clone(),
+            id: state.devices.len() - 1,
+            position,
+            tx: (DEFAULT_FREQUENCY, SpreadingFactor::SF7, LoRaBandwidth::BW125, DEFAULT_POWER),
+            rx: None,
+            downlinks: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    ///Keepalive and downlink path, stops when the gateway and all its communicators are dropped
+    async fn run(socket: Arc<UdpSocket>, gateway: Weak<GatewayInner>) {
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        let mut buf = vec![0_u8; 65535];
+        loop {
+            select! {
+                _ = keepalive.tick() => {
+                    let Some(gateway) = gateway.upgrade() else { break };
+                    if let Err(e) = gateway.send_packet(PacketType::PullData, Vec::new()).await {
+                        eprintln!("Error sending PULL_DATA: {e:?}");
+                    }
+                },
+                received = socket.recv(&mut buf) => {
+                    let Some(gateway) = gateway.upgrade() else { break };
+                    match received.map_err(|e| e.to_string()).and_then(|len| GWMPPacket::from_bytes(&buf[..len]).map_err(|e| e.to_string())) {
+                        Ok(packet) if packet.packet_type == PacketType::PullResp => gateway.handle_pull_resp(packet).await,
+                        //PUSH_ACK and PULL_ACK do not need an answer
+                        Ok(_) => (),
+                        Err(e) => eprintln!("Gateway {} received an invalid packet: {e}", gateway.eui),
+                    }
+                },
+            }
+        }
+    }
+}
+
+///Radio of a device in range of a `GWMPGateway`
+pub struct GWMPGatewayCommunicator {
+    gateway: Arc<GatewayInner>,
+    id: usize,
+    position: Position,
+    ///frequency, data rate and EIRP of the next uplink
+    tx: (u32, SpreadingFactor, LoRaBandwidth, i8),
+    rx: Option<(u32, SpreadingFactor, LoRaBandwidth)>,
+    downlinks: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Transmission, Instant)>>,
+}
+
+impl GWMPGatewayCommunicator {
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    fn listening(&self, transmission: &Transmission) -> bool {
+        self.rx.is_none_or(|(frequency, sf, bw)| {
+            (transmission.frequency - frequency as f64).abs() < 1_000.0 && transmission.spreading_factor == sf && transmission.bandwidth == bw
+        })
+    }
+}
+
+impl LoRaWANCommunicator for GWMPGatewayCommunicator {
+    type Config = GWMPGatewayDeviceConfig;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        Ok(GWMPGateway::connect(&config.gateway).await?.communicator(config.position))
+    }
+
+    ///The call lasts as long as the transmission, the gateway forwards the frame when it ends
+    async fn send(&self, bytes: &[u8], _src: Option<EUI64>, _dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        let (frequency, spreading_factor, bandwidth, power) = self.tx;
+        let transmission = Transmission {
+            start_position: self.position,
+            start_time: now_ms(),
+            frequency: frequency as f64,
+            bandwidth,
+            spreading_factor,
+            starting_power: power as f32,
+            uplink: true,
+            payload: bytes.to_vec(),
+            ..Default::default()
+        };
+        tokio::time::sleep(Duration::from_millis(transmission.time_on_air() as u64)).await;
+        self.gateway.push_uplink(self.id, &transmission).await
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let opened = Instant::now();
+        let deadline = timeout.map(|d| opened + d);
+        let mut downlinks = self.downlinks.lock().await;
+        loop {
+            let next = match deadline {
+                Some(d) => tokio::time::timeout_at(d, downlinks.recv()).await.map_err(|_| LoRaWANError::MissingDownlink)?,
+                None => downlinks.recv().await,
+            };
+            let (transmission, start) = next.ok_or(LoRaWANError::MissingDownlink)?;
+            //downlinks sent while the device was not listening are lost
+            if start + RX_WINDOW_TOLERANCE < opened || !self.listening(&transmission) {
+                continue;
+            }
+            let (rssi, snr) = self.gateway.link_budget(&transmission, &self.position);
+            if snr < transmission.spreading_factor.demodulation_floor() {
+                continue;
+            }
+            //once the preamble is detected the frame is received until its end
+            tokio::time::sleep_until(start + Duration::from_millis(transmission.time_on_air() as u64)).await;
+            return Ok(vec![ReceivedTransmission {
+                transmission,
+                arrival_stats: ArrivalStats { time: now_ms(), rssi, snr, ..Default::default() },
+            }]);
+        }
+    }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        self.rx = Some((frequency, spreading_factor, bandwidth));
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        self.tx = (frequency, spreading_factor, bandwidth, power);
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{devices::gwmp_device::GWMPCommunicator, split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator}};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn downlink_routing() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = GWMPGatewayConfig {
+            addr: String::from("127.0.0.1"),
+            port: socket.local_addr().unwrap().port(),
+            gateway_eui: EUI64::from([1, 2, 3, 4, 5, 6, 7, 8]),
+            position: Position::default(),
+            propagation: None,
+        };
+        let (sender, receiver) = GWMPCommunicator::new(socket).split_communicator().await.unwrap();
+        let receiver = Arc::new(receiver);
+
+        let gateway = GWMPGateway::connect(&config).await.unwrap();
+        let mut near = gateway.communicator(Position { x: 100.0, y: 0.0, z: 0.0 });
+        let far = gateway.communicator(Position { x: 2000.0, y: 0.0, z: 0.0 });
+        near.set_rx_window(868_100_000, SpreadingFactor::SF7, LoRaBandwidth::BW125);
+
+        let r = receiver.clone();
+        let uplinks = tokio::spawn(async move { (r.receive(Some(Duration::from_secs(1))).await, r.receive(Some(Duration::from_secs(1))).await) });
+        near.send(&[0x40, 1, 0, 0, 0, 0, 0, 0], None, None).await.unwrap();
+        far.send(&[0x40, 2, 0, 0, 0, 0, 0, 0], None, None).await.unwrap();
+        let (first, second) = uplinks.await.unwrap();
+        let (first, second) = (first.unwrap().remove(0), second.unwrap().remove(0));
+        assert_eq!(first.transmission.frequency, 868_100_000.0);
+        assert_eq!(first.transmission.spreading_factor, SpreadingFactor::SF7);
+        assert!(first.arrival_stats.rssi > second.arrival_stats.rssi);
+        assert!(first.arrival_stats.snr >= second.arrival_stats.snr);
+
+        //the receiver reads the TX_ACK
+        let r = receiver.clone();
+        tokio::spawn(async move { r.receive(Some(Duration::from_secs(1))).await });
+        let downlink = Transmission { frequency: 868_100_000.0, payload: vec![0x60, 1, 0, 0, 0, 0, 0, 0], ..Default::default() };
+        let bytes = serde_json::to_vec(&downlink).unwrap();
+        let (sent, near_received, far_received) = tokio::join!(
+            sender.send(&bytes, None),
+            near.receive(Some(Duration::from_millis(500))),
+            far.receive(Some(Duration::from_millis(500))),
+        );
+        sent.unwrap();
+        assert_eq!(near_received.unwrap()[0].transmission.payload, downlink.payload);
+        assert!(matches!(far_received, Err(CommunicatorError::LoRaWANError(LoRaWANError::MissingDownlink))));
+    }
+
+    #[tokio::test]
+    async fn join_accept_to_every_joining_device() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = GWMPGatewayConfig {
+            addr: String::from("127.0.0.1"),
+            port: socket.local_addr().unwrap().port(),
+            gateway_eui: EUI64::from([1, 2, 3, 4, 5, 6, 7, 8]),
+            position: Position::default(),
+            propagation: None,
+        };
+        let (sender, receiver) = GWMPCommunicator::new(socket).split_communicator().await.unwrap();
+        let receiver = Arc::new(receiver);
+
+        let gateway = GWMPGateway::connect(&config).await.unwrap();
+        let first = gateway.communicator(Position { x: 100.0, y: 0.0, z: 0.0 });
+        let second = gateway.communicator(Position { x: 200.0, y: 0.0, z: 0.0 });
+        assert!(gateway.inner.push_uplink(first.id, &Transmission::default()).await.is_err());
+
+        let r = receiver.clone();
+        let uplinks = tokio::spawn(async move { (r.receive(Some(Duration::from_secs(1))).await, r.receive(Some(Duration::from_secs(1))).await) });
+        first.send(&[0x00; 23], None, None).await.unwrap();
+        second.send(&[0x00; 23], None, None).await.unwrap();
+        let (first_uplink, second_uplink) = uplinks.await.unwrap();
+        assert!(first_uplink.is_ok() && second_uplink.is_ok());
+
+        //the gateway cannot tell which device a JoinAccept is for, both devices receive it and decrypt it to know
+        let r = receiver.clone();
+        tokio::spawn(async move { r.receive(Some(Duration::from_secs(1))).await });
+        let join_accept = Transmission { frequency: 868_100_000.0, payload: vec![0x20; 17], ..Default::default() };
+        let bytes = serde_json::to_vec(&join_accept).unwrap();
+        let (sent, first_received, second_received) = tokio::join!(
+            sender.send(&bytes, None),
+            first.receive(Some(Duration::from_millis(500))),
+            second.receive(Some(Duration::from_millis(500))),
+        );
+        sent.unwrap();
+        assert_eq!(first_received.unwrap()[0].transmission.payload, join_accept.payload);
+        assert_eq!(second_received.unwrap()[0].transmission.payload, join_accept.payload);
+    }
+}
@@ -1,7 +1,13 @@
-use std::{ops::{Deref, DerefMut}, cmp::Ordering, time::Duration};
+use std::{ops::{Deref, DerefMut}, time::Duration};
 use std::fmt::Debug;
-use lorawan::{device::Device, utils::{traits::ToBytes, errors::LoRaWANError}, lorawan_packet::{LoRaWANPacket, payload::Payload, mac_commands::{EDMacCommands, NCMacCommands}}};
-use crate::communicator::{LoRaWANCommunicator, CommunicatorError};
+use lorawan::{device::Device, utils::{traits::ToBytes, errors::LoRaWANError}, lorawan_packet::{LoRaWANPacket, MicContext, mhdr::{MType, MHDR}, payload::Payload, join::{JoinRequestType, RejoinType}, mac_commands::{EDMacCommands, NCMacCommands}}, regional_parameters::channel_list::ChannelList};
+use tokio::time::Instant;
+use crate::{communicator::{LoRaWANCommunicator, CommunicatorError, ReceivedTransmission}, mac_layer::{ForcedRejoin, MacLayer, RxWindow}};
+
+///How long RX2 stays open waiting for a downlink, RX1 is open until RX2 starts
+const RX2_WINDOW_LENGTH: Duration = Duration::from_secs(1);
+///Wait before retransmitting a frame, the regional parameters allow 1 to 3 s of ACK_TIMEOUT
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
 
 
 #[derive(PartialEq, Eq)]
@@ -9,9 +15,29 @@ pub struct LoRaWANDevice<T>
 where T: LoRaWANCommunicator + Send + Sync {
     device: Device,
     communicator: T,
+    mac: MacLayer,
+    ///FCnt of the last confirmed downlink, acknowledged by the next uplink
+    pending_ack: Option<u16>,
+    ///forced rejoin waiting for its next attempt, made before the first uplink after it is due
+    scheduled_rejoin: Option<ScheduledRejoin>,
+    ///MAC commands of the last downlink that the device did not apply
+    ignored_commands: Vec<NCMacCommands>,
     //config: T::Config,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ScheduledRejoin {
+    forced: ForcedRejoin,
+    at: Instant,
+    attempts_left: u8,
+}
+
+impl ScheduledRejoin {
+    fn new(forced: ForcedRejoin) -> Self {
+        Self { forced, at: Instant::now() + forced.period, attempts_left: forced.max_retries + 1 }
+    }
+}
+
 impl<T: LoRaWANCommunicator + Send + Sync> From<LoRaWANDevice<T>> for (Device, T) {
     fn from(val: LoRaWANDevice<T>) -> Self {
         (val.device, val.communicator)
@@ -20,11 +46,34 @@ impl<T: LoRaWANCommunicator + Send + Sync> From<LoRaWANDevice<T>> for (Device, T
 
 impl<T> LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
     pub fn new(device: Device, communicator: T/*, config: T::Config */) -> Self {
+        let mac = MacLayer::new(*device.regional_parameters().unwrap_or_default().region());
         Self {
-            device, communicator//, config
+            device, communicator, mac, pending_ack: None, scheduled_rejoin: None, ignored_commands: Vec::new()//, config
         }
     }
 
+    ///Channels currently usable for uplinks, updated by the CFList received in the JoinAccept and by the MAC commands
+    pub fn channels(&self) -> &ChannelList {
+        self.mac.channels()
+    }
+
+    pub fn channels_mut(&mut self) -> &mut ChannelList {
+        self.mac.channels_mut()
+    }
+
+    pub fn mac_layer(&self) -> &MacLayer {
+        &self.mac
+    }
+
+    pub fn mac_layer_mut(&mut self) -> &mut MacLayer {
+        &mut self.mac
+    }
+
+    ///Commands of the last downlink not handled by the device or with RFU values
+    pub fn ignored_commands(&self) -> &[NCMacCommands] {
+        &self.ignored_commands
+    }
+
     pub fn communicator(&self) -> &T {
         &self.communicator
     }
@@ -54,58 +103,202 @@ impl<T> LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
     }
 
     pub async fn send_uplink(&mut self, payload: Option<&[u8]>, confirmed: bool, fport: Option<u8>, fopts: Option<&[EDMacCommands]>) -> Result<(), CommunicatorError> {
-        let fopts: Option<Vec<u8>> = LoRaWANDevice::<T>::fold_maccomands(fopts);
-        let packet = self.device.create_uplink(payload, confirmed, fport, fopts)?;
-        self.communicator.send(&packet, Some(*self.dev_eui()),None).await?;
-        if confirmed {
-            //TODO REMOVE PERFORMANCES CHECKS
-            //let before = Instant::now();
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let payloads = self.communicator.receive(Some(Duration::from_secs(2))).await?;
-            //let after = Instant::now();
-            
-            //let mut file = OpenOptions::new()
-            //.append(true)
-            //.create(true)
-            //.open(format!("/root/times_{}.csv", self.dev_eui()))
-            //.expect("Failed to open file");
-            //writeln!(file, "{},{}", SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis(), (after - before).as_millis()).expect("Error while logging time to file");
-            
-            //TODO ESTRARRE MEGLIO I PAYLOAD - sf based?
-            let content = payloads.first().ok_or(LoRaWANError::MissingDownlink)?;
-
-            let packet = LoRaWANPacket::from_bytes(&content.transmission.payload, None, false)?;
-
-            if let Payload::MACPayload(p) = packet.payload() {
-                let fcnt = p.fhdr().fcnt();
-                let current_fcnt = self.device.session_mut().ok_or(LoRaWANError::ContextNeeded)?.network_context().nf_cnt_dwn(); 
-                let (fcnt_valid, fcnt_looped) = Self::nonce_valid(fcnt, current_fcnt as u16);
-                let new_fcnt = Self::increment_nonce(fcnt, current_fcnt, fcnt_looped);
-                if !fcnt_valid { eprintln!("Invalid {} fcnt down, expected > {current_fcnt}, received {fcnt}", if p.is_application() { "application" } else { "network" }) }
-                else if p.is_application() {
-                    self.device.session_mut().ok_or(LoRaWANError::ContextNeeded)?.application_context_mut().update_af_cnt_dwn(new_fcnt);
-                } else {
-                    self.device.session_mut().ok_or(LoRaWANError::ContextNeeded)?.network_context_mut().update_nf_cnt_dwn(new_fcnt);
-                }
-            };
-
-
-            let packet = LoRaWANPacket::from_bytes(&content.transmission.payload, Some(&self.device), false)?;
-            //println!("{packet:?}");
-            if let Payload::MACPayload(p) = packet.payload() {
-                if let Some(frmp) = p.frm_payload() {
-                    match p.fport() {
-                        Some(0) | None => {
-                            let commands = NCMacCommands::from_bytes(frmp)?;
-                            println!("{commands:?}")
-                        },
-                        Some(_port) => {
-                            //println!("Port: {port}, message: {}", String::from_utf8_lossy(frmp));
-                        },
+        self.pending_rejoins().await;
+        //FOpts cannot be used together with FPort 0, in that case the answers wait for the next uplink
+        let fopts = if fport != Some(0) && self.mac.has_answers() {
+            let mut answers = self.mac.take_answers();
+            answers.extend_from_slice(fopts.unwrap_or_default());
+            LoRaWANDevice::<T>::fold_maccomands(Some(&answers))
+        } else {
+            LoRaWANDevice::<T>::fold_maccomands(fopts)
+        };
+        let mut mic_context = MicContext { conf_fcnt: self.pending_ack.take(), ..self.select_uplink_channel() };
+        let mut packet = self.device.create_uplink(payload, confirmed, fport, fopts, &mic_context)?;
+        self.mac.uplink_sent();
+        //NbTrans transmissions of an unconfirmed frame, a confirmed one stops at the first ack
+        let nb_trans = self.mac.nb_trans().max(1);
+        for transmission in 1..=nb_trans {
+            //in LoRaWAN 1.1 the MIC covers TxDr and TxCh, a copy on another channel needs a new one
+            if transmission > 1 {
+                mic_context = MicContext { conf_fcnt: mic_context.conf_fcnt, ..self.select_uplink_channel() };
+                LoRaWANPacket::update_mic(&mut packet, &self.device, true, &mic_context)?;
+            }
+            self.communicator.send(&packet, Some(*self.dev_eui()),None).await?;
+            let tx_end = Instant::now();
+            //class A: both windows open after every transmission, a downlink in them stops the repetitions
+            match self.receive_windows(tx_end, self.mac.rx_windows()).await {
+                Ok(content) => {
+                    self.handle_downlink(&content)?;
+                    break;
+                },
+                Err(CommunicatorError::LoRaWANError(LoRaWANError::MissingDownlink)) if transmission < nb_trans => tokio::time::sleep(ACK_TIMEOUT).await,
+                //the network answers an unconfirmed uplink only if it has something to send
+                Err(CommunicatorError::LoRaWANError(LoRaWANError::MissingDownlink)) if !confirmed => (),
+                Err(e) => return Err(e),
+            }
+        }
+        //a new ForceRejoinReq replaces the one still pending
+        if let Some(forced) = self.mac.take_forced_rejoin() {
+            self.scheduled_rejoin = Some(ScheduledRejoin::new(forced));
+        }
+        Ok(())
+    }
+
+    ///Rejoins requested with ForceRejoinReq or due because of RejoinParamSetupReq. A failed rejoin does not invalidate the current session.
+    ///One attempt at most, a failed forced rejoin is tried again period later until max_retries
+    async fn pending_rejoins(&mut self) {
+        match self.scheduled_rejoin.take() {
+            Some(scheduled) if Instant::now() >= scheduled.at => {
+                if let Err(e) = self.forced_rejoin(scheduled.forced).await {
+                    eprintln!("Forced rejoin failed: {e:?}");
+                    if scheduled.attempts_left > 1 {
+                        self.scheduled_rejoin = Some(ScheduledRejoin { attempts_left: scheduled.attempts_left - 1, ..ScheduledRejoin::new(scheduled.forced) });
                     }
                 }
-            };
+            },
+            Some(scheduled) => self.scheduled_rejoin = Some(scheduled),
+            None if self.mac.periodic_rejoin_due() => {
+                if let Err(e) = self.rejoin(RejoinType::Type0).await {
+                    eprintln!("Periodic rejoin failed: {e:?}");
+                }
+            },
+            None => (),
         }
+    }
+
+    ///Send a RejoinRequest and wait for the JoinAccept in the join windows
+    pub async fn rejoin(&mut self, rejoin_type: RejoinType) -> Result<(), CommunicatorError> {
+        let rejoin_request = self.device.create_rejoin_request(rejoin_type)?;
+        self.select_uplink_channel();
+        self.communicator.send(&rejoin_request, Some(*self.dev_eui()), None).await?;
+        let tx_end = Instant::now();
+        self.mac.rejoin_sent();
+        let windows = self.mac.join_accept_windows();
+        let content = self.receive_windows(tx_end, windows).await?;
+        self.handle_join_accept(&content)
+    }
+
+    ///A rejoin at the requested data rate
+    async fn forced_rejoin(&mut self, forced: ForcedRejoin) -> Result<(), CommunicatorError> {
+        let data_rate = self.mac.data_rate();
+        self.mac.set_data_rate(forced.data_rate);
+        let ret = self.rejoin(forced.rejoin_type).await;
+        //an accepted type 0 rejoin already reset the MAC layer
+        if ret.is_err() || forced.rejoin_type != RejoinType::Type0 {
+            self.mac.set_data_rate(data_rate);
+        }
+        ret
+    }
+
+    ///Pick the channel of the next uplink and tune the communicator on it, returns the TxDr and TxCh of the MIC.
+    ///They are 0 when the communicator does not send on that channel, the network cannot see them either
+    fn select_uplink_channel(&mut self) -> MicContext {
+        if let Some(channel) = self.mac.select_uplink_channel() {
+            if let Some((sf, bw)) = self.mac.region().data_rate(self.mac.data_rate()) {
+                self.communicator.set_tx_parameters(channel.frequency(), sf, bw, self.mac.tx_power_dbm());
+            }
+        }
+        if !self.communicator.tx_parameters_applied() {
+            return MicContext::default();
+        }
+        MicContext { conf_fcnt: None, tx_dr: self.mac.data_rate().value(), tx_ch: self.mac.uplink_channel_index() as u8 }
+    }
+
+    ///An ack in a downlink acknowledges the last uplink, its FCnt is the ConfFCnt of the MIC
+    fn downlink_mic_context(&self) -> MicContext {
+        let conf_fcnt = self.device.session().map(|s| s.network_context().f_cnt_up() as u16);
+        MicContext { conf_fcnt, ..Default::default() }
+    }
+
+    ///Class A reception: RX1 opens rx1.delay after the end of the uplink and RX2 is opened only if nothing arrived in RX1
+    async fn receive_windows(&mut self, tx_end: Instant, windows: [RxWindow; 2]) -> Result<ReceivedTransmission, CommunicatorError> {
+        let [rx1, rx2] = windows;
+        for (window, length) in [(rx1, rx2.delay.saturating_sub(rx1.delay)), (rx2, RX2_WINDOW_LENGTH)] {
+            if let Some((sf, bw)) = self.mac.region().data_rate(window.data_rate) {
+                self.communicator.set_rx_window(window.frequency, sf, bw);
+            }
+            let opens = tx_end + window.delay;
+            tokio::time::sleep_until(opens).await;
+            //frames for other devices and replayed ones do not close the window
+            while let Some(remaining) = (opens + length).checked_duration_since(Instant::now()).filter(|r| !r.is_zero()) {
+                match self.communicator.receive(Some(remaining)).await {
+                    Ok(payloads) if payloads.is_empty() => break,
+                    Ok(payloads) => if let Some(content) = payloads.into_iter().find(|p| self.is_for_device(&p.transmission.payload)) { return Ok(content) },
+                    Err(CommunicatorError::LoRaWANError(LoRaWANError::MissingDownlink)) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(LoRaWANError::MissingDownlink.into())
+    }
+
+    ///Data downlinks carry the DevAddr in clear, a JoinAccept is encrypted and can only be checked by decrypting it.
+    ///Replayed data downlinks are not for the device anymore
+    fn is_for_device(&self, payload: &[u8]) -> bool {
+        let Some(mhdr) = payload.first().map(|b| MHDR::from_bytes(*b)) else { return false };
+        match mhdr.mtype() {
+            MType::JoinAccept => LoRaWANPacket::from_bytes(payload, Some(&self.device), false).is_ok(),
+            MType::UnconfirmedDataDown | MType::ConfirmedDataDown => payload.len() >= 5 && self.device.session().is_some_and(|s| {
+                let mut dev_addr = *s.network_context().dev_addr();
+                dev_addr.reverse();
+                payload[1..5] == dev_addr
+            }) && !matches!(self.decode_downlink(payload), Ok(None)),
+            _ => false,
+        }
+    }
+
+    ///Decode a data downlink and its full FCnt, None if the FCnt is not newer than the last one received: the frame is replayed or stale
+    fn decode_downlink(&self, payload: &[u8]) -> Result<Option<(LoRaWANPacket, u32)>, CommunicatorError> {
+        //the FCnt carries only the lower half of the counter, the MIC tells which upper half it has
+        let (packet, fcnt) = LoRaWANPacket::from_bytes_with_fcnt_search(payload, &self.device, false, &self.downlink_mic_context())?;
+        if let Payload::MACPayload(p) = packet.payload() {
+            let session = self.device.session().ok_or(LoRaWANError::ContextNeeded)?;
+            let current_fcnt = if p.is_application() { session.application_context().af_cnt_dwn() } else { session.network_context().nf_cnt_dwn() };
+            if fcnt <= current_fcnt {
+                return Ok(None);
+            }
+        }
+        Ok(Some((packet, fcnt)))
+    }
+
+    fn handle_downlink(&mut self, content: &ReceivedTransmission) -> Result<(), CommunicatorError> {
+        //a replayed or stale downlink is dropped before its MAC commands are applied again
+        let Some((packet, fcnt)) = self.decode_downlink(&content.transmission.payload)? else { return Ok(()) };
+        if let Payload::MACPayload(p) = packet.payload() {
+            let session = self.device.session_mut().ok_or(LoRaWANError::ContextNeeded)?;
+            if p.is_application() {
+                session.application_context_mut().update_af_cnt_dwn(fcnt);
+            } else {
+                session.network_context_mut().update_nf_cnt_dwn(fcnt);
+            }
+            if packet.mhdr().mtype() == MType::ConfirmedDataDown {
+                self.pending_ack = Some(fcnt as u16);
+            }
+        };
+
+        //println!("{packet:?}");
+        if let Payload::MACPayload(p) = packet.payload() {
+            self.mac.downlink_received();
+            self.ignored_commands.clear();
+            let fopts_len = p.fhdr().fctrl().f_opts_len() as usize;
+            if fopts_len > 0 {
+                let commands = NCMacCommands::from_bytes(&p.fhdr().fopts()[..fopts_len])?;
+                let ignored = self.mac.handle_commands(&commands, content.arrival_stats.snr);
+                self.ignored_commands.extend(ignored);
+            }
+            if let Some(frmp) = p.frm_payload() {
+                match p.fport() {
+                    Some(0) | None => {
+                        let commands = NCMacCommands::from_bytes(frmp)?;
+                        let ignored = self.mac.handle_commands(&commands, content.arrival_stats.snr);
+                        self.ignored_commands.extend(ignored);
+                    },
+                    Some(_port) => {
+                        //println!("Port: {port}, message: {}", String::from_utf8_lossy(frmp));
+                    },
+                }
+            }
+        };
         Ok(())
     }
 
@@ -128,12 +321,15 @@ impl<T> LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
         //println!("{}", PrettyHexSlice(&join_request));
         
         
+        self.select_uplink_channel();
         self.communicator.send(&join_request, Some(*self.dev_eui()), None).await?;
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        let payloads = self.communicator.receive(Some(Duration::from_secs(2))).await?;
-        
-        //TODO ESTRARRE MEGLIO I PAYLOAD
-        let content = payloads.first().ok_or(LoRaWANError::MissingDownlink)?;
+        let tx_end = Instant::now();
+        let windows = self.mac.join_accept_windows();
+        let content = self.receive_windows(tx_end, windows).await?;
+        self.handle_join_accept(&content)
+    }
+
+    fn handle_join_accept(&mut self, content: &ReceivedTransmission) -> Result<(), CommunicatorError> {
         let packet = LoRaWANPacket::from_bytes(&content.transmission.payload, Some(&self.device), false)?;
         if let Payload::JoinAccept(ja) = packet.payload() {
             let join_nonce = *ja.join_nonce();
@@ -147,29 +343,31 @@ impl<T> LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
                 return Err(CommunicatorError::LoRaWANError(LoRaWANError::InvalidNonce)) 
             }
             self.device.join_context_mut().update_join_nonce(jn_u32);
+
             self.device.generate_session_context(ja)?;
+            //a type 2 rejoin only rekeys, the MAC layer and the channels are kept
+            if *ja.join_req_type() != JoinRequestType::RejoinRequest2 {
+                self.mac.reset();
+                self.mac.set_join_accept_parameters(ja.dl_settings(), ja.rx_delay());
+                if let Some(cf_list) = ja.cf_list() {
+                    if let Err(e) = self.mac.channels_mut().apply_cf_list(cf_list) {
+                        eprintln!("Ignoring CFList {cf_list:?}: {e}");
+                    }
+                }
+            }
         }
         Ok(())
     }
     
     pub async fn send_maccommands(&mut self, mac_commands: &[EDMacCommands], confirmed: bool) -> Result<(), CommunicatorError> {        
-        let content = Device::create_maccommands(mac_commands)?;
-        let uplink = self.device.create_uplink(Some(&content), confirmed, Some(0), None)?;
+        let mut commands = self.mac.take_answers();
+        commands.extend_from_slice(mac_commands);
+        let content = Device::create_maccommands(&commands)?;
+        let mic_context = MicContext { conf_fcnt: self.pending_ack.take(), ..self.select_uplink_channel() };
+        let uplink = self.device.create_uplink(Some(&content), confirmed, Some(0), None, &mic_context)?;
+        self.mac.uplink_sent();
         self.communicator.send(&uplink, Some(*self.dev_eui()), None).await
     }
-
-    fn nonce_valid(received_nonce: u16, current_nonce: u16) -> (bool, bool) {
-        match received_nonce.cmp(&current_nonce) {
-            Ordering::Greater => (true, false),
-            Ordering::Equal => (false, false),
-            Ordering::Less => ((0xffff - current_nonce < 5) && received_nonce < 5, true),
-        }
-    }
-    
-    fn increment_nonce(received_nonce: u16, current_nonce: u32, nonce_looped: bool) -> u32 {
-        let increment_higher_half_dev_nonce = if nonce_looped { 0x00010000 } else { 0 };
-        received_nonce as u32 | ((current_nonce & 0xffff0000) + increment_higher_half_dev_nonce)
-    }
 }
 
 impl <T> Deref for LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
@@ -191,3 +389,190 @@ impl <T> Debug for LoRaWANDevice<T> where T: LoRaWANCommunicator + Send + Sync {
         f.debug_struct("LoRaWANDevice").field("device", &self.device).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::{
+        lorawan_packet::{fctrl::{DownlinkFCtrl, FCtrl}, fhdr::FHDR, fields::{ChMaskCntl, DLSettings, DataRateTxPower, Redundancy, RxDelay}, join::{CFList, JoinAcceptPayload}, mac_payload::MACPayload, mhdr::Major},
+        physical_parameters::DataRate,
+        utils::{eui::EUI64, traits::ToBytesWithContext},
+    };
+
+    use super::*;
+    use crate::communicator::Transmission;
+
+    ///Answers the RX windows with the frames queued by the test, the other windows stay empty
+    #[derive(Default)]
+    struct ScriptedCommunicator {
+        sent: Mutex<Vec<Vec<u8>>>,
+        downlinks: Mutex<VecDeque<Vec<u8>>>,
+    }
+
+    impl LoRaWANCommunicator for ScriptedCommunicator {
+        type Config = ();
+
+        async fn from_config(_config: &Self::Config) -> Result<Self, CommunicatorError> {
+            Ok(Self::default())
+        }
+
+        async fn send(&self, bytes: &[u8], _src: Option<EUI64>, _dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+            self.sent.lock().unwrap().push(bytes.to_vec());
+            Ok(())
+        }
+
+        async fn receive(&self, _timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+            let payload = self.downlinks.lock().unwrap().pop_front().ok_or(LoRaWANError::MissingDownlink)?;
+            Ok(vec![ReceivedTransmission { transmission: Transmission { payload, uplink: false, ..Default::default() }, ..Default::default() }])
+        }
+    }
+
+    ///Unconfirmed downlink with MAC commands in FOpts, as the network controller of the device builds it
+    fn downlink(network: &mut Device, fcnt: u32, commands: &[NCMacCommands]) -> Vec<u8> {
+        let session = network.session_mut().unwrap();
+        session.network_context_mut().update_nf_cnt_dwn(fcnt);
+        let mut fhdr = FHDR::new(*session.network_context().dev_addr(), FCtrl::Downlink(DownlinkFCtrl::new(false, false, false, false, 0)));
+        fhdr.set_fopts(&commands.iter().flat_map(|c| c.to_bytes()).collect::<Vec<u8>>());
+        fhdr.set_fcnt(fcnt as u16);
+        let packet = LoRaWANPacket::new(MHDR::new(MType::UnconfirmedDataDown, Major::R1), Payload::MACPayload(MACPayload::new(fhdr, None, None)));
+        packet.to_bytes_with_mic_context(network, &MicContext::default()).unwrap()
+    }
+
+    ///JoinAccept answering the first join request of the device
+    fn join_accept(network: &mut Device, dev_addr: [u8; 4]) -> Vec<u8> {
+        network.set_dev_nonce(1);
+        let regional_params = network.regional_parameters().unwrap_or_default();
+        let dl_settings = regional_params.default_dl_settings().with_opt_neg(network.version().is_1_1_or_greater());
+        let join_accept = JoinAcceptPayload::new(JoinRequestType::JoinRequest, [1, 0, 0], [1, 2, 3], dev_addr, dl_settings, regional_params.default_rx_delay(), None);
+        LoRaWANPacket::new(MHDR::new(MType::JoinAccept, Major::R1), Payload::JoinAccept(join_accept)).to_bytes_with_context(network).unwrap()
+    }
+
+    fn link_adr_req(data_rate: u8) -> NCMacCommands {
+        NCMacCommands::LinkADRReq {
+            data_rate_tx_power: DataRateTxPower::new(data_rate, DataRateTxPower::UNCHANGED).unwrap(),
+            ch_mask: 0b111,
+            redundancy: Redundancy::new(ChMaskCntl::new(0).unwrap(), 0).unwrap(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unconfirmed_uplink_receives_mac_commands() {
+        let device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        let mut network = device;
+        let communicator = ScriptedCommunicator::default();
+        communicator.downlinks.lock().unwrap().push_back(downlink(&mut network, 1, &[link_adr_req(5)]));
+        let mut device = LoRaWANDevice::new(device, communicator);
+
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert_eq!(device.mac_layer().data_rate(), DataRate::DR5);
+        assert!(device.mac_layer().has_answers());
+
+        //the LinkADRAns goes with the next uplink, that one gets no downlink
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert!(!device.mac_layer().has_answers());
+        assert_eq!(device.communicator().sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downlink_for_other_device_skipped() {
+        let device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        let mut network = device;
+        let mut other = BlockchainMockClient::create_initialized_device(&[5, 6, 7, 8]);
+        let communicator = ScriptedCommunicator::default();
+        communicator.downlinks.lock().unwrap().extend([downlink(&mut other, 1, &[link_adr_req(2)]), downlink(&mut network, 1, &[link_adr_req(5)])]);
+        let mut device = LoRaWANDevice::new(device, communicator);
+
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert_eq!(device.mac_layer().data_rate(), DataRate::DR5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_accept_for_other_device_skipped() {
+        let device = BlockchainMockClient::create_uninitialized_device(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut network = device;
+        let mut other = BlockchainMockClient::create_uninitialized_device(&[8, 7, 6, 5, 4, 3, 2, 1]);
+        let communicator = ScriptedCommunicator::default();
+        communicator.downlinks.lock().unwrap().extend([join_accept(&mut other, [5, 6, 7, 8]), join_accept(&mut network, [1, 2, 3, 4])]);
+        let mut device = LoRaWANDevice::new(device, communicator);
+
+        device.send_join_request().await.unwrap();
+        assert_eq!(device.session().unwrap().network_context().dev_addr(), &[1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forced_rejoin_before_next_uplink() {
+        let device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        let mut network = device;
+        let communicator = ScriptedCommunicator::default();
+        let force_rejoin = NCMacCommands::ForceRejoinReq { period: 0, max_retries: 1, rejoin_type: 0, dr: 0 };
+        communicator.downlinks.lock().unwrap().push_back(downlink(&mut network, 1, &[force_rejoin]));
+        let mut device = LoRaWANDevice::new(device, communicator);
+
+        //the uplink that received the command does not wait for the rejoin
+        let start = Instant::now();
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(32));
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+
+        //32 s later, the rejoin goes before the uplink. Without JoinAccept it is tried once more
+        tokio::time::sleep(Duration::from_secs(32)).await;
+        for _ in 0..3 {
+            device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(32)).await;
+        }
+        let mtypes: Vec<MType> = device.communicator().sent.lock().unwrap().iter().map(|f| MHDR::from_bytes(f[0]).mtype()).collect();
+        assert_eq!(mtypes, vec![
+            MType::UnconfirmedDataUp, MType::UnconfirmedDataUp,
+            MType::RejoinRequest, MType::UnconfirmedDataUp,
+            MType::RejoinRequest, MType::UnconfirmedDataUp,
+            MType::UnconfirmedDataUp,
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejoin_type_2_keeps_radio_parameters() {
+        let device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        let mut network = device;
+        network.create_rejoin_request(RejoinType::Type2).unwrap();
+        let regional_params = network.regional_parameters().unwrap_or_default();
+        let join_accept = JoinAcceptPayload::new(JoinRequestType::RejoinRequest2, [1, 0, 0], [1, 2, 3], [1, 2, 3, 4],
+            DLSettings::new(true, 2, 0).unwrap(), RxDelay::new(5).unwrap(), Some(CFList::Frequencies([867_100_000, 0, 0, 0, 0])));
+        let bytes = LoRaWANPacket::new(MHDR::new(MType::JoinAccept, Major::R1), Payload::JoinAccept(join_accept.clone())).to_bytes_with_context(&network).unwrap();
+        network.generate_session_context(&join_accept).unwrap();
+        let communicator = ScriptedCommunicator::default();
+        communicator.downlinks.lock().unwrap().push_back(bytes);
+        let mut device = LoRaWANDevice::new(device, communicator);
+        device.mac_layer_mut().set_data_rate(DataRate::DR3);
+
+        device.rejoin(RejoinType::Type2).await.unwrap();
+        assert_eq!(device.session(), network.session());
+        assert_eq!(device.mac_layer().data_rate(), DataRate::DR3);
+        assert_eq!(device.mac_layer().rx1_delay(), regional_params.default_rx_delay().delay());
+        assert_eq!(device.mac_layer().rx1_dr_offset(), 0);
+        assert_eq!(device.mac_layer().channels(), &ChannelList::new(*regional_params.region()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replayed_downlink_ignored() {
+        let device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        let mut network = device;
+        let communicator = ScriptedCommunicator::default();
+        let first = downlink(&mut network, 1, &[link_adr_req(5)]);
+        let second = downlink(&mut network, 2, &[]);
+        communicator.downlinks.lock().unwrap().extend([first.clone(), first, second]);
+        let mut device = LoRaWANDevice::new(device, communicator);
+
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert_eq!(device.mac_layer().data_rate(), DataRate::DR5);
+        device.mac_layer_mut().set_data_rate(DataRate::DR3);
+
+        //the copy of the first downlink is dropped without applying it again nor bringing back the LinkADRAns,
+        //the window stays open and receives the next downlink
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        assert_eq!(device.mac_layer().data_rate(), DataRate::DR3);
+        assert!(!device.mac_layer().has_answers());
+        assert_eq!(device.session().unwrap().network_context().nf_cnt_dwn(), 2);
+    }
+}
@@ -1,7 +1,13 @@
 pub mod tcp_device;
 pub mod udp_device;
+pub mod gwmp_device;
+pub mod gwmp_gateway;
+pub mod virtual_medium;
+pub mod adversary;
+pub mod replay_device;
 //pub mod colosseum_device;
 pub mod radio_device;
 pub mod lorawan_device;
 pub mod mock_device;
-pub mod debug_device;
\ No newline at end of file
+pub mod debug_device;
+pub mod pcap_device;
\ No newline at end of file
@@ -0,0 +1,208 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
+
+use lorawan::{physical_parameters::{LoRaBandwidth, SpreadingFactor}, utils::eui::EUI64};
+
+use crate::{
+    communicator::{CommunicatorError, LoRaWANCommunicator, ReceivedTransmission, Transmission},
+    configs::PcapConfig,
+    pcap::PcapWriter,
+    split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
+};
+
+///Gateways and network controllers send a JSON Transmission, devices the PHYPayload alone with the parameters of the last set_tx_parameters
+fn sent_transmission(bytes: &[u8], tx: Option<(u32, SpreadingFactor, LoRaBandwidth)>) -> Transmission {
+    serde_json::from_slice(bytes).unwrap_or_else(|_| {
+        let mut transmission = Transmission { payload: bytes.to_vec(), ..Default::default() };
+        if let Some((frequency, spreading_factor, bandwidth)) = tx {
+            (transmission.frequency, transmission.spreading_factor, transmission.bandwidth) = (frequency as f64, spreading_factor, bandwidth);
+        }
+        transmission
+    })
+}
+
+fn capture_sent(pcap: Option<&PcapWriter>, bytes: &[u8], tx: Option<(u32, SpreadingFactor, LoRaBandwidth)>) {
+    if let Some(pcap) = pcap {
+        if let Err(e) = pcap.write(&sent_transmission(bytes, tx), None) {
+            eprintln!("Cannot capture a sent frame: {e:?}");
+        }
+    }
+}
+
+fn capture_received(pcap: Option<&PcapWriter>, received: &Result<Vec<ReceivedTransmission>, CommunicatorError>) {
+    if let (Some(pcap), Ok(received)) = (pcap, received) {
+        for r in received {
+            if let Err(e) = pcap.write(&r.transmission, Some(&r.arrival_stats)) {
+                eprintln!("Cannot capture a received frame: {e:?}");
+            }
+        }
+    }
+}
+
+///Writes every frame sent and received by the inner communicator to a pcap file with LoRaTap headers, to be opened with Wireshark
+pub struct PcapCommunicator<T: LoRaWANCommunicator> {
+    inner: T,
+    pcap: Arc<PcapWriter>,
+    tx: Option<(u32, SpreadingFactor, LoRaBandwidth)>,
+}
+
+impl<T: LoRaWANCommunicator> PcapCommunicator<T> {
+    ///Several communicators can share the same capture
+    pub fn new(inner: T, pcap: Arc<PcapWriter>) -> Self {
+        Self { inner, pcap, tx: None }
+    }
+
+    pub fn pcap(&self) -> &Arc<PcapWriter> {
+        &self.pcap
+    }
+}
+
+impl<T: LoRaWANCommunicator> Deref for PcapCommunicator<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: LoRaWANCommunicator> DerefMut for PcapCommunicator<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T: LoRaWANCommunicator> LoRaWANCommunicator for PcapCommunicator<T> {
+    type Config = PcapConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        let pcap = PcapWriter::create(&config.path)?;
+        Ok(Self::new(T::from_config(&config.communicator).await?, Arc::new(pcap)))
+    }
+
+    async fn send(&self, bytes: &[u8], src: Option<EUI64>, dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        capture_sent(Some(&self.pcap), bytes, self.tx);
+        self.inner.send(bytes, src, dest).await
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let received = self.inner.receive(timeout).await;
+        capture_received(Some(&self.pcap), &received);
+        received
+    }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        self.inner.set_rx_window(frequency, spreading_factor, bandwidth)
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        self.tx = Some((frequency, spreading_factor, bandwidth));
+        self.inner.set_tx_parameters(frequency, spreading_factor, bandwidth, power)
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        self.inner.tx_parameters_applied()
+    }
+}
+
+///Sending half of a captured communicator, without capture the frames just go through
+pub struct PcapSender<T: LoRaSender> {
+    inner: T,
+    pcap: Option<Arc<PcapWriter>>,
+}
+
+impl<T: LoRaSender> PcapSender<T> {
+    pub fn new(inner: T, pcap: Option<Arc<PcapWriter>>) -> Self {
+        Self { inner, pcap }
+    }
+}
+
+impl<T: LoRaSender> Deref for PcapSender<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: LoRaSender + Send + Sync> LoRaSender for PcapSender<T> {
+    type OptionalInfo = T::OptionalInfo;
+
+    async fn send(&self, bytes: &[u8], optional_info: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
+        capture_sent(self.pcap.as_deref(), bytes, None);
+        self.inner.send(bytes, optional_info).await
+    }
+}
+
+///Receiving half of a captured communicator, without capture the frames just go through
+pub struct PcapReceiver<T: LoRaReceiver> {
+    inner: T,
+    pcap: Option<Arc<PcapWriter>>,
+}
+
+impl<T: LoRaReceiver> PcapReceiver<T> {
+    pub fn new(inner: T, pcap: Option<Arc<PcapWriter>>) -> Self {
+        Self { inner, pcap }
+    }
+}
+
+impl<T: LoRaReceiver> Deref for PcapReceiver<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: LoRaReceiver + Send + Sync> LoRaReceiver for PcapReceiver<T> {
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let received = self.inner.receive(timeout).await;
+        capture_received(self.pcap.as_deref(), &received);
+        received
+    }
+}
+
+impl<T: SplitCommunicator> SplitCommunicator for PcapCommunicator<T> {
+    type Sender = PcapSender<T::Sender>;
+    type Receiver = PcapReceiver<T::Receiver>;
+
+    async fn split_communicator(self) -> Result<(Self::Sender, Self::Receiver), CommunicatorError> {
+        let (sender, receiver) = self.inner.split_communicator().await?;
+        Ok((PcapSender::new(sender, Some(self.pcap.clone())), PcapReceiver::new(receiver, Some(self.pcap))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::mock_device::MockCommunicator;
+
+    #[tokio::test]
+    async fn captures_both_directions() {
+        let path = std::env::temp_dir().join(format!("pcap_device_{}.pcap", std::process::id()));
+        let mut communicator = PcapCommunicator::new(MockCommunicator, Arc::new(PcapWriter::create(&path).unwrap()));
+        communicator.set_tx_parameters(868_500_000, SpreadingFactor::SF10, LoRaBandwidth::BW125, 14);
+        communicator.send(&[0x40; 12], None, None).await.unwrap();
+        let received = communicator.receive(None).await.unwrap();
+
+        let (sender, _) = communicator.split_communicator().await.unwrap();
+        let downlink = Transmission { frequency: 869_525_000.0, spreading_factor: SpreadingFactor::SF9, payload: vec![0x60; 14], ..Default::default() };
+        sender.send(&serde_json::to_vec(&downlink).unwrap(), None).await.unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        //global header, then a record header and a LoRaTap header before every frame
+        let uplink = &bytes[24..];
+        assert_eq!(uplink[20..24], 868_500_000u32.to_be_bytes());
+        assert_eq!(uplink[25], 10);
+        assert_eq!(uplink[31..43], [0x40; 12]);
+        let mock = &uplink[43..];
+        assert_eq!(mock[31..41], received[0].transmission.payload[..]);
+        let sent = &mock[41..];
+        assert_eq!(sent[20..24], 869_525_000u32.to_be_bytes());
+        assert_eq!(sent[25], 9);
+        assert_eq!(sent[31..], [0x60; 14]);
+    }
+}
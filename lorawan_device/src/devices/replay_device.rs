@@ -0,0 +1,233 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use lorawan::{device::Device, lorawan_packet::MicContext, regional_parameters::channel_list::ChannelList, utils::{errors::LoRaWANError, eui::EUI64}};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    clock::now_ms,
+    communicator::{ArrivalStats, CommunicatorError, LoRaWANCommunicator, ReceivedTransmission, Transmission},
+    configs::ReplayConfig,
+    split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
+    trace::{read_trace, TraceRecord},
+};
+
+///Records of the same DevAddr and channel less than this apart, in seconds, are copies of a frame received by several gateways
+const COPY_TOLERANCE: f64 = 0.01;
+
+///A frame of the trace with a reception for every gateway that recorded it
+struct TraceFrame {
+    time: f64,
+    dev_addr: [u8; 4],
+    receptions: Vec<ReceivedTransmission>,
+}
+
+impl TraceFrame {
+    fn copy_of(&self, record: &TraceRecord) -> bool {
+        let t = &self.receptions[0].transmission;
+        self.dev_addr == record.dev_addr && t.frequency == record.frequency && t.spreading_factor == record.spreading_factor
+    }
+}
+
+///Data uplink of a DevAddr without session: plain FRMPayload and a zero MIC
+fn unprovisioned_uplink(dev_addr: &[u8; 4], fcnt: u16, payload_size: usize) -> Vec<u8> {
+    let mut frame = vec![0x40];
+    frame.extend(dev_addr.iter().rev());
+    frame.push(0x00);
+    frame.extend(fcnt.to_le_bytes());
+    frame.push(1);
+    frame.resize(frame.len() + payload_size + 4, 0);
+    frame
+}
+
+///TxDr and TxCh of the recorded radio parameters, over the default channels of the device region
+fn mic_context(device: &Device, record: &TraceRecord) -> MicContext {
+    let region = *device.regional_parameters().unwrap_or_default().region();
+    MicContext {
+        conf_fcnt: None,
+        tx_dr: region.data_rate_from(record.spreading_factor, record.bandwidth).map_or(0, |dr| dr.value()),
+        tx_ch: ChannelList::new(region).index_of(record.frequency as u32).unwrap_or(0) as u8,
+    }
+}
+
+///Network side communicator playing a reception trace back: every frame is received at its recorded time divided by the speedup,
+///by as many gateways as recorded it. Provisioned devices send valid data uplinks with increasing FCnts, downlinks go nowhere.
+///Receiving fails once the trace is over
+pub struct ReplayCommunicator {
+    frames: Vec<TraceFrame>,
+    speedup: f64,
+    started: Instant,
+    next: Mutex<usize>,
+}
+
+impl ReplayCommunicator {
+    pub fn new(mut records: Vec<TraceRecord>, config: &ReplayConfig) -> Result<Self, CommunicatorError> {
+        if config.speedup.is_nan() || config.speedup <= 0.0 {
+            return Err(CommunicatorError::Radio(format!("invalid speedup {}", config.speedup)));
+        }
+        records.retain(|r| config.duration.is_none_or(|d| r.time <= d));
+        records.sort_by(|a, b| a.time.total_cmp(&b.time));
+        let mut devices: HashMap<[u8; 4], Device> = config.devices.iter()
+            .filter_map(|d| d.session().map(|s| (*s.network_context().dev_addr(), *d)))
+            .collect();
+        let mut fcnts: HashMap<[u8; 4], u16> = HashMap::new();
+        let frm_payload = vec![0; config.payload_size];
+
+        let mut frames: Vec<TraceFrame> = Vec::new();
+        for record in records {
+            let copy = frames.iter().enumerate().rev()
+                .take_while(|(_, f)| record.time - f.time <= COPY_TOLERANCE)
+                .find(|(_, f)| f.copy_of(&record))
+                .map(|(i, _)| i);
+            let transmission = match copy {
+                Some(i) => frames[i].receptions[0].transmission.clone(),
+                None => {
+                    let payload = match devices.get_mut(&record.dev_addr) {
+                        Some(device) => device.create_uplink(Some(&frm_payload), false, Some(1), None, &mic_context(device, &record))?,
+                        None => {
+                            let fcnt = fcnts.entry(record.dev_addr).or_default();
+                            *fcnt = fcnt.wrapping_add(1);
+                            unprovisioned_uplink(&record.dev_addr, *fcnt, frm_payload.len())
+                        },
+                    };
+                    Transmission {
+                        frequency: record.frequency,
+                        bandwidth: record.bandwidth,
+                        spreading_factor: record.spreading_factor,
+                        uplink: true,
+                        payload,
+                        ..Default::default()
+                    }
+                },
+            };
+            let reception = ReceivedTransmission { transmission, arrival_stats: ArrivalStats { time: 0, rssi: record.rssi, snr: record.snr, ..Default::default() } };
+            match copy {
+                Some(i) => frames[i].receptions.push(reception),
+                None => frames.push(TraceFrame { time: record.time, dev_addr: record.dev_addr, receptions: vec![reception] }),
+            }
+        }
+
+        Ok(Self { frames, speedup: config.speedup, started: Instant::now(), next: Mutex::new(0) })
+    }
+
+    ///Frames still to be received
+    pub async fn remaining(&self) -> usize {
+        self.frames.len() - *self.next.lock().await
+    }
+}
+
+impl LoRaWANCommunicator for ReplayCommunicator {
+    type Config = ReplayConfig;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        Self::new(read_trace(&config.trace)?, config)
+    }
+
+    async fn send(&self, _bytes: &[u8], _src: Option<EUI64>, _dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        Ok(())
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let mut next = self.next.lock().await;
+        let frame = self.frames.get(*next).ok_or_else(|| CommunicatorError::Radio("end of the trace".to_string()))?;
+        let due = self.started + Duration::from_secs_f64(frame.time.max(0.0) / self.speedup);
+        if let Some(timeout) = timeout {
+            if Instant::now() + timeout < due {
+                tokio::time::sleep(timeout).await;
+                return Err(LoRaWANError::MissingDownlink.into());
+            }
+        }
+        tokio::time::sleep_until(due).await;
+        *next += 1;
+
+        let now = now_ms();
+        Ok(frame.receptions.iter().cloned().map(|mut r| {
+            r.arrival_stats.time = now;
+            r.transmission.start_time = now.saturating_sub(r.transmission.time_on_air());
+            r
+        }).collect())
+    }
+}
+
+pub struct ReplaySender;
+
+pub struct ReplayReceiver {
+    communicator: Arc<ReplayCommunicator>,
+}
+
+impl LoRaSender for ReplaySender {
+    type OptionalInfo = ();
+
+    async fn send(&self, _bytes: &[u8], _optional_info: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
+        Ok(())
+    }
+}
+
+impl LoRaReceiver for ReplayReceiver {
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        LoRaWANCommunicator::receive(self.communicator.as_ref(), timeout).await
+    }
+}
+
+impl SplitCommunicator for ReplayCommunicator {
+    type Sender = ReplaySender;
+    type Receiver = ReplayReceiver;
+
+    async fn split_communicator(self) -> Result<(Self::Sender, Self::Receiver), CommunicatorError> {
+        Ok((ReplaySender, ReplayReceiver { communicator: Arc::new(self) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::lorawan_packet::{payload::Payload, LoRaWANPacket};
+
+    use super::*;
+    use crate::{gwmp::dev_addr, trace::parse_trace};
+
+    const TRACE: &str = "Time,DeviceAddress,GatewayNode,FrequencyHz,SF,BW,RSSI,SNR
+1.0,01020304,200,868100000,7,125000,-100.5,5.5
+1.0,01020304,201,868100000,7,125000,-110.0,-2.0
+2.0,0a0b0c0d,200,868300000,9,125000,-120.0,-8.0
+2.5,01020304,201,868500000,7,125000,-111.0,-3.0
+";
+
+    ///The MIC of LoRaWAN 1.1 devices covers the radio parameters of the record
+    fn fcnt(payload: &[u8], device: &Device, record: &TraceRecord) -> u16 {
+        match LoRaWANPacket::from_bytes_with_mic_context(payload, Some(device), true, &mic_context(device, record)).unwrap().payload() {
+            Payload::MACPayload(p) => p.fhdr().fcnt(),
+            _ => panic!("not a data uplink"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copies_and_payloads() {
+        let provisioned = [0x01, 0x02, 0x03, 0x04];
+        let device = BlockchainMockClient::create_initialized_device(&provisioned);
+        let config = ReplayConfig { trace: String::new(), speedup: 10.0, duration: None, devices: vec![device], payload_size: 5 };
+        let records = parse_trace(TRACE.as_bytes()).unwrap();
+        let communicator = ReplayCommunicator::new(records.clone(), &config).unwrap();
+        assert_eq!(communicator.remaining().await, 3);
+        let start = Instant::now();
+
+        //both gateways get the same valid frame a tenth of a second in
+        let copies = communicator.receive(None).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(copies.len(), 2);
+        assert_eq!(copies[0].transmission.payload, copies[1].transmission.payload);
+        assert_eq!((copies[0].arrival_stats.rssi, copies[1].arrival_stats.snr), (-100.5, -2.0));
+        let first = fcnt(&copies[0].transmission.payload, &device, &records[0]);
+
+        //the unknown DevAddr is on the air but its frame cannot be authenticated
+        let unknown = communicator.receive(None).await.unwrap().remove(0);
+        assert_eq!(unknown.transmission.spreading_factor.value(), 9);
+        assert_eq!(dev_addr(&unknown.transmission.payload), Some([0x0d, 0x0c, 0x0b, 0x0a]));
+        assert!(unknown.transmission.payload.ends_with(&[0; 4]));
+
+        assert!(communicator.receive(Some(Duration::from_millis(10))).await.is_err());
+        let last = communicator.receive(None).await.unwrap().remove(0);
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+        assert_eq!(fcnt(&last.transmission.payload, &device, &records[3]), first + 1);
+        assert!(communicator.receive(None).await.is_err());
+    }
+}
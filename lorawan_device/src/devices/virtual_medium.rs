@@ -0,0 +1,504 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, OnceLock}, time::Duration};
+
+use lorawan::{
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
+    utils::{errors::LoRaWANError, eui::EUI64},
+};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    clock::now_ms,
+    communicator::{ArrivalStats, CommunicatorError, LoRaWANCommunicator, Position, ReceivedTransmission, Transmission},
+    configs::{MediumRole, VirtualMediumConfig},
+    devices::{adversary::FrameOrigin, gwmp_gateway::{DEFAULT_FREQUENCY, DEFAULT_POWER, RX_WINDOW_TOLERANCE}},
+    gwmp::dev_addr,
+    propagation::{link_budget, noise_floor, LogDistance, PropagationModel},
+    split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
+};
+
+///Minimum SIR in dB to demodulate a frame (rows) interfered by another one (columns), SF7 to SF12.
+///Same SF on the diagonal (capture effect), imperfect orthogonality elsewhere: co-channel rejection matrix of Goursaud and Gorce,
+///"Dedicated networks for IoT: PHY / MAC state of the art and challenges", EAI Endorsed Transactions on IoT, 2015
+const SIR_THRESHOLDS: [[f32; 6]; 6] = [
+    [6.0, -16.0, -18.0, -19.0, -19.0, -20.0],
+    [-24.0, 6.0, -20.0, -22.0, -22.0, -22.0],
+    [-27.0, -27.0, 6.0, -23.0, -25.0, -25.0],
+    [-30.0, -30.0, -30.0, 6.0, -26.0, -28.0],
+    [-33.0, -33.0, -33.0, -33.0, 6.0, -29.0],
+    [-36.0, -36.0, -36.0, -36.0, -36.0, 6.0],
+];
+///Transmissions are kept this long after their end to evaluate the collisions of the ones overlapping them
+const ON_AIR_RETENTION: Duration = Duration::from_secs(10);
+
+///Frames whose channels overlap interfere with each other
+fn same_channel(t1: &Transmission, t2: &Transmission) -> bool {
+    (t1.frequency - t2.frequency).abs() < (t1.bandwidth.hz().max(t2.bandwidth.hz()) / 2.0) as f64
+}
+
+///Frames received by a node with the start of their preamble and who sent them
+pub(super) type InboxReceiver = mpsc::UnboundedReceiver<(ReceivedTransmission, Instant, FrameOrigin)>;
+
+struct Node {
+    position: Position,
+    role: MediumRole,
+    inbox: mpsc::UnboundedSender<(ReceivedTransmission, Instant, FrameOrigin)>,
+    ///attackers and jammers, their receptions are not recorded
+    adversary: bool,
+}
+
+pub(super) struct OnAir {
+    pub(super) node: usize,
+    pub(super) transmission: Transmission,
+    pub(super) start: Instant,
+    pub(super) end: Instant,
+    pub(super) origin: FrameOrigin,
+    ///wideband noise instead of LoRa chirps, it raises the noise floor of every spreading factor
+    pub(super) noise: bool,
+}
+
+impl OnAir {
+    fn overlaps(&self, other: &OnAir) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+///Frame received by a gateway, labelled with what the adversaries did to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelledReception {
+    ///ms of the medium clock
+    pub time: u128,
+    pub gateway: usize,
+    pub transmission: Transmission,
+    pub rssi: f32,
+    pub snr: f32,
+    ///meters between the transmitter and the gateway
+    pub distance: f32,
+    pub origin: FrameOrigin,
+    ///a jammer was on air on the channel of the frame while it was received
+    pub jammed: bool,
+}
+
+impl LabelledReception {
+    ///Columns of gateway.csv and gatewayJammed.csv, Jammer is 1 for every anomalous frame and Label tells why
+    pub const CSV_HEADER: &'static str = "Time,DeviceAddress,GatewayNode,FrequencyHz,SF,BW,RSSI,SNR,Distance,Jammer,Label";
+
+    pub fn anomalous(&self) -> bool {
+        self.jammed || self.origin != FrameOrigin::Legitimate
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self.origin {
+            FrameOrigin::Legitimate if self.jammed => "jammed",
+            origin => origin.name(),
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let t = &self.transmission;
+        let dev_addr = dev_addr(&t.payload).map_or(0, |mut a| { a.reverse(); u32::from_be_bytes(a) });
+        format!("{:.3},{dev_addr:08x},{},{},{},{},{:.3},{:.3},{:.2},{},{}", self.time as f64 / 1000.0, self.gateway, t.frequency,
+            t.spreading_factor.value(), t.bandwidth.hz(), self.rssi, self.snr, self.distance, self.anomalous() as u8, self.label())
+    }
+}
+
+struct MediumState {
+    nodes: Vec<Node>,
+    on_air: Vec<Arc<OnAir>>,
+    propagation: Arc<dyn PropagationModel>,
+    ///reactive jammers, told about every frame when it starts
+    watchers: Vec<mpsc::UnboundedSender<Arc<OnAir>>>,
+    ///receptions of the gateways, recorded only if asked
+    receptions: Option<Vec<LabelledReception>>,
+}
+
+impl Default for MediumState {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            on_air: Vec::new(),
+            propagation: Arc::new(LogDistance::default()),
+            watchers: Vec::new(),
+            receptions: None,
+        }
+    }
+}
+
+impl MediumState {
+    ///RSSI, SINR and whether a jammer overlapped the frame, if the frame can be demodulated: the receiver is not transmitting,
+    ///the frame survives every overlapping LoRa frame on the same channel and it is above the noise floor raised by the noise jammers
+    fn reception(&self, frame: &OnAir, receiver: usize) -> Option<(f32, f32, bool)> {
+        let position = &self.nodes[receiver].position;
+        let power_at = |t: &Transmission| link_budget(self.propagation.as_ref(), t, position);
+        let (rssi, snr) = power_at(&frame.transmission);
+        let sf = frame.transmission.spreading_factor.value() as usize - 7;
+        //mW of noise, thermal plus the noise jammers. Not rssi - snr, the SNR is capped for strong frames
+        let mut noise = 10f32.powf(noise_floor(frame.transmission.bandwidth) / 10.0);
+        let mut jammed = false;
+        for other in self.on_air.iter().filter(|o| !std::ptr::eq(o.as_ref(), frame) && o.overlaps(frame)) {
+            if other.node == receiver {
+                return None;
+            }
+            if !same_channel(&other.transmission, &frame.transmission) {
+                continue;
+            }
+            jammed |= other.origin == FrameOrigin::Jammer;
+            let interference = power_at(&other.transmission).0;
+            if other.noise {
+                noise += 10f32.powf(interference / 10.0);
+            } else if rssi - interference < SIR_THRESHOLDS[sf][other.transmission.spreading_factor.value() as usize - 7] {
+                return None;
+            }
+        }
+        let sinr = (rssi - 10.0 * noise.log10()).min(snr);
+        (sinr >= frame.transmission.spreading_factor.demodulation_floor()).then_some((rssi, sinr, jammed))
+    }
+}
+
+///Radio channel shared by the devices and gateways of a process. Uplinks are heard by every gateway and downlinks by every device,
+///each receiver gets the frame only if it is not lost because of the path loss or of a collision
+#[derive(Clone, Default)]
+pub struct VirtualMedium {
+    state: Arc<Mutex<MediumState>>,
+}
+
+impl VirtualMedium {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Medium shared by every communicator created from a config with the same name
+    pub fn named(name: &str) -> Self {
+        static MEDIUMS: OnceLock<Mutex<HashMap<String, VirtualMedium>>> = OnceLock::new();
+        MEDIUMS.get_or_init(Default::default).lock().unwrap().entry(name.to_string()).or_default().clone()
+    }
+
+    ///Path loss between the nodes, log-distance without shadowing by default
+    pub fn set_propagation(&self, model: Arc<dyn PropagationModel>) {
+        self.state.lock().unwrap().propagation = model;
+    }
+
+    pub fn attach(&self, position: Position, role: MediumRole) -> VirtualMediumCommunicator {
+        let (id, receiver) = self.attach_node(position, role, false);
+        VirtualMediumCommunicator {
+            medium: self.clone(),
+            id,
+            position,
+            role,
+            tx: (DEFAULT_FREQUENCY, SpreadingFactor::SF7, LoRaBandwidth::BW125, DEFAULT_POWER),
+            rx: None,
+            inbox: Arc::new(tokio::sync::Mutex::new(receiver)),
+        }
+    }
+
+    pub(super) fn attach_node(&self, position: Position, role: MediumRole, adversary: bool) -> (usize, InboxReceiver) {
+        let (inbox, receiver) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        state.nodes.push(Node { position, role, inbox, adversary });
+        (state.nodes.len() - 1, receiver)
+    }
+
+    pub(super) fn watch(&self) -> mpsc::UnboundedReceiver<Arc<OnAir>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().watchers.push(sender);
+        receiver
+    }
+
+    ///Keep every frame received by the gateways from now on, with its label
+    pub fn record_receptions(&self) {
+        self.state.lock().unwrap().receptions.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_receptions(&self) -> Vec<LabelledReception> {
+        self.state.lock().unwrap().receptions.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    ///Put the frame on air now, the receivers get it when it ends. Returns the end of the transmission
+    fn transmit(&self, node: usize, transmission: Transmission) -> Instant {
+        let start = Instant::now();
+        let end = start + Duration::from_millis(transmission.time_on_air() as u64);
+        self.put_on_air(OnAir { node, transmission, start, end, origin: FrameOrigin::Legitimate, noise: false })
+    }
+
+    ///Frames of the jammers interfere with the others but nobody receives them
+    pub(super) fn put_on_air(&self, frame: OnAir) -> Instant {
+        let (start, end) = (frame.start, frame.end);
+        let frame = Arc::new(frame);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.on_air.retain(|o| o.end + ON_AIR_RETENTION > start);
+            state.on_air.push(frame.clone());
+            state.watchers.retain(|w| w.send(frame.clone()).is_ok());
+        }
+        if frame.origin == FrameOrigin::Jammer {
+            return end;
+        }
+
+        let medium = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(end).await;
+            let mut state = medium.state.lock().unwrap();
+            let listeners = if frame.transmission.uplink { MediumRole::Gateway } else { MediumRole::Device };
+            let recording = state.receptions.is_some();
+            let mut receptions = Vec::new();
+            for (id, receiver) in state.nodes.iter().enumerate().filter(|(id, n)| *id != frame.node && n.role == listeners) {
+                if let Some((rssi, snr, jammed)) = state.reception(&frame, id) {
+                    let received = ReceivedTransmission {
+                        transmission: frame.transmission.clone(),
+                        arrival_stats: ArrivalStats { time: now_ms(), rssi, snr, ..Default::default() },
+                    };
+                    if recording && !receiver.adversary && receiver.role == MediumRole::Gateway {
+                        receptions.push(LabelledReception {
+                            time: received.arrival_stats.time,
+                            gateway: id,
+                            transmission: frame.transmission.clone(),
+                            rssi,
+                            snr,
+                            distance: frame.transmission.start_position.distance(&receiver.position),
+                            origin: frame.origin,
+                            jammed,
+                        });
+                    }
+                    //the communicator has been dropped
+                    let _ = receiver.inbox.send((received, frame.start, frame.origin));
+                }
+            }
+            if let Some(log) = state.receptions.as_mut() {
+                log.extend(receptions);
+            }
+        });
+        end
+    }
+}
+
+type Inbox = Arc<tokio::sync::Mutex<InboxReceiver>>;
+
+///Frames received by the node. Devices get only downlinks whose preamble started in the window opened by the call
+///and sent with the frequency and data rate of the window, gateways listen on every channel and data rate
+async fn receive(inbox: &Inbox, rx: Option<(u32, SpreadingFactor, LoRaBandwidth)>, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+    let opened = Instant::now();
+    let deadline = timeout.map(|d| opened + d);
+    let mut inbox = inbox.lock().await;
+    loop {
+        let next = match deadline {
+            Some(d) => tokio::time::timeout_at(d, inbox.recv()).await.map_err(|_| LoRaWANError::MissingDownlink)?,
+            None => inbox.recv().await,
+        };
+        let (received, start, _) = next.ok_or(LoRaWANError::MissingDownlink)?;
+        let t = &received.transmission;
+        let listening = rx.is_none_or(|(frequency, sf, bw)| (t.frequency - frequency as f64).abs() < 1_000.0 && t.spreading_factor == sf && t.bandwidth == bw);
+        if listening && (rx.is_none() || start + RX_WINDOW_TOLERANCE >= opened) {
+            return Ok(vec![received]);
+        }
+    }
+}
+
+///A device or a gateway attached to a `VirtualMedium`. Devices send PHYPayloads with the parameters set by
+///`set_tx_parameters`, gateways send a JSON `Transmission` as produced by the downlink scheduler
+pub struct VirtualMediumCommunicator {
+    medium: VirtualMedium,
+    id: usize,
+    position: Position,
+    role: MediumRole,
+    ///frequency, data rate and EIRP of the next uplink
+    tx: (u32, SpreadingFactor, LoRaBandwidth, i8),
+    rx: Option<(u32, SpreadingFactor, LoRaBandwidth)>,
+    inbox: Inbox,
+}
+
+impl VirtualMediumCommunicator {
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn role(&self) -> MediumRole {
+        self.role
+    }
+
+    fn transmission(&self, bytes: &[u8]) -> Result<Transmission, CommunicatorError> {
+        let (frequency, spreading_factor, bandwidth, power) = self.tx;
+        let transmission = match self.role {
+            MediumRole::Device => Transmission {
+                frequency: frequency as f64,
+                bandwidth,
+                spreading_factor,
+                starting_power: power as f32,
+                uplink: true,
+                payload: bytes.to_vec(),
+                ..Default::default()
+            },
+            MediumRole::Gateway => {
+                let transmission = serde_json::from_slice::<Transmission>(bytes).map_err(|_| LoRaWANError::InvalidBufferContent)?;
+                Transmission {
+                    starting_power: if transmission.starting_power > 0.0 { transmission.starting_power } else { power as f32 },
+                    uplink: false,
+                    ..transmission
+                }
+            },
+        };
+        Ok(Transmission { start_position: self.position, start_time: now_ms(), ..transmission })
+    }
+}
+
+impl LoRaWANCommunicator for VirtualMediumCommunicator {
+    type Config = VirtualMediumConfig;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        let medium = VirtualMedium::named(&config.medium);
+        if let Some(propagation) = &config.propagation {
+            medium.set_propagation(propagation.build().map_err(|e| CommunicatorError::Radio(e.to_string()))?);
+        }
+        Ok(medium.attach(config.position, config.role))
+    }
+
+    ///Devices are busy until the end of the uplink, gateways return as soon as the downlink is on air
+    async fn send(&self, bytes: &[u8], _src: Option<EUI64>, _dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        let end = self.medium.transmit(self.id, self.transmission(bytes)?);
+        if self.role == MediumRole::Device {
+            tokio::time::sleep_until(end).await;
+        }
+        Ok(())
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        receive(&self.inbox, self.rx, timeout).await
+    }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        if self.role == MediumRole::Device {
+            self.rx = Some((frequency, spreading_factor, bandwidth));
+        }
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        self.tx = (frequency, spreading_factor, bandwidth, power);
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        true
+    }
+}
+
+pub struct VirtualMediumSender {
+    communicator: Arc<VirtualMediumCommunicator>,
+}
+
+pub struct VirtualMediumReceiver {
+    communicator: Arc<VirtualMediumCommunicator>,
+}
+
+impl LoRaSender for VirtualMediumSender {
+    type OptionalInfo = ();
+
+    async fn send(&self, bytes: &[u8], _optional_info: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
+        LoRaWANCommunicator::send(self.communicator.as_ref(), bytes, None, None).await
+    }
+}
+
+impl LoRaReceiver for VirtualMediumReceiver {
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        LoRaWANCommunicator::receive(self.communicator.as_ref(), timeout).await
+    }
+}
+
+impl SplitCommunicator for VirtualMediumCommunicator {
+    type Sender = VirtualMediumSender;
+    type Receiver = VirtualMediumReceiver;
+
+    async fn split_communicator(self) -> Result<(Self::Sender, Self::Receiver), CommunicatorError> {
+        let communicator = Arc::new(self);
+        Ok((
+            VirtualMediumSender { communicator: communicator.clone() },
+            VirtualMediumReceiver { communicator },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::propagation::MAX_SNR;
+
+    fn device(medium: &VirtualMedium, x: f32, spreading_factor: SpreadingFactor) -> VirtualMediumCommunicator {
+        let mut device = medium.attach(Position { x, y: 0.0, z: 0.0 }, MediumRole::Device);
+        device.set_tx_parameters(868_100_000, spreading_factor, LoRaBandwidth::BW125, 14);
+        device
+    }
+
+    #[test]
+    fn noise_jammer_over_thermal_noise() {
+        let mut state = MediumState::default();
+        for x in [0.0, 10.0, 40.0] {
+            let (inbox, _) = mpsc::unbounded_channel();
+            state.nodes.push(Node { position: Position { x, y: 0.0, z: 0.0 }, role: MediumRole::Device, inbox, adversary: false });
+        }
+        let start = Instant::now();
+        let on_air = |node: usize, noise: bool| OnAir {
+            node,
+            transmission: Transmission { start_position: state.nodes[node].position, frequency: 868_100_000.0, starting_power: 14.0, ..Default::default() },
+            start,
+            end: start + Duration::from_secs(1),
+            origin: if noise { FrameOrigin::Jammer } else { FrameOrigin::Legitimate },
+            noise,
+        };
+        let frame = on_air(1, false);
+        let jammer = on_air(2, true);
+        let (rssi, snr) = link_budget(state.propagation.as_ref(), &frame.transmission, &state.nodes[0].position);
+        let interference = link_budget(state.propagation.as_ref(), &jammer.transmission, &state.nodes[0].position).0;
+        assert_eq!(snr, MAX_SNR);
+        state.on_air.push(Arc::new(jammer));
+
+        //the jammer is 16 dB below a strong frame, far above the thermal noise: the SINR stays at the cap
+        let noise = 10f32.powf(noise_floor(frame.transmission.bandwidth) / 10.0) + 10f32.powf(interference / 10.0);
+        let (_, sinr, jammed) = state.reception(&frame, 0).unwrap();
+        assert!((sinr - (rssi - 10.0 * noise.log10()).min(MAX_SNR)).abs() < 1e-3);
+        assert_eq!(sinr, MAX_SNR);
+        assert!(jammed);
+    }
+
+    #[tokio::test]
+    async fn collisions_and_capture() {
+        let medium = VirtualMedium::new();
+        let gateway = medium.attach(Position::default(), MediumRole::Gateway);
+        let mut near = device(&medium, 100.0, SpreadingFactor::SF7);
+        let mut far = device(&medium, 1000.0, SpreadingFactor::SF7);
+        let orthogonal = device(&medium, 200.0, SpreadingFactor::SF9);
+        let out_of_range = device(&medium, 50_000.0, SpreadingFactor::SF7);
+
+        //near captures far, the SF9 frame survives both
+        let (a, b, c, d) = tokio::join!(
+            near.send(&[1; 10], None, None),
+            far.send(&[2; 10], None, None),
+            orthogonal.send(&[3; 10], None, None),
+            out_of_range.send(&[4; 10], None, None),
+        );
+        a.and(b).and(c).and(d).unwrap();
+        let first = gateway.receive(Some(Duration::from_millis(100))).await.unwrap().remove(0);
+        let second = gateway.receive(Some(Duration::from_millis(100))).await.unwrap().remove(0);
+        assert_eq!(first.transmission.payload, vec![1; 10]);
+        assert_eq!(second.transmission.payload, vec![3; 10]);
+        assert!(first.arrival_stats.rssi > second.arrival_stats.rssi);
+        assert!(gateway.receive(Some(Duration::from_millis(100))).await.is_err());
+
+        //two frames of similar power on the same SF destroy each other
+        let close = device(&medium, 120.0, SpreadingFactor::SF7);
+        let (a, b) = tokio::join!(near.send(&[1; 10], None, None), close.send(&[5; 10], None, None));
+        a.and(b).unwrap();
+        assert!(gateway.receive(Some(Duration::from_millis(100))).await.is_err());
+
+        //only the device listening on the downlink channel gets it
+        near.set_rx_window(869_525_000, SpreadingFactor::SF9, LoRaBandwidth::BW125);
+        far.set_rx_window(868_100_000, SpreadingFactor::SF7, LoRaBandwidth::BW125);
+        let downlink = Transmission { frequency: 869_525_000.0, spreading_factor: SpreadingFactor::SF9, payload: vec![6; 10], ..Default::default() };
+        let bytes = serde_json::to_vec(&downlink).unwrap();
+        let (sent, near_received, far_received) = tokio::join!(
+            gateway.send(&bytes, None, None),
+            near.receive(Some(Duration::from_millis(500))),
+            far.receive(Some(Duration::from_millis(500))),
+        );
+        sent.unwrap();
+        let near_received = near_received.unwrap().remove(0);
+        assert!(!near_received.transmission.uplink);
+        assert_eq!(near_received.transmission.payload, downlink.payload);
+        assert!(far_received.is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{ops::{Deref, DerefMut}, sync::Mutex, time::Duration};
+
+use lorawan::{lorawan_packet::{payload::Payload, LoRaWANPacket}, physical_parameters::{LoRaBandwidth, SpreadingFactor}, utils::eui::EUI64};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::communicator::{CommunicatorError, LoRaWANCommunicator, ReceivedTransmission, Transmission};
+
+///Preamble symbols the radio listens for before closing an empty RX window
+const RX_PREAMBLE_SYMBOLS: f64 = 12.25;
+
+///Currents drawn by the device and capacity of its battery. The defaults are those of an SX1276 with an MCU in deep sleep on a 2400 mAh battery
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnergyProfile {
+    ///(dBm, mA) points of the TX current, interpolated linearly and clamped at the ends
+    pub tx_current: Vec<(i8, f64)>,
+    ///mA while an RX window is open
+    pub rx_current: f64,
+    ///mA between frames
+    pub sleep_current: f64,
+    ///mAh
+    pub battery_capacity: f64,
+}
+
+impl Default for EnergyProfile {
+    fn default() -> Self {
+        Self {
+            tx_current: vec![(7, 20.0), (13, 29.0), (17, 90.0), (20, 120.0)],
+            rx_current: 11.5,
+            sleep_current: 0.01,
+            battery_capacity: 2400.0,
+        }
+    }
+}
+
+impl EnergyProfile {
+    ///mA drawn transmitting at power dBm
+    pub fn tx_current(&self, power: i8) -> f64 {
+        let (Some(first), Some(last)) = (self.tx_current.first(), self.tx_current.last()) else { return 0.0 };
+        if power <= first.0 {
+            return first.1;
+        }
+        self.tx_current.windows(2)
+            .find(|w| power <= w[1].0)
+            .map_or(last.1, |w| {
+                let ((p0, c0), (p1, c1)) = (w[0], w[1]);
+                c0 + (c1 - c0) * (f64::from(power) - f64::from(p0)) / (f64::from(p1) - f64::from(p0))
+            })
+    }
+
+    ///The TX points must be sorted by power without repetitions, the currents and the capacity not negative
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.tx_current.windows(2).all(|w| w[0].0 < w[1].0) {
+            return Err(format!("tx_current points {:?} are not sorted by power", self.tx_current));
+        }
+        let mut currents = self.tx_current.iter().map(|p| p.1).chain([self.rx_current, self.sleep_current, self.battery_capacity]);
+        if currents.all(|c| c.is_finite() && c >= 0.0) { Ok(()) } else { Err(format!("{self:?} has invalid currents")) }
+    }
+}
+
+///Time spent in each radio state and charge drawn, ms and mAh
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct EnergyUsage {
+    ///frames sent, retransmissions included
+    pub frames: u64,
+    ///data uplinks sent again with the DevAddr and FCnt of the previous one
+    pub retransmissions: u64,
+    pub tx_time: f64,
+    pub rx_time: f64,
+    pub sleep_time: f64,
+    pub tx_charge: f64,
+    pub rx_charge: f64,
+    pub sleep_charge: f64,
+}
+
+impl EnergyUsage {
+    ///mAh drawn since the device started
+    pub fn charge(&self) -> f64 {
+        self.tx_charge + self.rx_charge + self.sleep_charge
+    }
+
+    ///How long the battery lasts at the average current of the measured period
+    pub fn lifetime(&self, battery_capacity: f64) -> Option<Duration> {
+        let hours = (self.tx_time + self.rx_time + self.sleep_time) / 3_600_000.0;
+        let current = self.charge() / hours;
+        (current > 0.0).then(|| Duration::from_secs_f64(battery_capacity / current * 3600.0))
+    }
+}
+
+fn charge(current: f64, ms: f64) -> f64 {
+    current * ms / 3_600_000.0
+}
+
+///DevAddr and FCnt of a data uplink. The bytes cannot tell a retransmission apart, LoRaWAN 1.1 computes again the MIC of each copy
+fn uplink_counter(bytes: &[u8]) -> Option<([u8; 4], u16)> {
+    let packet = LoRaWANPacket::from_bytes(bytes, None, true).ok()?;
+    match packet.payload() {
+        Payload::MACPayload(p) => Some((p.fhdr().dev_addr(), p.fhdr().fcnt())),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct EnergyState {
+    usage: EnergyUsage,
+    spreading_factor: SpreadingFactor,
+    bandwidth: LoRaBandwidth,
+    power: i8,
+    rx_spreading_factor: SpreadingFactor,
+    rx_bandwidth: LoRaBandwidth,
+    last_uplink: Option<([u8; 4], u16)>,
+}
+
+///Wraps the communicator of a device to charge its battery: airtime of every frame at the TX current of its power, RX windows
+///until the downlink ends or, if nothing arrives, for the preamble, and sleep current for the rest of the time
+#[derive(Debug)]
+pub struct EnergyCommunicator<T: LoRaWANCommunicator> {
+    inner: T,
+    profile: EnergyProfile,
+    started: Instant,
+    state: Mutex<EnergyState>,
+}
+
+impl<T: LoRaWANCommunicator> EnergyCommunicator<T> {
+    pub fn new(inner: T, profile: EnergyProfile) -> Self {
+        let default = Transmission::default();
+        Self {
+            inner,
+            profile,
+            started: Instant::now(),
+            state: Mutex::new(EnergyState {
+                usage: EnergyUsage::default(),
+                spreading_factor: default.spreading_factor,
+                bandwidth: default.bandwidth,
+                power: 14,
+                rx_spreading_factor: default.spreading_factor,
+                rx_bandwidth: default.bandwidth,
+                last_uplink: None,
+            }),
+        }
+    }
+
+    pub fn profile(&self) -> &EnergyProfile {
+        &self.profile
+    }
+
+    ///Usage since the communicator was created, the device sleeps whenever it does not transmit or receive
+    pub fn usage(&self) -> EnergyUsage {
+        let mut usage = self.state.lock().unwrap().usage;
+        let elapsed = self.started.elapsed().as_secs_f64() * 1000.0;
+        usage.sleep_time = (elapsed - usage.tx_time - usage.rx_time).max(0.0);
+        usage.sleep_charge = charge(self.profile.sleep_current, usage.sleep_time);
+        usage
+    }
+
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.usage().lifetime(self.profile.battery_capacity)
+    }
+}
+
+impl<T: LoRaWANCommunicator> Deref for EnergyCommunicator<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: LoRaWANCommunicator> DerefMut for EnergyCommunicator<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<T: LoRaWANCommunicator> LoRaWANCommunicator for EnergyCommunicator<T> {
+    type Config = T::Config;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        Ok(Self::new(T::from_config(config).await?, EnergyProfile::default()))
+    }
+
+    async fn send(&self, bytes: &[u8], src: Option<EUI64>, dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let time_on_air = Transmission {
+                spreading_factor: state.spreading_factor,
+                bandwidth: state.bandwidth,
+                payload: bytes.to_vec(),
+                ..Default::default()
+            }.time_on_air() as f64;
+            let current = self.profile.tx_current(state.power);
+            let uplink = uplink_counter(bytes);
+            if uplink.is_some() && uplink == state.last_uplink {
+                state.usage.retransmissions += 1;
+            }
+            state.last_uplink = uplink;
+            state.usage.frames += 1;
+            state.usage.tx_time += time_on_air;
+            state.usage.tx_charge += charge(current, time_on_air);
+        }
+        self.inner.send(bytes, src, dest).await
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let opened = Instant::now();
+        let received = self.inner.receive(timeout).await;
+        let mut state = self.state.lock().unwrap();
+        let listened = match &received {
+            Ok(frames) if !frames.is_empty() => opened.elapsed().as_secs_f64() * 1000.0,
+            _ => {
+                let symbol = 2f64.powi(state.rx_spreading_factor.value() as i32) / state.rx_bandwidth.khz() as f64;
+                (opened.elapsed().as_secs_f64() * 1000.0).min(RX_PREAMBLE_SYMBOLS * symbol)
+            },
+        };
+        state.usage.rx_time += listened;
+        state.usage.rx_charge += charge(self.profile.rx_current, listened);
+        received
+    }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        let state = self.state.get_mut().unwrap();
+        (state.rx_spreading_factor, state.rx_bandwidth) = (spreading_factor, bandwidth);
+        self.inner.set_rx_window(frequency, spreading_factor, bandwidth)
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        let state = self.state.get_mut().unwrap();
+        (state.spreading_factor, state.bandwidth, state.power) = (spreading_factor, bandwidth, power);
+        self.inner.set_tx_parameters(frequency, spreading_factor, bandwidth, power)
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        self.inner.tx_parameters_applied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::{device::Device, lorawan_packet::MicContext};
+
+    use super::*;
+    use crate::devices::mock_device::MockCommunicator;
+
+    #[test]
+    fn tx_current() {
+        let profile = EnergyProfile::default();
+        assert_eq!(profile.tx_current(0), 20.0);
+        assert_eq!(profile.tx_current(13), 29.0);
+        assert!((profile.tx_current(14) - 44.25).abs() < 1e-9);
+        assert_eq!(profile.tx_current(30), 120.0);
+
+        //the whole i8 range between two points
+        let wide = EnergyProfile { tx_current: vec![(-128, 0.0), (127, 255.0)], ..Default::default() };
+        assert_eq!(wide.tx_current(127), 255.0);
+        assert_eq!(wide.tx_current(0), 128.0);
+    }
+
+    #[test]
+    fn unsorted_profile() {
+        assert!(EnergyProfile::default().validate().is_ok());
+        assert!(EnergyProfile { tx_current: vec![(14, 40.0), (7, 20.0)], ..Default::default() }.validate().is_err());
+        assert!(EnergyProfile { tx_current: vec![(7, 20.0), (7, 30.0)], ..Default::default() }.validate().is_err());
+        assert!(EnergyProfile { rx_current: -1.0, ..Default::default() }.validate().is_err());
+    }
+
+    ///20 bytes data uplink with the FCnt, the MIC of a LoRaWAN 1.1 device changes with tx_ch
+    fn data_up(device: &mut Device, fcnt: u32, tx_ch: u8) -> Vec<u8> {
+        BlockchainMockClient::create_data_up(device, fcnt, &[0xAA; 7], &MicContext { tx_ch, ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn airtime_and_retransmissions() {
+        let mut communicator = EnergyCommunicator::new(MockCommunicator, EnergyProfile::default());
+        communicator.set_tx_parameters(868_100_000, SpreadingFactor::SF12, LoRaBandwidth::BW125, 14);
+        let mut device = BlockchainMockClient::create_initialized_device(&[1, 2, 3, 4]);
+        //the second copy is sent on another channel and has another MIC
+        communicator.send(&data_up(&mut device, 1, 0), None, None).await.unwrap();
+        communicator.send(&data_up(&mut device, 1, 1), None, None).await.unwrap();
+        communicator.send(&data_up(&mut device, 2, 0), None, None).await.unwrap();
+
+        let usage = communicator.usage();
+        assert_eq!((usage.frames, usage.retransmissions), (3, 1));
+        //20 bytes at SF12 take 1319 ms
+        assert_eq!(usage.tx_time, 3.0 * 1319.0);
+        assert!((usage.tx_charge - 3.0 * 44.25 * 1319.0 / 3_600_000.0).abs() < 1e-9);
+
+        let day = EnergyUsage { tx_time: 1000.0, tx_charge: 0.01, sleep_time: 86_399_000.0, sleep_charge: 0.24, ..Default::default() };
+        assert_eq!(day.lifetime(2400.0).unwrap().as_secs() / 86_400, 9600);
+    }
+}
@@ -0,0 +1,363 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lorawan::{
+    lorawan_packet::mhdr::{MType, MHDR},
+    physical_parameters::{CodeRate, LoRaBandwidth, SpreadingFactor},
+    utils::{errors::LoRaWANError, eui::EUI64},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::communicator::{ArrivalStats, GatewayTimestamp, ReceivedTransmission, Transmission};
+
+//https://github.com/Lora-net/packet_forwarder/blob/master/PROTOCOL.TXT
+
+pub const PROTOCOL_VERSION: u8 = 2;
+///TX power in dBm used for downlinks that do not specify one
+pub const DEFAULT_TX_POWER: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    PushData,
+    PushAck,
+    PullData,
+    PullResp,
+    PullAck,
+    TxAck,
+}
+
+impl PacketType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(PacketType::PushData),
+            0x01 => Some(PacketType::PushAck),
+            0x02 => Some(PacketType::PullData),
+            0x03 => Some(PacketType::PullResp),
+            0x04 => Some(PacketType::PullAck),
+            0x05 => Some(PacketType::TxAck),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        match self {
+            PacketType::PushData => 0x00,
+            PacketType::PushAck => 0x01,
+            PacketType::PullData => 0x02,
+            PacketType::PullResp => 0x03,
+            PacketType::PullAck => 0x04,
+            PacketType::TxAck => 0x05,
+        }
+    }
+
+    ///Packets sent by the gateway carry its EUI after the identifier
+    pub fn has_gateway_eui(&self) -> bool {
+        matches!(self, PacketType::PushData | PacketType::PullData | PacketType::TxAck)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GWMPPacket {
+    pub version: u8,
+    pub token: [u8; 2],
+    pub packet_type: PacketType,
+    pub gateway: Option<EUI64>,
+    ///JSON object, empty for the acknowledgements
+    pub payload: Vec<u8>,
+}
+
+impl GWMPPacket {
+    pub fn new(token: [u8; 2], packet_type: PacketType, gateway: Option<EUI64>, payload: Vec<u8>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            token,
+            packet_type,
+            gateway,
+            payload,
+        }
+    }
+
+    ///Acknowledgement of this packet, with the same version and token
+    pub fn ack(&self, packet_type: PacketType) -> Self {
+        Self {
+            version: self.version,
+            token: self.token,
+            packet_type,
+            gateway: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoRaWANError> {
+        if bytes.len() < 4 {
+            return Err(LoRaWANError::InvalidBufferLength);
+        }
+        let packet_type = PacketType::from_byte(bytes[3]).ok_or(LoRaWANError::InvalidBufferContent)?;
+        let (gateway, payload) = if packet_type.has_gateway_eui() {
+            if bytes.len() < 12 {
+                return Err(LoRaWANError::InvalidBufferLength);
+            }
+            let eui: [u8; 8] = bytes[4..12].try_into()?;
+            (Some(EUI64::from(eui)), &bytes[12..])
+        } else {
+            (None, &bytes[4..])
+        };
+
+        Ok(Self {
+            version: bytes[0],
+            token: [bytes[1], bytes[2]],
+            packet_type,
+            gateway,
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = vec![self.version, self.token[0], self.token[1], self.packet_type.value()];
+        if let Some(gateway) = self.gateway {
+            ret.extend_from_slice(&*gateway);
+        }
+        ret.extend_from_slice(&self.payload);
+        ret
+    }
+}
+
+///DevAddr as it appears on the air, only data frames carry it in clear
+pub fn dev_addr(payload: &[u8]) -> Option<[u8; 4]> {
+    match MHDR::from_bytes(*payload.first()?).mtype() {
+        MType::UnconfirmedDataUp | MType::UnconfirmedDataDown | MType::ConfirmedDataUp | MType::ConfirmedDataDown => payload.get(1..5)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+///`datr` is a string for LoRa packets and the bit rate for FSK ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Datr {
+    LoRa(String),
+    FSK(u32),
+}
+
+impl Datr {
+    pub fn lora(spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) -> Self {
+        Datr::LoRa(format!("{spreading_factor}BW{}", bandwidth.khz() as u32))
+    }
+
+    ///Spreading factor and bandwidth of a LoRa data rate identifier, e.g. SF7BW125
+    pub fn lora_parameters(&self) -> Option<(SpreadingFactor, LoRaBandwidth)> {
+        match self {
+            Datr::LoRa(s) => {
+                let (sf, bw) = s.strip_prefix("SF")?.split_once("BW")?;
+                let sf: u8 = sf.parse().ok()?;
+                let bw: f32 = bw.parse().ok()?;
+                if !(7..=12).contains(&sf) || ![125.0, 250.0, 500.0].contains(&bw) {
+                    return None;
+                }
+                Some((SpreadingFactor::new(sf), LoRaBandwidth::from(bw)))
+            },
+            Datr::FSK(_) => None,
+        }
+    }
+}
+
+pub fn codr(code_rate: CodeRate) -> String {
+    match code_rate {
+        CodeRate::CR4_5 => "4/5",
+        CodeRate::CR4_6 => "4/6",
+        CodeRate::CR5_7 => "4/7",
+        CodeRate::CR4_8 => "4/8",
+    }.to_string()
+}
+
+pub fn code_rate(codr: &str) -> Option<CodeRate> {
+    match codr {
+        "4/5" => Some(CodeRate::CR4_5),
+        "4/6" => Some(CodeRate::CR4_6),
+        "4/7" => Some(CodeRate::CR5_7),
+        "4/8" => Some(CodeRate::CR4_8),
+        _ => None,
+    }
+}
+
+///Packet received by the gateway
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rxpk {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    ///internal timestamp of the gateway in µs
+    pub tmst: u32,
+    ///frequency in MHz
+    pub freq: f64,
+    pub chan: u8,
+    pub rfch: u8,
+    ///CRC status: 1 OK, -1 fail, 0 no CRC
+    pub stat: i8,
+    pub modu: String,
+    pub datr: Datr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codr: Option<String>,
+    pub rssi: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsnr: Option<f32>,
+    pub size: u16,
+    ///base64 encoded PHYPayload
+    pub data: String,
+}
+
+impl Rxpk {
+    ///LoRa packet received with a valid CRC at the given gateway timestamp
+    pub fn from_transmission(transmission: &Transmission, tmst: u32, rssi: f32, snr: f32) -> Self {
+        Self {
+            time: None,
+            tmst,
+            freq: transmission.frequency / 1_000_000.0,
+            chan: 0,
+            rfch: 0,
+            stat: 1,
+            modu: "LORA".to_string(),
+            datr: Datr::lora(transmission.spreading_factor, transmission.bandwidth),
+            codr: Some(codr(transmission.code_rate)),
+            rssi: rssi.round() as i32,
+            //the packet forwarder prints the SNR with one decimal
+            lsnr: Some((snr * 10.0).round() / 10.0),
+            size: transmission.payload.len() as u16,
+            data: STANDARD.encode(&transmission.payload),
+        }
+    }
+
+    pub fn payload(&self) -> Result<Vec<u8>, LoRaWANError> {
+        STANDARD.decode(&self.data).map_err(|_| LoRaWANError::InvalidBufferContent)
+    }
+
+    ///Only LoRa packets with a valid CRC can be converted. The tmst is kept with the gateway EUI, the answers are timed on it
+    pub fn to_received_transmission(&self, gateway: EUI64) -> Result<ReceivedTransmission, LoRaWANError> {
+        let (spreading_factor, bandwidth) = self.datr.lora_parameters().ok_or(LoRaWANError::InvalidBufferContent)?;
+        if self.stat == -1 {
+            return Err(LoRaWANError::InvalidBufferContent);
+        }
+        let transmission = Transmission {
+            frequency: self.freq * 1_000_000.0,
+            bandwidth,
+            spreading_factor,
+            code_rate: self.codr.as_deref().and_then(code_rate).unwrap_or_default(),
+            uplink: true,
+            payload: self.payload()?,
+            ..Default::default()
+        };
+        //the gateway clock cannot be related to the local one, the frame ended when it was received
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        Ok(ReceivedTransmission {
+            transmission: Transmission {
+                start_time: time.saturating_sub(transmission.time_on_air()),
+                ..transmission
+            },
+            arrival_stats: ArrivalStats {
+                time,
+                rssi: self.rssi as f32,
+                snr: self.lsnr.unwrap_or_default(),
+                gateway_tmst: Some(GatewayTimestamp { gateway, tmst: self.tmst }),
+            },
+        })
+    }
+}
+
+///Packet the gateway has to send
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Txpk {
+    ///send the packet as soon as it is received, ignoring tmst
+    #[serde(default)]
+    pub imme: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmst: Option<u32>,
+    ///frequency in MHz
+    pub freq: f64,
+    pub rfch: u8,
+    ///TX power in dBm
+    pub powe: u8,
+    pub modu: String,
+    pub datr: Datr,
+    pub codr: String,
+    ///inverted polarity, set for downlinks
+    #[serde(default)]
+    pub ipol: bool,
+    pub size: u16,
+    ///base64 encoded PHYPayload
+    pub data: String,
+}
+
+impl Txpk {
+    ///The packet is sent at the gateway tmst of the transmission, immediately if it has none
+    pub fn from_transmission(transmission: &Transmission) -> Self {
+        Self {
+            imme: transmission.gateway_tmst.is_none(),
+            tmst: transmission.gateway_tmst.map(|t| t.tmst),
+            freq: transmission.frequency / 1_000_000.0,
+            rfch: 0,
+            powe: if transmission.starting_power > 0.0 { transmission.starting_power.round() as u8 } else { DEFAULT_TX_POWER },
+            modu: "LORA".to_string(),
+            datr: Datr::lora(transmission.spreading_factor, transmission.bandwidth),
+            codr: codr(transmission.code_rate),
+            ipol: !transmission.uplink,
+            size: transmission.payload.len() as u16,
+            data: STANDARD.encode(&transmission.payload),
+        }
+    }
+
+    pub fn to_transmission(&self) -> Result<Transmission, LoRaWANError> {
+        let (spreading_factor, bandwidth) = self.datr.lora_parameters().ok_or(LoRaWANError::InvalidBufferContent)?;
+        Ok(Transmission {
+            frequency: self.freq * 1_000_000.0,
+            bandwidth,
+            spreading_factor,
+            code_rate: code_rate(&self.codr).unwrap_or_default(),
+            starting_power: self.powe as f32,
+            uplink: !self.ipol,
+            payload: STANDARD.decode(&self.data).map_err(|_| LoRaWANError::InvalidBufferContent)?,
+            ..Default::default()
+        })
+    }
+}
+
+///JSON object of a PUSH_DATA, the gateway statistics are ignored
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PushData {
+    #[serde(default)]
+    pub rxpk: Vec<Rxpk>,
+}
+
+///JSON object of a PULL_RESP
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullResp {
+    pub txpk: Txpk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxpkAck {
+    ///NONE if the packet has been accepted, the reason of the rejection otherwise
+    pub error: String,
+}
+
+///JSON object of a TX_ACK, forwarders send an empty payload when there is no error
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxAck {
+    pub txpk_ack: TxpkAck,
+}
+
+impl TxAck {
+    pub fn new(error: &str) -> Self {
+        Self {
+            txpk_ack: TxpkAck { error: error.to_string() },
+        }
+    }
+
+    pub fn error(payload: &[u8]) -> Option<String> {
+        if payload.is_empty() {
+            return None;
+        }
+        match serde_json::from_slice::<TxAck>(payload) {
+            Ok(ack) if ack.txpk_ack.error == "NONE" => None,
+            Ok(ack) => Some(ack.txpk_ack.error),
+            Err(_) => None,
+        }
+    }
+}
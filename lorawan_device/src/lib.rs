@@ -1,4 +1,11 @@
 pub mod devices;
 pub mod communicator;
+pub mod clock;
 pub mod configs;
-pub mod split_communicator;
\ No newline at end of file
+pub mod split_communicator;
+pub mod gwmp;
+pub mod propagation;
+pub mod trace;
+pub mod mac_layer;
+pub mod energy;
+pub mod pcap;
\ No newline at end of file
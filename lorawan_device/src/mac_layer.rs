@@ -0,0 +1,612 @@
+use std::time::Duration;
+
+use lorawan::{
+    lorawan_packet::{fields::{DLSettings, DataRateTxPower, RxDelay}, join::RejoinType, mac_commands::{EDMacCommands, NCMacCommands}},
+    physical_parameters::DataRate,
+    utils::traits::ToBytes,
+    regional_parameters::{channel_list::{ChannelList, MAX_DYNAMIC_CHANNELS}, region::{Channel, Region, JOIN_ACCEPT_DELAY1, JOIN_ACCEPT_DELAY2, RECEIVE_DELAY1, RECEIVE_DELAY2}},
+};
+use tokio::time::Instant;
+
+///Maximum size of the FOpts field, answers that do not fit are kept for the next uplink
+const MAX_FOPTS_LEN: usize = 15;
+
+///A receive window opened after an uplink: delay from the end of the transmission, frequency in Hz and data rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RxWindow {
+    pub delay: Duration,
+    pub frequency: u32,
+    pub data_rate: DataRate,
+}
+
+///Rejoin requested by the network with ForceRejoinReq
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForcedRejoin {
+    pub rejoin_type: RejoinType,
+    ///delay before each transmission
+    pub period: Duration,
+    ///transmissions after the first one
+    pub max_retries: u8,
+    pub data_rate: DataRate,
+}
+
+///Radio state of an end device, driven by the MAC commands sent by the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacLayer {
+    region: Region,
+    channels: ChannelList,
+    ///downlink frequency in Hz set by DlChannelReq for each uplink channel, None means RX1 uses the regional default
+    dl_frequencies: [Option<u32>; MAX_DYNAMIC_CHANNELS],
+    ///index of the channel used by the last uplink
+    uplink_channel: usize,
+    data_rate: DataRate,
+    tx_power: u8,
+    nb_trans: u8,
+    ///aggregated duty cycle is 1 / 2^max_duty_cycle
+    max_duty_cycle: u8,
+    rx1_dr_offset: u8,
+    rx1_delay: Duration,
+    rx2_data_rate: DataRate,
+    rx2_frequency: u32,
+    uplink_dwell_time: bool,
+    downlink_dwell_time: bool,
+    max_eirp: u8,
+    ///0 external power source, 1..254 battery level, 255 not able to measure
+    battery: u8,
+    last_link_check: Option<(u8, u8)>,
+    ///GPS time received with the last DeviceTimeAns
+    gps_time: Option<Duration>,
+
+    ///(MaxTimeN, MaxCountN) of RejoinParamSetupReq, periodic type 0 rejoins are disabled until it is received
+    rejoin_parameters: Option<(u8, u8)>,
+    uplinks_since_rejoin: u32,
+    last_rejoin: Instant,
+    forced_rejoin: Option<ForcedRejoin>,
+
+    pending_answers: Vec<EDMacCommands>,
+    ///RXParamSetupAns, RXTimingSetupAns and DlChannelAns are repeated in every uplink until a downlink is received
+    sticky_answers: Vec<EDMacCommands>,
+}
+
+impl MacLayer {
+    pub fn new(region: Region) -> Self {
+        //devices start at the fastest data rate supported by the default channels
+        let max_dr = region.default_channels().first().map_or(DataRate::DR0, |c| c.max_dr());
+        Self {
+            region,
+            channels: ChannelList::new(region),
+            dl_frequencies: [None; MAX_DYNAMIC_CHANNELS],
+            uplink_channel: 0,
+            data_rate: max_dr,
+            tx_power: 0,
+            nb_trans: 1,
+            max_duty_cycle: 0,
+            rx1_dr_offset: 0,
+            rx1_delay: RECEIVE_DELAY1,
+            rx2_data_rate: region.rx2_data_rate(),
+            rx2_frequency: region.rx2_frequency(),
+            uplink_dwell_time: false,
+            downlink_dwell_time: false,
+            max_eirp: region.default_max_eirp().max(0) as u8,
+            battery: 255,
+            last_link_check: None,
+            gps_time: None,
+            rejoin_parameters: None,
+            uplinks_since_rejoin: 0,
+            last_rejoin: Instant::now(),
+            forced_rejoin: None,
+            pending_answers: Vec::new(),
+            sticky_answers: Vec::new(),
+        }
+    }
+
+    ///Back to the regional defaults, as required after a (re)join
+    pub fn reset(&mut self) {
+        *self = Self {
+            battery: self.battery,
+            ..Self::new(self.region)
+        };
+    }
+
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    pub fn channels(&self) -> &ChannelList {
+        &self.channels
+    }
+
+    pub fn channels_mut(&mut self) -> &mut ChannelList {
+        &mut self.channels
+    }
+
+    pub fn dl_frequency(&self, ch_index: usize) -> Option<u32> {
+        self.dl_frequencies.get(ch_index).copied().flatten()
+    }
+
+    pub fn uplink_channel(&self) -> Option<Channel> {
+        self.channels.channel(self.uplink_channel)
+    }
+
+    ///Index in the channel list of the last selected uplink channel, the TxCh of the LoRaWAN 1.1 MIC
+    pub fn uplink_channel_index(&self) -> usize {
+        self.uplink_channel
+    }
+
+    ///Move to the next enabled channel supporting the current data rate, round robin over the channel list
+    pub fn select_uplink_channel(&mut self) -> Option<Channel> {
+        let len = self.channels.len();
+        let next = (1..=len)
+            .map(|i| (self.uplink_channel + i) % len)
+            .find(|i| self.channels.is_enabled(*i) && self.channels.channel(*i).is_some_and(|c| c.supports(self.data_rate)))?;
+        self.uplink_channel = next;
+        self.channels.channel(next)
+    }
+
+    ///RX1 and RX2 windows of the last uplink. RX1 uses the RX1DROffset and the DlChannelReq frequency, RX2 the RXParamSetupReq parameters
+    pub fn rx_windows(&self) -> [RxWindow; 2] {
+        let uplink_frequency = self.uplink_channel().map_or(self.rx2_frequency, |c| c.frequency());
+        let rx1 = RxWindow {
+            delay: self.rx1_delay,
+            frequency: self.dl_frequency(self.uplink_channel).unwrap_or_else(|| self.region.rx1_frequency(uplink_frequency)),
+            data_rate: self.region.rx1_data_rate(self.data_rate, self.rx1_dr_offset).unwrap_or(self.data_rate),
+        };
+        let rx2 = RxWindow {
+            delay: self.rx1_delay + (RECEIVE_DELAY2 - RECEIVE_DELAY1),
+            frequency: self.rx2_frequency,
+            data_rate: self.rx2_data_rate,
+        };
+        [rx1, rx2]
+    }
+
+    ///Windows of the JoinAccept, they always use the regional defaults since the device has no session yet
+    pub fn join_accept_windows(&self) -> [RxWindow; 2] {
+        let uplink_frequency = self.uplink_channel().map_or(self.region.rx2_frequency(), |c| c.frequency());
+        let rx1 = RxWindow {
+            delay: JOIN_ACCEPT_DELAY1,
+            frequency: self.region.rx1_frequency(uplink_frequency),
+            data_rate: self.region.rx1_data_rate(self.data_rate, 0).unwrap_or(self.data_rate),
+        };
+        let rx2 = RxWindow {
+            delay: JOIN_ACCEPT_DELAY2,
+            frequency: self.region.rx2_frequency(),
+            data_rate: self.region.rx2_data_rate(),
+        };
+        [rx1, rx2]
+    }
+
+    pub fn data_rate(&self) -> DataRate {
+        self.data_rate
+    }
+
+    pub fn set_data_rate(&mut self, data_rate: DataRate) {
+        self.data_rate = data_rate;
+    }
+
+    pub fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    ///EIRP in dBm corresponding to the current TXPower index, capped by the MaxEIRP set with TxParamSetupReq
+    pub fn tx_power_dbm(&self) -> i8 {
+        self.region.tx_power(self.tx_power).unwrap_or(0).min(self.max_eirp as i8)
+    }
+
+    pub fn nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
+
+    pub fn max_duty_cycle(&self) -> u8 {
+        self.max_duty_cycle
+    }
+
+    pub fn rx1_dr_offset(&self) -> u8 {
+        self.rx1_dr_offset
+    }
+
+    pub fn rx1_delay(&self) -> Duration {
+        self.rx1_delay
+    }
+
+    pub fn rx2_data_rate(&self) -> DataRate {
+        self.rx2_data_rate
+    }
+
+    pub fn rx2_frequency(&self) -> u32 {
+        self.rx2_frequency
+    }
+
+    pub fn uplink_dwell_time(&self) -> bool {
+        self.uplink_dwell_time
+    }
+
+    pub fn downlink_dwell_time(&self) -> bool {
+        self.downlink_dwell_time
+    }
+
+    pub fn max_eirp(&self) -> u8 {
+        self.max_eirp
+    }
+
+    pub fn battery(&self) -> u8 {
+        self.battery
+    }
+
+    pub fn set_battery(&mut self, battery: u8) {
+        self.battery = battery;
+    }
+
+    ///(margin, gw_cnt) of the last LinkCheckAns
+    pub fn last_link_check(&self) -> Option<(u8, u8)> {
+        self.last_link_check
+    }
+
+    pub fn gps_time(&self) -> Option<Duration> {
+        self.gps_time
+    }
+
+    pub fn rejoin_parameters(&self) -> Option<(u8, u8)> {
+        self.rejoin_parameters
+    }
+
+    pub fn uplink_sent(&mut self) {
+        self.uplinks_since_rejoin += 1;
+    }
+
+    pub fn rejoin_sent(&mut self) {
+        self.uplinks_since_rejoin = 0;
+        self.last_rejoin = Instant::now();
+    }
+
+    ///True when 2^(MaxCountN+4) uplinks or 2^(MaxTimeN+10) seconds passed since the last rejoin
+    pub fn periodic_rejoin_due(&self) -> bool {
+        self.rejoin_parameters.is_some_and(|(max_time_n, max_count_n)| {
+            self.uplinks_since_rejoin >= 1 << (max_count_n as u32 + 4)
+                || self.last_rejoin.elapsed() >= Duration::from_secs(1 << (max_time_n as u64 + 10))
+        })
+    }
+
+    pub fn take_forced_rejoin(&mut self) -> Option<ForcedRejoin> {
+        self.forced_rejoin.take()
+    }
+
+    ///RX parameters negotiated in the JoinAccept
+    pub fn set_join_accept_parameters(&mut self, dl_settings: DLSettings, rx_delay: RxDelay) {
+        if dl_settings.rx1_dr_offset() <= self.region.max_rx1_dr_offset() {
+            self.rx1_dr_offset = dl_settings.rx1_dr_offset();
+        }
+        let rx2_data_rate = DataRate::new(dl_settings.rx2_data_rate());
+        if self.region.data_rate(rx2_data_rate).is_some() {
+            self.rx2_data_rate = rx2_data_rate;
+        }
+        self.rx1_delay = rx_delay.delay();
+    }
+
+    ///Must be called when a downlink is received, before handling its commands: it stops the repetition of the sticky answers
+    pub fn downlink_received(&mut self) {
+        self.sticky_answers.clear();
+    }
+
+    ///Answers to put in the FOpts of the next uplink, sticky ones first. Answers that exceed 15 bytes stay queued
+    pub fn take_answers(&mut self) -> Vec<EDMacCommands> {
+        let mut ret: Vec<EDMacCommands> = Vec::new();
+        let mut len = 0;
+        for a in &self.sticky_answers {
+            let a_len = a.to_bytes().len();
+            if len + a_len <= MAX_FOPTS_LEN {
+                len += a_len;
+                ret.push(a.clone());
+            }
+        }
+        let mut left = Vec::new();
+        for a in self.pending_answers.drain(..) {
+            let a_len = a.to_bytes().len();
+            if len + a_len <= MAX_FOPTS_LEN {
+                len += a_len;
+                ret.push(a);
+            } else {
+                left.push(a);
+            }
+        }
+        self.pending_answers = left;
+        ret
+    }
+
+    pub fn has_answers(&self) -> bool {
+        !self.pending_answers.is_empty() || !self.sticky_answers.is_empty()
+    }
+
+    ///Apply the commands of a downlink (FOpts or FPort 0) and queue their answers. snr is the one of the downlink, used for DevStatusAns.
+    ///Returns the commands the device ignored: those it does not handle (ADRParamSetupReq, the class B commands, ResetConf and RekeyConf
+    ///since it never sends ResetInd and RekeyInd, TxParamSetupReq outside AS923 and AU915) and those with RFU values
+    pub fn handle_commands(&mut self, commands: &[NCMacCommands], snr: f32) -> Vec<NCMacCommands> {
+        let mut ignored = Vec::new();
+        let mut i = 0;
+        while i < commands.len() {
+            if let NCMacCommands::LinkADRReq { .. } = commands[i] {
+                let block_len = commands[i..].iter().take_while(|c| matches!(c, NCMacCommands::LinkADRReq { .. })).count();
+                self.handle_link_adr_block(&commands[i..i + block_len]);
+                i += block_len;
+                continue;
+            }
+            match &commands[i] {
+                NCMacCommands::LinkCheckAns { margin, gw_cnt } => {
+                    self.last_link_check = Some((*margin, *gw_cnt));
+                },
+                NCMacCommands::DutyCycleReq(max_duty_cycle) => {
+                    self.max_duty_cycle = *max_duty_cycle;
+                    self.pending_answers.push(EDMacCommands::DutyCycleAns);
+                },
+                NCMacCommands::RXParamSetupReq { dl_settings, freq } => {
+                    let frequency = freq * 100;
+                    let rx2_data_rate = DataRate::new(dl_settings.rx2_data_rate());
+                    let rx1_dr_offset_ack = dl_settings.rx1_dr_offset() <= self.region.max_rx1_dr_offset();
+                    let rx2_data_rate_ack = self.region.data_rate(rx2_data_rate).is_some();
+                    let channel_ack = self.region.is_valid_frequency(frequency);
+                    if rx1_dr_offset_ack && rx2_data_rate_ack && channel_ack {
+                        self.rx1_dr_offset = dl_settings.rx1_dr_offset();
+                        self.rx2_data_rate = rx2_data_rate;
+                        self.rx2_frequency = frequency;
+                    }
+                    self.sticky_answers.push(EDMacCommands::RXParamSetupAns { rx1_dr_offset_ack, rx2_data_rate_ack, channel_ack });
+                },
+                NCMacCommands::DevStatusReq => {
+                    let margin = (snr.round().clamp(-32.0, 31.0) as i8) as u8 & 0b00111111;
+                    self.pending_answers.push(EDMacCommands::DevStatusAns { battery: self.battery, margin });
+                },
+                NCMacCommands::NewChannelReq { ch_index, freq, max_dr, min_dr } => {
+                    let answer = self.handle_new_channel(*ch_index, freq * 100, *min_dr, *max_dr);
+                    self.pending_answers.push(answer);
+                },
+                NCMacCommands::RXTimingSetupReq(delay) => {
+                    self.rx1_delay = delay.delay();
+                    self.sticky_answers.push(EDMacCommands::RXTimingSetupAns);
+                },
+                NCMacCommands::TxParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp } => {
+                    //only implemented in the regions with dwell time limitations, elsewhere the command is not answered
+                    if matches!(self.region, Region::AS923 | Region::AU915_928) {
+                        self.downlink_dwell_time = *downlink_dwell_time;
+                        self.uplink_dwell_time = *uplink_dwell_time;
+                        self.max_eirp = max_eirp.dbm();
+                        self.pending_answers.push(EDMacCommands::TxParamSetupAns);
+                    } else {
+                        ignored.push(commands[i].clone());
+                    }
+                },
+                NCMacCommands::DlChannelReq { ch_index, freq } => {
+                    let frequency = freq * 100;
+                    let index = *ch_index as usize;
+                    let uplink_frequency_exists = !self.region.has_fixed_channel_plan() && self.channels.channel(index).is_some();
+                    let channel_frequency_ok = !self.region.has_fixed_channel_plan() && self.region.is_valid_frequency(frequency);
+                    if uplink_frequency_exists && channel_frequency_ok {
+                        self.dl_frequencies[index] = Some(frequency);
+                    }
+                    self.sticky_answers.push(EDMacCommands::DlChannelAns { uplink_frequency_exists, channel_frequency_ok });
+                },
+                NCMacCommands::DeviceTimeAns { epoch, second_fraction } => {
+                    self.gps_time = Some(Duration::from_secs(*epoch as u64) + Duration::from_secs_f64(*second_fraction as f64 / 256.0));
+                },
+                NCMacCommands::ForceRejoinReq { period, max_retries, rejoin_type, dr } => {
+                    //0 and 1 both request a type 0 rejoin, 3 to 7 are RFU and the command is ignored
+                    let forced_type = match *rejoin_type {
+                        0 | 1 => Some(RejoinType::Type0),
+                        2 => Some(RejoinType::Type2),
+                        _ => None,
+                    };
+                    match forced_type {
+                        Some(rejoin_type) => self.forced_rejoin = Some(ForcedRejoin {
+                            rejoin_type,
+                            period: Duration::from_secs(32 << (*period & 0b111)),
+                            max_retries: (*max_retries).min(7),
+                            data_rate: DataRate::new(*dr),
+                        }),
+                        None => ignored.push(commands[i].clone()),
+                    }
+                },
+                NCMacCommands::RejoinParamSetupReq { max_time_n, max_count_n } => {
+                    self.rejoin_parameters = Some((*max_time_n & 0x0F, *max_count_n & 0x0F));
+                    self.pending_answers.push(EDMacCommands::RejoinParamSetupAns { time_ack: true });
+                },
+                c => ignored.push(c.clone()),
+            }
+            i += 1;
+        }
+        ignored
+    }
+
+    ///A contiguous block of LinkADRReq is applied atomically, every request of the block gets the same answer
+    fn handle_link_adr_block(&mut self, block: &[NCMacCommands]) {
+        let mut channels = self.channels;
+        let mut channel_mask_ack = true;
+        let (mut data_rate, mut tx_power, mut nb_trans) = (self.data_rate, self.tx_power, self.nb_trans);
+
+        for c in block {
+            if let NCMacCommands::LinkADRReq { data_rate_tx_power, ch_mask, redundancy } = c {
+                channel_mask_ack &= channels.apply_ch_mask(redundancy.ch_mask_cntl(), *ch_mask).is_ok();
+                if data_rate_tx_power.data_rate() != DataRateTxPower::UNCHANGED { data_rate = DataRate::new(data_rate_tx_power.data_rate()) }
+                if data_rate_tx_power.tx_power() != DataRateTxPower::UNCHANGED { tx_power = data_rate_tx_power.tx_power() }
+                if redundancy.nb_trans() != 0 { nb_trans = redundancy.nb_trans() }
+            }
+        }
+
+        let enabled = channels.enabled_channels();
+        channel_mask_ack &= !enabled.is_empty();
+        let data_rate_ack = self.region.uplink_data_rates().contains(&data_rate) && enabled.iter().any(|c| c.supports(data_rate));
+        let power_ack = tx_power <= self.region.max_tx_power_index();
+
+        if channel_mask_ack && data_rate_ack && power_ack {
+            self.channels = channels;
+            self.data_rate = data_rate;
+            self.tx_power = tx_power;
+            self.nb_trans = nb_trans;
+        }
+        for _ in block {
+            self.pending_answers.push(EDMacCommands::LinkADRAns { power_ack, data_rate_ack, channel_mask_ack });
+        }
+    }
+
+    fn handle_new_channel(&mut self, ch_index: u8, frequency: u32, min_dr: u8, max_dr: u8) -> EDMacCommands {
+        if self.region.has_fixed_channel_plan() {
+            return EDMacCommands::NewChannelAns { data_range_ok: false, channel_frequency_ok: false };
+        }
+        let uplink_drs = self.region.uplink_data_rates();
+        let data_range_ok = frequency == 0 || (min_dr <= max_dr && uplink_drs.contains(&DataRate::new(min_dr)) && uplink_drs.contains(&DataRate::new(max_dr)));
+        let mut channel_frequency_ok = frequency == 0 || self.region.is_valid_frequency(frequency);
+
+        if data_range_ok && channel_frequency_ok {
+            let channel = if frequency == 0 { None } else { Some(Channel::new(frequency, DataRate::new(min_dr), DataRate::new(max_dr))) };
+            if self.channels.set_channel(ch_index as usize, channel).is_ok() {
+                if channel.is_none() {
+                    self.dl_frequencies[ch_index as usize] = None;
+                }
+            } else {
+                channel_frequency_ok = false;
+            }
+        }
+        EDMacCommands::NewChannelAns { data_range_ok, channel_frequency_ok }
+    }
+}
+
+impl Default for MacLayer {
+    fn default() -> Self {
+        Self::new(Region::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lorawan::lorawan_packet::fields::{ChMaskCntl, MaxEIRP, Redundancy};
+
+    fn link_adr_req(data_rate: u8, tx_power: u8, ch_mask: u16, ch_mask_cntl: u8, nb_trans: u8) -> NCMacCommands {
+        NCMacCommands::LinkADRReq {
+            data_rate_tx_power: DataRateTxPower::new(data_rate, tx_power).unwrap(),
+            ch_mask,
+            redundancy: Redundancy::new(ChMaskCntl::new(ch_mask_cntl).unwrap(), nb_trans).unwrap(),
+        }
+    }
+
+    #[test]
+    fn link_adr_block() {
+        let mut mac = MacLayer::new(Region::EU863_870);
+        let commands = [
+            link_adr_req(3, 2, 0b011, 0, 2),
+        ];
+        mac.handle_commands(&commands, 0.0);
+        assert_eq!(mac.data_rate(), DataRate::DR3);
+        assert_eq!(mac.tx_power_dbm(), 12);
+        assert_eq!(mac.nb_trans(), 2);
+        assert_eq!(mac.channels().enabled_channels().len(), 2);
+        assert_eq!(mac.take_answers(), vec![EDMacCommands::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: true }]);
+
+        //channel 5 is not defined, nothing is applied
+        let commands = [
+            link_adr_req(5, DataRateTxPower::UNCHANGED, 0b100000, 0, 0),
+        ];
+        mac.handle_commands(&commands, 0.0);
+        assert_eq!(mac.data_rate(), DataRate::DR3);
+        assert_eq!(mac.take_answers(), vec![EDMacCommands::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: false }]);
+
+        //ChMaskCntl 5 keeps the second sub-band, channels 8 to 15 and 65
+        let mut mac = MacLayer::new(Region::US902_928);
+        mac.handle_commands(&[link_adr_req(DataRateTxPower::UNCHANGED, DataRateTxPower::UNCHANGED, 0b10, 5, 0)], 0.0);
+        assert_eq!(mac.take_answers(), vec![EDMacCommands::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: true }]);
+        let enabled: Vec<usize> = (0..72).filter(|i| mac.channels().is_enabled(*i)).collect();
+        assert_eq!(enabled, (8..16).chain([65]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sticky_answers() {
+        let mut mac = MacLayer::new(Region::EU863_870);
+        let commands = [
+            NCMacCommands::RXParamSetupReq { dl_settings: DLSettings::new(false, 2, 3).unwrap(), freq: 8_695_250 },
+            NCMacCommands::RXTimingSetupReq(RxDelay::new(3).unwrap()),
+            NCMacCommands::DevStatusReq,
+        ];
+        mac.handle_commands(&commands, -5.2);
+        assert_eq!(mac.rx1_dr_offset(), 2);
+        assert_eq!(mac.rx2_data_rate(), DataRate::DR3);
+        assert_eq!(mac.rx1_delay(), Duration::from_secs(3));
+
+        let answers = mac.take_answers();
+        assert_eq!(answers.len(), 3);
+        assert!(answers.contains(&EDMacCommands::DevStatusAns { battery: 255, margin: 0b00111011 }));
+        assert_eq!(mac.take_answers().len(), 2);
+
+        mac.downlink_received();
+        assert!(mac.take_answers().is_empty());
+    }
+
+    #[test]
+    fn rx_windows() {
+        let mut mac = MacLayer::new(Region::EU863_870);
+        mac.set_data_rate(DataRate::DR5);
+        let uplink = mac.select_uplink_channel().unwrap();
+        assert_eq!(uplink.frequency(), 868_300_000);
+
+        let [rx1, rx2] = mac.join_accept_windows();
+        assert_eq!((rx1.delay, rx1.frequency, rx1.data_rate), (JOIN_ACCEPT_DELAY1, 868_300_000, DataRate::DR5));
+        assert_eq!((rx2.delay, rx2.frequency, rx2.data_rate), (JOIN_ACCEPT_DELAY2, 869_525_000, DataRate::DR0));
+
+        //the RFU bits of RXTimingSetupReq are ignored
+        let mut commands = vec![NCMacCommands::RXParamSetupReq { dl_settings: DLSettings::new(false, 2, 3).unwrap(), freq: 8_695_250 }];
+        commands.extend(NCMacCommands::from_bytes(&[0x08, 0xF3]).unwrap());
+        mac.handle_commands(&commands, 0.0);
+        let [rx1, rx2] = mac.rx_windows();
+        assert_eq!((rx1.delay, rx1.frequency, rx1.data_rate), (Duration::from_secs(3), 868_300_000, DataRate::DR3));
+        assert_eq!((rx2.delay, rx2.frequency, rx2.data_rate), (Duration::from_secs(4), 869_525_000, DataRate::DR3));
+    }
+
+    #[test]
+    fn rejoin_commands() {
+        let mut mac = MacLayer::new(Region::EU863_870);
+        assert!(!mac.periodic_rejoin_due());
+
+        let commands = [
+            NCMacCommands::RejoinParamSetupReq { max_time_n: 15, max_count_n: 0 },
+            NCMacCommands::ForceRejoinReq { period: 1, max_retries: 2, rejoin_type: 2, dr: 3 },
+        ];
+        mac.handle_commands(&commands, 0.0);
+        assert_eq!(mac.take_answers(), vec![EDMacCommands::RejoinParamSetupAns { time_ack: true }]);
+        assert_eq!(mac.take_forced_rejoin(), Some(ForcedRejoin { rejoin_type: RejoinType::Type2, period: Duration::from_secs(64), max_retries: 2, data_rate: DataRate::DR3 }));
+        assert_eq!(mac.take_forced_rejoin(), None);
+
+        mac.handle_commands(&[NCMacCommands::ForceRejoinReq { period: 0, max_retries: 0, rejoin_type: 1, dr: 0 }], 0.0);
+        assert_eq!(mac.take_forced_rejoin().map(|f| f.rejoin_type), Some(RejoinType::Type0));
+        let rfu = NCMacCommands::ForceRejoinReq { period: 0, max_retries: 0, rejoin_type: 3, dr: 0 };
+        //a class A device does not handle the class B commands
+        assert_eq!(mac.handle_commands(&[rfu.clone(), NCMacCommands::PingSlotInfoAns], 0.0), vec![rfu, NCMacCommands::PingSlotInfoAns]);
+        //nor the commands without effect in its region
+        let unhandled = [
+            NCMacCommands::ADRParamSetupReq { limit_exp: 1, delay_exp: 1 },
+            NCMacCommands::TxParamSetupReq { downlink_dwell_time: true, uplink_dwell_time: true, max_eirp: MaxEIRP::new(5).unwrap() },
+            NCMacCommands::RekeyConf(1),
+        ];
+        assert_eq!(mac.handle_commands(&unhandled, 0.0), unhandled);
+        assert!(!mac.has_answers());
+        assert_eq!(mac.take_forced_rejoin(), None);
+
+        for _ in 0..15 {
+            mac.uplink_sent();
+        }
+        assert!(!mac.periodic_rejoin_due());
+        mac.uplink_sent();
+        assert!(mac.periodic_rejoin_due());
+        mac.rejoin_sent();
+        assert!(!mac.periodic_rejoin_due());
+    }
+
+    #[test]
+    fn new_channel() {
+        let mut mac = MacLayer::new(Region::EU863_870);
+        mac.handle_commands(&[
+            NCMacCommands::NewChannelReq { ch_index: 3, freq: 8_671_000, max_dr: 5, min_dr: 0 },
+            NCMacCommands::NewChannelReq { ch_index: 1, freq: 8_671_000, max_dr: 5, min_dr: 0 },
+        ], 0.0);
+        assert_eq!(mac.channels().enabled_channels().len(), 4);
+        assert_eq!(mac.take_answers(), vec![
+            EDMacCommands::NewChannelAns { data_range_ok: true, channel_frequency_ok: true },
+            EDMacCommands::NewChannelAns { data_range_ok: true, channel_frequency_ok: false },
+        ]);
+    }
+}
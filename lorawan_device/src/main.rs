@@ -218,12 +218,14 @@ mod test {
                 code_rate: CodeRate::CR4_5,
                 starting_power: 14.0,
                 uplink: true,
+                gateway_tmst: None,
                 payload: vec![1,2,3,4,5,6,7],
             },
             arrival_stats: ArrivalStats {
                 time: 1234567890,
                 rssi: -20.0,
                 snr: 20.0,
+                gateway_tmst: None,
             },
         };
 
@@ -268,7 +270,7 @@ mod test {
     async fn test() {
         let mut ld = DebugDevice::from(LoRaWANDevice::new(create_initialized_device(), MockCommunicator));         
         for _ in 0..1000 {
-            let uplink = ld.create_uplink(Some("###  confirmed 5 message  ###".as_bytes()), false, Some(1), None).unwrap();
+            let uplink = ld.create_uplink(Some("###  confirmed 5 message  ###".as_bytes()), false, Some(1), None, &Default::default()).unwrap();
     
             match LoRaWANPacket::from_bytes(&uplink, Some(&*ld), true) {
                 Ok(_) => {
@@ -0,0 +1,121 @@
+use std::{fs::File, io::{self, Write}, path::Path, sync::Mutex};
+
+use crate::{clock::now_ms, communicator::{ArrivalStats, Transmission}};
+
+///LINKTYPE_LORATAP of the pcap format
+const LINKTYPE_LORATAP: u32 = 270;
+///Length of a version 0 LoRaTap header
+const LORATAP_HEADER_LENGTH: u16 = 15;
+///Sync word of public LoRaWAN networks
+const LORAWAN_SYNC_WORD: u8 = 0x34;
+///LoRaTap RSSIs are offsets from this, in dBm
+const LORATAP_RSSI_OFFSET: f32 = -139.0;
+
+///LoRaTap header of a frame: channel, RSSI and SNR, multi-byte fields in network order. Sent frames have no RSSI nor SNR
+fn loratap_header(transmission: &Transmission, arrival: Option<&ArrivalStats>) -> [u8; LORATAP_HEADER_LENGTH as usize] {
+    let snr = arrival.map_or(0.0, |a| a.snr);
+    //with a positive SNR the packet RSSI has a 16/15 scale
+    let rssi = arrival.map_or(0, |a| {
+        let rssi = a.rssi - LORATAP_RSSI_OFFSET;
+        let rssi = if snr >= 0.0 { rssi * 15.0 / 16.0 } else { rssi };
+        rssi.round().clamp(0.0, 255.0) as u8
+    });
+    let [length_high, length_low] = LORATAP_HEADER_LENGTH.to_be_bytes();
+    let [f0, f1, f2, f3] = (transmission.frequency.round() as u32).to_be_bytes();
+    [
+        0, 0, length_high, length_low,
+        f0, f1, f2, f3,
+        (transmission.bandwidth.khz() / 125.0) as u8,
+        transmission.spreading_factor.value(),
+        rssi, rssi, rssi,
+        (snr * 4.0).round().clamp(i8::MIN as f32, i8::MAX as f32) as i8 as u8,
+        LORAWAN_SYNC_WORD,
+    ]
+}
+
+///pcap capture of LoRa frames with LoRaTap headers, Wireshark decodes them with its LoRaWAN dissector.
+///Every frame is written at once, the file can be followed while it grows
+pub struct PcapWriter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl PcapWriter {
+    pub fn new(mut writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(0xa1b2c3d4u32.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        //UTC timestamps, no accuracy given
+        header.extend([0; 8]);
+        header.extend((u16::MAX as u32).to_le_bytes());
+        header.extend(LINKTYPE_LORATAP.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer: Mutex::new(Box::new(writer)) })
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    ///Timestamped with the arrival time of received frames and with the clock of the crate for sent ones
+    pub fn write(&self, transmission: &Transmission, arrival: Option<&ArrivalStats>) -> io::Result<()> {
+        let time = arrival.map_or_else(now_ms, |a| a.time);
+        let length = (LORATAP_HEADER_LENGTH as usize + transmission.payload.len()) as u32;
+        let mut record = Vec::with_capacity(16 + length as usize);
+        record.extend(((time / 1000) as u32).to_le_bytes());
+        record.extend((((time % 1000) * 1000) as u32).to_le_bytes());
+        record.extend(length.to_le_bytes());
+        record.extend(length.to_le_bytes());
+        record.extend(loratap_header(transmission, arrival));
+        record.extend(&transmission.payload);
+        self.writer.lock().unwrap().write_all(&record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+
+    use super::*;
+
+    ///Keeps what the writer wrote readable by the test
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn loratap_records() {
+        let buffer = Shared::default();
+        let pcap = PcapWriter::new(buffer.clone()).unwrap();
+        let transmission = Transmission {
+            frequency: 868_300_000.0,
+            spreading_factor: SpreadingFactor::SF9,
+            bandwidth: LoRaBandwidth::BW250,
+            payload: vec![0x40, 1, 2, 3, 4],
+            ..Default::default()
+        };
+        pcap.write(&transmission, Some(&ArrivalStats { time: 1_700_000_000_123, rssi: -119.0, snr: -7.25, ..Default::default() })).unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        assert_eq!(bytes[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(bytes[20..24], 270u32.to_le_bytes());
+        let record = &bytes[24..];
+        assert_eq!(record[..4], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(record[4..8], 123_000u32.to_le_bytes());
+        assert_eq!(record[8..12], 20u32.to_le_bytes());
+        //868.3 MHz, 250 kHz, SF9, -119 dBm, -7.25 dB, LoRaWAN sync word
+        assert_eq!(record[16..31], [0, 0, 0, 15, 0x33, 0xc1, 0x34, 0xe0, 2, 9, 20, 20, 20, 0xe3, 0x34]);
+        assert_eq!(record[31..], [0x40, 1, 2, 3, 4]);
+    }
+}
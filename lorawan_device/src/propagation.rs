@@ -0,0 +1,333 @@
+use std::{f32::consts::PI, io::{Error, ErrorKind}, sync::{Arc, Mutex}};
+
+use lorawan::physical_parameters::LoRaBandwidth;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{communicator::{Position, Transmission}, trace::{read_trace, TraceRecord}};
+
+///Log-distance defaults: free space loss at 1 m at 868 MHz and exponent of a suburban environment
+pub const REFERENCE_LOSS: f32 = 31.2;
+pub const PATH_LOSS_EXPONENT: f32 = 2.7;
+///Noise figure of the receivers in dB
+pub const NOISE_FIGURE: f32 = 6.0;
+///The SX1301 does not report a better SNR
+pub const MAX_SNR: f32 = 13.5;
+
+///Thermal noise in dBm over the bandwidth, including the noise figure of the receiver
+pub fn noise_floor(bandwidth: LoRaBandwidth) -> f32 {
+    -174.0 + 10.0 * bandwidth.hz().log10() + NOISE_FIGURE
+}
+
+///RSSI and SNR in dB of the transmission at the receiver position
+pub fn link_budget(model: &dyn PropagationModel, transmission: &Transmission, receiver: &Position) -> (f32, f32) {
+    let rssi = transmission.starting_power - model.path_loss(&transmission.start_position, receiver, transmission.frequency);
+    (rssi, (rssi - noise_floor(transmission.bandwidth)).min(MAX_SNR))
+}
+
+pub trait PropagationModel: Send + Sync {
+    ///Loss in dB between two positions in meters for a carrier at frequency Hz
+    fn path_loss(&self, tx: &Position, rx: &Position, frequency: f64) -> f32;
+}
+
+///Gaussian loss variation in dB added to every frame
+struct Shadowing {
+    sigma: f32,
+    rng: Mutex<StdRng>,
+}
+
+impl Shadowing {
+    fn new(sigma: f32, seed: Option<u64>) -> Self {
+        Self {
+            sigma,
+            rng: Mutex::new(seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64)),
+        }
+    }
+
+    ///Box-Muller transform
+    fn sample(&self, sigma: f32) -> f32 {
+        let mut rng = self.rng.lock().unwrap();
+        let u1 = 1.0 - rng.gen::<f32>();
+        let u2 = rng.gen::<f32>();
+        sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+///Distances under 1 m are not meaningful for any model
+fn distance(tx: &Position, rx: &Position) -> f32 {
+    tx.distance(rx).max(1.0)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreeSpace;
+
+impl PropagationModel for FreeSpace {
+    fn path_loss(&self, tx: &Position, rx: &Position, frequency: f64) -> f32 {
+        20.0 * distance(tx, rx).log10() + 20.0 * (frequency as f32).log10() - 147.55
+    }
+}
+
+///loss = reference_loss + 10 * exponent * log10(d / 1 m) + N(0, sigma)
+pub struct LogDistance {
+    reference_loss: f32,
+    exponent: f32,
+    shadowing: Option<Shadowing>,
+}
+
+impl Default for LogDistance {
+    fn default() -> Self {
+        Self::new(REFERENCE_LOSS, PATH_LOSS_EXPONENT)
+    }
+}
+
+impl LogDistance {
+    pub fn new(reference_loss: f32, exponent: f32) -> Self {
+        Self {
+            reference_loss,
+            exponent,
+            shadowing: None,
+        }
+    }
+
+    ///Log-normal shadowing with the given standard deviation, a seed makes the sequence reproducible
+    pub fn with_shadowing(self, sigma: f32, seed: Option<u64>) -> Self {
+        Self {
+            shadowing: (sigma > 0.0).then(|| Shadowing::new(sigma, seed)),
+            ..self
+        }
+    }
+
+    ///Least squares fit of the records with a distance, sent at tx_power dBm. The standard deviation of the residuals is kept as shadowing sigma
+    pub fn fit(records: &[TraceRecord], tx_power: f32, seed: Option<u64>) -> Option<Self> {
+        let points: Vec<(f64, f64)> = records.iter()
+            .filter_map(|r| r.distance.map(|d| ((d.max(1.0) as f64).log10(), (tx_power - r.rssi) as f64)))
+            .collect();
+        let n = points.len() as f64;
+        let (sx, sy) = points.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let (mx, my) = (sx / n, sy / n);
+        let sxx: f64 = points.iter().map(|(x, _)| (x - mx).powi(2)).sum();
+        if points.len() < 2 || sxx == 0.0 {
+            return None;
+        }
+        let slope = points.iter().map(|(x, y)| (x - mx) * (y - my)).sum::<f64>() / sxx;
+        let intercept = my - slope * mx;
+        let sigma = (points.iter().map(|(x, y)| (y - intercept - slope * x).powi(2)).sum::<f64>() / n).sqrt();
+        Some(Self::new(intercept as f32, (slope / 10.0) as f32).with_shadowing(sigma as f32, seed))
+    }
+
+    pub fn reference_loss(&self) -> f32 {
+        self.reference_loss
+    }
+
+    pub fn exponent(&self) -> f32 {
+        self.exponent
+    }
+
+    pub fn shadowing_sigma(&self) -> f32 {
+        self.shadowing.as_ref().map_or(0.0, |s| s.sigma)
+    }
+}
+
+impl PropagationModel for LogDistance {
+    fn path_loss(&self, tx: &Position, rx: &Position, _frequency: f64) -> f32 {
+        let loss = self.reference_loss + 10.0 * self.exponent * distance(tx, rx).log10();
+        loss + self.shadowing.as_ref().map_or(0.0, |s| s.sample(s.sigma))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HataEnvironment {
+    ///small or medium sized city
+    Urban,
+    Suburban,
+}
+
+///Okumura-Hata model, defined for 150-1500 MHz, antenna heights in meters
+#[derive(Debug, Clone, Copy)]
+pub struct OkumuraHata {
+    pub environment: HataEnvironment,
+    pub gateway_height: f32,
+    pub device_height: f32,
+}
+
+impl PropagationModel for OkumuraHata {
+    fn path_loss(&self, tx: &Position, rx: &Position, frequency: f64) -> f32 {
+        let f = (frequency / 1_000_000.0) as f32;
+        let d = distance(tx, rx) / 1000.0;
+        let hb = self.gateway_height;
+        let a_hm = (1.1 * f.log10() - 0.7) * self.device_height - (1.56 * f.log10() - 0.8);
+        let urban = 69.55 + 26.16 * f.log10() - 13.82 * hb.log10() - a_hm + (44.9 - 6.55 * hb.log10()) * d.log10();
+        match self.environment {
+            HataEnvironment::Urban => urban,
+            HataEnvironment::Suburban => urban - 2.0 * (f / 28.0).log10().powi(2) - 5.4,
+        }
+    }
+}
+
+///Mean loss and standard deviation measured in distance bins of a trace, interpolated on log10(d)
+pub struct Empirical {
+    ///log10 of the distance, mean loss and standard deviation of each bin
+    bins: Vec<(f32, f32, f32)>,
+    shadowing: Option<Shadowing>,
+}
+
+impl Empirical {
+    ///Bins with the same number of records each, sent at tx_power dBm
+    pub fn fit(records: &[TraceRecord], tx_power: f32, bins: usize) -> Option<Self> {
+        let mut points: Vec<(f32, f32)> = records.iter()
+            .filter_map(|r| r.distance.map(|d| (d.max(1.0).log10(), tx_power - r.rssi)))
+            .collect();
+        if bins == 0 || points.len() < bins {
+            return None;
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let size = points.len().div_ceil(bins);
+        let bins = points.chunks(size).map(|chunk| {
+            let n = chunk.len() as f32;
+            let x = chunk.iter().map(|p| p.0).sum::<f32>() / n;
+            let mean = chunk.iter().map(|p| p.1).sum::<f32>() / n;
+            let sigma = (chunk.iter().map(|p| (p.1 - mean).powi(2)).sum::<f32>() / n).sqrt();
+            (x, mean, sigma)
+        }).collect();
+        Some(Self { bins, shadowing: None })
+    }
+
+    ///Add the standard deviation of the bins as shadowing
+    pub fn with_shadowing(self, seed: Option<u64>) -> Self {
+        Self {
+            shadowing: Some(Shadowing::new(0.0, seed)),
+            ..self
+        }
+    }
+
+    ///Mean loss and standard deviation at log10(d), extrapolated with the slope of the first and last bins
+    fn interpolate(&self, x: f32) -> (f32, f32) {
+        if self.bins.len() == 1 {
+            return (self.bins[0].1, self.bins[0].2);
+        }
+        let i = self.bins.iter().position(|b| b.0 >= x).unwrap_or(self.bins.len() - 1).clamp(1, self.bins.len() - 1);
+        let ((x0, m0, s0), (x1, m1, s1)) = (self.bins[i - 1], self.bins[i]);
+        let t = if x1 > x0 { (x - x0) / (x1 - x0) } else { 0.0 };
+        (m0 + t * (m1 - m0), (s0 + t.clamp(0.0, 1.0) * (s1 - s0)).max(0.0))
+    }
+}
+
+impl PropagationModel for Empirical {
+    fn path_loss(&self, tx: &Position, rx: &Position, _frequency: f64) -> f32 {
+        let (mean, sigma) = self.interpolate(distance(tx, rx).log10());
+        mean + self.shadowing.as_ref().map_or(0.0, |s| s.sample(sigma))
+    }
+}
+
+///Propagation model of a scenario
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "model")]
+pub enum PropagationConfig {
+    FreeSpace,
+    LogDistance {
+        reference_loss: f32,
+        exponent: f32,
+        #[serde(default)]
+        shadowing_sigma: f32,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    OkumuraHata {
+        environment: HataEnvironment,
+        gateway_height: f32,
+        device_height: f32,
+    },
+    ///log-distance fitted on the Distance and RSSI columns of a trace
+    FittedLogDistance {
+        trace: String,
+        tx_power: f32,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    Empirical {
+        trace: String,
+        tx_power: f32,
+        bins: usize,
+        #[serde(default)]
+        shadowing: bool,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+}
+
+impl Default for PropagationConfig {
+    fn default() -> Self {
+        PropagationConfig::LogDistance {
+            reference_loss: REFERENCE_LOSS,
+            exponent: PATH_LOSS_EXPONENT,
+            shadowing_sigma: 0.0,
+            seed: None,
+        }
+    }
+}
+
+impl PropagationConfig {
+    pub fn build(&self) -> Result<Arc<dyn PropagationModel>, Error> {
+        let not_enough = |trace: &str| Error::new(ErrorKind::InvalidData, format!("{trace} has not enough records with a distance"));
+        Ok(match self {
+            PropagationConfig::FreeSpace => Arc::new(FreeSpace),
+            PropagationConfig::LogDistance { reference_loss, exponent, shadowing_sigma, seed } => {
+                Arc::new(LogDistance::new(*reference_loss, *exponent).with_shadowing(*shadowing_sigma, *seed))
+            },
+            PropagationConfig::OkumuraHata { environment, gateway_height, device_height } => {
+                Arc::new(OkumuraHata { environment: *environment, gateway_height: *gateway_height, device_height: *device_height })
+            },
+            PropagationConfig::FittedLogDistance { trace, tx_power, seed } => {
+                Arc::new(LogDistance::fit(&read_trace(trace)?, *tx_power, *seed).ok_or_else(|| not_enough(trace))?)
+            },
+            PropagationConfig::Empirical { trace, tx_power, bins, shadowing, seed } => {
+                let model = Empirical::fit(&read_trace(trace)?, *tx_power, *bins).ok_or_else(|| not_enough(trace))?;
+                Arc::new(if *shadowing { model.with_shadowing(*seed) } else { model })
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Position {
+        Position { x, y: 0.0, z: 0.0 }
+    }
+
+    #[test]
+    fn models() {
+        let origin = Position::default();
+        assert!((FreeSpace.path_loss(&origin, &at(1000.0), 868_000_000.0) - 91.2).abs() < 0.1);
+        assert!((LogDistance::default().path_loss(&origin, &at(10.0), 868_000_000.0) - 58.2).abs() < 0.01);
+
+        let urban = OkumuraHata { environment: HataEnvironment::Urban, gateway_height: 30.0, device_height: 1.5 };
+        let suburban = OkumuraHata { environment: HataEnvironment::Suburban, ..urban };
+        let loss = urban.path_loss(&origin, &at(1000.0), 868_000_000.0);
+        assert!((loss - 126.0).abs() < 0.1, "{loss}");
+        assert!(suburban.path_loss(&origin, &at(1000.0), 868_000_000.0) < loss);
+
+        let seeded = || LogDistance::default().with_shadowing(8.0, Some(42));
+        let (a, b) = (seeded(), seeded());
+        let losses: Vec<f32> = (0..5).map(|_| a.path_loss(&origin, &at(500.0), 868_000_000.0)).collect();
+        assert_eq!(losses, (0..5).map(|_| b.path_loss(&origin, &at(500.0), 868_000_000.0)).collect::<Vec<f32>>());
+        assert!(losses.windows(2).any(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn fit_gateway_trace() {
+        let records = read_trace("../gateway.csv").unwrap();
+        //the trace follows a 3.76 exponent, but a fifth of the frames were received 4 dB stronger
+        let fitted = LogDistance::fit(&records, 14.0, None).unwrap();
+        assert!((fitted.exponent() - 3.76).abs() < 0.3, "{}", fitted.exponent());
+        assert!(fitted.shadowing_sigma() > 1.0 && fitted.shadowing_sigma() < 2.0, "{}", fitted.shadowing_sigma());
+
+        let empirical = Empirical::fit(&records, 14.0, 20).unwrap();
+        let record = &records[1];
+        let transmission = Transmission { starting_power: 14.0, frequency: record.frequency, bandwidth: record.bandwidth, ..Default::default() };
+        let (rssi, snr) = link_budget(&empirical, &transmission, &at(record.distance.unwrap()));
+        assert!((rssi - record.rssi).abs() < 1.0, "{rssi} {}", record.rssi);
+        assert!((snr - record.snr).abs() < 1.0, "{snr} {}", record.snr);
+    }
+}
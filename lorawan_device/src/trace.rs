@@ -0,0 +1,72 @@
+use std::{collections::HashMap, fs::File, io::{BufRead, BufReader, Error, ErrorKind}, path::Path};
+
+use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+
+///A reception recorded by a gateway, one line of a trace like gateway.csv:
+///Time,DeviceAddress,GatewayNode,FrequencyHz,SF,BW,RSSI,SNR[,Distance][,Jammer]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    ///seconds from the beginning of the capture
+    pub time: f64,
+    pub dev_addr: [u8; 4],
+    pub gateway: u32,
+    ///frequency in Hz, some traces use MHz and are converted
+    pub frequency: f64,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: LoRaBandwidth,
+    pub rssi: f32,
+    pub snr: f32,
+    ///meters between the device and the gateway, if recorded
+    pub distance: Option<f32>,
+    ///true if the frame was sent by a jammer
+    pub jammer: Option<bool>,
+}
+
+fn invalid(line: usize, what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {line}: invalid {what}"))
+}
+
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Vec<TraceRecord>, Error> {
+    parse_trace(BufReader::new(File::open(path)?))
+}
+
+///Columns are matched by name, the order does not matter
+pub fn parse_trace(reader: impl BufRead) -> Result<Vec<TraceRecord>, Error> {
+    let mut lines = reader.lines();
+    let header = lines.next().ok_or_else(|| invalid(1, "header"))??;
+    let columns: HashMap<&str, usize> = header.trim().split(',').enumerate().map(|(i, c)| (c, i)).collect();
+    let column = |name: &str| columns.get(name).copied().ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("missing column {name}")));
+    let (time, dev_addr, gateway, frequency, sf, bw, rssi, snr) = (column("Time")?, column("DeviceAddress")?, column("GatewayNode")?, column("FrequencyHz")?, column("SF")?, column("BW")?, column("RSSI")?, column("SNR")?);
+    let (distance, jammer) = (column("Distance").ok(), column("Jammer").ok());
+
+    let mut ret = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line_number = i + 2;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        let field = |index: usize, what: &str| fields.get(index).copied().ok_or_else(|| invalid(line_number, what));
+        let number = |index: usize, what: &str| field(index, what)?.parse::<f64>().map_err(|_| invalid(line_number, what));
+
+        let frequency = number(frequency, "frequency")?;
+        let sf = number(sf, "spreading factor")? as u8;
+        if !(7..=12).contains(&sf) {
+            return Err(invalid(line_number, "spreading factor"));
+        }
+        ret.push(TraceRecord {
+            time: number(time, "time")?,
+            dev_addr: u32::from_str_radix(field(dev_addr, "device address")?, 16).map_err(|_| invalid(line_number, "device address"))?.to_be_bytes(),
+            gateway: number(gateway, "gateway")? as u32,
+            frequency: if frequency < 1_000_000.0 { frequency * 1_000_000.0 } else { frequency },
+            spreading_factor: SpreadingFactor::new(sf),
+            bandwidth: LoRaBandwidth::from(number(bw, "bandwidth")? as f32),
+            rssi: number(rssi, "RSSI")? as f32,
+            snr: number(snr, "SNR")? as f32,
+            distance: distance.map(|d| number(d, "distance")).transpose()?.map(|d| d as f32),
+            jammer: jammer.map(|j| number(j, "jammer")).transpose()?.map(|j| j != 0.0),
+        });
+    }
+    Ok(ret)
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, iter::once, sync::Mutex};
+
+use lorawan::{lorawan_packet::{fields::{ChMaskCntl, DataRateTxPower, Redundancy}, join::CFList, mac_commands::NCMacCommands}, physical_parameters::DataRate, regional_parameters::{channel_list::ChannelList, region::{Channel, Region}}, utils::errors::LoRaWANError};
+
+use super::{circular_buffer::CircularBuffer, mac_handler::UplinkMetadata};
+
+///Number of uplinks the ADR decision is based on
+pub const ADR_HISTORY_SIZE: usize = 20;
+///Safety margin in dB kept above the demodulation floor
+pub const DEFAULT_INSTALLATION_MARGIN: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdrUplinkRecord {
+    pub fcnt: u32,
+    pub snr: f32,
+    pub gw_cnt: u8,
+}
+
+#[derive(Debug, Default)]
+struct DeviceAdrState {
+    history: CircularBuffer<AdrUplinkRecord, ADR_HISTORY_SIZE>,
+    ///TXPower index of the last LinkADRReq the device accepted, devices start at 0 (max power)
+    tx_power: u8,
+    ///channels enabled on the device by the JoinAccept and the acknowledged NewChannelReq and LinkADRReq, the regional defaults if unknown
+    channels: Option<ChannelList>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AdrDecision {
+    ///block of LinkADRReq, the device applies it as a whole
+    pub commands: Vec<NCMacCommands>,
+    ///ADRACKReq was set, the device expects a downlink to be sure it is still connected
+    pub force_downlink: bool,
+}
+
+///Network side ADR, based on the Semtech reference algorithm: the best SNR of the last uplinks
+///minus the required SNR and the installation margin is spent in 3 dB steps, first raising the data rate then lowering the power
+#[derive(Debug)]
+pub struct AdrEngine {
+    installation_margin: f32,
+    states: Mutex<HashMap<[u8; 4], DeviceAdrState>>,
+}
+
+impl Default for AdrEngine {
+    fn default() -> Self {
+        Self::new(DEFAULT_INSTALLATION_MARGIN)
+    }
+}
+
+impl AdrEngine {
+    pub fn new(installation_margin: f32) -> Self {
+        Self {
+            installation_margin,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn history(&self, dev_addr: &[u8; 4]) -> Vec<AdrUplinkRecord> {
+        self.states.lock().unwrap().get(dev_addr).map_or(Vec::new(), |s| s.history.iter().copied().collect())
+    }
+
+    ///Channels the device transmits on, None if it has not been seen since the network controller started
+    pub fn channels(&self, dev_addr: &[u8; 4]) -> Option<ChannelList> {
+        self.states.lock().unwrap().get(dev_addr).and_then(|s| s.channels)
+    }
+
+    ///A JoinAccept starts a new session, the device goes back to the default channels plus the ones of the CFList
+    pub fn join_accepted(&self, dev_addr: [u8; 4], region: Region, cf_list: Option<CFList>) {
+        let mut channels = ChannelList::new(region);
+        if let Some(cf_list) = cf_list {
+            let _ = channels.apply_cf_list(&cf_list);
+        }
+        self.states.lock().unwrap().insert(dev_addr, DeviceAdrState { channels: Some(channels), ..Default::default() });
+    }
+
+    ///The device accepted a block of LinkADRReq, its channel mask and TXPower are the ones the block sets
+    pub fn link_adr_acked(&self, dev_addr: [u8; 4], requests: &[NCMacCommands]) {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(&dev_addr) else { return };
+        for r in requests {
+            if let NCMacCommands::LinkADRReq { data_rate_tx_power, ch_mask, redundancy } = r {
+                if let Some(channels) = state.channels.as_mut() {
+                    let _ = channels.apply_ch_mask(redundancy.ch_mask_cntl(), *ch_mask);
+                }
+                if data_rate_tx_power.tx_power() != DataRateTxPower::UNCHANGED {
+                    state.tx_power = data_rate_tx_power.tx_power();
+                }
+            }
+        }
+    }
+
+    ///The device accepted a NewChannelReq, the channel is added, replaced or removed (frequency 0) as the device does
+    pub fn new_channel_acked(&self, dev_addr: [u8; 4], request: &NCMacCommands) {
+        let mut states = self.states.lock().unwrap();
+        let Some(channels) = states.get_mut(&dev_addr).and_then(|s| s.channels.as_mut()) else { return };
+        if let NCMacCommands::NewChannelReq { ch_index, freq, max_dr, min_dr } = request {
+            let channel = if *freq == 0 { None } else { Some(Channel::new(freq * 100, DataRate::new(*min_dr), DataRate::new(*max_dr))) };
+            let _ = channels.set_channel(*ch_index as usize, channel);
+        }
+    }
+
+    pub fn register_uplink(&self, dev_addr: [u8; 4], region: &Region, fcnt: u32, adr: bool, adr_ack_req: bool, metadata: &UplinkMetadata) -> AdrDecision {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dev_addr).or_default();
+        let channels = *state.channels.get_or_insert_with(|| ChannelList::new(*region));
+        //every gateway forwards its copy of the frame, the history keeps one record per FCnt
+        match (&mut state.history).into_iter().find(|r| r.fcnt == fcnt) {
+            Some(record) => {
+                record.snr = record.snr.max(metadata.snr);
+                //copies of the same batch already count each other, a copy received alone adds its gateway
+                record.gw_cnt = if metadata.gw_cnt > 1 { record.gw_cnt.max(metadata.gw_cnt) } else { record.gw_cnt.saturating_add(1) };
+                return AdrDecision { commands: Vec::new(), force_downlink: adr_ack_req };
+            },
+            None => state.history.enqueue(AdrUplinkRecord { fcnt, snr: metadata.snr, gw_cnt: metadata.gw_cnt }),
+        }
+
+        let mut decision = AdrDecision { commands: Vec::new(), force_downlink: adr_ack_req };
+        if !adr || !state.history.is_full() {
+            return decision;
+        }
+
+        let current_dr = match region.data_rate_from(metadata.spreading_factor, metadata.bandwidth) {
+            Some(dr) => dr,
+            None => return decision,
+        };
+        let (data_rate, tx_power) = self.optimal_settings(region, current_dr, state.tx_power, &state.history);
+        if data_rate != current_dr || tx_power != state.tx_power {
+            match Self::link_adr_req(&channels, data_rate, tx_power, Self::nb_trans(&state.history)) {
+                Ok(commands) => decision.commands = commands,
+                Err(e) => {
+                    eprintln!("Cannot request {data_rate:?} and TXPower {tx_power}: {e}");
+                    return decision;
+                },
+            }
+            //the next decision must be based on uplinks sent with the new settings
+            state.history.clear();
+        }
+        decision
+    }
+
+    fn optimal_settings(&self, region: &Region, current_dr: DataRate, current_tx_power: u8, history: &CircularBuffer<AdrUplinkRecord, ADR_HISTORY_SIZE>) -> (DataRate, u8) {
+        let snr_max = history.iter().map(|r| r.snr).fold(f32::MIN, f32::max);
+        let required_snr = region.data_rate(current_dr).map_or(-20.0, |(sf, _)| sf.demodulation_floor());
+        let mut steps = ((snr_max - required_snr - self.installation_margin) / 3.0).floor() as i32;
+
+        //ADR only moves between LoRa data rates of the default channels
+        let max_dr = region.default_channels().first().map_or(current_dr, |c| c.max_dr());
+        let mut data_rate = current_dr;
+        let mut tx_power = current_tx_power;
+        while steps > 0 {
+            if data_rate.value() < max_dr.value() {
+                data_rate = DataRate::new(data_rate.value() + 1);
+            } else if tx_power < region.max_tx_power_index() {
+                tx_power += 1;
+            } else {
+                break;
+            }
+            steps -= 1;
+        }
+        while steps < 0 && tx_power > 0 {
+            tx_power -= 1;
+            steps += 1;
+        }
+        (data_rate, tx_power)
+    }
+
+    ///Packet error rate from the FCnt gaps of the history, mapped to the number of transmissions of each uplink
+    fn nb_trans(history: &CircularBuffer<AdrUplinkRecord, ADR_HISTORY_SIZE>) -> u8 {
+        let first = history.iter().map(|r| r.fcnt).min().unwrap_or(0);
+        let last = history.iter().map(|r| r.fcnt).max().unwrap_or(0);
+        let expected = (last - first + 1) as f32;
+        let per = 1.0 - history.len() as f32 / expected;
+        if per < 0.05 {
+            1
+        } else if per < 0.1 {
+            2
+        } else {
+            3
+        }
+    }
+
+    fn link_adr_req(channels: &ChannelList, data_rate: DataRate, tx_power: u8, nb_trans: u8) -> Result<Vec<NCMacCommands>, LoRaWANError> {
+        let data_rate_tx_power = DataRateTxPower::new(data_rate.value(), tx_power)?;
+        Self::keep_channels_masks(channels).into_iter().map(|(ch_mask_cntl, ch_mask)| Ok(NCMacCommands::LinkADRReq {
+            data_rate_tx_power,
+            ch_mask,
+            redundancy: Redundancy::new(ChMaskCntl::new(ch_mask_cntl)?, nb_trans)?,
+        })).collect()
+    }
+
+    ///ChMaskCntl and ChMask pairs that set again the current channel mask of the device
+    fn keep_channels_masks(channels: &ChannelList) -> Vec<(u8, u16)> {
+        let masks = channels.masks();
+        match channels.region() {
+            //7 turns off the 125 kHz channels and sets the 500 kHz ones, then the blocks with 125 kHz channels on
+            Region::US902_928 | Region::AU915_928 => once((7, masks[4]))
+                .chain((0..4).filter(|b| masks[*b] != 0).map(|b| (b as u8, masks[b])))
+                .collect(),
+            _ => (0..channels.len().div_ceil(16)).map(|b| (b as u8, masks[b])).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lorawan::{physical_parameters::{LoRaBandwidth, SpreadingFactor}, utils::traits::ToBytes};
+
+    use super::*;
+
+    #[test]
+    fn adr_decision() {
+        let engine = AdrEngine::default();
+        let dev_addr = [1, 2, 3, 4];
+        let metadata = UplinkMetadata { snr: 5.0, rssi: -90.0, spreading_factor: SpreadingFactor::SF12, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 1, time: 0 };
+
+        for fcnt in 0..ADR_HISTORY_SIZE as u32 - 1 {
+            assert_eq!(engine.register_uplink(dev_addr, &Region::EU863_870, fcnt, true, false, &metadata), AdrDecision::default());
+        }
+        //5 - (-20) - 10 = 15 dB, 5 steps: DR0 -> DR5
+        let decision = engine.register_uplink(dev_addr, &Region::EU863_870, ADR_HISTORY_SIZE as u32 - 1, true, true, &metadata);
+        assert!(decision.force_downlink);
+        assert_eq!(decision.commands, AdrEngine::link_adr_req(&ChannelList::new(Region::EU863_870), DataRate::DR5, 0, 1).unwrap());
+        //the 3 default channels stay on
+        assert_eq!(decision.commands[0].to_bytes(), vec![0x03, 0x50, 0x07, 0x00, 0x01]);
+        assert!(engine.history(&dev_addr).is_empty());
+    }
+
+    #[test]
+    fn adr_history_per_fcnt() {
+        let engine = AdrEngine::default();
+        let dev_addr = [1, 2, 3, 4];
+        let metadata = UplinkMetadata { snr: -5.0, rssi: -110.0, spreading_factor: SpreadingFactor::SF12, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 1, time: 0 };
+        engine.register_uplink(dev_addr, &Region::EU863_870, 1, true, false, &metadata);
+        engine.register_uplink(dev_addr, &Region::EU863_870, 1, true, false, &UplinkMetadata { snr: 2.0, ..metadata });
+        engine.register_uplink(dev_addr, &Region::EU863_870, 2, true, false, &metadata);
+        assert_eq!(engine.history(&dev_addr), vec![AdrUplinkRecord { fcnt: 1, snr: 2.0, gw_cnt: 2 }, AdrUplinkRecord { fcnt: 2, snr: -5.0, gw_cnt: 1 }]);
+    }
+
+    #[test]
+    fn tx_power_after_link_adr_ans() {
+        let engine = AdrEngine::default();
+        let dev_addr = [1, 2, 3, 4];
+        //DR5 already, 10 - (-7.5) - 10 = 7.5 dB go to the power in 2 steps
+        let metadata = UplinkMetadata { snr: 10.0, rssi: -90.0, spreading_factor: SpreadingFactor::SF7, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 1, time: 0 };
+        let decision = (0..ADR_HISTORY_SIZE as u32).map(|fcnt| engine.register_uplink(dev_addr, &Region::EU863_870, fcnt, true, false, &metadata)).last().unwrap();
+        assert_eq!(decision.commands, AdrEngine::link_adr_req(&ChannelList::new(Region::EU863_870), DataRate::DR5, 2, 1).unwrap());
+
+        //not acknowledged, the next decision starts again from the max power
+        let fcnts = ADR_HISTORY_SIZE as u32..2 * ADR_HISTORY_SIZE as u32;
+        let decision = fcnts.map(|fcnt| engine.register_uplink(dev_addr, &Region::EU863_870, fcnt, true, false, &metadata)).last().unwrap();
+        assert_eq!(decision.commands, AdrEngine::link_adr_req(&ChannelList::new(Region::EU863_870), DataRate::DR5, 2, 1).unwrap());
+
+        //acknowledged, the same SNR moves 2 steps from there
+        engine.link_adr_acked(dev_addr, &decision.commands);
+        let fcnts = 2 * ADR_HISTORY_SIZE as u32..3 * ADR_HISTORY_SIZE as u32;
+        let decision = fcnts.map(|fcnt| engine.register_uplink(dev_addr, &Region::EU863_870, fcnt, true, false, &metadata)).last().unwrap();
+        assert_eq!(decision.commands, AdrEngine::link_adr_req(&ChannelList::new(Region::EU863_870), DataRate::DR5, 4, 1).unwrap());
+    }
+
+    #[test]
+    fn adr_keeps_channel_mask() {
+        let engine = AdrEngine::default();
+        let dev_addr = [1, 2, 3, 4];
+        //sub-band 2: channels 8 to 15 and 65
+        engine.join_accepted(dev_addr, Region::US902_928, Some(CFList::ChannelMasks([0xFF00, 0, 0, 0, 0x0002, 0])));
+        let metadata = UplinkMetadata { snr: 5.0, rssi: -90.0, spreading_factor: SpreadingFactor::SF10, bandwidth: LoRaBandwidth::BW125, frequency: 903_900_000.0, gw_cnt: 1, time: 0 };
+        let decision = (0..ADR_HISTORY_SIZE as u32).map(|fcnt| engine.register_uplink(dev_addr, &Region::US902_928, fcnt, true, false, &metadata)).last().unwrap();
+        let masks: Vec<(u8, u16)> = decision.commands.iter().map(|c| match c {
+            NCMacCommands::LinkADRReq { ch_mask, redundancy, .. } => (redundancy.ch_mask_cntl().value(), *ch_mask),
+            c => panic!("{:?}", c),
+        }).collect();
+        assert_eq!(masks, vec![(7, 0x0002), (0, 0xFF00)]);
+
+        //the device applies the block to its own channels, the mask does not change
+        let mut channels = ChannelList::new(Region::US902_928);
+        channels.apply_cf_list(&CFList::ChannelMasks([0xFF00, 0, 0, 0, 0x0002, 0])).unwrap();
+        let expected = channels;
+        for (ch_mask_cntl, ch_mask) in masks {
+            channels.apply_ch_mask(ChMaskCntl::new(ch_mask_cntl).unwrap(), ch_mask).unwrap();
+        }
+        assert_eq!(channels, expected);
+    }
+}
@@ -210,6 +210,7 @@ mod tests {
                     rssi,
                     snr,
                     time,
+                    gateway_tmst: None,
                 },
             };
 
@@ -375,6 +376,7 @@ mod tests {
                     rssi,
                     snr,
                     time,
+                    gateway_tmst: None,
                 },
             };
 
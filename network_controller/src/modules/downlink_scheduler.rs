@@ -1,12 +1,40 @@
-use std::{cmp::Reverse, collections::BinaryHeap, time::Duration};
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, time::Duration};
 
+use lorawan::utils::eui::EUI64;
 use lorawan_device::{communicator::Transmission, split_communicator::LoRaSender};
-use tokio::{select, sync::mpsc::Receiver, time::Instant};
+use tokio::{select, sync::{mpsc::Receiver, oneshot}, time::Instant};
+
+///Maximum delay between the scheduled moment and the actual transmission, after that the window is considered missed
+pub const SCHEDULING_TOLERANCE: Duration = Duration::from_millis(20);
+///Downlinks timed by the gateway are handed over this long before their start, the backhaul latency does not delay them
+pub const GATEWAY_LEAD_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveWindow {
+    RX1,
+    RX2,
+}
+
+///Outcome of a downlink, sent back to whoever scheduled it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxAck {
+    Sent(ReceiveWindow),
+    ///every window of the downlink had already expired
+    TooLate,
+    ///the gateway was already transmitting in every window of the downlink
+    Collision,
+    ///the sender returned an error
+    Failed,
+}
 
 pub struct DownlinkSchedulerMessage<T> {
+    ///the scheduler serializes it and sends it at `moment`, or GATEWAY_LEAD_TIME earlier if it has a gateway tmst
     pub transmission: Transmission,
     pub moment: Instant,
+    ///transmission and moment for RX2, used if RX1 is busy or missed
+    pub rx2: Option<(Transmission, Instant)>,
     pub additional_info: Option<T>,
+    pub ack: Option<oneshot::Sender<TxAck>>,
 }
 
 impl <T> PartialEq for DownlinkSchedulerMessage<T> {
@@ -29,10 +57,61 @@ impl <T> Ord for DownlinkSchedulerMessage<T> {
     }
 }
 
+struct ScheduledDownlink<T> {
+    message: DownlinkSchedulerMessage<T>,
+    window: ReceiveWindow,
+}
+
+impl <T> PartialEq for ScheduledDownlink<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+    }
+}
+
+impl <T> Eq for ScheduledDownlink<T> {}
+
+impl <T> PartialOrd for ScheduledDownlink<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl <T> Ord for ScheduledDownlink<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.handover().cmp(&other.handover())
+    }
+}
+
+impl <T> ScheduledDownlink<T> {
+    ///Gateway that sends the downlink, None for senders that do not tell which one
+    fn gateway(&self) -> Option<EUI64> {
+        self.message.transmission.gateway_tmst.map(|t| t.gateway)
+    }
+
+    fn end(&self) -> Instant {
+        self.message.moment + airtime(&self.message.transmission)
+    }
+
+    ///When the downlink is given to the sender
+    fn handover(&self) -> Instant {
+        match self.message.transmission.gateway_tmst {
+            Some(_) => self.message.moment.checked_sub(GATEWAY_LEAD_TIME).unwrap_or(self.message.moment),
+            None => self.message.moment,
+        }
+    }
+}
+
+fn airtime(transmission: &Transmission) -> Duration {
+    Duration::from_millis(transmission.time_on_air() as u64)
+}
+
 pub struct DownlinkScheduler<T: LoRaSender> {
     receiver: Receiver<DownlinkSchedulerMessage<T::OptionalInfo>>,
     downlink_communicator: T,
-    message_storage: BinaryHeap<Reverse<DownlinkSchedulerMessage<T::OptionalInfo>>>
+    message_storage: BinaryHeap<Reverse<ScheduledDownlink<T::OptionalInfo>>>,
+    ///end of the last transmission of each gateway, a gateway can send one downlink at a time
+    ///downlinks without a known gateway go through different forwarders and are never kept apart
+    busy_until: HashMap<EUI64, Instant>,
 }
 
 
@@ -42,7 +121,8 @@ impl <T: LoRaSender> DownlinkScheduler<T> {
         Self {
             receiver,
             downlink_communicator,
-            message_storage: BinaryHeap::new()
+            message_storage: BinaryHeap::new(),
+            busy_until: HashMap::new(),
         }
     }
 
@@ -51,24 +131,160 @@ impl <T: LoRaSender> DownlinkScheduler<T> {
             select! {
                 message = self.receiver.recv() => {
                     match message {
-                        Some(t) => {
-                            //TODO RISISTEMARE TUTTO COME PRIMA PRE-TESTS
-                            //self.message_storage.push(Reverse(t,));
-                            self.downlink_communicator.send(&t.transmission.payload, t.additional_info).await.unwrap();
-                        },
+                        Some(t) => self.schedule(t, ReceiveWindow::RX1),
                         None => break,
                     }
-                    
                 },
-                _ = tokio::time::sleep_until(self.message_storage.peek().map_or(Instant::now() + Duration::from_millis(100), |v| v.0.moment)), if self.message_storage.peek().is_some() => {
-                    if let Some(head) = self.message_storage.pop() {
-                        //println!("Sending downlink transmission");
-                        self.downlink_communicator.send(&head.0.transmission.payload, head.0.additional_info).await.unwrap();
+                _ = tokio::time::sleep_until(self.message_storage.peek().map_or(Instant::now() + Duration::from_millis(100), |v| v.0.handover())), if self.message_storage.peek().is_some() => {
+                    if let Some(Reverse(head)) = self.message_storage.pop() {
+                        if Instant::now() > head.message.moment + SCHEDULING_TOLERANCE {
+                            self.fallback(head.message, head.window, TxAck::TooLate);
+                        } else {
+                            self.send(head).await;
+                        }
                     }
                 }
             }
         }
     }
+
+    fn schedule(&mut self, message: DownlinkSchedulerMessage<T::OptionalInfo>, window: ReceiveWindow) {
+        if Instant::now() > message.moment + SCHEDULING_TOLERANCE {
+            return self.fallback(message, window, TxAck::TooLate);
+        }
+        let scheduled = ScheduledDownlink { message, window };
+        if scheduled.gateway().is_some_and(|gateway| self.is_busy(gateway, scheduled.message.moment, scheduled.end())) {
+            return self.fallback(scheduled.message, window, TxAck::Collision);
+        }
+        self.message_storage.push(Reverse(scheduled));
+    }
+
+    ///Move the downlink to RX2 if it was scheduled for RX1, otherwise report the failure
+    fn fallback(&mut self, mut message: DownlinkSchedulerMessage<T::OptionalInfo>, window: ReceiveWindow, reason: TxAck) {
+        match (window, message.rx2.take()) {
+            (ReceiveWindow::RX1, Some((transmission, moment))) => {
+                message.transmission = transmission;
+                message.moment = moment;
+                self.schedule(message, ReceiveWindow::RX2);
+            },
+            _ => Self::acknowledge(message.ack, reason),
+        }
+    }
+
+    fn is_busy(&self, gateway: EUI64, start: Instant, end: Instant) -> bool {
+        self.busy_until.get(&gateway).is_some_and(|busy_until| *busy_until > start)
+            || self.message_storage.iter().any(|s| s.0.gateway() == Some(gateway) && s.0.message.moment < end && start < s.0.end())
+    }
+
+    async fn send(&mut self, scheduled: ScheduledDownlink<T::OptionalInfo>) {
+        let gateway = scheduled.gateway();
+        let ScheduledDownlink { message, window } = scheduled;
+        let bytes = serde_json::to_vec(&message.transmission).unwrap();
+        //println!("Sending downlink transmission");
+        match self.downlink_communicator.send(&bytes, message.additional_info).await {
+            Ok(()) => {
+                let now = Instant::now();
+                self.busy_until.retain(|_, busy_until| *busy_until > now);
+                if let Some(gateway) = gateway {
+                    self.busy_until.insert(gateway, message.moment.max(now) + airtime(&message.transmission));
+                }
+                Self::acknowledge(message.ack, TxAck::Sent(window));
+            },
+            Err(e) => {
+                eprintln!("Error sending downlink: {e:?}");
+                Self::acknowledge(message.ack, TxAck::Failed);
+            },
+        }
+    }
+
+    fn acknowledge(ack: Option<oneshot::Sender<TxAck>>, result: TxAck) {
+        if let Some(ack) = ack {
+            //the caller may not be interested anymore
+            let _ = ack.send(result);
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use lorawan_device::{communicator::GatewayTimestamp, devices::mock_device::MockSender};
 
+    use super::*;
+
+    fn message(moment: Instant, rx2: Option<Instant>) -> (DownlinkSchedulerMessage<()>, oneshot::Receiver<TxAck>) {
+        gateway_message(None, moment, rx2)
+    }
+
+    fn gateway_message(gateway: Option<EUI64>, moment: Instant, rx2: Option<Instant>) -> (DownlinkSchedulerMessage<()>, oneshot::Receiver<TxAck>) {
+        let gateway_tmst = gateway.map(|gateway| GatewayTimestamp { gateway, tmst: 0 });
+        let transmission = Transmission { payload: vec![0; 20], gateway_tmst, ..Default::default() };
+        let (ack, ack_receiver) = oneshot::channel();
+        (DownlinkSchedulerMessage {
+            rx2: rx2.map(|m| (transmission.clone(), m)),
+            transmission,
+            moment,
+            additional_info: None,
+            ack: Some(ack),
+        }, ack_receiver)
+    }
+
+    #[tokio::test]
+    async fn rx1_rx2_scheduling() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let mut scheduler = DownlinkScheduler::new(MockSender, receiver);
+        tokio::spawn(async move { scheduler.run().await });
+
+        let gateway = Some(EUI64::from([1; 8]));
+        let now = Instant::now();
+        let rx1 = now + GATEWAY_LEAD_TIME + Duration::from_millis(100);
+        let (first, first_ack) = gateway_message(gateway, rx1, None);
+        let (second, second_ack) = gateway_message(gateway, rx1, Some(rx1 + Duration::from_secs(1)));
+        let (third, third_ack) = gateway_message(gateway, rx1, None);
+        let (expired, expired_ack) = gateway_message(gateway, now - Duration::from_secs(1), Some(now - Duration::from_millis(500)));
+        for m in [first, second, third, expired] {
+            sender.send(m).await.unwrap();
+        }
+
+        assert_eq!(first_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX1));
+        assert_eq!(third_ack.await.unwrap(), TxAck::Collision);
+        assert_eq!(expired_ack.await.unwrap(), TxAck::TooLate);
+        assert_eq!(second_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX2));
+    }
+
+    #[tokio::test]
+    async fn gateways_send_at_the_same_time() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let mut scheduler = DownlinkScheduler::new(MockSender, receiver);
+        tokio::spawn(async move { scheduler.run().await });
+
+        let (first_gateway, second_gateway) = (EUI64::from([1; 8]), EUI64::from([2; 8]));
+        let moment = Instant::now() + GATEWAY_LEAD_TIME + Duration::from_millis(100);
+        let (first, first_ack) = gateway_message(Some(first_gateway), moment, None);
+        let (second, second_ack) = gateway_message(Some(second_gateway), moment, None);
+        let (third, third_ack) = gateway_message(Some(first_gateway), moment, None);
+        for m in [first, second, third] {
+            sender.send(m).await.unwrap();
+        }
+
+        assert_eq!(first_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX1));
+        assert_eq!(second_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX1));
+        assert_eq!(third_ack.await.unwrap(), TxAck::Collision);
+    }
+
+    #[tokio::test]
+    async fn unknown_gateways_are_never_busy() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let mut scheduler = DownlinkScheduler::new(MockSender, receiver);
+        tokio::spawn(async move { scheduler.run().await });
+
+        let moment = Instant::now() + Duration::from_millis(100);
+        let (first, first_ack) = message(moment, Some(moment + Duration::from_secs(1)));
+        let (second, second_ack) = message(moment, Some(moment + Duration::from_secs(1)));
+        for m in [first, second] {
+            sender.send(m).await.unwrap();
+        }
+
+        assert_eq!(first_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX1));
+        assert_eq!(second_ack.await.unwrap(), TxAck::Sent(ReceiveWindow::RX1));
+    }
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use lorawan::{device::LoRaWANVersion, lorawan_packet::{fields::{DLSettings, RxDelay}, join::CFList, mac_commands::{EDMacCommands, NCMacCommands}}, physical_parameters::{DataRate, LoRaBandwidth, SpreadingFactor}, regional_parameters::region::{Region, JOIN_ACCEPT_DELAY1, RECEIVE_DELAY1}};
+
+use super::{adr::AdrEngine, stats::DUPLICATE_WINDOW};
+
+///Seconds between the unix epoch and the GPS epoch (1980-01-06T00:00:00Z)
+const GPS_EPOCH_OFFSET: u64 = 315_964_800;
+///Leap seconds introduced since the GPS epoch, GPS time does not include them
+const GPS_LEAP_SECONDS: u64 = 18;
+///Uplinks after the one a request was sent for in which the device has to answer it, later the request is dropped as lost
+pub const ANSWER_UPLINKS: u32 = 3;
+
+///Radio information about the uplink that carried the MAC commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UplinkMetadata {
+    pub snr: f32,
+    pub rssi: f32,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: LoRaBandwidth,
+    ///uplink frequency in Hz
+    pub frequency: f64,
+    ///number of gateways that received the frame
+    pub gw_cnt: u8,
+    ///reception time in ms since the unix epoch, 0 if unknown
+    pub time: u128,
+}
+
+///Receive windows opened by a device after an uplink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RxParameters {
+    pub rx1_dr_offset: u8,
+    ///delay of RX1 from the end of the uplink, RX2 opens one second later
+    pub rx1_delay: Duration,
+    ///RX2 frequency in Hz
+    pub rx2_frequency: u32,
+    pub rx2_data_rate: DataRate,
+}
+
+impl RxParameters {
+    ///Windows of a device that has not received any JoinAccept nor MAC command
+    pub fn regional_default(region: Region) -> Self {
+        Self { rx1_dr_offset: 0, rx1_delay: RECEIVE_DELAY1, rx2_frequency: region.rx2_frequency(), rx2_data_rate: region.rx2_data_rate() }
+    }
+
+    ///Windows of a JoinAccept, the device applies its DLSettings and RxDelay only after receiving it
+    pub fn join_accept(region: Region) -> Self {
+        Self { rx1_delay: JOIN_ACCEPT_DELAY1, ..Self::regional_default(region) }
+    }
+
+    pub fn rx2_delay(&self) -> Duration {
+        self.rx1_delay + Duration::from_secs(1)
+    }
+}
+
+///What the network knows about the MAC layer of a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceMacState {
+    ///last DevStatusAns: 0 external power source, 1..254 battery level, 255 not able to measure
+    pub battery: Option<u8>,
+    ///last DevStatusAns margin in dB
+    pub margin: Option<i8>,
+    pub link_adr_acked: Option<bool>,
+    pub rx_param_setup_acked: Option<bool>,
+    ///RX1DROffset of the last JoinAccept or acknowledged RXParamSetupReq, None for the regional default
+    pub rx1_dr_offset: Option<u8>,
+    ///RX1 delay of the last JoinAccept or acknowledged RXTimingSetupReq
+    pub rx1_delay: Option<Duration>,
+    ///RX2 frequency (Hz) and data rate of the last JoinAccept or acknowledged RXParamSetupReq
+    pub rx2: Option<(u32, DataRate)>,
+    ///commands waiting for the next downlink
+    pub queued: Vec<NCMacCommands>,
+    ///commands sent whose answer has not been received yet, with the FCnt of the uplink they were sent after
+    pub awaiting: Vec<(NCMacCommands, u32)>,
+    ///commands received from the device that the network controller does not handle
+    pub unhandled: u32,
+    ///requests dropped because the device did not answer them within ANSWER_UPLINKS uplinks
+    pub dropped: u32,
+    ///FCnt of the last uplink handled and arrival of its first copy
+    pub last_uplink: Option<(u32, Instant)>,
+    ///LoRaWAN version and region of the device, the session in the ledger does not carry them
+    pub profile: Option<(LoRaWANVersion, Region)>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MacAnswer {
+    pub commands: Vec<NCMacCommands>,
+    ///true if a downlink must be sent even without commands, e.g. to stop the repetition of sticky answers
+    pub force_downlink: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MacCommandHandler {
+    states: Mutex<HashMap<[u8; 4], DeviceMacState>>,
+    adr: AdrEngine,
+}
+
+impl MacCommandHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn adr(&self) -> &AdrEngine {
+        &self.adr
+    }
+
+    pub fn device_state(&self, dev_addr: &[u8; 4]) -> Option<DeviceMacState> {
+        self.states.lock().unwrap().get(dev_addr).cloned()
+    }
+
+    ///Receive windows of the next downlink of the device, the regional defaults for what it has not been told
+    pub fn rx_parameters(&self, dev_addr: &[u8; 4], region: Region) -> RxParameters {
+        let default = RxParameters::regional_default(region);
+        self.states.lock().unwrap().get(dev_addr).map_or(default, |state| {
+            let (rx2_frequency, rx2_data_rate) = state.rx2.unwrap_or((default.rx2_frequency, default.rx2_data_rate));
+            RxParameters {
+                rx1_dr_offset: state.rx1_dr_offset.unwrap_or(default.rx1_dr_offset),
+                rx1_delay: state.rx1_delay.unwrap_or(default.rx1_delay),
+                rx2_frequency,
+                rx2_data_rate,
+            }
+        })
+    }
+
+    ///The device takes the DLSettings, RxDelay and CFList of the JoinAccept, the RX2 frequency goes back to the regional one
+    pub fn set_join_accept_parameters(&self, dev_addr: [u8; 4], dl_settings: DLSettings, rx_delay: RxDelay, cf_list: Option<CFList>, region: Region) {
+        self.adr.join_accepted(dev_addr, region, cf_list);
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dev_addr).or_default();
+        state.rx1_dr_offset = Some(dl_settings.rx1_dr_offset());
+        state.rx1_delay = Some(rx_delay.delay());
+        state.rx2 = Some((region.rx2_frequency(), DataRate::new(dl_settings.rx2_data_rate())));
+    }
+
+    ///Cached LoRaWAN version and region of the device, None if the configuration must be read from the ledger
+    pub fn profile(&self, dev_addr: &[u8; 4]) -> Option<(LoRaWANVersion, Region)> {
+        self.states.lock().unwrap().get(dev_addr).and_then(|state| state.profile)
+    }
+
+    pub fn set_profile(&self, dev_addr: [u8; 4], version: LoRaWANVersion, region: Region) {
+        self.states.lock().unwrap().entry(dev_addr).or_default().profile = Some((version, region));
+    }
+
+    ///Queue a command that will be sent with the next downlink of the device
+    pub fn queue_command(&self, dev_addr: [u8; 4], command: NCMacCommands) {
+        self.states.lock().unwrap().entry(dev_addr).or_default().queued.push(command);
+    }
+
+    ///Put back at the head of the queue the requests that did not fit in the downlink or whose downlink was not sent. The answers are dropped:
+    ///LinkCheckAns and DeviceTimeAns would be stale later, the device repeats ResetInd, RekeyInd and DeviceModeInd until confirmed
+    pub fn requeue(&self, dev_addr: [u8; 4], commands: Vec<NCMacCommands>) {
+        let requests: Vec<NCMacCommands> = commands.into_iter()
+            .filter(|c| !matches!(c, NCMacCommands::LinkCheckAns { .. } | NCMacCommands::DeviceTimeAns { .. }
+                | NCMacCommands::ResetConf(_) | NCMacCommands::RekeyConf(_) | NCMacCommands::DeviceModeConf(_)))
+            .collect();
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dev_addr).or_default();
+        state.awaiting.retain(|(c, _)| !requests.contains(c));
+        state.queued.splice(0..0, requests);
+    }
+
+    ///false for a copy of the last uplink forwarded by another gateway within DUPLICATE_WINDOW of the first one:
+    ///its MAC commands are handled and answered only once
+    pub fn first_copy(&self, dev_addr: [u8; 4], fcnt_up: u32) -> bool {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dev_addr).or_default();
+        if state.last_uplink.is_some_and(|(fcnt, first)| fcnt == fcnt_up && now.duration_since(first) < DUPLICATE_WINDOW) {
+            return false;
+        }
+        state.last_uplink = Some((fcnt_up, now));
+        true
+    }
+
+    ///Process the commands of the uplink fcnt_up, returns the answers followed by the queued commands
+    pub fn handle_uplink(&self, dev_addr: [u8; 4], fcnt_up: u32, commands: &[EDMacCommands], metadata: &UplinkMetadata) -> MacAnswer {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dev_addr).or_default();
+        let mut ret = MacAnswer::default();
+        let mut link_adr_acked = Vec::new();
+        let mut new_channel_acked = Vec::new();
+
+        for c in commands {
+            match c {
+                EDMacCommands::LinkCheckReq => {
+                    let margin = (metadata.snr - metadata.spreading_factor.demodulation_floor()).round().clamp(0.0, 254.0) as u8;
+                    ret.commands.push(NCMacCommands::LinkCheckAns { margin, gw_cnt: metadata.gw_cnt });
+                },
+                EDMacCommands::DeviceTimeReq => {
+                    let (epoch, second_fraction) = Self::gps_time(metadata.time);
+                    ret.commands.push(NCMacCommands::DeviceTimeAns { epoch, second_fraction });
+                },
+                EDMacCommands::DevStatusAns { battery, margin } => {
+                    state.battery = Some(*battery);
+                    //6 bits two's complement
+                    state.margin = Some(((margin << 2) as i8) >> 2);
+                    Self::answered(state, |c| matches!(c, NCMacCommands::DevStatusReq));
+                },
+                EDMacCommands::LinkADRAns { power_ack, data_rate_ack, channel_mask_ack } => {
+                    let acked = *power_ack && *data_rate_ack && *channel_mask_ack;
+                    state.link_adr_acked = Some(acked);
+                    let request = Self::answered(state, |c| matches!(c, NCMacCommands::LinkADRReq { .. }));
+                    link_adr_acked.extend(request.filter(|_| acked));
+                },
+                EDMacCommands::RXParamSetupAns { rx1_dr_offset_ack, rx2_data_rate_ack, channel_ack } => {
+                    let acked = *rx1_dr_offset_ack && *rx2_data_rate_ack && *channel_ack;
+                    state.rx_param_setup_acked = Some(acked);
+                    //the device keeps its windows unless it accepts all of the parameters
+                    let request = Self::answered(state, |c| matches!(c, NCMacCommands::RXParamSetupReq { .. }));
+                    if let (true, Some(NCMacCommands::RXParamSetupReq { dl_settings, freq })) = (acked, request) {
+                        state.rx1_dr_offset = Some(dl_settings.rx1_dr_offset());
+                        state.rx2 = Some((freq * 100, DataRate::new(dl_settings.rx2_data_rate())));
+                    }
+                    ret.force_downlink = true;
+                },
+                EDMacCommands::RXTimingSetupAns => {
+                    if let Some(NCMacCommands::RXTimingSetupReq(delay)) = Self::answered(state, |c| matches!(c, NCMacCommands::RXTimingSetupReq(_))) {
+                        state.rx1_delay = Some(delay.delay());
+                    }
+                    ret.force_downlink = true;
+                },
+                EDMacCommands::DlChannelAns { .. } => {
+                    Self::answered(state, |c| matches!(c, NCMacCommands::DlChannelReq { .. }));
+                    ret.force_downlink = true;
+                },
+                EDMacCommands::DutyCycleAns => { Self::answered(state, |c| matches!(c, NCMacCommands::DutyCycleReq(_))); },
+                EDMacCommands::NewChannelAns { data_range_ok, channel_frequency_ok } => {
+                    let request = Self::answered(state, |c| matches!(c, NCMacCommands::NewChannelReq { .. }));
+                    new_channel_acked.extend(request.filter(|_| *data_range_ok && *channel_frequency_ok));
+                },
+                EDMacCommands::TxParamSetupAns => { Self::answered(state, |c| matches!(c, NCMacCommands::TxParamSetupReq { .. })); },
+                EDMacCommands::ADRParamSetupAns => { Self::answered(state, |c| matches!(c, NCMacCommands::ADRParamSetupReq { .. })); },
+                EDMacCommands::RejoinParamSetupAns { .. } => { Self::answered(state, |c| matches!(c, NCMacCommands::RejoinParamSetupReq { .. })); },
+                EDMacCommands::ResetInd(version) => ret.commands.push(NCMacCommands::ResetConf(*version)),
+                EDMacCommands::RekeyInd(version) => ret.commands.push(NCMacCommands::RekeyConf(*version)),
+                EDMacCommands::DeviceModeInd(class) => ret.commands.push(NCMacCommands::DeviceModeConf(*class)),
+                _ => state.unhandled += 1,
+            }
+        }
+
+        //first the new channels, a LinkADRReq of the same downlink may enable them
+        for request in &new_channel_acked {
+            self.adr.new_channel_acked(dev_addr, request);
+        }
+        if !link_adr_acked.is_empty() {
+            self.adr.link_adr_acked(dev_addr, &link_adr_acked);
+        }
+
+        let awaiting = state.awaiting.len();
+        state.awaiting.retain(|(_, sent)| fcnt_up.saturating_sub(*sent) <= ANSWER_UPLINKS);
+        state.dropped += (awaiting - state.awaiting.len()) as u32;
+        let queued: Vec<NCMacCommands> = state.queued.drain(..).collect();
+        state.awaiting.extend(queued.iter().map(|c| (c.clone(), fcnt_up)));
+        ret.commands.extend(queued);
+        ret
+    }
+
+    ///Remove from the awaiting commands the request of an answer and return it
+    fn answered(state: &mut DeviceMacState, is_request: impl Fn(&NCMacCommands) -> bool) -> Option<NCMacCommands> {
+        state.awaiting.iter().position(|(c, _)| is_request(c)).map(|i| state.awaiting.remove(i).0)
+    }
+
+    ///GPS epoch seconds and 1/256 s fraction of a unix time in ms, times before the GPS epoch
+    ///(e.g. the virtual clock of a simulation starting from 0) saturate to it
+    pub fn gps_time(unix_ms: u128) -> (u32, u8) {
+        let gps_ms = (unix_ms + (GPS_LEAP_SECONDS * 1000) as u128).saturating_sub((GPS_EPOCH_OFFSET * 1000) as u128);
+        ((gps_ms / 1000) as u32, ((gps_ms % 1000) * 256 / 1000) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_and_queue() {
+        let handler = MacCommandHandler::new();
+        let dev_addr = [1, 2, 3, 4];
+        let metadata = UplinkMetadata { snr: 2.4, rssi: -80.0, spreading_factor: SpreadingFactor::SF9, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 2, time: 1_700_000_000_500 };
+
+        handler.queue_command(dev_addr, NCMacCommands::DevStatusReq);
+        let ans = handler.handle_uplink(dev_addr, 1, &[EDMacCommands::LinkCheckReq, EDMacCommands::DeviceTimeReq], &metadata);
+        assert_eq!(ans.commands, vec![
+            NCMacCommands::LinkCheckAns { margin: 15, gw_cnt: 2 },
+            NCMacCommands::DeviceTimeAns { epoch: 1_384_035_218, second_fraction: 128 },
+            NCMacCommands::DevStatusReq,
+        ]);
+        assert!(!ans.force_downlink);
+        assert_eq!(handler.device_state(&dev_addr).unwrap().awaiting.len(), 1);
+
+        let ans = handler.handle_uplink(dev_addr, 2, &[EDMacCommands::DevStatusAns { battery: 120, margin: 0b00111011 }, EDMacCommands::RXTimingSetupAns], &metadata);
+        assert!(ans.commands.is_empty());
+        assert!(ans.force_downlink);
+        let state = handler.device_state(&dev_addr).unwrap();
+        assert_eq!(state.battery, Some(120));
+        assert_eq!(state.margin, Some(-5));
+        assert!(state.awaiting.is_empty());
+
+        //class B commands are not handled, a request not answered within ANSWER_UPLINKS uplinks is dropped
+        handler.queue_command(dev_addr, NCMacCommands::DevStatusReq);
+        handler.handle_uplink(dev_addr, 3, &[EDMacCommands::BeaconTimingReq], &metadata);
+        handler.handle_uplink(dev_addr, 3 + ANSWER_UPLINKS + 1, &[], &metadata);
+        let state = handler.device_state(&dev_addr).unwrap();
+        assert_eq!((state.unhandled, state.dropped), (1, 1));
+        assert!(state.awaiting.is_empty());
+    }
+
+    #[test]
+    fn gps_time_before_gps_epoch() {
+        assert_eq!(MacCommandHandler::gps_time(1_700_000_000_500), (1_384_035_218, 128));
+        assert_eq!(MacCommandHandler::gps_time(315_964_783_500), (1, 128));
+        //a simulation clock starting from the unix epoch saturates to the GPS epoch
+        assert_eq!(MacCommandHandler::gps_time(5_000), (0, 0));
+        assert_eq!(MacCommandHandler::gps_time(0), (0, 0));
+    }
+
+    #[test]
+    fn requeue_only_requests() {
+        let handler = MacCommandHandler::new();
+        let dev_addr = [1, 2, 3, 4];
+        let metadata = UplinkMetadata { snr: 2.4, rssi: -80.0, spreading_factor: SpreadingFactor::SF9, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 2, time: 1_700_000_000_500 };
+
+        handler.queue_command(dev_addr, NCMacCommands::DevStatusReq);
+        let ans = handler.handle_uplink(dev_addr, 1, &[EDMacCommands::DeviceTimeReq, EDMacCommands::LinkCheckReq], &metadata);
+        //none of them fit in the downlink
+        handler.requeue(dev_addr, ans.commands);
+        let state = handler.device_state(&dev_addr).unwrap();
+        assert_eq!(state.queued, vec![NCMacCommands::DevStatusReq]);
+        assert!(state.awaiting.is_empty());
+
+        let ans = handler.handle_uplink(dev_addr, 2, &[], &metadata);
+        assert_eq!(ans.commands, vec![NCMacCommands::DevStatusReq]);
+
+        //never answered, the request is dropped
+        handler.handle_uplink(dev_addr, 2 + ANSWER_UPLINKS, &[], &metadata);
+        assert_eq!(handler.device_state(&dev_addr).unwrap().awaiting.len(), 1);
+        handler.handle_uplink(dev_addr, 3 + ANSWER_UPLINKS, &[], &metadata);
+        assert!(handler.device_state(&dev_addr).unwrap().awaiting.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 pub mod network_controller;
 pub mod downlink_scheduler;
+pub mod mac_handler;
+pub mod adr;
 pub mod error;
 pub mod anomaly_detector_ewma;
 pub mod anomaly_detector_mahalanobis;
-pub mod circular_buffer;
\ No newline at end of file
+pub mod circular_buffer;
+pub mod stats;
\ No newline at end of file
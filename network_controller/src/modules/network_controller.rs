@@ -1,15 +1,15 @@
-use std::{sync::Arc, time::Duration};
+use std::{borrow::Borrow, sync::Arc, time::Duration};
 
 use blockchain_api::{udp_bridge::Logger, BlockchainClient, BlockchainError};
-use consensus::{consensus_server::{ConsensusConfig, ConsensusServer}, ConsensusMessage};
+use consensus::{consensus_server::{ConsensusConfig, ConsensusServer}, local_consensus::LocalConsensusNetwork, ConsensusMessage};
 use lazy_static::lazy_static;
-use lorawan::{device::Device, lorawan_packet::{fctrl::{DownlinkFCtrl, FCtrl}, fhdr::FHDR, join::{JoinAcceptPayload, JoinRequestType}, mac_commands, mac_payload::MACPayload, mhdr::{MType, Major, MHDR}, payload::Payload, LoRaWANPacket}, utils::{errors::LoRaWANError, increment_nonce, nonce_valid, traits::ToBytesWithContext, PrettyHexSlice}};
-use lorawan_device::{communicator::{ReceivedTransmission, Transmission}, configs::UDPNCConfig, devices::udp_device::UDPSender, split_communicator::SplitCommunicator};
+use lorawan::{device::{Device, DeviceClass}, encryption::key::Key, lorawan_packet::{fctrl::{DownlinkFCtrl, FCtrl}, fhdr::FHDR, join::{CFList, JoinAcceptPayload, JoinRequestType, RejoinRequestPayload}, mac_commands::{EDMacCommands, NCMacCommands}, mac_payload::MACPayload, mhdr::{MType, Major, MHDR}, payload::Payload, LoRaWANPacket, MicContext}, regional_parameters::{channel_list::ChannelList, region::{Region, RegionalParameters}}, utils::{errors::LoRaWANError, eui::EUI64, increment_nonce, nonce_valid, traits::{ToBytes, ToBytesWithContext}, PrettyHexSlice}};
+use lorawan_device::{communicator::{ReceivedTransmission, Transmission}, configs::UDPNCConfig, devices::{pcap_device::{PcapReceiver, PcapSender}, udp_device::UDPSender}, pcap::PcapWriter, split_communicator::SplitCommunicator};
 use openssl::sha::sha256;
 
-use tokio::{net::UdpSocket, sync::{mpsc::Sender, oneshot}, task::JoinHandle};
+use tokio::{net::UdpSocket, sync::{mpsc::Sender, oneshot}, task::JoinHandle, time::Instant};
 use crate::modules::error::NCError;
-use super::downlink_scheduler::{DownlinkScheduler, DownlinkSchedulerMessage};
+use super::{adr::AdrDecision, downlink_scheduler::{DownlinkScheduler, DownlinkSchedulerMessage, TxAck}, mac_handler::{MacAnswer, MacCommandHandler, RxParameters, UplinkMetadata}, stats::NetworkStats};
 use lorawan_device::split_communicator::LoRaReceiver;
 
 #[derive(Debug)]
@@ -23,12 +23,22 @@ struct DispatchResults {
     session_derivation_info: Option<(Vec<String>, String)>,
     consensus_info: Option<DownlinkConsensusLedgerUpdateInfo>,
     answer: Option<Vec<u8>>,
+    ///MAC commands carried by the answer, queued again if it is not sent
+    mac_commands: Vec<NCMacCommands>,
+    ///region of the device, defines the RX windows of the answer
+    region: Region,
+    ///delays, RX1DROffset and RX2 of the windows the device opens for the answer
+    rx_parameters: RxParameters,
 }
 
 #[derive(Clone)]
 pub struct NetworkController {
-    nc_id: &'static str,
+    nc_id: Arc<str>,
     consensus_sender: Arc<Sender<ConsensusMessage>>,
+    cf_list: Option<CFList>,
+    mac_handler: Arc<MacCommandHandler>,
+    stats: Arc<NetworkStats>,
+    capture: Option<Arc<PcapWriter>>,
 }
 
 lazy_static!(
@@ -37,16 +47,63 @@ lazy_static!(
 );
 
 impl NetworkController {
-    pub fn new(nc_id: &'static str, consensus_config: ConsensusConfig) -> Self {
+    pub fn new(nc_id: &str, consensus_config: ConsensusConfig) -> Self {
 
         let consensus_sender = ConsensusServer::run_instance(nc_id.to_string(), consensus_config).expect("Without consensus server the network controller cannot work");
         Self {
-            nc_id,
+            nc_id: nc_id.into(),
             consensus_sender: Arc::new(consensus_sender),
+            cf_list: None,
+            mac_handler: Arc::new(MacCommandHandler::new()),
+            stats: Arc::new(NetworkStats::new()),
+            capture: None,
         }
     }
 
-    async fn handle_join_request(join_request: &[u8], bc_client: &Arc<impl BlockchainClient>, nc_id: &'static str) -> Result<DispatchResults, NCError> {
+    ///Network controller whose consensus server reaches the others of the same process without network.
+    ///Nothing goes through sockets so it can run on the virtual clock of a simulation
+    pub fn local(nc_id: &str, consensus_network: &LocalConsensusNetwork) -> Self {
+        let consensus_sender = consensus_network.run_instance(nc_id.to_string());
+        Self {
+            nc_id: nc_id.into(),
+            consensus_sender: Arc::new(consensus_sender),
+            cf_list: None,
+            mac_handler: Arc::new(MacCommandHandler::new()),
+            stats: Arc::new(NetworkStats::new()),
+            capture: None,
+        }
+    }
+
+    ///Per-device MAC state, commands queued here are sent with the next downlink of the device
+    pub fn mac_handler(&self) -> &Arc<MacCommandHandler> {
+        &self.mac_handler
+    }
+
+    ///Counters of the uplinks, consensus rounds and downlinks handled by every routine
+    pub fn stats(&self) -> &Arc<NetworkStats> {
+        &self.stats
+    }
+
+    ///Count in stats shared with other network controllers, e.g. to report on the whole network. Used by the routines started afterwards
+    pub fn set_stats(&mut self, stats: Arc<NetworkStats>) {
+        self.stats = stats;
+    }
+
+    ///CFList sent in every JoinAccept to devices whose region supports it, e.g. extra EU868 channels
+    pub fn set_cf_list(&mut self, cf_list: Option<CFList>) {
+        self.cf_list = cf_list;
+    }
+
+    pub fn cf_list(&self) -> &Option<CFList> {
+        &self.cf_list
+    }
+
+    ///Frames received and sent by the routines started afterwards are written to the capture, to be opened with Wireshark
+    pub fn set_capture(&mut self, capture: Option<Arc<PcapWriter>>) {
+        self.capture = capture;
+    }
+
+    async fn handle_join_request(join_request: &[u8], bc_client: &Arc<impl BlockchainClient>, nc_id: &str, cf_list: Option<CFList>, mac_handler: &MacCommandHandler) -> Result<DispatchResults, NCError> {
         let packet = LoRaWANPacket::from_bytes(join_request, None, true)?;
         if let Payload::JoinRequest(jr_p) = packet.payload() {
             match bc_client.get_device_config(jr_p.dev_eui()).await  {
@@ -60,14 +117,12 @@ impl NetworkController {
                     let (dev_nonce_valid, dev_nonce_looped) = nonce_valid(jr_p.dev_nonce(), device_nonce_u16);
                     if !dev_nonce_valid { Err(NCError::InvalidJoinRequest(format!("Invalid dev_nonce, expected > {device_nonce_u16}, received {}", jr_p.dev_nonce()))) }
                     else {
-                        LoRaWANPacket::validate_mic(join_request, &packet, &device)?;
+                        LoRaWANPacket::validate_mic(join_request, &packet, &device, &MicContext::default())?;
                         
-                        let mut dl_settings = 0_u8;
-                        let opt_neg_v1_1 = 0b10000000;
-                        dl_settings |= 0b00010001; //TODO per ora rx1_dr_offset 1, rx2_data_rate 1, capire come farle bene poi
-                        if device.version().is_1_1_or_greater() {
-                            dl_settings |= opt_neg_v1_1
-                        }
+                        let regional_params = device.regional_parameters().unwrap_or_default();
+                        let dl_settings = regional_params.default_dl_settings().with_opt_neg(device.version().is_1_1_or_greater());
+
+                        let cf_list = cf_list.filter(|c| ChannelList::new(*regional_params.region()).apply_cf_list(c).is_ok());
 
                         let mut dev_addr = [0_u8; 4]; 
                         let dev_addr_sha256 = sha256(&[device.dev_eui().as_slice(), device.join_eui().as_slice(), &jr_p.dev_nonce().to_be_bytes()].concat());
@@ -81,10 +136,12 @@ impl NetworkController {
                             [1,2,3], 
                             dev_addr, 
                             dl_settings, 
-                            2, 
-                            None
+                            regional_params.default_rx_delay(), 
+                            cf_list
                         );
                         
+                        mac_handler.set_join_accept_parameters(dev_addr, dl_settings, regional_params.default_rx_delay(), cf_list, *regional_params.region());
+                        mac_handler.set_profile(dev_addr, *device.version(), *regional_params.region());
                         device.set_dev_nonce(increment_nonce(jr_p.dev_nonce(), device.dev_nonce(), dev_nonce_looped));
                         device.generate_session_context(&join_accept)?;
                         device.set_last_join_request_received(JoinRequestType::JoinRequest);
@@ -100,12 +157,18 @@ impl NetworkController {
                                     session_derivation_info: Some((keys, device.dev_eui().to_string())),
                                     consensus_info: None,
                                     answer: Some(join_accept),
+                                    mac_commands: Vec::new(),
+                                    region: *regional_params.region(),
+                                    rx_parameters: RxParameters::join_accept(*regional_params.region()),
                             })
                         } else {
                             Ok(DispatchResults {
                                 session_derivation_info: None,
                                 consensus_info: None,
                                 answer: None,
+                                mac_commands: Vec::new(),
+                                region: *regional_params.region(),
+                                rx_parameters: RxParameters::join_accept(*regional_params.region()),
                             })
                         }   
                     }
@@ -114,24 +177,164 @@ impl NetworkController {
         } else { Err(NCError::InvalidJoinRequest("Not a join request".to_string())) }
     }
 
-    async fn handle_unconfirmed_data_up(data_up: &[u8], bc_client: &Arc<impl BlockchainClient>) -> Result<(Device, DispatchResults), NCError> {
+    ///Type 0 and 1 rejoins reset the session and the radio parameters like a join. Type 2 only rekeys: new session keys and frame counters
+    ///derived from RJcount0 on the same DevAddr, the radio parameters of the device are kept
+    async fn handle_rejoin_request(rejoin_request: &[u8], bc_client: &Arc<impl BlockchainClient>, nc_id: &str, cf_list: Option<CFList>, mac_handler: &MacCommandHandler) -> Result<DispatchResults, NCError> {
+        let packet = LoRaWANPacket::from_bytes(rejoin_request, None, true)?;
+        let rj_p = if let Payload::RejoinRequest(rj_p) = packet.payload() { rj_p } else { return Err(NCError::InvalidJoinRequest("Not a rejoin request".to_string())) };
+        let join_req_type = rj_p.join_request_type();
+
+        let device_config = bc_client.get_device_config(rj_p.dev_eui()).await?;
+        let current_dev_addr = device_config.dev_addr;
+        let mut device: Device = device_config.into();
+        if !device.version().is_1_1_or_greater() {
+            return Err(NCError::InvalidJoinRequest(format!("Rejoin requests are not supported by {:?} devices", device.version())));
+        }
+
+        //type 0 and 2 are signed with SNwkSIntKey, the current session is needed
+        if let RejoinRequestPayload::T02(_) = rj_p {
+            let dev_addr = current_dev_addr.ok_or(NCError::InvalidJoinRequest("Rejoin request from a device without session".to_string()))?;
+            let session = bc_client.get_device_session(&dev_addr).await?;
+            device.set_session(session.into());
+        }
+        LoRaWANPacket::validate_mic(rejoin_request, &packet, &device, &MicContext::default())?;
+
+        let rj_count = rj_p.rj_count();
+        match rj_p {
+            RejoinRequestPayload::T1(_) => {
+                let current = device.join_context().rj_count1();
+                if rj_count <= current { return Err(NCError::InvalidJoinRequest(format!("Invalid RJcount1, expected > {current}, received {rj_count}"))) }
+                device.join_context_mut().update_rj_count1(rj_count);
+            },
+            RejoinRequestPayload::T02(_) => {
+                let network_context = device.session_mut().ok_or(LoRaWANError::SessionContextMissing)?.network_context_mut();
+                let current = network_context.rj_count0();
+                if rj_count <= current { return Err(NCError::InvalidJoinRequest(format!("Invalid RJcount0, expected > {current}, received {rj_count}"))) }
+                network_context.update_rj_count0(rj_count);
+            },
+        }
+        //stored before answering, a replay of the same request is then rejected by the check above
+        bc_client.update_rj_count(rj_p.dev_eui(), join_req_type, rj_count).await?;
+
+        let regional_params = device.regional_parameters().unwrap_or_default();
+        let dl_settings = regional_params.default_dl_settings().with_opt_neg(true);
+        let cf_list = cf_list.filter(|c| ChannelList::new(*regional_params.region()).apply_cf_list(c).is_ok());
+
+        //type 2 may change the DevAddr, keeping it keeps the MAC state of the device too
+        let dev_addr = match (join_req_type, current_dev_addr) {
+            (JoinRequestType::RejoinRequest2, Some(dev_addr)) => dev_addr,
+            _ => {
+                let mut dev_addr = [0_u8; 4];
+                let dev_addr_sha256 = sha256(&[device.dev_eui().as_slice(), device.join_eui().as_slice(), &[join_req_type.to_byte()], &rj_count.to_be_bytes()].concat());
+                dev_addr.copy_from_slice(&dev_addr_sha256[..4]);
+                dev_addr
+            },
+        };
+
+        LOGGER.write(&format!("Rejoin {join_req_type:?} for {}", PrettyHexSlice(&dev_addr))).await;
+
+        let join_accept = JoinAcceptPayload::new(
+            join_req_type,
+            device.join_context_mut().join_nonce_autoinc(),
+            [1,2,3],
+            dev_addr,
+            dl_settings,
+            regional_params.default_rx_delay(),
+            cf_list
+        );
+
+        if join_req_type != JoinRequestType::RejoinRequest2 {
+            mac_handler.set_join_accept_parameters(dev_addr, dl_settings, regional_params.default_rx_delay(), cf_list, *regional_params.region());
+        }
+        mac_handler.set_profile(dev_addr, *device.version(), *regional_params.region());
+        device.set_last_join_request_received(join_req_type);
+        device.generate_session_context(&join_accept)?;
+
+        let packet = LoRaWANPacket::new(MHDR::new(MType::JoinAccept, Major::R1), Payload::JoinAccept(join_accept));
+        let join_accept = packet.to_bytes_with_context(&device).map_err(NCError::from)?;
+
+        let deduplication_ans = bc_client.join_procedure(rejoin_request, &join_accept, rj_p.dev_eui()).await?;
+        if deduplication_ans.is_winner(nc_id) {
+            let (_, keys) = deduplication_ans.into_tuple();
+            Ok(DispatchResults {
+                session_derivation_info: Some((keys, device.dev_eui().to_string())),
+                consensus_info: None,
+                answer: Some(join_accept),
+                mac_commands: Vec::new(),
+                region: *regional_params.region(),
+                rx_parameters: RxParameters::join_accept(*regional_params.region()),
+            })
+        } else {
+            Ok(DispatchResults {
+                session_derivation_info: None,
+                consensus_info: None,
+                answer: None,
+                mac_commands: Vec::new(),
+                region: *regional_params.region(),
+                rx_parameters: RxParameters::join_accept(*regional_params.region()),
+            })
+        }
+    }
+
+    ///ConfFCnt, TxDr and TxCh of the LoRaWAN 1.1 MIC of a data uplink, from the radio parameters it was received with.
+    ///The channel index is looked up in the channels the device was told to use (JoinAccept CFList, NewChannelReq and LinkADRReq),
+    ///the default channels plus the CFList if the device has not been seen since the network controller started.
+    ///Communicators without radio (UDP, TCP) carry no frequency, in that case the device uses 0 for both TxDr and TxCh
+    fn uplink_mic_context(device: &Device, channels: Option<ChannelList>, cf_list: Option<CFList>, metadata: &UplinkMetadata) -> MicContext {
+        //an ack can only be for the last confirmed downlink, which carries an application payload
+        let conf_fcnt = device.session().map(|s| s.application_context().af_cnt_dwn() as u16);
+        if metadata.frequency <= 0.0 {
+            return MicContext { conf_fcnt, ..Default::default() };
+        }
+        let region = *device.regional_parameters().unwrap_or_default().region();
+        let channels = channels.unwrap_or_else(|| {
+            let mut channels = ChannelList::new(region);
+            if let Some(cf_list) = cf_list {
+                let _ = channels.apply_cf_list(&cf_list);
+            }
+            channels
+        });
+        MicContext {
+            conf_fcnt,
+            tx_dr: region.data_rate_from(metadata.spreading_factor, metadata.bandwidth).map_or(0, |dr| dr.value()),
+            tx_ch: channels.index_of(metadata.frequency as u32).unwrap_or(0) as u8,
+        }
+    }
+
+    ///The MAC answer is None for the copies of an uplink forwarded by other gateways, they are not answered nor sent to the consensus
+    async fn handle_unconfirmed_data_up(data_up: &[u8], bc_client: &Arc<impl BlockchainClient>, mac_handler: &MacCommandHandler, metadata: &UplinkMetadata, cf_list: Option<CFList>) -> Result<(Device, DispatchResults, Option<MacAnswer>), NCError> {
         let packet = LoRaWANPacket::from_bytes(data_up, None, true)?;
         if let Payload::MACPayload(payload) = packet.payload() {
             let dev_addr = payload.fhdr().dev_addr();
 
             match bc_client.get_device_session(&dev_addr).await {
                 Ok(session) => {
-                    let fcnt_u16 = payload.fhdr().fcnt();
-                    let current_fcnt = session.f_cnt_up;  
-                            
-                    let (fcnt_up_valid, _fcnt_up_looped) = nonce_valid(fcnt_u16, current_fcnt as u16);
-                    if !fcnt_up_valid { return Err(NCError::InvalidUplink(format!("Invalid fcnt_up, expected > {current_fcnt}, received {fcnt_u16}"))); }
-
+                    let current_fcnt = session.f_cnt_up;
                     let nc_list = session.nc_ids.clone();
-                    let device = session.into();
-
-                    let p = LoRaWANPacket::from_bytes(data_up, Some(&device), true)?; 
-                    if let Payload::MACPayload(_mp) = p.payload() {
+                    //the session does not tell the LoRaWAN version, which changes the MIC and the FOpts encryption,
+                    //the configuration is read from the ledger only for the first uplink of a device this controller did not join
+                    let (version, region) = match mac_handler.profile(&dev_addr) {
+                        Some(profile) => profile,
+                        None => {
+                            let config = bc_client.get_device_config(&session.dev_eui).await?;
+                            mac_handler.set_profile(dev_addr, config.version, config.region);
+                            (config.version, config.region)
+                        },
+                    };
+                    let mut device = Device::new(DeviceClass::default(), Some(RegionalParameters::new(region)), session.dev_eui, EUI64::default(), Key::default(), Key::default(), version);
+                    device.set_session(session.into());
+
+                    //the FCnt carries only the lower half of the counter, the MIC tells which upper half it has
+                    let (p, fcnt_up) = LoRaWANPacket::from_bytes_with_fcnt_search(data_up, &device, true, &Self::uplink_mic_context(&device, mac_handler.adr().channels(&dev_addr), cf_list, metadata))?;
+                    if fcnt_up <= current_fcnt { return Err(NCError::InvalidUplink(format!("Invalid fcnt_up, expected > {current_fcnt}, received {fcnt_up}"))); }
+                    device.session_mut().ok_or(LoRaWANError::SessionContextMissing)?.network_context_mut().update_f_cnt_up(fcnt_up);
+
+                    let mut mac_commands = Vec::new();
+                    if let Payload::MACPayload(mp) = p.payload() {
+                        let fopts_len = mp.fhdr().fctrl().f_opts_len() as usize;
+                        if fopts_len > 0 {
+                            mac_commands.extend(EDMacCommands::from_bytes(&mp.fhdr().fopts()[..fopts_len])?);
+                        }
                         if payload.is_application() {
                             //let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
                             //let data_to_send = json!({
@@ -140,34 +343,103 @@ impl NetworkController {
                             //    "tmst": now.as_secs()
                             //});
                             //utils::uplink_to_application_server(data_to_send.to_string().as_bytes()).await?;
-                        } else {
-                            let mac_commands = mac_commands::EDMacCommands::from_bytes(&p.payload().to_bytes_with_context(&device).unwrap())?;
-                            println!("{mac_commands:?}");
-                            //TODO analyze mac commands and act accordingly
+                        } else if let Some(frm_payload) = mp.frm_payload() {
+                            mac_commands.extend(EDMacCommands::from_bytes(frm_payload)?);
                         }
                     }
-                    Ok((device, DispatchResults {
+                    //the ADR history takes the SNR of every copy
+                    let adr_decision = if let FCtrl::Uplink(fctrl) = payload.fhdr().fctrl() {
+                        let region = device.regional_parameters().unwrap_or_default();
+                        mac_handler.adr().register_uplink(dev_addr, region.region(), fcnt_up, fctrl.adr, fctrl.adr_ack_req, metadata)
+                    } else { AdrDecision::default() };
+                    let region = *device.regional_parameters().unwrap_or_default().region();
+                    let rx_parameters = mac_handler.rx_parameters(&dev_addr, region);
+                    let mut results = DispatchResults {
                         session_derivation_info: None,
-                        consensus_info: Some(DownlinkConsensusLedgerUpdateInfo {
-                            dev_addr,
-                            nc_list,
-                        }),
+                        consensus_info: None,
                         answer: None,
-                    }))
+                        mac_commands: Vec::new(),
+                        region,
+                        rx_parameters,
+                    };
+                    if !mac_handler.first_copy(dev_addr, fcnt_up) {
+                        return Ok((device, results, None));
+                    }
+                    for link_adr_req in adr_decision.commands {
+                        mac_handler.queue_command(dev_addr, link_adr_req);
+                    }
+                    let mut mac_answer = mac_handler.handle_uplink(dev_addr, fcnt_up, &mac_commands, metadata);
+                    mac_answer.force_downlink |= adr_decision.force_downlink;
+                    results.consensus_info = Some(DownlinkConsensusLedgerUpdateInfo { dev_addr, nc_list });
+                    Ok((device, results, Some(mac_answer)))
                 },
                 Err(e) => Err(NCError::GenericError(format!("Error getting device session: {e:?}"))),
             }
         } else { Err(NCError::InvalidUplink("Not a MACPayload payload".to_string())) }
     }
 
-    async fn handle_confirmed_data_up(data_up: &[u8], bc_client: &Arc<impl BlockchainClient>) -> Result<DispatchResults, NCError> {
-        let (mut device, mut results) = Self::handle_unconfirmed_data_up(data_up, bc_client).await?;
+    async fn handle_confirmed_data_up(data_up: &[u8], bc_client: &Arc<impl BlockchainClient>, mac_handler: &MacCommandHandler, metadata: &UplinkMetadata, cf_list: Option<CFList>) -> Result<DispatchResults, NCError> {
+        let (mut device, mut results, mac_answer) = Self::handle_unconfirmed_data_up(data_up, bc_client, mac_handler, metadata, cf_list).await?;
+        let Some(mac_answer) = mac_answer else { return Ok(results) };
+        let conf_fcnt = device.session().ok_or(LoRaWANError::SessionContextMissing)?.network_context().f_cnt_up() as u16;
+        let max_payload = Self::max_downlink_payload(&results.region, &results.rx_parameters, metadata);
+        let (data_down, mac_commands) = Self::create_data_down(&mut device, Some(conf_fcnt), mac_answer.commands, Some((1, "Blablabla".bytes().collect())), max_payload, mac_handler)?;
+        results.answer = Some(data_down);
+        results.mac_commands = mac_commands;
+        Ok(results)
+    }
 
-        let dev_addr = *device.session().unwrap().network_context().dev_addr();
-        let fctrl = FCtrl::Downlink(DownlinkFCtrl::new(false, false, true, false, 0));
+    ///FRMPayload size that fits both windows of the answer, FOpts excluded
+    fn max_downlink_payload(region: &Region, rx_parameters: &RxParameters, metadata: &UplinkMetadata) -> usize {
+        let rx1 = region.data_rate_from(metadata.spreading_factor, metadata.bandwidth).and_then(|dr| region.rx1_data_rate(dr, rx_parameters.rx1_dr_offset));
+        [rx1, Some(rx_parameters.rx2_data_rate)].iter().flatten()
+            .filter_map(|dr| region.max_app_payload_size(*dr))
+            .min()
+            .map_or(usize::MAX, usize::from)
+    }
+
+    ///Bytes of the commands that fit in max_len, in order, and the commands left out
+    fn fit_commands(mac_commands: &[NCMacCommands], max_len: usize) -> (Vec<u8>, Vec<NCMacCommands>) {
+        let mut bytes = Vec::new();
+        let mut left_out = Vec::new();
+        for c in mac_commands {
+            let command = c.to_bytes();
+            if left_out.is_empty() && bytes.len() + command.len() <= max_len {
+                bytes.extend(command);
+            } else {
+                left_out.push(c.clone());
+            }
+        }
+        (bytes, left_out)
+    }
+
+    ///MAC commands go in FOpts when they fit, otherwise in a FPort 0 payload of at most max_payload bytes if there is no application payload.
+    ///Commands left out are queued again for the next downlink, the ones sent are returned with the frame. conf_fcnt is the FCnt of the confirmed uplink acknowledged
+    fn create_data_down(device: &mut Device, conf_fcnt: Option<u16>, mac_commands: Vec<NCMacCommands>, app_payload: Option<(u8, Vec<u8>)>, max_payload: usize, mac_handler: &MacCommandHandler) -> Result<(Vec<u8>, Vec<NCMacCommands>), NCError> {
+        let dev_addr = *device.session().ok_or(LoRaWANError::SessionContextMissing)?.network_context().dev_addr();
+
+        let (mut fopts, mut left_out) = Self::fit_commands(&mac_commands, 15);
+        let (fport, frm_payload) = match app_payload {
+            None if !left_out.is_empty() => {
+                let (frm_payload, fport_0_left_out) = Self::fit_commands(&mac_commands, max_payload);
+                fopts.clear();
+                left_out = fport_0_left_out;
+                (Some(0), Some(frm_payload))
+            },
+            None => (None, None),
+            Some((port, payload)) => (Some(port), Some(payload)),
+        };
+        let sent = mac_commands.into_iter().filter(|c| !left_out.contains(c)).collect();
+        if !left_out.is_empty() {
+            mac_handler.requeue(dev_addr, left_out);
+        }
+
+        let fctrl = FCtrl::Downlink(DownlinkFCtrl::new(false, false, conf_fcnt.is_some(), false, 0));
         let mut fhdr = FHDR::new(dev_addr, fctrl);
+        if !fopts.is_empty() {
+            fhdr.set_fopts(&fopts);
+        }
         
-        let fport = Some(1);
         let session_context = device.session_mut().ok_or(LoRaWANError::SessionContextMissing)?;
         let new_value = if fport.is_none() || fport == Some(0) {
             session_context.network_context_mut().nf_cnt_dwn_autoinc() as u16
@@ -176,29 +448,34 @@ impl NetworkController {
         };
         fhdr.set_fcnt(new_value);
 
-        let downlink_payload = Payload::MACPayload(MACPayload::new(fhdr, fport, Some("Blablabla".bytes().collect())));
+        let downlink_payload = Payload::MACPayload(MACPayload::new(fhdr, fport, frm_payload));
         let mhdr = MHDR::new(MType::UnconfirmedDataDown, Major::R1);
         let packet = LoRaWANPacket::new(mhdr, downlink_payload);
 
-        let data_down = packet.to_bytes_with_context(&device).map_err(NCError::from)?;
-        results.answer = Some(data_down);
-        Ok(results)
+        let data_down = packet.to_bytes_with_mic_context(device, &MicContext { conf_fcnt, ..Default::default() }).map_err(NCError::from)?;
+        Ok((data_down, sent))
     }
     
-    async fn dispatch_task(mhdr: &MHDR, buf: &[u8], bc_client: &Arc<impl BlockchainClient>, nc_id: &'static str) -> Result<DispatchResults, NCError> {
+    async fn dispatch_task(mhdr: &MHDR, buf: &[u8], bc_client: &Arc<impl BlockchainClient>, nc_id: &str, cf_list: Option<CFList>, mac_handler: &MacCommandHandler, metadata: &UplinkMetadata) -> Result<DispatchResults, NCError> {
         match mhdr.mtype() {
             MType::JoinRequest => {
-                Self::handle_join_request(buf, bc_client, nc_id).await
+                Self::handle_join_request(buf, bc_client, nc_id, cf_list, mac_handler).await
             },
             MType::UnconfirmedDataUp => {
-                let (_, results) = Self::handle_unconfirmed_data_up(buf, bc_client).await?;
+                let (mut device, mut results, mac_answer) = Self::handle_unconfirmed_data_up(buf, bc_client, mac_handler, metadata, cf_list).await?;
+                if let Some(mac_answer) = mac_answer.filter(|a| !a.commands.is_empty() || a.force_downlink) {
+                    let max_payload = Self::max_downlink_payload(&results.region, &results.rx_parameters, metadata);
+                    let (data_down, mac_commands) = Self::create_data_down(&mut device, None, mac_answer.commands, None, max_payload, mac_handler)?;
+                    results.answer = Some(data_down);
+                    results.mac_commands = mac_commands;
+                }
                 Ok(results)
             },
             MType::ConfirmedDataUp => {
-                Self::handle_confirmed_data_up(buf, bc_client).await
+                Self::handle_confirmed_data_up(buf, bc_client, mac_handler, metadata, cf_list).await
             },
             MType::RejoinRequest => {
-                unimplemented!("RejoinRequest")
+                Self::handle_rejoin_request(buf, bc_client, nc_id, cf_list, mac_handler).await
             },
             
             MType::UnconfirmedDataDown |
@@ -227,11 +504,76 @@ impl NetworkController {
         consensus_receiver.await.map_err(|e| NCError::CommandTransmissionFailed(e.to_string()))
     }
 
+    ///Answer to an uplink for RX1, with RX2 as fallback, in the windows the device opens for it.
+    ///Uplinks from a packet forwarder carry its tmst, the windows are then timed by the gateway itself
+    fn downlink_message<T>(received: &ReceivedTransmission, answer: &[u8], rx_parameters: &RxParameters, region: &Region, received_at: Instant, additional_info: Option<T>, ack: oneshot::Sender<TxAck>) -> DownlinkSchedulerMessage<T> {
+        let uplink = &received.transmission;
+        let (rx1_delay, rx2_delay) = (rx_parameters.rx1_delay, rx_parameters.rx2_delay());
+        let (spreading_factor, bandwidth) = region.data_rate_from(uplink.spreading_factor, uplink.bandwidth)
+            .and_then(|dr| region.rx1_data_rate(dr, rx_parameters.rx1_dr_offset))
+            .and_then(|dr| region.data_rate(dr))
+            .unwrap_or((uplink.spreading_factor, uplink.bandwidth));
+        let rx1 = Transmission {
+            frequency: region.rx1_frequency(uplink.frequency as u32) as f64,
+            bandwidth,
+            spreading_factor,
+            code_rate: uplink.code_rate,
+            uplink: false,
+            gateway_tmst: received.arrival_stats.gateway_tmst.map(|t| t.after(rx1_delay)),
+            payload: answer.to_vec(),
+            ..Default::default()
+        };
+        let rx2 = region.data_rate(rx_parameters.rx2_data_rate).map(|(spreading_factor, bandwidth)| (Transmission {
+            frequency: rx_parameters.rx2_frequency as f64,
+            bandwidth,
+            spreading_factor,
+            gateway_tmst: received.arrival_stats.gateway_tmst.map(|t| t.after(rx2_delay)),
+            ..rx1.clone()
+        }, received_at + rx2_delay));
+
+        DownlinkSchedulerMessage {
+            transmission: rx1,
+            moment: received_at + rx1_delay,
+            rx2,
+            additional_info,
+            ack: Some(ack),
+        }
+    }
+
+    ///Returns whether the gateway sent the answer
+    async fn log_tx_ack(ack_receiver: oneshot::Receiver<TxAck>, answer: &[u8], stats: &NetworkStats) -> bool {
+        let ack = ack_receiver.await;
+        stats.downlink(ack.as_ref().ok());
+        match ack {
+            Ok(TxAck::Sent(_)) => true,
+            Ok(ack) => {
+                eprintln!("Downlink {} not sent: {ack:?}", PrettyHexSlice(answer));
+                false
+            },
+            Err(e) => {
+                eprintln!("Downlink scheduler dropped {}: {e:?}", PrettyHexSlice(answer));
+                false
+            },
+        }
+    }
+
+    ///The MAC requests of an answer that never reached the device go back to the queue, no answer is awaited for them
+    fn requeue_not_sent(mac_handler: &MacCommandHandler, results: &DispatchResults) {
+        if let Some(info) = &results.consensus_info {
+            if !results.mac_commands.is_empty() {
+                mac_handler.requeue(info.dev_addr, results.mac_commands.clone());
+            }
+        }
+    }
+
     pub fn udp_routine<BC>(&self, config: &'static UDPNCConfig, blockchain_config: &BC::Config) -> JoinHandle<()> 
     where BC: BlockchainClient + 'static  {
-        let nc_id: &str = self.nc_id;        
+        let nc_id = self.nc_id.clone();
         let c = blockchain_config.clone();
         let consensus_sender = self.consensus_sender.clone();
+        let cf_list = self.cf_list;
+        let mac_handler = self.mac_handler.clone();
+        let stats = self.stats.clone();
 
         tokio::spawn( async move {
             let client: Arc<BC> = Arc::new(*BC::from_config(&c).await.unwrap());
@@ -255,19 +597,34 @@ impl NetworkController {
                 let c = Arc::clone(&client);
                 let dlsc = Arc::clone(&downlink_sender);
                 let csc = Arc::clone(&consensus_sender);
+                let mh = Arc::clone(&mac_handler);
+                let st = Arc::clone(&stats);
+                let nc_id = Arc::clone(&nc_id);
                 tokio::spawn(async move {
                     let data = &transmission.transmission.payload;
                     let mhdr = MHDR::from_bytes(data[0]);     
-                    match Self::dispatch_task(&mhdr, data, &c, nc_id).await {
+                    let metadata = UplinkMetadata {
+                        snr: transmission.arrival_stats.snr,
+                        rssi: transmission.arrival_stats.rssi,
+                        spreading_factor: transmission.transmission.spreading_factor,
+                        bandwidth: transmission.transmission.bandwidth,
+                        frequency: transmission.transmission.frequency,
+                        gw_cnt: 1,
+                        time: transmission.arrival_stats.time,
+                    };
+                    match Self::dispatch_task(&mhdr, data, &c, &nc_id, cf_list, &mh, &metadata).await {
                         Ok(ans) => {
+                            st.uplink_received(data, just_arrived);
                             let should_downlink_and_update_ledger = if mhdr.is_join_rejoin() && ans.answer.is_some() { //consensus fatto tramite blockchain
+                                st.join_accepted();
                                 true
-                            } else if let Some(info) = ans.consensus_info {
+                            } else if let Some(info) = &ans.consensus_info {
                                 if info.nc_list.len() == 1 {
-                                    info.nc_list[0] == nc_id //se sono l'unico nodo del consensus allora non c'è bisogno di fare il consenso
+                                    *info.nc_list[0] == *nc_id //se sono l'unico nodo del consensus allora non c'è bisogno di fare il consenso
                                 } else {
-                                    match Self::consensus_round(&csc, info.nc_list , PrettyHexSlice(&info.dev_addr).to_string(), &transmission.transmission.payload, transmission.arrival_stats.rssi).await {
+                                    match Self::consensus_round(&csc, info.nc_list.clone(), PrettyHexSlice(&info.dev_addr).to_string(), &transmission.transmission.payload, transmission.arrival_stats.rssi).await {
                                         Ok(v) => {
+                                            st.consensus_round(v);
                                             v
                                         },
                                         Err(e) => {
@@ -279,31 +636,17 @@ impl NetworkController {
                             } else {
                                 false
                             };
-                            if should_downlink_and_update_ledger {
+                            if !should_downlink_and_update_ledger {
+                                Self::requeue_not_sent(&mh, &ans);
+                            } else {
                                 //println!("Should downlink and update ledger about");
                                 if let Some(v) = &ans.answer {
-                                    let mut t = Transmission {
-                                        frequency: transmission.transmission.frequency,
-                                        bandwidth: transmission.transmission.bandwidth,
-                                        spreading_factor: transmission.transmission.spreading_factor,
-                                        code_rate: transmission.transmission.code_rate,
-                                        uplink: false,
-                                        payload: v.clone(),
-                                        ..Default::default()
-                                    };
-                                    let bytes = serde_json::to_vec(&t).unwrap();
-                                    t.payload = bytes; 
-    
-                                    let downlink_message = DownlinkSchedulerMessage {
-                                        transmission: t,
-                                        moment: if mhdr.is_join_rejoin() {
-                                            just_arrived + tokio::time::Duration::from_secs(5)
-                                        } else {
-                                            just_arrived + tokio::time::Duration::from_secs(1)
-                                        },
-                                        additional_info: Some(addr)
-                                    };
+                                    let (ack, ack_receiver) = oneshot::channel();
+                                    let downlink_message = Self::downlink_message(&transmission, v, &ans.rx_parameters, &ans.region, just_arrived, Some(addr), ack);
                                     dlsc.send(downlink_message).await.unwrap();
+                                    if !Self::log_tx_ack(ack_receiver, v, &st).await {
+                                        Self::requeue_not_sent(&mh, &ans);
+                                    }
                                 }
 
                                 tokio::time::sleep(Duration::from_secs(15)).await;
@@ -316,8 +659,7 @@ impl NetworkController {
                                             eprintln!("Error creating uplink with answer: {e:?}")
                                         },
                                     };
-                                } else {
-                                    let (keys, dev_eui) = ans.session_derivation_info.unwrap();
+                                } else if let Some((keys, dev_eui)) = ans.session_derivation_info {
                                     let k = keys.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
                                     match c.session_generation(&k, &dev_eui).await {
                                         Ok(_) => {},//println!("Session generated successfully for {dev_eui}"),
@@ -326,19 +668,24 @@ impl NetworkController {
                                 }
                             }
                         },                        
-                        Err(e) => eprintln!("Packet {}: {e:?}", PrettyHexSlice(data)),
+                        Err(e) => {
+                            st.invalid_uplink();
+                            eprintln!("Packet {}: {e:?}", PrettyHexSlice(data))
+                        },
                     };
                 });
             };
         })
     }
 
-    async fn communicator_routine<LC,BC>(config: &'static LC::Config, nc_id: &'static str, blockchain_config: &BC::Config, consensus_sender: Arc<Sender<ConsensusMessage>>) 
+    #[allow(clippy::too_many_arguments)]
+    async fn communicator_routine<LC,BC>(config: impl Borrow<LC::Config>, nc_id: Arc<str>, blockchain_config: BC::Config, consensus_sender: Arc<Sender<ConsensusMessage>>, cf_list: Option<CFList>, mac_handler: Arc<MacCommandHandler>, stats: Arc<NetworkStats>, capture: Option<Arc<PcapWriter>>) 
     where LC: SplitCommunicator + 'static, 
           BC: BlockchainClient + 'static {
         
-        let client: Arc<BC> = Arc::new(*BC::from_config(blockchain_config).await.unwrap());
-        let (sender, receiver) = LC::from_config(config).await.unwrap().split_communicator().await.unwrap();
+        let client: Arc<BC> = Arc::new(*BC::from_config(&blockchain_config).await.unwrap());
+        let (sender, receiver) = LC::from_config(config.borrow()).await.unwrap().split_communicator().await.unwrap();
+        let (sender, receiver) = (PcapSender::new(sender, capture.clone()), PcapReceiver::new(receiver, capture));
         
         let (downlink_sender, downlink_receiver) = tokio::sync::mpsc::channel(100);
         let downlink_sender = Arc::new(downlink_sender);
@@ -351,8 +698,19 @@ impl NetworkController {
         loop {
             match receiver.receive(None).await {
                 Ok(content) => {
-                    for packet in content {
+                    for packet in content.iter().cloned() {
                         if !packet.transmission.payload.is_empty() {
+                            //copies of the same frame received in the same batch come from different gateways
+                            let gw_cnt = content.iter().filter(|p| p.transmission.payload == packet.transmission.payload).count();
+                            let metadata = UplinkMetadata {
+                                snr: packet.arrival_stats.snr,
+                                rssi: packet.arrival_stats.rssi,
+                                spreading_factor: packet.transmission.spreading_factor,
+                                bandwidth: packet.transmission.bandwidth,
+                                frequency: packet.transmission.frequency,
+                                gw_cnt: gw_cnt.min(u8::MAX as usize) as u8,
+                                time: packet.arrival_stats.time,
+                            };
                             println!("Received {} at sf {}",PrettyHexSlice(&packet.transmission.payload), packet.transmission.spreading_factor);
                             let just_arrived = tokio::time::Instant::now();
 
@@ -361,16 +719,24 @@ impl NetworkController {
                             let client_clone = Arc::clone(&client);
                             let csc = Arc::clone(&consensus_sender);
                             let dsc = Arc::clone(&downlink_sender);
+                            let mh = Arc::clone(&mac_handler);
+                            let st = Arc::clone(&stats);
+                            let nc_id = Arc::clone(&nc_id);
     
                             tokio::spawn(async move {
-                                match Self::dispatch_task(&mhdr, &packet.transmission.payload, &client_clone, nc_id).await {
+                                match Self::dispatch_task(&mhdr, &packet.transmission.payload, &client_clone, &nc_id, cf_list, &mh, &metadata).await {
                                     Ok(ans) => {
+                                        st.uplink_received(&packet.transmission.payload, just_arrived);
                                         //TODO fixare i parametri
                                         let should_downlink_and_update_ledger = if mhdr.is_join_rejoin() && ans.answer.is_some() {
+                                            st.join_accepted();
                                             true
-                                        } else if let Some(info) = ans.consensus_info {
-                                            match Self::consensus_round(&csc, info.nc_list, PrettyHexSlice(&info.dev_addr).to_string(), &packet.transmission.payload, packet.arrival_stats.rssi).await {
-                                                Ok(v) => v,
+                                        } else if let Some(info) = &ans.consensus_info {
+                                            match Self::consensus_round(&csc, info.nc_list.clone(), PrettyHexSlice(&info.dev_addr).to_string(), &packet.transmission.payload, packet.arrival_stats.rssi).await {
+                                                Ok(v) => {
+                                                    st.consensus_round(v);
+                                                    v
+                                                },
                                                 Err(e) => {
                                                     eprintln!("Consensus error: {e:?}");
                                                     false
@@ -380,30 +746,16 @@ impl NetworkController {
                                             false
                                         };
             
-                                        if should_downlink_and_update_ledger {
+                                        if !should_downlink_and_update_ledger {
+                                            Self::requeue_not_sent(&mh, &ans);
+                                        } else {
                                             if let Some(v) = &ans.answer {
-                                                let mut t = Transmission {
-                                                    frequency: packet.transmission.frequency,
-                                                    bandwidth: packet.transmission.bandwidth,
-                                                    spreading_factor: packet.transmission.spreading_factor,
-                                                    code_rate: packet.transmission.code_rate,
-                                                    uplink: false,
-                                                    payload: v.clone(),
-                                                    ..Default::default()
-                                                };
-                                                let bytes = serde_json::to_vec(&t).unwrap();
-                                                t.payload = bytes;
-                
-                                                let downlink_message = DownlinkSchedulerMessage {
-                                                    transmission: t,
-                                                    moment: if mhdr.is_join_rejoin() {
-                                                        just_arrived + tokio::time::Duration::from_secs(5)
-                                                    } else {
-                                                        just_arrived + tokio::time::Duration::from_secs(1)
-                                                    },
-                                                    additional_info: None
-                                                };
+                                                let (ack, ack_receiver) = oneshot::channel();
+                                                let downlink_message = Self::downlink_message(&packet, v, &ans.rx_parameters, &ans.region, just_arrived, None, ack);
                                                 dsc.send(downlink_message).await.unwrap();
+                                                if !Self::log_tx_ack(ack_receiver, v, &st).await {
+                                                    Self::requeue_not_sent(&mh, &ans);
+                                                }
                                             }
                                             if !mhdr.is_join_rejoin() {
                                                 match client_clone.create_uplink(&packet.transmission.payload, ans.answer.as_deref()).await {
@@ -417,7 +769,10 @@ impl NetworkController {
                                             }
                                         }
                                     },
-                                    Err(e) => eprintln!("Packet {}: {e:?}", PrettyHexSlice(&packet.transmission.payload)),
+                                    Err(e) => {
+                                        st.invalid_uplink();
+                                        eprintln!("Packet {}: {e:?}", PrettyHexSlice(&packet.transmission.payload))
+                                    },
                                 }
                             });
                         };
@@ -431,8 +786,231 @@ impl NetworkController {
         }
     }
 
-    pub fn routine<LC,BC>(&self, config: &'static LC::Config, bc_config: &'static BC::Config) -> JoinHandle<()> 
+    ///The config can be a static reference or an Arc, the routine keeps it until its communicator is created
+    pub fn routine<LC,BC>(&self, config: impl Borrow<LC::Config> + Send + 'static, bc_config: &BC::Config) -> JoinHandle<()> 
     where LC: SplitCommunicator + 'static, BC: BlockchainClient + 'static {
-        tokio::spawn(Self::communicator_routine::<LC, BC>(config, self.nc_id, bc_config, self.consensus_sender.clone()))
+        tokio::spawn(Self::communicator_routine::<LC, BC>(config, self.nc_id.clone(), bc_config.clone(), self.consensus_sender.clone(), self.cf_list, self.mac_handler.clone(), self.stats.clone(), self.capture.clone()))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::{lorawan_packet::{fields::{DLSettings, RxDelay}, join::RejoinType}, physical_parameters::{DataRate, LoRaBandwidth, SpreadingFactor}, utils::eui::EUI64};
+    use lorawan_device::{communicator::{CommunicatorError, GatewayTimestamp, LoRaWANCommunicator}, devices::lorawan_device::LoRaWANDevice};
+
+    use super::*;
+
+    ///UDP and TCP like communicator: frames go through as they are, without frequency nor data rate
+    #[derive(Default)]
+    struct RecordingCommunicator {
+        sent: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl LoRaWANCommunicator for RecordingCommunicator {
+        type Config = ();
+
+        async fn from_config(_config: &Self::Config) -> Result<Self, CommunicatorError> {
+            Ok(Self::default())
+        }
+
+        async fn send(&self, bytes: &[u8], _src: Option<EUI64>, _dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+            self.sent.lock().unwrap().push(bytes.to_vec());
+            Ok(())
+        }
+
+        async fn receive(&self, _timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+            Err(LoRaWANError::MissingDownlink.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn uplink_1_1_without_radio_parameters() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = LoRaWANDevice::new(BlockchainMockClient::create_initialized_device(&dev_addr), RecordingCommunicator::default());
+        assert!(device.version().is_1_1_or_greater());
+        //the channels are used round robin, the second uplink is not on the first one
+        for _ in 0..2 {
+            device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        }
+
+        let client = Arc::new(BlockchainMockClient::default());
+        let mac_handler = MacCommandHandler::new();
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: Default::default(), bandwidth: Default::default(), frequency: 0.0, gw_cnt: 1, time: 0 };
+        let uplinks = device.communicator().sent.lock().unwrap().clone();
+        for uplink in uplinks.iter() {
+            let (device, _, _) = NetworkController::handle_unconfirmed_data_up(uplink, &client, &mac_handler, &metadata, None).await.unwrap();
+            assert!(device.session().unwrap().network_context().f_cnt_up() > 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn gateway_copies_handled_once() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = LoRaWANDevice::new(BlockchainMockClient::create_initialized_device(&dev_addr), RecordingCommunicator::default());
+        device.send_uplink(Some(b"hello"), false, Some(1), None).await.unwrap();
+        let uplink = device.communicator().sent.lock().unwrap()[0].clone();
+
+        let client = Arc::new(BlockchainMockClient::default());
+        let mac_handler = MacCommandHandler::new();
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: Default::default(), bandwidth: Default::default(), frequency: 0.0, gw_cnt: 2, time: 0 };
+        mac_handler.queue_command(dev_addr, NCMacCommands::DevStatusReq);
+        let (_, results, answer) = NetworkController::handle_unconfirmed_data_up(&uplink, &client, &mac_handler, &metadata, None).await.unwrap();
+        assert!(results.consensus_info.is_some());
+        assert_eq!(answer.unwrap().commands, vec![NCMacCommands::DevStatusReq]);
+        //the copy of the other gateway is neither answered nor sent to the consensus
+        let (_, results, answer) = NetworkController::handle_unconfirmed_data_up(&uplink, &client, &mac_handler, &metadata, None).await.unwrap();
+        assert!(results.consensus_info.is_none());
+        assert!(answer.is_none());
+    }
+
+    #[test]
+    fn tx_ch_after_new_channel() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let device = BlockchainMockClient::create_initialized_device(&dev_addr);
+        let region = *device.regional_parameters().unwrap_or_default().region();
+        let mac_handler = MacCommandHandler::new();
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: SpreadingFactor::SF7, bandwidth: LoRaBandwidth::BW125, frequency: 867_100_000.0, gw_cnt: 1, time: 0 };
+        mac_handler.set_join_accept_parameters(dev_addr, DLSettings::new(true, 0, 0).unwrap(), RxDelay::new(1).unwrap(), None, region);
+        mac_handler.queue_command(dev_addr, NCMacCommands::NewChannelReq { ch_index: 3, freq: 8_671_000, max_dr: 5, min_dr: 0 });
+        mac_handler.handle_uplink(dev_addr, 1, &[], &metadata);
+        //not one of the channels of the device until it acknowledges it
+        let tx_ch = |mac_handler: &MacCommandHandler| NetworkController::uplink_mic_context(&device, mac_handler.adr().channels(&dev_addr), None, &metadata).tx_ch;
+        assert_eq!(tx_ch(&mac_handler), 0);
+        mac_handler.handle_uplink(dev_addr, 2, &[EDMacCommands::NewChannelAns { data_range_ok: true, channel_frequency_ok: true }], &metadata);
+        assert_eq!(tx_ch(&mac_handler), 3);
+    }
+
+    #[tokio::test]
+    async fn requests_requeued_when_downlink_not_sent() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = BlockchainMockClient::create_initialized_device(&dev_addr);
+        let uplink = device.create_uplink(Some(b"hello"), false, Some(1), None, &MicContext::default()).unwrap();
+
+        let client = Arc::new(BlockchainMockClient::default());
+        let mac_handler = MacCommandHandler::new();
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: Default::default(), bandwidth: Default::default(), frequency: 0.0, gw_cnt: 1, time: 0 };
+        mac_handler.queue_command(dev_addr, NCMacCommands::DevStatusReq);
+        let results = NetworkController::dispatch_task(&MHDR::from_bytes(uplink[0]), &uplink, &client, "nc", None, &mac_handler, &metadata).await.unwrap();
+        assert!(results.answer.is_some());
+        assert_eq!(results.mac_commands, vec![NCMacCommands::DevStatusReq]);
+        assert_eq!(mac_handler.device_state(&dev_addr).unwrap().awaiting.len(), 1);
+
+        let (ack, ack_receiver) = oneshot::channel();
+        ack.send(TxAck::TooLate).unwrap();
+        assert!(!NetworkController::log_tx_ack(ack_receiver, results.answer.as_deref().unwrap(), &NetworkStats::new()).await);
+        NetworkController::requeue_not_sent(&mac_handler, &results);
+        let state = mac_handler.device_state(&dev_addr).unwrap();
+        assert_eq!(state.queued, vec![NCMacCommands::DevStatusReq]);
+        assert!(state.awaiting.is_empty());
+    }
+
+    #[test]
+    fn fport_0_payload_capped() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = BlockchainMockClient::create_initialized_device(&dev_addr);
+        let region = Region::EU863_870;
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: SpreadingFactor::SF12, bandwidth: LoRaBandwidth::BW125, frequency: 868_100_000.0, gw_cnt: 1, time: 0 };
+        //DR0 in both windows: 51 bytes
+        let max_payload = NetworkController::max_downlink_payload(&region, &RxParameters::regional_default(region), &metadata);
+        assert_eq!(max_payload, 51);
+
+        let mac_handler = MacCommandHandler::new();
+        let commands: Vec<NCMacCommands> = (3..13).map(|ch_index| NCMacCommands::NewChannelReq { ch_index, freq: 867_100_000, max_dr: 5, min_dr: 0 }).collect();
+        let (data_down, sent) = NetworkController::create_data_down(&mut device, None, commands.clone(), None, max_payload, &mac_handler).unwrap();
+        //8 commands of 6 bytes, the other 2 wait for the next downlink
+        assert_eq!(sent, commands[..8]);
+        assert_eq!(mac_handler.device_state(&dev_addr).unwrap().queued, commands[8..]);
+        assert_eq!(data_down.len(), 1 + 7 + 1 + 48 + 4);
+    }
+
+    #[tokio::test]
+    async fn replayed_rejoin_request_rejected() {
+        let dev_eui = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut device = BlockchainMockClient::create_initialized_device(&dev_eui);
+        let first = device.create_rejoin_request(RejoinType::Type1).unwrap();
+        let second = device.create_rejoin_request(RejoinType::Type1).unwrap();
+
+        let client = Arc::new(BlockchainMockClient::default());
+        let mac_handler = MacCommandHandler::new();
+        assert!(NetworkController::handle_rejoin_request(&first, &client, "nc", None, &mac_handler).await.is_ok());
+        assert!(matches!(NetworkController::handle_rejoin_request(&first, &client, "nc", None, &mac_handler).await, Err(NCError::InvalidJoinRequest(_))));
+        assert!(NetworkController::handle_rejoin_request(&second, &client, "nc", None, &mac_handler).await.is_ok());
+        assert_eq!(client.get_device_config(&EUI64::from(dev_eui)).await.unwrap().rj_count1, 2);
+    }
+
+    #[tokio::test]
+    async fn rejoin_type_2_keeps_radio_parameters() {
+        let dev_eui = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut device = BlockchainMockClient::create_uninitialized_device(&dev_eui);
+        let client = Arc::new(BlockchainMockClient::default());
+        let mac_handler = MacCommandHandler::new();
+        let join_request = device.create_join_request().unwrap();
+        let join_accept = NetworkController::handle_join_request(&join_request, &client, "", None, &mac_handler).await.unwrap().answer.unwrap();
+        if let Payload::JoinAccept(ja) = LoRaWANPacket::from_bytes(&join_accept, Some(&device), false).unwrap().payload() {
+            device.generate_session_context(ja).unwrap();
+        }
+        let dev_addr = *device.session().unwrap().network_context().dev_addr();
+        let region = *device.regional_parameters().unwrap_or_default().region();
+
+        mac_handler.queue_command(dev_addr, NCMacCommands::RXTimingSetupReq(RxDelay::new(5).unwrap()));
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: Default::default(), bandwidth: Default::default(), frequency: 0.0, gw_cnt: 1, time: 0 };
+        mac_handler.handle_uplink(dev_addr, 1, &[], &metadata);
+        mac_handler.handle_uplink(dev_addr, 2, &[EDMacCommands::RXTimingSetupAns], &metadata);
+        let rx_parameters = mac_handler.rx_parameters(&dev_addr, region);
+
+        //a new session is derived, the mock ledger makes a1b2c3d4 win every rejoin
+        let rejoin_request = device.create_rejoin_request(RejoinType::Type2).unwrap();
+        let results = NetworkController::handle_rejoin_request(&rejoin_request, &client, "a1b2c3d4", None, &mac_handler).await.unwrap();
+        assert!(results.session_derivation_info.is_some());
+        assert_eq!(mac_handler.rx_parameters(&dev_addr, region), rx_parameters);
+    }
+
+    #[test]
+    fn downlink_timed_on_gateway_tmst() {
+        let gateway = EUI64::from([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut received = ReceivedTransmission { transmission: Transmission { frequency: 868_100_000.0, ..Default::default() }, ..Default::default() };
+        received.arrival_stats.gateway_tmst = Some(GatewayTimestamp { gateway, tmst: u32::MAX - 499_999 });
+        let (ack, _) = oneshot::channel();
+        let message = NetworkController::downlink_message::<()>(&received, &[0x60], &RxParameters::regional_default(Region::EU863_870), &Region::EU863_870, Instant::now(), None, ack);
+        assert_eq!(message.transmission.gateway_tmst, Some(GatewayTimestamp { gateway, tmst: 500_000 }));
+        assert_eq!(message.rx2.unwrap().0.gateway_tmst, Some(GatewayTimestamp { gateway, tmst: 1_500_000 }));
+
+        //without a gateway counter the downlink is sent on arrival
+        received.arrival_stats.gateway_tmst = None;
+        let (ack, _) = oneshot::channel();
+        let message = NetworkController::downlink_message::<()>(&received, &[0x60], &RxParameters::regional_default(Region::EU863_870), &Region::EU863_870, Instant::now(), None, ack);
+        assert_eq!(message.transmission.gateway_tmst, None);
+    }
+
+    #[test]
+    fn downlink_after_rx_timing_setup() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let region = Region::EU863_870;
+        let mac_handler = MacCommandHandler::new();
+        let metadata = UplinkMetadata { snr: 0.0, rssi: 0.0, spreading_factor: Default::default(), bandwidth: Default::default(), frequency: 868_100_000.0, gw_cnt: 1, time: 0 };
+        mac_handler.set_join_accept_parameters(dev_addr, DLSettings::new(true, 1, 0).unwrap(), RxDelay::new(2).unwrap(), None, region);
+        mac_handler.queue_command(dev_addr, NCMacCommands::RXTimingSetupReq(RxDelay::new(5).unwrap()));
+        mac_handler.handle_uplink(dev_addr, 1, &[], &metadata);
+        //not applied until the device acknowledges it
+        assert_eq!(mac_handler.rx_parameters(&dev_addr, region).rx1_delay, Duration::from_secs(2));
+        mac_handler.handle_uplink(dev_addr, 2, &[EDMacCommands::RXTimingSetupAns], &metadata);
+        let rx_parameters = mac_handler.rx_parameters(&dev_addr, region);
+        assert_eq!(rx_parameters, RxParameters { rx1_dr_offset: 1, rx1_delay: Duration::from_secs(5), rx2_frequency: 869_525_000, rx2_data_rate: DataRate::DR0 });
+
+        let gateway = EUI64::from([1, 2, 3, 4, 5, 6, 7, 8]);
+        let uplink = Transmission { frequency: 868_300_000.0, spreading_factor: SpreadingFactor::SF9, bandwidth: LoRaBandwidth::BW125, ..Default::default() };
+        let mut received = ReceivedTransmission { transmission: uplink, ..Default::default() };
+        received.arrival_stats.gateway_tmst = Some(GatewayTimestamp { gateway, tmst: 1_000 });
+        let (ack, _) = oneshot::channel();
+        let received_at = Instant::now();
+        let message = NetworkController::downlink_message::<()>(&received, &[0x60], &rx_parameters, &region, received_at, None, ack);
+        assert_eq!(message.moment, received_at + Duration::from_secs(5));
+        assert_eq!(message.transmission.gateway_tmst, Some(GatewayTimestamp { gateway, tmst: 5_001_000 }));
+        assert_eq!(message.transmission.spreading_factor, SpreadingFactor::SF10);
+        let (rx2, rx2_moment) = message.rx2.unwrap();
+        assert_eq!(rx2_moment, received_at + Duration::from_secs(6));
+        assert_eq!((rx2.frequency, rx2.spreading_factor), (869_525_000.0, SpreadingFactor::SF12));
+    }
+}
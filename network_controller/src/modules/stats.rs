@@ -0,0 +1,170 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use lorawan::lorawan_packet::{mhdr::{MType, MHDR}, payload::Payload, LoRaWANPacket};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use super::downlink_scheduler::TxAck;
+
+///Copies received through other gateways arrive within this window, a retransmission of the same FCnt (NbTrans) only after the receive windows
+pub const DUPLICATE_WINDOW: Duration = Duration::from_secs(1);
+
+///Traffic handled by a network controller since it started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct NetworkCounters {
+    ///valid data uplinks, each DevAddr and FCnt counted once whatever the number of gateways and transmissions
+    pub uplinks: u64,
+    ///copies of a data uplink already received through another gateway
+    pub duplicates: u64,
+    ///transmissions of an FCnt already received, e.g. retries of confirmed uplinks or NbTrans
+    pub retransmissions: u64,
+    pub join_requests: u64,
+    pub join_accepts: u64,
+    ///frames the network controller could not handle, e.g. unknown device, invalid MIC or FCnt
+    pub invalid: u64,
+    pub consensus_rounds: u64,
+    pub consensus_won: u64,
+    pub downlinks_sent: u64,
+    ///downlinks too late, colliding or failed in the gateway
+    pub downlinks_failed: u64,
+}
+
+///Data uplinks of a device as seen by the network controller
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceCounters {
+    pub uplinks: u64,
+    pub duplicates: u64,
+    pub retransmissions: u64,
+    pub last_fcnt: u16,
+}
+
+#[derive(Debug, Default)]
+struct StatsState {
+    counters: NetworkCounters,
+    devices: HashMap<[u8; 4], DeviceCounters>,
+    ///arrival of the first copy of the last transmission of each device
+    first_received: HashMap<[u8; 4], Instant>,
+}
+
+///Counters shared by every routine of a network controller
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+    state: Mutex<StatsState>,
+}
+
+impl NetworkStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Count a frame the network controller handled. A data uplink with the same FCnt as the last one of its device is a duplicate
+    ///when it arrives within DUPLICATE_WINDOW of the first copy of the last transmission, later it is a retransmission
+    pub fn uplink_received(&self, payload: &[u8], received_at: Instant) {
+        let Some(first) = payload.first() else { return };
+        let mut state = self.state.lock().unwrap();
+        match MHDR::from_bytes(*first).mtype() {
+            MType::JoinRequest | MType::RejoinRequest => state.counters.join_requests += 1,
+            MType::UnconfirmedDataUp | MType::ConfirmedDataUp => {
+                let header = LoRaWANPacket::from_bytes(payload, None, true).ok().and_then(|packet| match packet.payload() {
+                    Payload::MACPayload(p) => Some((p.fhdr().dev_addr(), p.fhdr().fcnt())),
+                    _ => None,
+                });
+                let Some((dev_addr, fcnt)) = header else {
+                    state.counters.invalid += 1;
+                    return;
+                };
+                let first_received = state.first_received.get(&dev_addr).copied();
+                let device = state.devices.entry(dev_addr).or_default();
+                let same_fcnt = device.uplinks > 0 && device.last_fcnt == fcnt;
+                let duplicate = same_fcnt && first_received.is_some_and(|first| received_at.saturating_duration_since(first) < DUPLICATE_WINDOW);
+                if duplicate {
+                    device.duplicates += 1;
+                    state.counters.duplicates += 1;
+                } else if same_fcnt {
+                    device.retransmissions += 1;
+                    state.counters.retransmissions += 1;
+                    state.first_received.insert(dev_addr, received_at);
+                } else {
+                    device.uplinks += 1;
+                    device.last_fcnt = fcnt;
+                    state.counters.uplinks += 1;
+                    state.first_received.insert(dev_addr, received_at);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    pub fn invalid_uplink(&self) {
+        self.state.lock().unwrap().counters.invalid += 1;
+    }
+
+    pub fn join_accepted(&self) {
+        self.state.lock().unwrap().counters.join_accepts += 1;
+    }
+
+    pub fn consensus_round(&self, won: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.counters.consensus_rounds += 1;
+        if won {
+            state.counters.consensus_won += 1;
+        }
+    }
+
+    pub fn downlink(&self, ack: Option<&TxAck>) {
+        let mut state = self.state.lock().unwrap();
+        match ack {
+            Some(TxAck::Sent(_)) => state.counters.downlinks_sent += 1,
+            _ => state.counters.downlinks_failed += 1,
+        }
+    }
+
+    pub fn counters(&self) -> NetworkCounters {
+        self.state.lock().unwrap().counters
+    }
+
+    pub fn device(&self, dev_addr: &[u8; 4]) -> Option<DeviceCounters> {
+        self.state.lock().unwrap().devices.get(dev_addr).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::{device::Device, lorawan_packet::MicContext};
+
+    use super::*;
+
+    fn data_up(device: &mut Device, fcnt: u32) -> Vec<u8> {
+        BlockchainMockClient::create_data_up(device, fcnt, &[0xAA], &MicContext::default())
+    }
+
+    #[test]
+    fn duplicates() {
+        let stats = NetworkStats::new();
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = BlockchainMockClient::create_initialized_device(&dev_addr);
+        let mut other = BlockchainMockClient::create_initialized_device(&[0x05, 0x06, 0x07, 0x08]);
+        let now = Instant::now();
+        stats.uplink_received(&data_up(&mut device, 1), now);
+        stats.uplink_received(&data_up(&mut device, 1), now + Duration::from_millis(20));
+        stats.uplink_received(&data_up(&mut device, 2), now + Duration::from_secs(5));
+        //retransmission of the same frame after the receive windows
+        stats.uplink_received(&data_up(&mut device, 2), now + Duration::from_secs(8));
+        //copy of the retransmission through another gateway
+        stats.uplink_received(&data_up(&mut device, 2), now + Duration::from_millis(8_020));
+        stats.uplink_received(&data_up(&mut other, 2), now);
+        stats.uplink_received(&[0x00; 23], now);
+        stats.consensus_round(true);
+        stats.consensus_round(false);
+
+        let counters = stats.counters();
+        assert_eq!(counters.uplinks, 3);
+        assert_eq!(counters.duplicates, 2);
+        assert_eq!(counters.retransmissions, 1);
+        assert_eq!(counters.join_requests, 1);
+        assert_eq!((counters.consensus_rounds, counters.consensus_won), (2, 1));
+        let device = stats.device(&dev_addr).unwrap();
+        assert_eq!((device.uplinks, device.duplicates, device.retransmissions, device.last_fcnt), (2, 2, 1, 2));
+    }
+}
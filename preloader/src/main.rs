@@ -9,7 +9,8 @@ use std::{
 use application_server::application_server::{ApplicationServer, ApplicationServerConfig};
 use clap::Parser;
 use consensus::{consensus_server::ConsensusConfig, ConsensusCerts};
-use lorawan_device::configs::{ColosseumDeviceConfig, DeviceConfig, DeviceConfigType, RadioDeviceConfig, UDPNCConfig};
+use lorawan_device::configs::{ColosseumDeviceConfig, DeviceConfig, DeviceConfigType, RadioDeviceConfig, UDPNCConfig, GWMPNCConfig};
+use lorawan_device::devices::gwmp_device::GWMPCommunicator;
 use lazy_static::lazy_static;
 use lorawan::{
     device::{
@@ -17,6 +18,7 @@ use lorawan::{
         Device, DeviceClass, LoRaWANVersion,
     },
     encryption::key::Key,
+    lorawan_packet::join::CFList,
     physical_parameters::{CodeRate, DataRate, LoRaBandwidth, SpreadingFactor},
     regional_parameters::region::Region,
     utils::eui::EUI64,
@@ -44,9 +46,13 @@ pub struct NetworkControllerConfig {
     pub nc_id: String,
     pub orderer_address: String,
     udp_config: Option<UDPNCConfig>,
+    gwmp_config: Option<GWMPNCConfig>,
     radio_config: Option<RadioDeviceConfig>,
     colosseum_config: Option<ColosseumDeviceConfig>,
     consensus_config: ConsensusConfig,
+    ///Extra channels sent in the JoinAccept CFList
+    #[serde(default)]
+    cf_list: Option<CFList>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -76,10 +82,11 @@ impl Config {
 }
 
 async fn network_controller_main(config: &'static NetworkControllerConfig) {
-    let nc = NetworkController::new(
+    let mut nc = NetworkController::new(
         config.nc_id.as_ref(),
         config.consensus_config.clone()
     );
+    nc.set_cf_list(config.cf_list);
 
     lazy_static!(
         static ref CONFIG: Config = serde_json::from_reader::<BufReader<File>, Config>(BufReader::new(
@@ -104,10 +111,12 @@ async fn network_controller_main(config: &'static NetworkControllerConfig) {
     //let t2 = config.radio_config.as_ref().map(|radio_config| nc.routine::<RadioCommunicator, BlockchainUDPClient>(radio_config, &BC_CONFIG));
     //let t3 = config.tcp_config.as_ref().map(|tcp_config| nc.tcp_routine::<BlockchainUDPClient>(tcp_config, &BC_CONFIG));
     let t3 = config.udp_config.as_ref().map(|udp_config| nc.udp_routine::<BlockchainUDPClient>(udp_config, &BC_CONFIG));
+    let t4 = config.gwmp_config.as_ref().map(|gwmp_config| nc.routine::<GWMPCommunicator, BlockchainUDPClient>(gwmp_config, &BC_CONFIG));
 
     //if let Some(t) = t1 { t.await.unwrap(); }
     //if let Some(t) = t2 { t.await.unwrap(); }
     if let Some(t) = t3 { t.await.unwrap(); }
+    if let Some(t) = t4 { t.await.unwrap(); }
 }
 
 async fn application_server_main(config: &'static ApplicationServerConfig) {
@@ -164,6 +173,10 @@ async fn main() -> Result<(), std::io::Error> {
                 addr: "0.0.0.0".to_string(), 
                 port: 9090 
             }),
+            gwmp_config: Some(GWMPNCConfig {
+                addr: "0.0.0.0".to_string(),
+                port: 1700
+            }),
             radio_config: Some(RadioDeviceConfig {
                 region: Region::EU863_870,
                 spreading_factor: SpreadingFactor::SF7,
@@ -199,6 +212,7 @@ async fn main() -> Result<(), std::io::Error> {
                     ca_cert_path: String::from("")
                 }
             },
+            cf_list: Some(CFList::Frequencies([867_100_000, 867_300_000, 867_500_000, 867_700_000, 867_900_000])),
         }),
         application_server: Some(ApplicationServerConfig {
             tcp_receive_port: 5050,
@@ -4,7 +4,7 @@ use std::{collections::HashMap, time::{Duration, Instant, SystemTime}, fs::File,
 
 use lorawan_device::{communicator::LoRaWANCommunicator, configs::{TcpDeviceConfig, UDPDeviceConfig}, devices::{lorawan_device::LoRaWANDevice, mock_device::MockDevice, udp_device::UDPDevice}};
 use lorawan::{
-    device::{Device, DeviceClass, LoRaWANVersion}, encryption::key::Key, lorawan_packet::{mhdr::MType, payload::Payload, LoRaWANPacket}, utils::{eui::EUI64, PrettyHexSlice}
+    device::{Device, DeviceClass, LoRaWANVersion}, encryption::key::Key, lorawan_packet::{mhdr::MType, payload::Payload, LoRaWANPacket, MicContext}, utils::{eui::EUI64, PrettyHexSlice}
 };
 use paho_mqtt::Client;
 use prost::Message;
@@ -13,7 +13,16 @@ use serde::{Deserialize, Serialize};
 use tokio::{sync::mpsc, process::Command};
 use std::io::Write;
 
-use crate::{compiled::gw::{modulation::Parameters, DownlinkFrame, LoraModulationInfo, Modulation, UplinkFrame, UplinkRxInfo, UplinkTxInfo}, FIXED_JOIN_DELAY, FIXED_PACKET_DELAY, NUM_DEVICES, NUM_PACKETS, RANDOM_JOIN_DELAY, RANDOM_PACKET_DELAY, STARTING_DEV_NONCE};
+use crate::{compiled::gw::{modulation::Parameters, DownlinkFrame, LoraModulationInfo, Modulation, UplinkFrame, UplinkRxInfo, UplinkTxInfo}};
+
+//the ChirpStack comparison keeps its own parameters, scenario files drive the other simulations
+const NUM_DEVICES: usize = 2000;
+const NUM_PACKETS: usize = 100;
+const FIXED_JOIN_DELAY: u64 = 60;
+const RANDOM_JOIN_DELAY: u64 = 180;
+const FIXED_PACKET_DELAY: u64 = 60;
+const RANDOM_PACKET_DELAY: u64 = 180;
+const STARTING_DEV_NONCE: u32 = 12;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct DeviceStatus {
@@ -176,6 +185,9 @@ async fn network_live_stats_loop(sender: mpsc::Sender<Msg>) {
     }
 }
 
+///TxDr and TxCh of the frames built by create_uplink: EU868 DR5 on the first default channel
+const UPLINK_MIC_CONTEXT: MicContext = MicContext { conf_fcnt: None, tx_dr: 5, tx_ch: 0 };
+
 fn create_uplink(payload: &[u8]) -> UplinkFrame {
     UplinkFrame {
         phy_payload: payload.to_vec(),
@@ -284,7 +296,7 @@ async fn device_routine<T: LoRaWANCommunicator + Send + Sync>(mut fd: LoRaWANDev
                                 fd.generate_session_context(&ja).unwrap();
         
                                 let payload: Vec<u8> = format!("### confirmed {i} message  ###").into();
-                                let uplink = fd.create_uplink(Some(&payload), true, Some(1), None).unwrap(); 
+                                let uplink = fd.create_uplink(Some(&payload), true, Some(1), None, &UPLINK_MIC_CONTEXT).unwrap(); 
                                 let uplink = create_uplink(&uplink).encode_to_vec();
                                 
                                 sleep_time = rng.gen_range(FIXED_JOIN_DELAY..RANDOM_JOIN_DELAY);
@@ -309,7 +321,7 @@ async fn device_routine<T: LoRaWANCommunicator + Send + Sync>(mut fd: LoRaWANDev
                             sender.send(Msg { thread_id: i, stats: Stats::Rtt, content: format!("{}, {}",before.elapsed().as_millis(), start.elapsed().as_millis()) }).await.unwrap();
                             if let Payload::MACPayload(dd) = decrypted.into_payload() {
                                 let payload: Vec<u8> = format!("ProvaProvaProva {i}").into();
-                                let uplink_vec = fd.create_uplink(Some(&payload), true, Some(1), None).unwrap();
+                                let uplink_vec = fd.create_uplink(Some(&payload), true, Some(1), None, &UPLINK_MIC_CONTEXT).unwrap();
                                 let uplink = create_uplink(&uplink_vec).encode_to_vec();
         
                                 sleep_time = rng.gen_range(FIXED_JOIN_DELAY..RANDOM_JOIN_DELAY);
@@ -0,0 +1,221 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+use blockchain_api::mock_bridge::{BlockchainMockClient, BlockchainMockClientConfig, MockLedger};
+use lorawan_device::{
+    clock,
+    configs::{MediumRole, VirtualMediumConfig},
+    devices::{lorawan_device::LoRaWANDevice, virtual_medium::{LabelledReception, VirtualMedium, VirtualMediumCommunicator}},
+    energy::EnergyCommunicator,
+    pcap::PcapWriter,
+};
+use consensus::local_consensus::LocalConsensusNetwork;
+use network_controller::modules::{network_controller::NetworkController, stats::NetworkStats};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+use crate::{metered::MeteredCommunicator, report::{DeviceRun, UplinkRecord}, scenario::{Network, Scenario, ScenarioError}};
+
+///What a run produced
+pub struct Outcome {
+    pub devices: Vec<DeviceRun>,
+    ///counters of every network controller together
+    pub network: Arc<NetworkStats>,
+    ///every frame received by the gateways, labelled
+    pub receptions: Vec<LabelledReception>,
+}
+
+///Run a virtual network scenario on a single-threaded runtime with paused time: whenever every task is waiting the clock jumps
+///to the next timer, so device delays, RX windows, DownlinkScheduler deadlines and consensus timeouts take no real time.
+///Everything, network controller included, runs in-process on a virtual medium and every random choice comes from the seed,
+///two runs of the same scenario give the same results
+pub fn run(scenario: &Scenario, capture: Option<Arc<PcapWriter>>) -> Result<Outcome, ScenarioError> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap()
+        .block_on(simulate(scenario.clone(), capture))
+}
+
+async fn simulate(scenario: Scenario, capture: Option<Arc<PcapWriter>>) -> Result<Outcome, ScenarioError> {
+    let Network::Virtual { gateways, network_controllers, propagation, adversaries, joins, join_traffic } = &scenario.network else {
+        return Err(ScenarioError::Invalid("only virtual networks can run on virtual time".to_string()));
+    };
+    //each run gets its own medium, the registry keeps the ones of the previous runs
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let medium_name = format!("des-{}-{}", scenario.seed, RUNS.fetch_add(1, Ordering::Relaxed));
+    let medium = VirtualMedium::named(&medium_name);
+    medium.set_propagation(propagation.build()?);
+    medium.record_receptions();
+    clock::set_epoch(0);
+    let start = Instant::now();
+
+    let nc_ids: Vec<String> = (0..*network_controllers).map(|i| format!("des-nc{i}")).collect();
+    let ledger = Arc::new(MockLedger::default());
+    let blockchain_configs: Vec<BlockchainMockClientConfig> = nc_ids.iter().map(|id| BlockchainMockClientConfig {
+        nc_id: id.clone(),
+        nc_ids: nc_ids.clone(),
+        ledger: ledger.clone(),
+    }).collect();
+    let consensus_network = LocalConsensusNetwork::new();
+    let stats = Arc::new(NetworkStats::new());
+    let ncs: Vec<NetworkController> = nc_ids.iter().map(|id| {
+        let mut nc = NetworkController::local(id, &consensus_network);
+        nc.set_capture(capture.clone());
+        nc.set_stats(stats.clone());
+        nc
+    }).collect();
+    for (i, position) in gateways.iter().enumerate() {
+        let config = Arc::new(VirtualMediumConfig {
+            medium: medium_name.clone(),
+            position: *position,
+            role: MediumRole::Gateway,
+            propagation: None,
+        });
+        ncs[i % ncs.len()].routine::<VirtualMediumCommunicator, BlockchainMockClient>(config, &blockchain_configs[i % ncs.len()]);
+    }
+
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+    let mut handles = Vec::with_capacity(scenario.devices());
+    for (g, group) in scenario.device_groups.iter().enumerate() {
+        for i in 0..group.count {
+            //the mock ledger derives the device from the DevEUI or the DevAddr, the network controller finds the same keys and version
+            let configuration = if *joins > 0 {
+                BlockchainMockClient::create_uninitialized_device(&rng.gen::<[u8; 8]>())
+            } else {
+                BlockchainMockClient::create_initialized_device(&rng.gen::<[u8; 4]>())
+            };
+            let position = group.placement.position(i, &mut rng);
+            //every device draws from its own generator, the values do not depend on the order the tasks run in
+            let mut device_rng = StdRng::seed_from_u64(rng.gen());
+
+            let communicator = EnergyCommunicator::new(MeteredCommunicator::new(medium.attach(position, MediumRole::Device)), group.energy.clone());
+            let mut device = LoRaWANDevice::new(configuration, communicator);
+            let (group, join_traffic, joins, duration) = (group.clone(), join_traffic.clone(), *joins, scenario.duration());
+
+            handles.push(tokio::spawn(async move {
+                let mut run = DeviceRun { group: g, device: i, ..Default::default() };
+                if joins > 0 {
+                    run.dev_eui = Some(device.dev_eui().to_string());
+                }
+                for join in 0..joins {
+                    tokio::time::sleep(join_traffic.delay(join, &mut device_rng)).await;
+                    run.join_requests += 1;
+                    let before = Instant::now();
+                    if let Err(e) = device.send_join_request().await {
+                        eprintln!("Device {i} of group {g} join {join}: {e:?}");
+                        continue;
+                    }
+                    run.join_latencies.push(before.elapsed().as_secs_f64() * 1000.0);
+                }
+                //the JoinAccepts are measured by the join latency
+                device.communicator().take_downlink_latencies();
+                let Some(session) = device.session() else {
+                    eprintln!("Device {i} of group {g} never joined");
+                    run.energy = device.communicator().usage();
+                    run.lifetime = device.communicator().lifetime();
+                    return run;
+                };
+                run.dev_addr = Some(*session.network_context().dev_addr());
+
+                for packet in 0.. {
+                    tokio::time::sleep(group.traffic.delay(packet, &mut device_rng)).await;
+                    if start.elapsed() >= duration {
+                        break;
+                    }
+                    let confirmed = device_rng.gen_bool(group.confirmed_ratio);
+                    let payload: Vec<u8> = (0..group.payload_size).map(|_| device_rng.gen()).collect();
+
+                    let before = Instant::now();
+                    let result = device.send_uplink(Some(&payload), confirmed, Some(1), None).await;
+                    if let Err(e) = &result {
+                        eprintln!("Device {i} of group {g} uplink {packet}: {e:?}");
+                    }
+                    run.uplinks.push(UplinkRecord {
+                        group: g,
+                        device: i,
+                        packet,
+                        sent_at: before.duration_since(start).as_millis(),
+                        confirmed,
+                        acked: confirmed && result.is_ok(),
+                        duration: before.elapsed().as_millis(),
+                    });
+                }
+                run.downlink_latencies = device.communicator().take_downlink_latencies();
+                run.energy = device.communicator().usage();
+                run.lifetime = device.communicator().lifetime();
+                run
+            }));
+        }
+    }
+
+    //drawn after the devices, adding an adversary does not change them
+    for adversary in adversaries.iter() {
+        medium.add_adversary(adversary, rng.gen());
+    }
+
+    let mut devices = Vec::with_capacity(handles.len());
+    for handle in handles {
+        devices.push(handle.await.unwrap());
+    }
+    Ok(Outcome { devices, network: stats, receptions: medium.take_receptions() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENARIO: &str = r#"
+        seed = 7
+        duration = 1800
+
+        [network]
+        mode = "Virtual"
+        network_controllers = 2
+        gateways = [{ x = 0.0, y = 0.0, z = 30.0 }, { x = 1000.0, y = 0.0, z = 30.0 }]
+
+        [[device_groups]]
+        count = 20
+        placement = { type = "Disc", center = { x = 500.0, y = 0.0, z = 1.5 }, radius = 1000.0 }
+        traffic = { type = "Poisson", mean_interval = 120.0 }
+        confirmed_ratio = 0.5
+    "#;
+
+    #[test]
+    fn same_seed_same_outcome() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        let (first, second) = (run(&scenario, None).unwrap(), run(&scenario, None).unwrap());
+
+        let uplinks = |outcome: &Outcome| outcome.devices.iter().map(|d| (d.uplinks.clone(), d.downlink_latencies.clone())).collect::<Vec<_>>();
+        assert_eq!(uplinks(&first), uplinks(&second));
+        assert_eq!(first.network.counters(), second.network.counters());
+        assert_eq!(first.receptions, second.receptions);
+
+        //the downlinks went through consensus rounds between the two network controllers
+        let counters = first.network.counters();
+        assert!(counters.consensus_won > 0 && counters.downlinks_sent > 0);
+        assert!(first.devices.iter().flat_map(|d| d.uplinks.iter()).any(|u| u.acked));
+    }
+
+    #[test]
+    fn devices_join_over_the_air() {
+        let mut scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        if let Network::Virtual { joins, .. } = &mut scenario.network {
+            *joins = 1;
+        }
+        let outcome = run(&scenario, None).unwrap();
+
+        //only the devices that joined send uplinks, with the DevAddr of their session
+        let joined: Vec<&DeviceRun> = outcome.devices.iter().filter(|d| !d.join_latencies.is_empty()).collect();
+        assert!(!joined.is_empty());
+        assert!(outcome.devices.iter().all(|d| d.join_requests == 1 && d.dev_addr.is_some() != d.join_latencies.is_empty()));
+        assert!(joined.iter().flat_map(|d| d.uplinks.iter()).any(|u| u.acked));
+    }
+
+    #[test]
+    fn only_virtual_networks() {
+        let mut scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        scenario.network = Network::Replay { trace: "trace.csv".to_string(), speedup: 1.0 };
+        assert!(matches!(run(&scenario, None), Err(ScenarioError::Invalid(_))));
+    }
+}
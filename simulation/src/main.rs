@@ -1,11 +1,15 @@
 #![allow(non_snake_case, unreachable_code, clippy::iter_skip_zero, unused)]
 mod chirpstack;
 mod compiled;
+mod des;
+mod metered;
+mod report;
+mod scenario;
 
-use blockchain_api::{ udp_bridge::BlockchainUDPClient, BlockchainClient};
+use blockchain_api::{mock_bridge::{BlockchainMockClient, BlockchainMockClientConfig}, udp_bridge::BlockchainUDPClient, BlockchainClient};
 use chirpstack::main_chirpstack;
 use std::{
-    fs::{self, File, OpenOptions}, io::BufReader, sync::Arc, time::{Duration, SystemTime}
+    collections::BTreeSet, fs::{self, File}, io::BufReader, path::Path, sync::Arc, time::Duration
 };
 
 use lorawan::{
@@ -15,32 +19,29 @@ use lorawan::{
     utils::{eui::EUI64, PrettyHexSlice},
 };
 use lorawan_device::{
-    configs::{DeviceConfig, UDPDeviceConfig},
-    devices::{debug_device::DebugDevice, udp_device::UDPDevice},
+    configs::{DeviceConfig, ReplayConfig, UDPDeviceConfig},
+    devices::{debug_device::DebugCommunicator, lorawan_device::LoRaWANDevice, replay_device::ReplayCommunicator, udp_device::UDPDevice, virtual_medium::LabelledReception},
+    energy::EnergyCommunicator,
+    pcap::PcapWriter,
+    trace::read_trace,
 };
+use consensus::local_consensus::LocalConsensusNetwork;
+use network_controller::modules::network_controller::NetworkController;
+use metered::MeteredCommunicator;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use report::{DeviceRun, Report, UplinkRecord};
+use scenario::{Network, Scenario};
 use serde::Deserialize;
-use std::io::Write;
 use tokio::{task::JoinHandle, time::Instant};
 
-const NUM_DEVICES: usize = 2000;
-const NUM_PACKETS: usize = 100;
-const FIXED_JOIN_DELAY: u64 = 60;
-const RANDOM_JOIN_DELAY: u64 = 180;
-const FIXED_PACKET_DELAY: u64 = 60;
-const RANDOM_PACKET_DELAY: u64 = 180;
-const _CONFIRMED_AVERAGE_SEND: u8 = 10;
-const DEVICES_TO_SKIP: usize = 0;
-const STARTING_DEV_NONCE: u32 = 12;
-const JUST_CREATE_DEVICE: bool = true;
-
 #[derive(Deserialize)]
 struct DevicesFile {
     devices: Vec<DeviceConfig>,
 }
 
-async fn create_all_devices() {
+async fn create_all_devices(path: &str) {
     let content: DevicesFile =
-        serde_json::from_reader(BufReader::new(File::open("./devices.json").unwrap())).unwrap();
+        serde_json::from_reader(BufReader::new(File::open(path).unwrap())).unwrap();
     //fs::read_to_string("./devices.json").unwrap();
     //let client = BlockchainExeClient::new(
     //    "orderer1.orderers.dlwan.phd:6050",
@@ -80,16 +81,18 @@ async fn create_all_devices() {
     }
 }
 
-async fn blockchain_main() {
-    if JUST_CREATE_DEVICE {
-        create_all_devices().await;
-        return;
-    }
+///Devices of the scenario join and send their uplinks to the network controllers over UDP, in real time.
+///The network controllers are remote, the report has only what the devices saw
+async fn udp_main(scenario: Scenario, name: &str) {
+    let Network::Udp { network_controllers, port, devices_file, devices_to_skip, starting_dev_nonce, joins, join_traffic } = scenario.network.clone() else {
+        unreachable!("udp_main runs only UDP scenarios")
+    };
     //let _args = Args::parse();
     //let path = Path::new("./output/simulation.lock");
     //File::create(path).unwrap();
 
-    let file_content = fs::read_to_string("./devices_augmented.csv").unwrap();
+    let file_content = fs::read_to_string(&devices_file).unwrap();
+    let mut lines = file_content.split('\n').skip(devices_to_skip);
 
     let mut join_handlers = Vec::new();
 
@@ -99,43 +102,23 @@ async fn blockchain_main() {
     //    stats_holder(receiver).await
     //});
 
-    let nc_ips = [
-        "10.207.19.155",
-        "10.207.19.20",
-        "10.207.19.81",
-        "10.207.19.223",
-        //"10.207.19.66",
-        //"10.207.19.206",
-        //"10.207.19.38",
-        //"10.207.19.26",
-        //"10.207.19.94",
-        //"10.207.19.113",
-        //"10.207.19.95",
-        //"10.207.19.70",
-        //"10.207.19.71",
-        //"10.207.19.24",
-        //"10.207.19.212",
-        //"10.207.19.102",
-    ]
-    .map(|a| a.to_string());
-
-    let nc_ips_len = nc_ips.len();
-
-    file_content
-        .split('\n')
-        .skip(DEVICES_TO_SKIP)
-        .take(NUM_DEVICES)
-        .enumerate()
-        .for_each(|(i, line)| {
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+    let start = Instant::now();
+    let mut i = 0;
+    for (g, group) in scenario.device_groups.iter().enumerate() {
+        for index in 0..group.count {
+            let line = lines.next().expect("The devices file has fewer devices than the scenario");
             let splitted = line.split(',').collect::<Vec<&str>>();
             let dev_eui = EUI64::from_hex(splitted[0]).unwrap();
             let join_eui = EUI64::from_hex(splitted[1]).unwrap();
             let key = Key::from_hex(splitted[2]).unwrap();
 
-            let nc_ip = nc_ips[i % nc_ips_len].clone();
+            let nc_ip = network_controllers[i % network_controllers.len()].clone();
+            let (group, join_traffic, duration) = (group.clone(), join_traffic.clone(), scenario.duration());
+            let mut device_rng = StdRng::seed_from_u64(rng.gen());
+            let thread_id = i;
 
             let handle = tokio::spawn(async move {
-                let thread_id = i;
                 let d = Device::new(
                     DeviceClass::A,
                     Some(RegionalParameters::new(Region::EU863_870)),
@@ -145,67 +128,167 @@ async fn blockchain_main() {
                     key,
                     LoRaWANVersion::V1_0_4,
                 );
-                let mut device = DebugDevice::from(UDPDevice::create(d,&UDPDeviceConfig { addr: nc_ip, port: 9090,}).await);
-
-                device.set_dev_nonce(STARTING_DEV_NONCE);
-
-                let mut sleep_time: u64 = rand::random::<u64>() % RANDOM_JOIN_DELAY;
-                for _ in 0..NUM_PACKETS {
-                    sleep_time = rand::random::<u64>() % RANDOM_JOIN_DELAY;
-                    tokio::time::sleep(Duration::from_secs(FIXED_JOIN_DELAY + sleep_time)).await;
-
-                    //if let Some(_s) = device.session() {
-                    //    println!("Device already initialized:");
-                    //} else {
-                    //    println!(
-                    //        "Device {} needs initialization, sending join request...",
-                    //        device.dev_eui()
-                    //    );
+                let (d, communicator) = UDPDevice::create(d,&UDPDeviceConfig { addr: nc_ip, port }).await.into();
+                let communicator = EnergyCommunicator::new(MeteredCommunicator::new(communicator), group.energy.clone());
+                let mut device = LoRaWANDevice::new(d, DebugCommunicator::from(communicator, Some(dev_eui)));
+                let mut run = DeviceRun { group: g, device: index, dev_eui: Some(dev_eui.to_string()), ..Default::default() };
+
+                device.set_dev_nonce(starting_dev_nonce);
+
+                for join in 0..joins {
+                    tokio::time::sleep(join_traffic.delay(join, &mut device_rng)).await;
+                    run.join_requests += 1;
+                    let before = Instant::now();
                     if let Err(e) = device.send_join_request().await {
-                        panic!("Error joining: {e:?}");
+                        eprintln!("Device {dev_eui} join {join}: {e:?}");
+                        continue;
                     };
+                    run.join_latencies.push(before.elapsed().as_secs_f64() * 1000.0);
                     println!("Initialized: {}",/*serde_json::to_string(&*device).unwrap()*/PrettyHexSlice(device.session().unwrap().network_context().dev_addr()));
-                    //}
                 }
-
-                tokio::time::sleep(Duration::from_secs(
-                    FIXED_JOIN_DELAY + RANDOM_JOIN_DELAY - sleep_time,
-                ))
-                .await;
                 //device.session_mut().unwrap().application_context_mut().update_af_cnt_dwn(10);
-
-                for i in 0..NUM_PACKETS {
-                    let sleep_time = rand::random::<u64>() % RANDOM_PACKET_DELAY;
-                    tokio::time::sleep(Duration::from_secs(FIXED_PACKET_DELAY + sleep_time)).await;
+                //the JoinAccepts are measured by the join latency
+                device.communicator().take_downlink_latencies();
+                let Some(session) = device.session() else {
+                    eprintln!("Device {dev_eui} never joined");
+                    run.energy = device.communicator().usage();
+                    run.lifetime = device.communicator().lifetime();
+                    return run;
+                };
+                run.dev_addr = Some(*session.network_context().dev_addr());
+
+                for packet in 0.. {
+                    tokio::time::sleep(group.traffic.delay(packet, &mut device_rng)).await;
+                    if start.elapsed() >= duration {
+                        break;
+                    }
+                    let confirmed = device_rng.gen_bool(group.confirmed_ratio);
+                    let payload: Vec<u8> = (0..group.payload_size).map(|_| device_rng.gen()).collect();
                     let before = Instant::now();
 
-                    let confirmed = true;
-                    device.send_uplink(Some(format!("###  {}confirmed {i} message  ###",if confirmed { "un" } else { "" }).as_bytes(),),confirmed,Some(1),None,).await.unwrap();
-                    let rtt = before.elapsed().as_millis();
-                    println!("Device {} sent and received {i}-th message", dev_eui);
-
-                    if true {
-                        let mut file = OpenOptions::new().append(true).create(true).open("/root/rtt_response_times.csv").expect("Failed to open file");
-                        writeln!(file,"{},{}",SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis(),rtt).expect("Error while logging time to file");
+                    let result = device.send_uplink(Some(&payload), confirmed, Some(1), None).await;
+                    match &result {
+                        Ok(_) => println!("Device {} sent and received {packet}-th message", dev_eui),
+                        Err(e) => eprintln!("Device {dev_eui} uplink {packet}: {e:?}"),
                     }
+                    run.uplinks.push(UplinkRecord {
+                        group: g,
+                        device: index,
+                        packet,
+                        sent_at: before.duration_since(start).as_millis(),
+                        confirmed,
+                        acked: confirmed && result.is_ok(),
+                        duration: before.elapsed().as_millis(),
+                    });
                 }
+                run.downlink_latencies = device.communicator().take_downlink_latencies();
+                run.energy = device.communicator().usage();
+                run.lifetime = device.communicator().lifetime();
                 println!("Task {thread_id} completed successfully");
+                run
             });
             join_handlers.push(handle);
-        });
+            i += 1;
+        }
+    }
 
+    let mut runs = Vec::with_capacity(join_handlers.len());
     for handle in join_handlers {
-        handle.await.unwrap();
+        runs.push(handle.await.unwrap());
+    }
+    let report = Report::new(name, &scenario, &runs, None, None, start.elapsed());
+    write_report(&report, name, &runs, None);
+}
+
+///Virtual network scenarios run on virtual time, the report has the counters of the in-process network controller too
+fn des_main(scenario: &Scenario, name: &str) {
+    let before = std::time::Instant::now();
+    let outcome = match des::run(scenario, capture(scenario, name)) {
+        Ok(v) => v,
+        Err(e) => return eprintln!("{e}"),
+    };
+    let report = Report::new(name, scenario, &outcome.devices, Some(&outcome.network), Some(&outcome.receptions), before.elapsed());
+    write_report(&report, name, &outcome.devices, Some(&outcome.receptions));
+}
+
+///Trace replays run in real time against an in-process network controller, the report has only its counters
+async fn replay_main(scenario: Scenario, name: &str) {
+    let Network::Replay { trace, speedup } = &scenario.network else {
+        panic!("Not a trace replay");
+    };
+    let start = std::time::Instant::now();
+    let records = read_trace(trace).expect("Cannot read the trace");
+    //the mock ledger derives the device from the DevAddr, the network controller finds the same keys and version
+    let devices = records.iter().map(|r| r.dev_addr).collect::<BTreeSet<_>>().iter()
+        .map(|dev_addr| BlockchainMockClient::create_initialized_device(dev_addr))
+        .collect::<Vec<_>>();
+    println!("Replaying {} receptions of {} devices", records.len(), devices.len());
+
+    let config = Arc::new(ReplayConfig {
+        trace: trace.clone(),
+        speedup: *speedup,
+        duration: Some(scenario.duration as f64),
+        devices,
+        payload_size: 12,
+    });
+    let blockchain_config = BlockchainMockClientConfig { nc_ids: vec!["replay-nc".to_string()], ..Default::default() };
+    let mut nc = NetworkController::local("replay-nc", &LocalConsensusNetwork::new());
+    nc.set_capture(capture(&scenario, name));
+    //the routine stops at the end of the trace
+    let _ = nc.routine::<ReplayCommunicator, BlockchainMockClient>(config, &blockchain_config).await;
+    //the last uplinks may still be waiting for their downlinks
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    let report = Report::new(name, &scenario, &[], Some(nc.stats()), None, start.elapsed());
+    write_report(&report, name, &[], None);
+}
+
+///Every run writes its report in ./output/<scenario>_s<seed>
+fn output_dir(name: &str, seed: u64) -> String {
+    format!("./output/{name}_s{seed}")
+}
+
+///Capture of the network controller in the output directory, if the scenario asks for it
+fn capture(scenario: &Scenario, name: &str) -> Option<Arc<PcapWriter>> {
+    if !scenario.capture {
+        return None;
     }
+    let dir = output_dir(name, scenario.seed);
+    fs::create_dir_all(&dir).expect("Cannot create the output directory");
+    Some(Arc::new(PcapWriter::create(format!("{dir}/capture.pcap")).expect("Cannot create the capture")))
+}
+
+fn write_report(report: &Report, name: &str, runs: &[DeviceRun], receptions: Option<&[LabelledReception]>) {
+    let dir = output_dir(name, report.seed);
+    report.write(&dir, runs, receptions).expect("Cannot write the report");
+    println!("{}, written to {dir}", report.summary());
 }
 
+const USAGE: &str = "Usage: simulation <scenario.json|scenario.toml> | create-devices [devices.json] | chirpstack";
+
 fn main() {
-    tokio::runtime::Builder::new_multi_thread()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let runtime = || tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        //.block_on(blockchain_main())
-        .block_on(main_chirpstack())
+        .unwrap();
+    match args.first().map(String::as_str) {
+        None => eprintln!("{USAGE}"),
+        Some("create-devices") => runtime().block_on(create_all_devices(args.get(1).map_or("./devices.json", String::as_str))),
+        Some("chirpstack") => runtime().block_on(main_chirpstack()),
+        Some(path) => {
+            let scenario = match Scenario::load(path) {
+                Ok(v) => v,
+                Err(e) => return eprintln!("{e}\n{USAGE}"),
+            };
+            let name = Path::new(path).file_stem().map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+            match scenario.network {
+                Network::Virtual { .. } => des_main(&scenario, &name),
+                Network::Udp { .. } => runtime().block_on(udp_main(scenario, &name)),
+                Network::Replay { .. } => runtime().block_on(replay_main(scenario, &name)),
+            }
+        },
+    }
 }
 
 /*
@@ -0,0 +1,84 @@
+use std::{sync::Mutex, time::Duration};
+
+use lorawan::{physical_parameters::{LoRaBandwidth, SpreadingFactor}, utils::eui::EUI64};
+use lorawan_device::communicator::{CommunicatorError, LoRaWANCommunicator, ReceivedTransmission};
+use tokio::time::Instant;
+
+///Wraps the communicator of a device to measure the downlink latency, from the end of the uplink to the reception of the answer
+#[derive(Debug)]
+pub struct MeteredCommunicator<T: LoRaWANCommunicator> {
+    inner: T,
+    last_uplink: Mutex<Option<Instant>>,
+    ///ms of every downlink received
+    downlink_latencies: Mutex<Vec<f64>>,
+}
+
+impl<T: LoRaWANCommunicator> MeteredCommunicator<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner, last_uplink: Mutex::new(None), downlink_latencies: Mutex::new(Vec::new()) }
+    }
+
+    pub fn take_downlink_latencies(&self) -> Vec<f64> {
+        std::mem::take(&mut *self.downlink_latencies.lock().unwrap())
+    }
+}
+
+impl<T: LoRaWANCommunicator> LoRaWANCommunicator for MeteredCommunicator<T> {
+    type Config = T::Config;
+
+    async fn from_config(config: &Self::Config) -> Result<Self, CommunicatorError> {
+        Ok(Self::new(T::from_config(config).await?))
+    }
+
+    async fn send(&self, bytes: &[u8], src: Option<EUI64>, dest: Option<EUI64>) -> Result<(), CommunicatorError> {
+        let result = self.inner.send(bytes, src, dest).await;
+        *self.last_uplink.lock().unwrap() = Some(Instant::now());
+        result
+    }
+
+    async fn receive(&self, timeout: Option<Duration>) -> Result<Vec<ReceivedTransmission>, CommunicatorError> {
+        let received = self.inner.receive(timeout).await?;
+        if !received.is_empty() {
+            //only the first downlink after an uplink answers it
+            if let Some(sent) = self.last_uplink.lock().unwrap().take() {
+                self.downlink_latencies.lock().unwrap().push(sent.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+        Ok(received)
+    }
+
+    fn set_rx_window(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth) {
+        self.inner.set_rx_window(frequency, spreading_factor, bandwidth)
+    }
+
+    fn set_tx_parameters(&mut self, frequency: u32, spreading_factor: SpreadingFactor, bandwidth: LoRaBandwidth, power: i8) {
+        self.inner.set_tx_parameters(frequency, spreading_factor, bandwidth, power)
+    }
+
+    fn tx_parameters_applied(&self) -> bool {
+        self.inner.tx_parameters_applied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lorawan_device::devices::mock_device::MockCommunicator;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn downlink_latency() {
+        let communicator = MeteredCommunicator::new(MockCommunicator);
+        communicator.send(&[0x40], None, None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        communicator.receive(None).await.unwrap();
+        //a second downlink without a new uplink does not answer anything
+        communicator.receive(None).await.unwrap();
+        communicator.send(&[0x40], None, None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        communicator.receive(None).await.unwrap();
+
+        assert_eq!(communicator.take_downlink_latencies(), vec![1000.0, 2000.0]);
+        assert!(communicator.take_downlink_latencies().is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use lorawan::utils::PrettyHexSlice;
+use lorawan_device::{devices::virtual_medium::LabelledReception, energy::EnergyUsage};
+use network_controller::modules::stats::{NetworkCounters, NetworkStats};
+use serde::Serialize;
+
+use crate::scenario::Scenario;
+
+///Outcome of an uplink, times are ms from the start of the run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UplinkRecord {
+    pub group: usize,
+    pub device: usize,
+    pub packet: usize,
+    pub sent_at: u128,
+    pub confirmed: bool,
+    ///the ack of a confirmed uplink arrived in one of the RX windows
+    pub acked: bool,
+    ///time spent in send_uplink, RX windows included
+    pub duration: u128,
+}
+
+impl UplinkRecord {
+    pub const CSV_HEADER: &'static str = "group,device,packet,sent_at,confirmed,acked,duration";
+
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{},{},{}", self.group, self.device, self.packet, self.sent_at, self.confirmed, self.acked, self.duration)
+    }
+}
+
+///What a device went through during the run
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRun {
+    pub group: usize,
+    pub device: usize,
+    pub dev_eui: Option<String>,
+    ///DevAddr the network knows the device by, none if it never joined
+    pub dev_addr: Option<[u8; 4]>,
+    pub join_requests: usize,
+    ///ms from the join request to the JoinAccept, one per successful join
+    pub join_latencies: Vec<f64>,
+    pub uplinks: Vec<UplinkRecord>,
+    ///ms from the end of an uplink to its downlink
+    pub downlink_latencies: Vec<f64>,
+    pub energy: EnergyUsage,
+    ///at the average current of the run, none if the device drew nothing
+    pub lifetime: Option<Duration>,
+}
+
+///Distribution of a latency, in ms
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Latency {
+    pub samples: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        Some(Self {
+            samples: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+fn ratio(part: usize, total: usize) -> Option<f64> {
+    (total > 0).then(|| part as f64 / total as f64)
+}
+
+///KPIs of a set of devices. delivered and pdr need the counters of the network controller, only virtual networks have them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Kpis {
+    pub devices: usize,
+    pub uplinks: usize,
+    ///distinct uplinks (DevAddr and FCnt) received by the network controller, retransmissions counted once
+    pub delivered: Option<usize>,
+    ///packet delivery ratio
+    pub pdr: Option<f64>,
+    pub confirmed: usize,
+    pub acked: usize,
+    pub ack_ratio: Option<f64>,
+    pub join_requests: usize,
+    pub joins: usize,
+    pub join_success: Option<f64>,
+    pub join_latency: Option<Latency>,
+    pub downlinks: usize,
+    pub downlink_latency: Option<Latency>,
+    ///mAh drawn by a device on average
+    pub charge: f64,
+    ///battery lifetime in days, average and shortest among the devices
+    pub lifetime: Option<f64>,
+    pub min_lifetime: Option<f64>,
+}
+
+impl Kpis {
+    fn new<'a>(runs: impl IntoIterator<Item = &'a DeviceRun>, network: Option<&NetworkStats>) -> Self {
+        let runs: Vec<&DeviceRun> = runs.into_iter().collect();
+        let uplinks = runs.iter().map(|r| r.uplinks.len()).sum();
+        let confirmed = runs.iter().flat_map(|r| r.uplinks.iter()).filter(|u| u.confirmed).count();
+        let acked = runs.iter().flat_map(|r| r.uplinks.iter()).filter(|u| u.acked).count();
+        let join_requests = runs.iter().map(|r| r.join_requests).sum();
+        let join_latencies: Vec<f64> = runs.iter().flat_map(|r| r.join_latencies.iter().copied()).collect();
+        let downlink_latencies: Vec<f64> = runs.iter().flat_map(|r| r.downlink_latencies.iter().copied()).collect();
+        let delivered = network.map(|stats| runs.iter()
+            .filter_map(|r| r.dev_addr.and_then(|dev_addr| stats.device(&dev_addr)))
+            .map(|d| d.uplinks as usize)
+            .sum());
+        let lifetimes: Vec<f64> = runs.iter().filter_map(|r| r.lifetime).map(|l| l.as_secs_f64() / 86_400.0).collect();
+
+        Self {
+            devices: runs.len(),
+            uplinks,
+            delivered,
+            pdr: delivered.and_then(|d| ratio(d, uplinks)),
+            confirmed,
+            acked,
+            ack_ratio: ratio(acked, confirmed),
+            join_requests,
+            joins: join_latencies.len(),
+            join_success: ratio(join_latencies.len(), join_requests),
+            join_latency: Latency::from_samples(&join_latencies),
+            downlinks: downlink_latencies.len(),
+            downlink_latency: Latency::from_samples(&downlink_latencies),
+            charge: runs.iter().map(|r| r.energy.charge()).sum::<f64>() / runs.len().max(1) as f64,
+            lifetime: (!lifetimes.is_empty()).then(|| lifetimes.iter().sum::<f64>() / lifetimes.len() as f64),
+            min_lifetime: lifetimes.iter().copied().reduce(f64::min),
+        }
+    }
+
+    const CSV_HEADER: &'static str = "devices,uplinks,delivered,pdr,confirmed,acked,ack_ratio,join_requests,joins,join_success,join_latency_mean,join_latency_p95,downlinks,downlink_latency_mean,downlink_latency_p95,charge,lifetime,min_lifetime";
+
+    fn to_csv(&self) -> String {
+        fn opt<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+        format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.devices, self.uplinks, opt(self.delivered), opt(self.pdr), self.confirmed, self.acked, opt(self.ack_ratio),
+            self.join_requests, self.joins, opt(self.join_success), opt(self.join_latency.map(|l| l.mean)), opt(self.join_latency.map(|l| l.p95)),
+            self.downlinks, opt(self.downlink_latency.map(|l| l.mean)), opt(self.downlink_latency.map(|l| l.p95)),
+            self.charge, opt(self.lifetime), opt(self.min_lifetime))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupReport {
+    pub name: String,
+    #[serde(flatten)]
+    pub kpis: Kpis,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceReport {
+    pub group: usize,
+    pub device: usize,
+    pub dev_eui: Option<String>,
+    pub dev_addr: Option<String>,
+    ///copies of the uplinks of the device received through more than one gateway
+    pub duplicates: Option<u64>,
+    ///uplinks received again after a retransmission of the device
+    pub retransmissions: Option<u64>,
+    ///time in each radio state, ms, and charge drawn in it, mAh
+    pub energy: EnergyUsage,
+    #[serde(flatten)]
+    pub kpis: Kpis,
+}
+
+///Frames received by the gateways of a virtual network, by label
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DatasetSummary {
+    pub receptions: usize,
+    pub legitimate: usize,
+    ///legitimate frames received while a jammer was on air
+    pub jammed: usize,
+    pub replayed: usize,
+    pub spoofed: usize,
+}
+
+impl DatasetSummary {
+    pub fn new(receptions: &[LabelledReception]) -> Self {
+        let count = |label: &str| receptions.iter().filter(|r| r.label() == label).count();
+        Self {
+            receptions: receptions.len(),
+            legitimate: count("legitimate"),
+            jammed: count("jammed"),
+            replayed: count("replay"),
+            spoofed: count("spoof"),
+        }
+    }
+}
+
+///Results of a run, written at its end as report.json plus CSV summaries
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub scenario: String,
+    pub seed: u64,
+    ///seconds of traffic of the scenario
+    pub duration: u64,
+    ///seconds the run took, on virtual networks much less than the duration
+    pub wall_time: f64,
+    pub network: Kpis,
+    ///counters of the network controller, duplicates and consensus rounds included. Only virtual networks and replays have them
+    pub network_controller: Option<NetworkCounters>,
+    ///labels of dataset.csv, only for virtual networks
+    pub dataset: Option<DatasetSummary>,
+    pub groups: Vec<GroupReport>,
+    pub devices: Vec<DeviceReport>,
+}
+
+impl Report {
+    pub fn new(name: &str, scenario: &Scenario, runs: &[DeviceRun], network: Option<&NetworkStats>, receptions: Option<&[LabelledReception]>, wall_time: Duration) -> Self {
+        let groups = scenario.device_groups.iter().enumerate().map(|(g, group)| GroupReport {
+            name: group.name.clone(),
+            kpis: Kpis::new(runs.iter().filter(|r| r.group == g), network),
+        }).collect();
+        let devices = runs.iter().map(|run| DeviceReport {
+            group: run.group,
+            device: run.device,
+            dev_eui: run.dev_eui.clone(),
+            dev_addr: run.dev_addr.map(|a| PrettyHexSlice(&a).to_string()),
+            duplicates: network.and_then(|stats| stats.device(&run.dev_addr?)).map(|d| d.duplicates),
+            retransmissions: network.and_then(|stats| stats.device(&run.dev_addr?)).map(|d| d.retransmissions),
+            energy: run.energy,
+            kpis: Kpis::new([run], network),
+        }).collect();
+
+        Self {
+            scenario: name.to_string(),
+            seed: scenario.seed,
+            duration: scenario.duration,
+            wall_time: wall_time.as_secs_f64(),
+            network: Kpis::new(runs, network),
+            network_controller: network.map(NetworkStats::counters),
+            dataset: receptions.map(DatasetSummary::new),
+            groups,
+            devices,
+        }
+    }
+
+    ///report.json, groups.csv with a line per group and one for the whole network, devices.csv, uplinks.csv
+    ///and, for virtual networks, dataset.csv with the labelled receptions of the gateways in the format of gateway.csv
+    pub fn write(&self, dir: impl AsRef<Path>, runs: &[DeviceRun], receptions: Option<&[LabelledReception]>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        serde_json::to_writer_pretty(File::create(dir.join("report.json"))?, self)?;
+
+        let mut groups = File::create(dir.join("groups.csv"))?;
+        writeln!(groups, "group,{}", Kpis::CSV_HEADER)?;
+        for group in self.groups.iter() {
+            writeln!(groups, "{},{}", group.name, group.kpis.to_csv())?;
+        }
+        writeln!(groups, "all,{}", self.network.to_csv())?;
+
+        let mut devices = File::create(dir.join("devices.csv"))?;
+        writeln!(devices, "group,device,dev_eui,dev_addr,duplicates,retransmissions,{}", Kpis::CSV_HEADER)?;
+        for device in self.devices.iter() {
+            let count = |c: Option<u64>| c.map(|c| c.to_string()).unwrap_or_default();
+            writeln!(devices, "{},{},{},{},{},{},{}", device.group, device.device, device.dev_eui.as_deref().unwrap_or_default(),
+                device.dev_addr.as_deref().unwrap_or_default(), count(device.duplicates), count(device.retransmissions), device.kpis.to_csv())?;
+        }
+
+        let mut uplinks = File::create(dir.join("uplinks.csv"))?;
+        writeln!(uplinks, "{}", UplinkRecord::CSV_HEADER)?;
+        for record in runs.iter().flat_map(|r| r.uplinks.iter()) {
+            writeln!(uplinks, "{}", record.to_csv())?;
+        }
+
+        if let Some(receptions) = receptions {
+            let mut dataset = File::create(dir.join("dataset.csv"))?;
+            writeln!(dataset, "{}", LabelledReception::CSV_HEADER)?;
+            for reception in receptions {
+                writeln!(dataset, "{}", reception.to_csv())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        //replays have no simulated devices, only what the network controller saw
+        if let (0, Some(c)) = (self.network.devices, &self.network_controller) {
+            return format!("network controller: {} uplinks, {} duplicates, {} retransmissions, {} invalid, {} downlinks sent, {} failed",
+                c.uplinks, c.duplicates, c.retransmissions, c.invalid, c.downlinks_sent, c.downlinks_failed);
+        }
+        let percent = |v: Option<f64>| v.map_or_else(|| "-".to_string(), |v| format!("{:.1}%", v * 100.0));
+        let ms = |l: Option<Latency>| l.map_or_else(|| "-".to_string(), |l| format!("{:.0} ms", l.mean));
+        let n = &self.network;
+        let days = |v: Option<f64>| v.map_or_else(|| "-".to_string(), |v| format!("{v:.0} days"));
+        let summary = format!("{} devices, {} uplinks, PDR {}, ACK ratio {} ({}/{}), joins {} ({}/{}, {}), downlink latency {}, {:.3} mAh per device, battery lifetime {} (shortest {})",
+            n.devices, n.uplinks, percent(n.pdr), percent(n.ack_ratio), n.acked, n.confirmed,
+            percent(n.join_success), n.joins, n.join_requests, ms(n.join_latency), ms(n.downlink_latency),
+            n.charge, days(n.lifetime), days(n.min_lifetime));
+        match &self.dataset {
+            Some(d) if d.receptions > d.legitimate => format!("{summary}, dataset of {} receptions ({} legitimate, {} jammed, {} replayed, {} spoofed)",
+                d.receptions, d.legitimate, d.jammed, d.replayed, d.spoofed),
+            _ => summary,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use blockchain_api::mock_bridge::BlockchainMockClient;
+    use lorawan::lorawan_packet::MicContext;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn uplink(packet: usize, confirmed: bool, acked: bool) -> UplinkRecord {
+        UplinkRecord { group: 0, device: 0, packet, sent_at: 0, confirmed, acked, duration: 0 }
+    }
+
+    #[test]
+    fn latency() {
+        assert_eq!(Latency::from_samples(&[]), None);
+        let latency = Latency::from_samples(&[40.0, 10.0, 30.0, 20.0]).unwrap();
+        assert_eq!(latency, Latency { samples: 4, mean: 25.0, p50: 30.0, p95: 40.0, max: 40.0 });
+    }
+
+    #[test]
+    fn kpis() {
+        let dev_addr = [0x01, 0x02, 0x03, 0x04];
+        let mut device = BlockchainMockClient::create_initialized_device(&dev_addr);
+        let stats = NetworkStats::new();
+        let now = Instant::now();
+        //the second uplink arrives through two gateways, the duplicate is not delivered twice
+        for (fcnt, after) in [(1, 0), (2, 5_000), (2, 5_020)] {
+            stats.uplink_received(&BlockchainMockClient::create_data_up(&mut device, fcnt, &[0xAA], &MicContext::default()), now + Duration::from_millis(after));
+        }
+        let runs = [
+            DeviceRun {
+                dev_addr: Some(dev_addr),
+                join_requests: 2,
+                join_latencies: vec![3000.0],
+                uplinks: (0..4).map(|p| uplink(p, p % 2 == 0, p == 0)).collect(),
+                downlink_latencies: vec![1000.0, 2000.0],
+                ..Default::default()
+            },
+            //never joined, its uplinks cannot be delivered
+            DeviceRun { device: 1, join_requests: 1, ..Default::default() },
+        ];
+
+        let kpis = Kpis::new(&runs, Some(&stats));
+        assert_eq!((kpis.devices, kpis.uplinks, kpis.delivered, kpis.pdr), (2, 4, Some(2), Some(0.5)));
+        assert_eq!((kpis.confirmed, kpis.acked, kpis.ack_ratio), (2, 1, Some(0.5)));
+        assert_eq!((kpis.join_requests, kpis.joins), (3, 1));
+        assert_eq!(kpis.join_success, Some(1.0 / 3.0));
+        assert_eq!(kpis.downlink_latency.map(|l| (l.samples, l.mean)), Some((2, 1500.0)));
+        //without the counters of the network controller the delivered uplinks are unknown
+        let kpis = Kpis::new(&runs, None);
+        assert_eq!((kpis.delivered, kpis.pdr), (None, None));
+        assert_eq!(Kpis::new([], None).ack_ratio, None);
+    }
+
+    #[test]
+    fn csv_columns() {
+        let columns = |line: &str| line.split(',').count();
+        let kpis = Kpis::new(&[DeviceRun { uplinks: vec![uplink(0, true, true)], ..Default::default() }], None);
+        assert_eq!(columns(&kpis.to_csv()), columns(Kpis::CSV_HEADER));
+        assert_eq!(columns(&uplink(0, true, false).to_csv()), columns(UplinkRecord::CSV_HEADER));
+        assert_eq!(uplink(3, true, false).to_csv(), "0,0,3,0,true,false,0");
+    }
+}
@@ -0,0 +1,256 @@
+use std::{fs, path::Path, time::Duration};
+
+use lorawan_device::{communicator::Position, devices::adversary::AdversaryConfig, energy::EnergyProfile, propagation::PropagationConfig};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScenarioError {
+    #[error("cannot read the scenario: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid JSON scenario: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid TOML scenario: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("unsupported scenario file {0}, use .json or .toml")]
+    Extension(String),
+    #[error("invalid scenario: {0}")]
+    Invalid(String),
+}
+
+///Experiment passed to the simulation binary as a JSON or TOML file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    ///every random choice of the run comes from this seed
+    #[serde(default)]
+    pub seed: u64,
+    ///seconds of traffic, no uplink starts after this
+    pub duration: u64,
+    pub network: Network,
+    ///not used by trace replays
+    #[serde(default)]
+    pub device_groups: Vec<DeviceGroup>,
+    ///the in-process network controller of virtual networks and replays writes its frames to capture.pcap
+    #[serde(default)]
+    pub capture: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode")]
+pub enum Network {
+    ///Network controllers, gateways and devices in-process on a virtual medium, on virtual time
+    Virtual {
+        ///one gateway per position, connected to the network controllers round-robin
+        gateways: Vec<Position>,
+        ///every session lists all of them, each uplink goes through a consensus round among them
+        #[serde(default = "default_network_controllers")]
+        network_controllers: usize,
+        #[serde(default)]
+        propagation: PropagationConfig,
+        ///jammers and attackers, the receptions of the gateways are labelled with what they did
+        #[serde(default)]
+        adversaries: Vec<AdversaryConfig>,
+        ///join requests sent by every device before its uplinks, with none the devices are activated by personalization
+        #[serde(default)]
+        joins: usize,
+        #[serde(default = "default_join_traffic")]
+        join_traffic: Traffic,
+    },
+    ///Devices send their frames over UDP to the network controllers, in real time
+    Udp {
+        ///devices are assigned to the network controllers round-robin
+        network_controllers: Vec<String>,
+        port: u16,
+        ///DevEUI,JoinEUI,AppKey of the devices, one per line
+        devices_file: String,
+        #[serde(default)]
+        devices_to_skip: usize,
+        #[serde(default)]
+        starting_dev_nonce: u32,
+        ///join requests sent by every device before its uplinks
+        #[serde(default = "default_joins")]
+        joins: usize,
+        #[serde(default = "default_join_traffic")]
+        join_traffic: Traffic,
+    },
+    ///Reception trace like gateway.csv played back to an in-process network controller, in real time. Only the first duration
+    ///seconds of the trace are played and every DevAddr in it is provisioned
+    Replay {
+        trace: String,
+        ///1 plays the trace at the recorded pace, 60 plays a minute of it every second
+        #[serde(default = "default_speedup")]
+        speedup: f64,
+    },
+}
+
+fn default_network_controllers() -> usize {
+    1
+}
+
+fn default_speedup() -> f64 {
+    1.0
+}
+
+fn default_joins() -> usize {
+    1
+}
+
+fn default_join_traffic() -> Traffic {
+    Traffic::Periodic { period: 60.0, jitter: 180.0 }
+}
+
+///Devices sharing placement and traffic model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceGroup {
+    #[serde(default)]
+    pub name: String,
+    pub count: usize,
+    ///not used by the UDP network
+    #[serde(default)]
+    pub placement: Placement,
+    pub traffic: Traffic,
+    ///fraction of confirmed uplinks, between 0 and 1
+    #[serde(default)]
+    pub confirmed_ratio: f64,
+    ///bytes of application payload
+    #[serde(default = "default_payload_size")]
+    pub payload_size: usize,
+    ///currents and battery of the devices
+    #[serde(default)]
+    pub energy: EnergyProfile,
+}
+
+fn default_payload_size() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Placement {
+    ///uniform in a disc of radius meters
+    Disc { center: Position, radius: f32 },
+    ///the positions are assigned in order, cycling if there are fewer than the devices
+    Fixed { positions: Vec<Position> },
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::Disc { center: Position::default(), radius: 2000.0 }
+    }
+}
+
+impl Placement {
+    pub fn position(&self, index: usize, rng: &mut impl Rng) -> Position {
+        match self {
+            Placement::Disc { center, radius } => {
+                let angle = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
+                let distance = radius * rng.gen::<f32>().sqrt();
+                Position { x: center.x + distance * angle.cos(), y: center.y + distance * angle.sin(), z: center.z }
+            },
+            Placement::Fixed { positions } => positions[index % positions.len()],
+        }
+    }
+}
+
+///Time between uplinks, in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Traffic {
+    ///period plus a uniform jitter
+    Periodic {
+        period: f64,
+        #[serde(default)]
+        jitter: f64,
+    },
+    ///exponential inter-arrival times
+    Poisson { mean_interval: f64 },
+    ///bursts of burst_size uplinks spaced by interval, the bursts start with exponential inter-arrival times
+    Bursty {
+        burst_size: usize,
+        interval: f64,
+        mean_burst_interval: f64,
+    },
+}
+
+fn exponential(mean: f64, rng: &mut impl Rng) -> f64 {
+    -mean * (1.0 - rng.gen::<f64>()).ln()
+}
+
+impl Traffic {
+    ///Delay before the uplink after the first `sent` ones
+    pub fn delay(&self, sent: usize, rng: &mut impl Rng) -> Duration {
+        let seconds = match *self {
+            Traffic::Periodic { period, jitter } => period + rng.gen::<f64>() * jitter,
+            Traffic::Poisson { mean_interval } => exponential(mean_interval, rng),
+            Traffic::Bursty { burst_size, interval, mean_burst_interval } => {
+                if sent.is_multiple_of(burst_size) { exponential(mean_burst_interval, rng) } else { interval }
+            },
+        };
+        Duration::from_secs_f64(seconds)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            Traffic::Periodic { period, jitter } => period >= 0.0 && jitter >= 0.0 && period + jitter > 0.0,
+            Traffic::Poisson { mean_interval } => mean_interval > 0.0,
+            Traffic::Bursty { burst_size, interval, mean_burst_interval } => burst_size > 0 && interval >= 0.0 && mean_burst_interval > 0.0,
+        };
+        if valid { Ok(()) } else { Err(format!("{self:?} has invalid intervals")) }
+    }
+}
+
+impl Scenario {
+    ///The format is chosen by the extension of the file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ScenarioError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        let scenario: Scenario = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)?,
+            Some("toml") => toml::from_str(&content)?,
+            _ => return Err(ScenarioError::Extension(path.display().to_string())),
+        };
+        scenario.validate().map_err(ScenarioError::Invalid)?;
+        Ok(scenario)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.device_groups.is_empty() && !matches!(self.network, Network::Replay { .. }) {
+            return Err("no device groups".to_string());
+        }
+        match &self.network {
+            Network::Virtual { gateways, .. } if gateways.is_empty() => return Err("no gateways".to_string()),
+            Network::Virtual { gateways, network_controllers, .. } if *network_controllers == 0 || *network_controllers > gateways.len() => {
+                return Err("network_controllers must be between 1 and the number of gateways".to_string())
+            },
+            Network::Udp { network_controllers, .. } if network_controllers.is_empty() => return Err("no network controllers".to_string()),
+            Network::Udp { join_traffic, .. } | Network::Virtual { join_traffic, .. } => join_traffic.validate()?,
+            Network::Replay { speedup, .. } if speedup.is_nan() || *speedup <= 0.0 => return Err("speedup must be positive".to_string()),
+            Network::Replay { .. } => (),
+        }
+        if let Network::Virtual { adversaries, .. } = &self.network {
+            for adversary in adversaries.iter() {
+                adversary.validate()?;
+            }
+        }
+        for group in self.device_groups.iter() {
+            group.traffic.validate().map_err(|e| format!("group {}: {e}", group.name))?;
+            group.energy.validate().map_err(|e| format!("group {}: {e}", group.name))?;
+            if !(0.0..=1.0).contains(&group.confirmed_ratio) {
+                return Err(format!("group {}: confirmed_ratio must be between 0 and 1", group.name));
+            }
+            if matches!(&group.placement, Placement::Fixed { positions } if positions.is_empty()) {
+                return Err(format!("group {}: no positions", group.name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
+
+    pub fn devices(&self) -> usize {
+        self.device_groups.iter().map(|g| g.count).sum()
+    }
+}